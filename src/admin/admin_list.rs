@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex};
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+
+use super::Admin;
+
+#[derive(Clone)]
+pub struct AdminList<D> {
+    db: Arc<Mutex<D>>,
+    pub admins: Vec<Admin>,
+}
+
+pub type FlatAdminList = AdminList<FlatTable<String, String>>;
+
+impl FlatAdminList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        AdminList {
+            db: Arc::new(db),
+            admins: vec![],
+        }
+    }
+
+    pub fn get_by_id(&self, id: u128) -> Option<Admin> {
+        AdminList::get_by_attr::<FlatTable<String, String>, Admin>(&self.db, "id", id.to_string())
+    }
+
+    pub fn get_by_access_token(&self, access_token: &str) -> Option<Admin> {
+        AdminList::get_by_attr::<FlatTable<String, String>, Admin>(
+            &self.db,
+            "access_token",
+            access_token.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<Admin> {
+        AdminList::get_all::<FlatTable<String, String>, Admin>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Admin> {
+        AdminList::search_records::<FlatTable<String, String>, Admin>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        AdminList::count_records(&self.db, filter)
+    }
+}
+
+impl ModelAble<String, String> for FlatAdminList {}
+
+impl From<Record<String, String>> for Admin {
+    fn from(map: Record<String, String>) -> Self {
+        match (map.get("id"), map.get("name"), map.get("access_token")) {
+            (Some(id), Some(name), Some(access_token)) => Admin {
+                id: id.parse::<u128>().unwrap(),
+                name: name.clone(),
+                access_token: access_token.clone(),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_admin_by_access_token() {
+        let access_token = "admin-2";
+
+        let table = "\
+        id, name, access_token
+        1, Operator A, admin-1
+        2, Operator B, admin-2
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let admin_list = AdminList::new(db);
+        let admin = admin_list.get_by_access_token(access_token).unwrap();
+
+        assert_eq!(admin.id, 2);
+        assert_eq!(admin.name, "Operator B")
+    }
+}
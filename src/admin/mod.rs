@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::db::Record;
+
+pub mod admin_list;
+pub mod routes;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Admin {
+    pub id: u128,
+    pub name: String,
+    #[serde(skip)]
+    pub access_token: String,
+}
+
+impl Admin {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Admin {
+        Admin {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            name: attr.get("name").unwrap_or(&"default_admin").to_string(),
+            access_token: attr.get("access_token").unwrap_or(&"X-Y-Z").to_string(),
+        }
+    }
+}
+
+/// Rejects values that would break a flat table row.
+pub fn validate_text(field: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("`{field}` can't be empty"));
+    }
+    if value.contains(',') || value.contains('\n') {
+        return Err(format!("`{field}` can't contain commas or line breaks"));
+    }
+    Ok(())
+}
+
+/// Collects the fields that were set into a record of changes.
+pub fn changes(fields: Vec<(&str, Option<String>)>) -> Result<Record<String, String>, String> {
+    let mut record = Record::new();
+    for (field, value) in fields {
+        if let Some(value) = value {
+            validate_text(field, &value)?;
+            record.insert(field.to_string(), value.trim().to_string());
+        }
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_invalid_text() {
+        assert!(validate_text("name", "Subscriber A").is_ok());
+        assert!(validate_text("name", "  ").is_err());
+        assert!(validate_text("name", "A, B").is_err());
+    }
+
+    #[test]
+    fn collect_only_set_fields() {
+        let record = changes(vec![("name", Some(" New ".to_string())), ("slug", None)]).unwrap();
+
+        assert_eq!(
+            record,
+            Record::from([("name".to_string(), "New".to_string())])
+        );
+    }
+}
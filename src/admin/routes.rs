@@ -0,0 +1,2020 @@
+use chrono::Utc;
+use rocket::http::{ContentType, Status};
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::alert::{alert_list::FlatAlertList, Alert};
+use crate::biller;
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::cache;
+use crate::consumer::{consumer_list::FlatConsumerList, Consumer};
+use crate::db::{Filter, Query, Record};
+use crate::entitlement::Entitlements;
+use crate::errors::{error, ApiError};
+use crate::guards::{generate_key, AdminKey};
+use crate::idempotency::idempotency_list::FlatIdempotencyList;
+use crate::integrity::{self, Problem};
+use crate::job::job_list::FlatJobList;
+
+use super::{changes, validate_text};
+use crate::invoice::{self, BillingMonth, Invoice};
+use crate::ledger::{self, ledger_list::FlatLedgerList, Entry, EntryKind, Mismatch};
+use crate::overage::{overage_list::FlatOverageList, Overage};
+use crate::plan::{plan_list::FlatPlanList, BillingPeriod, OveragePolicy, Plan};
+use crate::pricing::{self, pricing_list::FlatPricingRuleList, PricingRule, RuleKind};
+use crate::product::{product_list::FlatProductList, Product};
+use crate::renewal::{renewal_list::FlatRenewalList, Renewal};
+use crate::request::{request_list::FlatRequestList, Request};
+use crate::router::table::Routing;
+use crate::sandbox::{self, SandboxRequestList};
+use crate::service::{service_list::FlatServiceList, Service, WebSocketBilling};
+use crate::subscriber::subscriber_list::{FlatSubscriberList, FlatSubscriptionList};
+use crate::subscriber::{format_date, parse_date, Subscriber, Subscription, SubscriptionStatus};
+use crate::transform::{self, transform_list::FlatTransformRuleList, TransformKind, TransformRule};
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+pub type AdminResult<T> = Result<T, Custom<Json<ApiError>>>;
+
+fn not_found(entity: &str, id: u128) -> Custom<Json<ApiError>> {
+    error(
+        Status::NotFound,
+        &format!("{entity} with id:{id} is not found"),
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+}
+
+/// Refuses to delete `entity` `id` while rows of other tables refer to it,
+/// as they would then refer to a record that is gone. `references` pairs
+/// what refers to it with whether any row does.
+fn check_unreferenced(entity: &str, id: u128, references: &[(&str, bool)]) -> AdminResult<()> {
+    match references.iter().find(|(_, referenced)| *referenced) {
+        Some((what, _)) => Err(error(
+            Status::Conflict,
+            &format!("{entity} with id:{id} {what}"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Whether any row of a table has `id` in `column`.
+fn refers(count: impl FnOnce(Option<&Filter>) -> usize, column: &str, id: u128) -> bool {
+    count(Some(&Filter::eq(column, &id.to_string()))) > 0
+}
+
+fn page_bounds(page: Option<usize>, per_page: Option<usize>) -> (usize, usize) {
+    (
+        page.unwrap_or(1).max(1),
+        per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE),
+    )
+}
+
+/// Slices `items` into the 1-based `page` of `per_page` items.
+pub fn paginate<T>(items: Vec<T>, page: Option<usize>, per_page: Option<usize>) -> Page<T> {
+    let (page, per_page) = page_bounds(page, per_page);
+    let total = items.len();
+
+    Page {
+        items: items
+            .into_iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .collect(),
+        page,
+        per_page,
+        total,
+    }
+}
+
+/// Runs `search` for the 1-based `page` of `per_page` records matching
+/// `filter`, only converting the records on that page.
+fn search_page<T>(
+    filter: Filter,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    search: impl Fn(&Query) -> Vec<T>,
+    count: impl Fn(Option<&Filter>) -> usize,
+) -> Page<T> {
+    let (page, per_page) = page_bounds(page, per_page);
+    let query = Query::default()
+        .filter(filter.clone())
+        .offset((page - 1) * per_page)
+        .limit(per_page);
+    Page {
+        items: search(&query),
+        page,
+        per_page,
+        total: count(Some(&filter)),
+    }
+}
+
+/// Matches records whose attributes equal the given values, skipping those
+/// without a value.
+fn equal_to(conditions: &[(&str, Option<String>)]) -> Filter {
+    Filter::All(
+        conditions
+            .iter()
+            .filter_map(|(attr, value)| Some(Filter::eq(attr, value.as_deref()?)))
+            .collect(),
+    )
+}
+
+fn unprocessable(message: String) -> Custom<Json<ApiError>> {
+    error(Status::UnprocessableEntity, &message)
+}
+
+fn check_status(status: Option<u8>) -> AdminResult<()> {
+    match status.map(SubscriptionStatus::from_u8) {
+        Some(None) => Err(unprocessable("`status` is not a valid status".to_string())),
+        _ => Ok(()),
+    }
+}
+
+fn check_date(field: &str, value: &Option<String>) -> AdminResult<()> {
+    match value.as_deref().map(parse_date) {
+        Some(None) => Err(unprocessable(format!(
+            "`{field}` should look like `2024-01-31 00:00:00`"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Plans
+
+#[derive(Debug, Deserialize)]
+pub struct NewPlan {
+    pub name: String,
+    pub price: u128,
+    pub quota: u128,
+    pub period: String,
+    pub overage_policy: Option<String>,
+    #[serde(default)]
+    pub overage_price: u128,
+    #[serde(default)]
+    pub overage_cap: u128,
+    /// Grants such as `product_a` or `product_b/service_b/v1.0.0`, every
+    /// product if missing.
+    pub entitlements: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlanChanges {
+    pub name: Option<String>,
+    pub price: Option<u128>,
+    pub quota: Option<u128>,
+    pub period: Option<String>,
+    pub overage_policy: Option<String>,
+    pub overage_price: Option<u128>,
+    pub overage_cap: Option<u128>,
+    pub entitlements: Option<Vec<String>>,
+}
+
+fn check_period(period: &str) -> AdminResult<BillingPeriod> {
+    BillingPeriod::parse(period.trim())
+        .ok_or_else(|| unprocessable("`period` should be `monthly` or `annual`".to_string()))
+}
+
+fn check_entitlements(field: &str, grants: &Option<Vec<String>>) -> AdminResult<Entitlements> {
+    match grants {
+        Some(grants) if grants.is_empty() => Err(unprocessable(format!(
+            "`{field}` should list at least one grant"
+        ))),
+        Some(grants) => Entitlements::from_list(grants.iter().map(String::as_str))
+            .map_err(|e| unprocessable(format!("`{field}`: {e}"))),
+        None => Ok(Entitlements::all()),
+    }
+}
+
+fn check_overage_policy(policy: &str) -> AdminResult<OveragePolicy> {
+    OveragePolicy::parse(policy.trim()).ok_or_else(|| {
+        unprocessable("`overage_policy` should be `hard`, `soft` or `capped`".to_string())
+    })
+}
+
+#[get("/plans?<page>&<per_page>")]
+fn list_plans(
+    _admin: AdminKey,
+    plans: &State<FlatPlanList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Plan>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| plans.filter(query),
+        |filter| plans.count(filter),
+    ))
+}
+
+#[get("/plans/<id>")]
+fn get_plan(_admin: AdminKey, plans: &State<FlatPlanList>, id: u128) -> AdminResult<Json<Plan>> {
+    plans
+        .get_by_id(id)
+        .map(Json)
+        .ok_or_else(|| not_found("Plan", id))
+}
+
+#[post("/plans", data = "<input>")]
+fn create_plan(
+    _admin: AdminKey,
+    plans: &State<FlatPlanList>,
+    input: Json<NewPlan>,
+) -> AdminResult<Custom<Json<Plan>>> {
+    let input = input.into_inner();
+    let period = check_period(&input.period)?;
+    let overage_policy = match &input.overage_policy {
+        Some(policy) => check_overage_policy(policy)?,
+        None => OveragePolicy::Hard,
+    };
+    let entitlements = check_entitlements("entitlements", &input.entitlements)?;
+    let record = changes(vec![
+        ("name", Some(input.name)),
+        ("price", Some(input.price.to_string())),
+        ("quota", Some(input.quota.to_string())),
+        ("period", Some(period.as_str().to_string())),
+        ("overage_policy", Some(overage_policy.as_str().to_string())),
+        ("overage_price", Some(input.overage_price.to_string())),
+        ("overage_cap", Some(input.overage_cap.to_string())),
+        ("entitlements", Some(entitlements.to_string())),
+    ])
+    .map_err(unprocessable)?;
+
+    Ok(Custom(Status::Created, Json(plans.create(record))))
+}
+
+#[put("/plans/<id>", data = "<input>")]
+fn update_plan(
+    _admin: AdminKey,
+    plans: &State<FlatPlanList>,
+    id: u128,
+    input: Json<PlanChanges>,
+) -> AdminResult<Json<Plan>> {
+    let input = input.into_inner();
+    if let Some(period) = &input.period {
+        check_period(period)?;
+    }
+    if let Some(policy) = &input.overage_policy {
+        check_overage_policy(policy)?;
+    }
+    let entitlements = match &input.entitlements {
+        Some(_) => Some(check_entitlements("entitlements", &input.entitlements)?.to_string()),
+        None => None,
+    };
+    let changes = changes(vec![
+        ("name", input.name),
+        ("price", input.price.map(|v| v.to_string())),
+        ("quota", input.quota.map(|v| v.to_string())),
+        ("period", input.period),
+        ("overage_policy", input.overage_policy),
+        ("overage_price", input.overage_price.map(|v| v.to_string())),
+        ("overage_cap", input.overage_cap.map(|v| v.to_string())),
+        ("entitlements", entitlements),
+    ])
+    .map_err(unprocessable)?;
+
+    plans
+        .update(id, changes)
+        .map(Json)
+        .ok_or_else(|| not_found("Plan", id))
+}
+
+#[delete("/plans/<id>")]
+fn delete_plan(
+    _admin: AdminKey,
+    plans: &State<FlatPlanList>,
+    subscriptions: &State<FlatSubscriptionList>,
+    renewals: &State<FlatRenewalList>,
+    id: u128,
+) -> AdminResult<Status> {
+    check_unreferenced(
+        "Plan",
+        id,
+        &[
+            (
+                "is used by a subscription",
+                subscriptions.get_by_plan(id).is_some(),
+            ),
+            (
+                "has renewals",
+                refers(|filter| renewals.count(filter), "plan", id),
+            ),
+        ],
+    )?;
+    plans
+        .delete(id)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| not_found("Plan", id))
+}
+
+// Subscriptions
+
+/// A subscription to `plan`, whose name, price and quota default to the plan's.
+#[derive(Debug, Deserialize)]
+pub struct NewSubscription {
+    pub plan: u128,
+    pub name: Option<String>,
+    pub status: Option<u8>,
+    pub price: Option<u128>,
+    pub quota: Option<u128>,
+    pub expiry_date: Option<String>,
+    #[serde(default)]
+    pub auto_renew: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionChanges {
+    pub plan: Option<u128>,
+    pub name: Option<String>,
+    pub status: Option<u8>,
+    pub price: Option<u128>,
+    pub quota: Option<u128>,
+    pub expiry_date: Option<String>,
+    pub auto_renew: Option<bool>,
+}
+
+fn check_plan(plans: &FlatPlanList, plan_id: u128) -> AdminResult<Plan> {
+    plans
+        .get_by_id(plan_id)
+        .ok_or_else(|| unprocessable(format!("Plan with id:{plan_id} does not exist")))
+}
+
+#[get("/subscriptions?<page>&<per_page>")]
+fn list_subscriptions(
+    _admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Subscription>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| subscriptions.filter(query),
+        |filter| subscriptions.count(filter),
+    ))
+}
+
+#[get("/subscriptions/<id>")]
+fn get_subscription(
+    _admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    id: u128,
+) -> AdminResult<Json<Subscription>> {
+    subscriptions
+        .get_by_id(id)
+        .map(Json)
+        .ok_or_else(|| not_found("Subscription", id))
+}
+
+#[post("/subscriptions", data = "<input>")]
+fn create_subscription(
+    admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    plans: &State<FlatPlanList>,
+    ledger: &State<FlatLedgerList>,
+    input: Json<NewSubscription>,
+) -> AdminResult<Custom<Json<Subscription>>> {
+    let input = input.into_inner();
+    let plan = check_plan(plans, input.plan)?;
+    check_status(input.status)?;
+    check_date("expiry_date", &input.expiry_date)?;
+    let expiry_date = input
+        .expiry_date
+        .unwrap_or_else(|| format_date(&plan.period.next(Utc::now().naive_utc())));
+
+    let record = changes(vec![
+        ("plan", Some(plan.id.to_string())),
+        ("name", Some(input.name.unwrap_or(plan.name))),
+        (
+            "status",
+            Some(
+                input
+                    .status
+                    .unwrap_or(SubscriptionStatus::Active as u8)
+                    .to_string(),
+            ),
+        ),
+        ("price", Some(input.price.unwrap_or(plan.price).to_string())),
+        ("quota", Some("0".to_string())),
+        ("expiry_date", Some(expiry_date)),
+        ("auto_renew", Some(input.auto_renew.to_string())),
+    ])
+    .map_err(unprocessable)?;
+
+    // the opening quota goes through the ledger like any other credit
+    let subscription = subscriptions.create(record);
+    set_quota(
+        subscriptions,
+        ledger,
+        &subscription,
+        input.quota.unwrap_or(plan.quota),
+        EntryKind::Credit,
+        &format!("opening balance by {}", admin.0.name),
+        &admin.0.name,
+    )?;
+
+    Ok(Custom(
+        Status::Created,
+        Json(subscriptions.get_by_id(subscription.id).unwrap()),
+    ))
+}
+
+#[put("/subscriptions/<id>", data = "<input>")]
+fn update_subscription(
+    admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    plans: &State<FlatPlanList>,
+    ledger: &State<FlatLedgerList>,
+    id: u128,
+    input: Json<SubscriptionChanges>,
+) -> AdminResult<Json<Subscription>> {
+    let input = input.into_inner();
+    let subscription = subscriptions
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Subscription", id))?;
+    if let Some(plan_id) = input.plan {
+        check_plan(plans, plan_id)?;
+    }
+    check_status(input.status)?;
+    check_date("expiry_date", &input.expiry_date)?;
+    let changes = changes(vec![
+        ("plan", input.plan.map(|v| v.to_string())),
+        ("name", input.name),
+        ("status", input.status.map(|v| v.to_string())),
+        ("price", input.price.map(|v| v.to_string())),
+        ("expiry_date", input.expiry_date),
+        ("auto_renew", input.auto_renew.map(|v| v.to_string())),
+    ])
+    .map_err(unprocessable)?;
+
+    if let Some(quota) = input.quota {
+        set_quota(
+            subscriptions,
+            ledger,
+            &subscription,
+            quota,
+            EntryKind::Adjustment,
+            &format!("quota set by {}", admin.0.name),
+            &admin.0.name,
+        )?;
+    }
+
+    subscriptions
+        .update(id, changes)
+        .map(Json)
+        .ok_or_else(|| not_found("Subscription", id))
+}
+
+fn set_quota(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    subscription: &Subscription,
+    quota: u128,
+    kind: EntryKind,
+    reason: &str,
+    operator: &str,
+) -> AdminResult<()> {
+    biller::reset_quota(
+        subscriptions,
+        ledger,
+        subscription,
+        quota,
+        kind,
+        reason,
+        Some(operator),
+        Utc::now().naive_utc(),
+    )
+    .map(|_| ())
+    .map_err(|e| error(Status::Conflict, &e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewRefund {
+    /// Ids of the requests whose charges are given back.
+    pub requests: Vec<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewAdjustment {
+    /// Tokens to credit, or to debit when negative.
+    pub amount: i128,
+    pub reason: String,
+}
+
+fn billing_error(e: biller::BillingError) -> Custom<Json<ApiError>> {
+    match e {
+        biller::BillingError::NotRefundable { .. } => unprocessable(e.to_string()),
+        _ => error(Status::Conflict, &e.to_string()),
+    }
+}
+
+/// Gives back the tokens charged for some requests, e.g. after an outage.
+#[post("/subscriptions/<id>/refunds", data = "<input>")]
+fn refund_requests(
+    admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    ledger: &State<FlatLedgerList>,
+    overages: &State<FlatOverageList>,
+    id: u128,
+    input: Json<NewRefund>,
+) -> AdminResult<Custom<Json<Vec<Entry>>>> {
+    subscriptions
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Subscription", id))?;
+    validate_text("reason", &input.reason).map_err(unprocessable)?;
+    if input.requests.is_empty() {
+        return Err(unprocessable(
+            "`requests` should list at least one request".to_string(),
+        ));
+    }
+
+    biller::refund(
+        subscriptions,
+        ledger,
+        overages,
+        id,
+        &input.requests,
+        input.reason.trim(),
+        &admin.0.name,
+        Utc::now().naive_utc(),
+    )
+    .map(|entries| Custom(Status::Created, Json(entries)))
+    .map_err(billing_error)
+}
+
+/// Credits or debits tokens outside of any request.
+#[post("/subscriptions/<id>/adjustments", data = "<input>")]
+fn adjust_quota(
+    admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    ledger: &State<FlatLedgerList>,
+    id: u128,
+    input: Json<NewAdjustment>,
+) -> AdminResult<Custom<Json<Entry>>> {
+    let subscription = subscriptions
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Subscription", id))?;
+    validate_text("reason", &input.reason).map_err(unprocessable)?;
+    if input.amount == 0 {
+        return Err(unprocessable("`amount` can't be zero".to_string()));
+    }
+
+    biller::adjust(
+        subscriptions,
+        ledger,
+        &subscription,
+        input.amount,
+        input.reason.trim(),
+        &admin.0.name,
+        Utc::now().naive_utc(),
+    )
+    .map(|entry| Custom(Status::Created, Json(entry)))
+    .map_err(billing_error)
+}
+
+#[delete("/subscriptions/<id>")]
+#[allow(clippy::too_many_arguments)]
+fn delete_subscription(
+    _admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    subscribers: &State<FlatSubscriberList>,
+    ledger: &State<FlatLedgerList>,
+    renewals: &State<FlatRenewalList>,
+    overages: &State<FlatOverageList>,
+    alerts: &State<FlatAlertList>,
+    id: u128,
+) -> AdminResult<Status> {
+    check_unreferenced(
+        "Subscription",
+        id,
+        &[
+            (
+                "is used by a subscriber",
+                subscribers.get_by_subscription(id).is_some(),
+            ),
+            (
+                "has ledger entries",
+                refers(|filter| ledger.count(filter), "subscription", id),
+            ),
+            (
+                "has renewals",
+                refers(|filter| renewals.count(filter), "subscription", id),
+            ),
+            (
+                "has overages",
+                refers(|filter| overages.count(filter), "subscription", id),
+            ),
+            (
+                "has alerts",
+                refers(|filter| alerts.count(filter), "subscription", id),
+            ),
+        ],
+    )?;
+    subscriptions
+        .delete(id)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| not_found("Subscription", id))
+}
+
+// Subscribers
+
+#[derive(Debug, Deserialize)]
+pub struct NewSubscriber {
+    pub name: String,
+    pub subscription: u128,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriberChanges {
+    pub name: Option<String>,
+    pub subscription: Option<u128>,
+}
+
+fn check_subscription(
+    subscriptions: &FlatSubscriptionList,
+    subscription_id: u128,
+) -> AdminResult<()> {
+    match subscriptions.get_by_id(subscription_id) {
+        Some(_) => Ok(()),
+        None => Err(unprocessable(format!(
+            "Subscription with id:{subscription_id} does not exist"
+        ))),
+    }
+}
+
+#[get("/subscribers?<page>&<per_page>")]
+fn list_subscribers(
+    _admin: AdminKey,
+    subscribers: &State<FlatSubscriberList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Subscriber>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| subscribers.filter(query),
+        |filter| subscribers.count(filter),
+    ))
+}
+
+#[get("/subscribers/<id>")]
+fn get_subscriber(
+    _admin: AdminKey,
+    subscribers: &State<FlatSubscriberList>,
+    id: u128,
+) -> AdminResult<Json<Subscriber>> {
+    subscribers
+        .get_by_id(id)
+        .map(Json)
+        .ok_or_else(|| not_found("Subscriber", id))
+}
+
+#[post("/subscribers", data = "<input>")]
+fn create_subscriber(
+    _admin: AdminKey,
+    subscribers: &State<FlatSubscriberList>,
+    subscriptions: &State<FlatSubscriptionList>,
+    input: Json<NewSubscriber>,
+) -> AdminResult<Custom<Json<Subscriber>>> {
+    let input = input.into_inner();
+    validate_text("name", &input.name).map_err(unprocessable)?;
+    check_subscription(subscriptions, input.subscription)?;
+
+    let subscriber = subscribers.create(
+        input.name.trim().to_string(),
+        generate_key("sub"),
+        input.subscription,
+    );
+    Ok(Custom(Status::Created, Json(subscriber)))
+}
+
+#[put("/subscribers/<id>", data = "<input>")]
+fn update_subscriber(
+    _admin: AdminKey,
+    subscribers: &State<FlatSubscriberList>,
+    subscriptions: &State<FlatSubscriptionList>,
+    id: u128,
+    input: Json<SubscriberChanges>,
+) -> AdminResult<Json<Subscriber>> {
+    let input = input.into_inner();
+    if let Some(subscription_id) = input.subscription {
+        check_subscription(subscriptions, subscription_id)?;
+    }
+    let changes = changes(vec![
+        ("name", input.name),
+        ("subscription", input.subscription.map(|v| v.to_string())),
+    ])
+    .map_err(unprocessable)?;
+
+    subscribers
+        .update(id, changes)
+        .map(Json)
+        .ok_or_else(|| not_found("Subscriber", id))
+}
+
+#[delete("/subscribers/<id>")]
+fn delete_subscriber(
+    _admin: AdminKey,
+    subscribers: &State<FlatSubscriberList>,
+    consumers: &State<FlatConsumerList>,
+    alerts: &State<FlatAlertList>,
+    id: u128,
+) -> AdminResult<Status> {
+    check_unreferenced(
+        "Subscriber",
+        id,
+        &[
+            (
+                "still has consumers",
+                consumers.get_by_subscriber(id).is_some(),
+            ),
+            (
+                "has alerts",
+                refers(|filter| alerts.count(filter), "subscriber", id),
+            ),
+        ],
+    )?;
+    subscribers
+        .delete(id)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| not_found("Subscriber", id))
+}
+
+// Consumers
+
+#[derive(Debug, Deserialize)]
+pub struct NewConsumer {
+    pub access_token: String,
+    pub subscriber: u128,
+    /// Narrows the plan's entitlements, which it keeps whole if missing.
+    pub scopes: Option<Vec<String>>,
+    /// Tokens it may spend per period, unlimited if missing.
+    pub budget: Option<u128>,
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConsumerChanges {
+    pub access_token: Option<String>,
+    pub subscriber: Option<u128>,
+    pub scopes: Option<Vec<String>>,
+    pub budget: Option<u128>,
+    pub sandbox: Option<bool>,
+}
+
+fn check_subscriber(subscribers: &FlatSubscriberList, subscriber_id: u128) -> AdminResult<()> {
+    match subscribers.get_by_id(subscriber_id) {
+        Some(_) => Ok(()),
+        None => Err(unprocessable(format!(
+            "Subscriber with id:{subscriber_id} does not exist"
+        ))),
+    }
+}
+
+fn check_access_token(
+    consumers: &FlatConsumerList,
+    access_token: &str,
+    consumer_id: Option<u128>,
+) -> AdminResult<()> {
+    match consumers.get_by_access_token(access_token.trim()) {
+        Some(consumer) if Some(consumer.id) != consumer_id => Err(error(
+            Status::Conflict,
+            "`access_token` is already used by another consumer",
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[get("/consumers?<page>&<per_page>")]
+fn list_consumers(
+    _admin: AdminKey,
+    consumers: &State<FlatConsumerList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Consumer>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| consumers.filter(query),
+        |filter| consumers.count(filter),
+    ))
+}
+
+#[get("/consumers/<id>")]
+fn get_consumer(
+    _admin: AdminKey,
+    consumers: &State<FlatConsumerList>,
+    id: u128,
+) -> AdminResult<Json<Consumer>> {
+    consumers
+        .get_by_id(id)
+        .map(Json)
+        .ok_or_else(|| not_found("Consumer", id))
+}
+
+#[post("/consumers", data = "<input>")]
+fn create_consumer(
+    _admin: AdminKey,
+    consumers: &State<FlatConsumerList>,
+    subscribers: &State<FlatSubscriberList>,
+    input: Json<NewConsumer>,
+) -> AdminResult<Custom<Json<Consumer>>> {
+    let input = input.into_inner();
+    validate_text("access_token", &input.access_token).map_err(unprocessable)?;
+    check_subscriber(subscribers, input.subscriber)?;
+    check_access_token(consumers, &input.access_token, None)?;
+    let scopes = check_entitlements("scopes", &input.scopes)?;
+
+    let consumer = consumers.create(
+        input.access_token.trim().to_string(),
+        input.subscriber,
+        &scopes,
+        input.budget,
+        input.sandbox,
+    );
+    Ok(Custom(Status::Created, Json(consumer)))
+}
+
+#[put("/consumers/<id>", data = "<input>")]
+fn update_consumer(
+    _admin: AdminKey,
+    consumers: &State<FlatConsumerList>,
+    subscribers: &State<FlatSubscriberList>,
+    id: u128,
+    input: Json<ConsumerChanges>,
+) -> AdminResult<Json<Consumer>> {
+    let input = input.into_inner();
+    if let Some(subscriber_id) = input.subscriber {
+        check_subscriber(subscribers, subscriber_id)?;
+    }
+    if let Some(access_token) = &input.access_token {
+        check_access_token(consumers, access_token, Some(id))?;
+    }
+    let scopes = match &input.scopes {
+        Some(_) => Some(check_entitlements("scopes", &input.scopes)?.to_string()),
+        None => None,
+    };
+    let changes = changes(vec![
+        ("access_token", input.access_token),
+        ("subscriber", input.subscriber.map(|v| v.to_string())),
+        ("scopes", scopes),
+        ("budget", input.budget.map(|v| v.to_string())),
+        ("sandbox", input.sandbox.map(|v| v.to_string())),
+    ])
+    .map_err(unprocessable)?;
+
+    consumers
+        .update(id, changes)
+        .map(Json)
+        .ok_or_else(|| not_found("Consumer", id))
+}
+
+#[delete("/consumers/<id>")]
+#[allow(clippy::too_many_arguments)]
+fn delete_consumer(
+    _admin: AdminKey,
+    consumers: &State<FlatConsumerList>,
+    requests: &State<FlatRequestList>,
+    sandbox_requests: &State<SandboxRequestList>,
+    budget_usages: &State<FlatBudgetUsageList>,
+    idempotency_keys: &State<FlatIdempotencyList>,
+    jobs: &State<FlatJobList>,
+    id: u128,
+) -> AdminResult<Status> {
+    check_unreferenced(
+        "Consumer",
+        id,
+        &[
+            (
+                "has logged requests",
+                requests.get_by_consumer(id).is_some()
+                    || sandbox_requests.get_by_consumer(id).is_some(),
+            ),
+            (
+                "has budget usage",
+                refers(|filter| budget_usages.count(filter), "consumer", id),
+            ),
+            (
+                "has idempotency keys",
+                refers(|filter| idempotency_keys.count(filter), "consumer", id),
+            ),
+            (
+                "has jobs",
+                refers(|filter| jobs.count(filter), "consumer", id),
+            ),
+        ],
+    )?;
+    consumers
+        .delete(id)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| not_found("Consumer", id))
+}
+
+// Products
+
+#[derive(Debug, Deserialize)]
+pub struct NewProduct {
+    pub slug: String,
+    #[serde(default)]
+    pub requests: u128,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProductChanges {
+    pub slug: Option<String>,
+    pub requests: Option<u128>,
+}
+
+#[get("/products?<page>&<per_page>")]
+fn list_products(
+    _admin: AdminKey,
+    products: &State<FlatProductList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Product>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| products.filter(query),
+        |filter| products.count(filter),
+    ))
+}
+
+#[get("/products/<id>")]
+fn get_product(
+    _admin: AdminKey,
+    products: &State<FlatProductList>,
+    id: u128,
+) -> AdminResult<Json<Product>> {
+    products
+        .get_by_id(id)
+        .map(Json)
+        .ok_or_else(|| not_found("Product", id))
+}
+
+#[post("/products", data = "<input>")]
+fn create_product(
+    _admin: AdminKey,
+    products: &State<FlatProductList>,
+    services: &State<FlatServiceList>,
+    routing: &State<Routing>,
+    input: Json<NewProduct>,
+) -> AdminResult<Custom<Json<Product>>> {
+    let input = input.into_inner();
+    validate_text("slug", &input.slug).map_err(unprocessable)?;
+    if products.get_by_slug(input.slug.trim()).is_some() {
+        return Err(error(Status::Conflict, "`slug` is already used"));
+    }
+
+    let product = products.create(input.slug.trim().to_string(), input.requests);
+    routing.reload_logged(products, services, "admin change");
+    Ok(Custom(Status::Created, Json(product)))
+}
+
+#[put("/products/<id>", data = "<input>")]
+fn update_product(
+    _admin: AdminKey,
+    products: &State<FlatProductList>,
+    services: &State<FlatServiceList>,
+    routing: &State<Routing>,
+    id: u128,
+    input: Json<ProductChanges>,
+) -> AdminResult<Json<Product>> {
+    let input = input.into_inner();
+    if let Some(existing) = input
+        .slug
+        .as_ref()
+        .and_then(|s| products.get_by_slug(s.trim()))
+    {
+        if existing.id != id {
+            return Err(error(Status::Conflict, "`slug` is already used"));
+        }
+    }
+    let changes = changes(vec![
+        ("slug", input.slug),
+        ("requests", input.requests.map(|v| v.to_string())),
+    ])
+    .map_err(unprocessable)?;
+
+    let product = products
+        .update(id, changes)
+        .ok_or_else(|| not_found("Product", id))?;
+    routing.reload_logged(products, services, "admin change");
+    Ok(Json(product))
+}
+
+#[delete("/products/<id>")]
+fn delete_product(
+    _admin: AdminKey,
+    products: &State<FlatProductList>,
+    services: &State<FlatServiceList>,
+    routing: &State<Routing>,
+    id: u128,
+) -> AdminResult<Status> {
+    if services.get_by_product(id).is_some() {
+        return Err(error(
+            Status::Conflict,
+            &format!("Product with id:{id} still has services"),
+        ));
+    }
+    products
+        .delete(id)
+        .ok_or_else(|| not_found("Product", id))?;
+    routing.reload_logged(products, services, "admin change");
+    Ok(Status::NoContent)
+}
+
+// Services
+
+#[derive(Debug, Deserialize)]
+pub struct NewService {
+    pub name: String,
+    pub slug: String,
+    pub version: String,
+    pub status: u32,
+    pub base_url: String,
+    pub price: u128,
+    #[serde(default)]
+    pub requests: u128,
+    pub product: u128,
+    /// Serves sandbox keys, which get fixtures if it is missing.
+    pub sandbox_url: Option<String>,
+    /// `connection` by default.
+    pub websocket_billing: Option<String>,
+    /// Leaves `GET` calls uncached if missing or `0`.
+    pub cache_ttl: Option<u64>,
+    pub cache_headers: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServiceChanges {
+    pub name: Option<String>,
+    pub slug: Option<String>,
+    pub version: Option<String>,
+    pub status: Option<u32>,
+    pub base_url: Option<String>,
+    pub price: Option<u128>,
+    pub requests: Option<u128>,
+    pub product: Option<u128>,
+    /// An empty string goes back to fixtures.
+    pub sandbox_url: Option<String>,
+    pub websocket_billing: Option<String>,
+    /// `0` stops caching.
+    pub cache_ttl: Option<u64>,
+    pub cache_headers: Option<Vec<String>>,
+}
+
+/// Adds a checked `sandbox_url` to `record`, where an empty one clears it.
+fn set_sandbox_url(
+    record: &mut Record<String, String>,
+    sandbox_url: Option<String>,
+) -> AdminResult<()> {
+    if let Some(url) = sandbox_url {
+        if !url.trim().is_empty() {
+            validate_text("sandbox_url", &url).map_err(unprocessable)?;
+        }
+        sandbox::check_url(&url).map_err(unprocessable)?;
+        record.insert("sandbox_url".to_string(), url.trim().to_string());
+    }
+    Ok(())
+}
+
+/// Adds the service's cache settings to `record`, where a `cache_ttl` of `0`
+/// clears it.
+fn set_cache(
+    record: &mut Record<String, String>,
+    cache_ttl: Option<u64>,
+    cache_headers: Option<Vec<String>>,
+) -> AdminResult<()> {
+    if let Some(ttl) = cache_ttl {
+        let ttl = match ttl {
+            0 => String::new(),
+            ttl => ttl.to_string(),
+        };
+        record.insert("cache_ttl".to_string(), ttl);
+    }
+    if let Some(headers) = cache_headers {
+        let headers = cache::parse_headers(&headers.join(" ")).map_err(unprocessable)?;
+        record.insert("cache_headers".to_string(), headers.join(" "));
+    }
+    Ok(())
+}
+
+fn check_websocket_billing(billing: &str) -> AdminResult<WebSocketBilling> {
+    WebSocketBilling::parse(billing.trim()).ok_or_else(|| {
+        unprocessable(
+            "`websocket_billing` should be `connection`, `message` or `minute`".to_string(),
+        )
+    })
+}
+
+fn check_product(products: &FlatProductList, product_id: u128) -> AdminResult<()> {
+    match products.get_by_id(product_id) {
+        Some(_) => Ok(()),
+        None => Err(unprocessable(format!(
+            "Product with id:{product_id} does not exist"
+        ))),
+    }
+}
+
+#[get("/services?<page>&<per_page>")]
+fn list_services(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Service>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| services.filter(query),
+        |filter| services.count(filter),
+    ))
+}
+
+#[get("/services/<id>")]
+fn get_service(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    id: u128,
+) -> AdminResult<Json<Service>> {
+    services
+        .get_by_id(id)
+        .map(Json)
+        .ok_or_else(|| not_found("Service", id))
+}
+
+#[post("/services", data = "<input>")]
+fn create_service(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    products: &State<FlatProductList>,
+    routing: &State<Routing>,
+    input: Json<NewService>,
+) -> AdminResult<Custom<Json<Service>>> {
+    let input = input.into_inner();
+    check_product(products, input.product)?;
+    let websocket_billing = match &input.websocket_billing {
+        Some(billing) => check_websocket_billing(billing)?,
+        None => WebSocketBilling::Connection,
+    };
+    let mut record = changes(vec![
+        ("name", Some(input.name)),
+        ("slug", Some(input.slug)),
+        ("version", Some(input.version)),
+        ("status", Some(input.status.to_string())),
+        ("base_url", Some(input.base_url)),
+        ("price", Some(input.price.to_string())),
+        ("requests", Some(input.requests.to_string())),
+        ("product", Some(input.product.to_string())),
+        (
+            "websocket_billing",
+            Some(websocket_billing.as_str().to_string()),
+        ),
+    ])
+    .map_err(unprocessable)?;
+    set_sandbox_url(&mut record, input.sandbox_url)?;
+    set_cache(&mut record, input.cache_ttl, input.cache_headers)?;
+
+    let service = services.create(record);
+    routing.reload_logged(products, services, "admin change");
+    Ok(Custom(Status::Created, Json(service)))
+}
+
+#[put("/services/<id>", data = "<input>")]
+fn update_service(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    products: &State<FlatProductList>,
+    routing: &State<Routing>,
+    id: u128,
+    input: Json<ServiceChanges>,
+) -> AdminResult<Json<Service>> {
+    let input = input.into_inner();
+    if let Some(product_id) = input.product {
+        check_product(products, product_id)?;
+    }
+    let websocket_billing = match &input.websocket_billing {
+        Some(billing) => Some(check_websocket_billing(billing)?.as_str().to_string()),
+        None => None,
+    };
+    let mut changes = changes(vec![
+        ("name", input.name),
+        ("slug", input.slug),
+        ("version", input.version),
+        ("status", input.status.map(|v| v.to_string())),
+        ("base_url", input.base_url),
+        ("price", input.price.map(|v| v.to_string())),
+        ("requests", input.requests.map(|v| v.to_string())),
+        ("product", input.product.map(|v| v.to_string())),
+        ("websocket_billing", websocket_billing),
+    ])
+    .map_err(unprocessable)?;
+    set_sandbox_url(&mut changes, input.sandbox_url)?;
+    set_cache(&mut changes, input.cache_ttl, input.cache_headers)?;
+
+    let service = services
+        .update(id, changes)
+        .ok_or_else(|| not_found("Service", id))?;
+    routing.reload_logged(products, services, "admin change");
+    Ok(Json(service))
+}
+
+#[delete("/services/<id>")]
+#[allow(clippy::too_many_arguments)]
+fn delete_service(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    products: &State<FlatProductList>,
+    requests: &State<FlatRequestList>,
+    sandbox_requests: &State<SandboxRequestList>,
+    pricing_rules: &State<FlatPricingRuleList>,
+    transform_rules: &State<FlatTransformRuleList>,
+    jobs: &State<FlatJobList>,
+    routing: &State<Routing>,
+    id: u128,
+) -> AdminResult<Status> {
+    check_unreferenced(
+        "Service",
+        id,
+        &[
+            (
+                "has logged requests",
+                requests.get_by_service(id).is_some()
+                    || sandbox_requests.get_by_service(id).is_some(),
+            ),
+            (
+                "has pricing rules",
+                !pricing_rules.list_by_service(id).is_empty(),
+            ),
+            (
+                "has transform rules",
+                !transform_rules.list_by_service(id).is_empty(),
+            ),
+            (
+                "has jobs",
+                refers(|filter| jobs.count(filter), "service", id),
+            ),
+        ],
+    )?;
+    services
+        .delete(id)
+        .ok_or_else(|| not_found("Service", id))?;
+    routing.reload_logged(products, services, "admin change");
+    Ok(Status::NoContent)
+}
+
+// Routing
+
+#[derive(Debug, Serialize)]
+pub struct Reloaded {
+    pub routes: usize,
+}
+
+/// Lists the services the gateway currently routes to.
+#[get("/routes")]
+fn list_routes(_admin: AdminKey, routing: &State<Routing>) -> Json<Vec<Service>> {
+    Json(routing.current().services().into_iter().cloned().collect())
+}
+
+/// Rebuilds the routing table from the tables on disk, keeping the current
+/// one if they are invalid.
+#[post("/routes/reload")]
+fn reload_routes(
+    _admin: AdminKey,
+    products: &State<FlatProductList>,
+    services: &State<FlatServiceList>,
+    routing: &State<Routing>,
+) -> AdminResult<Json<Reloaded>> {
+    routing
+        .reload(products, services)
+        .map(|routes| Json(Reloaded { routes }))
+        .map_err(|e| unprocessable(e.to_string()))
+}
+
+// Pricing rules
+
+#[derive(Debug, Deserialize)]
+pub struct NewPricingRule {
+    pub kind: String,
+    pub param: String,
+    pub price: u128,
+}
+
+#[get("/services/<id>/pricing")]
+fn list_pricing_rules(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    pricing_rules: &State<FlatPricingRuleList>,
+    id: u128,
+) -> AdminResult<Json<Vec<PricingRule>>> {
+    services
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Service", id))?;
+    Ok(Json(pricing_rules.list_by_service(id)))
+}
+
+#[post("/services/<id>/pricing", data = "<input>")]
+fn create_pricing_rule(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    pricing_rules: &State<FlatPricingRuleList>,
+    id: u128,
+    input: Json<NewPricingRule>,
+) -> AdminResult<Custom<Json<PricingRule>>> {
+    let input = input.into_inner();
+    services
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Service", id))?;
+    let kind = RuleKind::parse(input.kind.trim()).ok_or_else(|| {
+        unprocessable(
+            "`kind` should be `method`, `request_bytes`, `response_bytes`, `duration` or `usage_header`"
+                .to_string(),
+        )
+    })?;
+    validate_text("param", &input.param).map_err(unprocessable)?;
+    let param = input.param.trim().to_string();
+    pricing::validate(kind, &param).map_err(unprocessable)?;
+
+    Ok(Custom(
+        Status::Created,
+        Json(pricing_rules.create(id, kind, param, input.price)),
+    ))
+}
+
+#[delete("/pricing/<id>")]
+fn delete_pricing_rule(
+    _admin: AdminKey,
+    pricing_rules: &State<FlatPricingRuleList>,
+    id: u128,
+) -> AdminResult<Status> {
+    pricing_rules
+        .delete(id)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| not_found("Pricing rule", id))
+}
+
+// Transform rules
+
+#[derive(Debug, Deserialize)]
+pub struct NewTransformRule {
+    pub kind: String,
+    pub param: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+#[get("/services/<id>/transforms")]
+fn list_transform_rules(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    transform_rules: &State<FlatTransformRuleList>,
+    id: u128,
+) -> AdminResult<Json<Vec<TransformRule>>> {
+    services
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Service", id))?;
+    Ok(Json(transform_rules.list_by_service(id)))
+}
+
+#[post("/services/<id>/transforms", data = "<input>")]
+fn create_transform_rule(
+    _admin: AdminKey,
+    services: &State<FlatServiceList>,
+    transform_rules: &State<FlatTransformRuleList>,
+    id: u128,
+    input: Json<NewTransformRule>,
+) -> AdminResult<Custom<Json<TransformRule>>> {
+    let input = input.into_inner();
+    services
+        .get_by_id(id)
+        .ok_or_else(|| not_found("Service", id))?;
+    let kind = TransformKind::parse(input.kind.trim()).ok_or_else(|| {
+        unprocessable(
+            "`kind` should be `remove_request_header`, `set_request_header`, `rewrite_path_prefix`, \
+             `rewrite_path_regex`, `remove_response_header` or `set_response_header`"
+                .to_string(),
+        )
+    })?;
+    validate_text("param", &input.param).map_err(unprocessable)?;
+    if !input.value.trim().is_empty() {
+        validate_text("value", &input.value).map_err(unprocessable)?;
+    }
+    let param = input.param.trim().to_string();
+    let value = input.value.trim().to_string();
+    transform::validate(kind, &param, &value).map_err(unprocessable)?;
+
+    Ok(Custom(
+        Status::Created,
+        Json(transform_rules.create(id, kind, param, value)),
+    ))
+}
+
+#[delete("/transforms/<id>")]
+fn delete_transform_rule(
+    _admin: AdminKey,
+    transform_rules: &State<FlatTransformRuleList>,
+    id: u128,
+) -> AdminResult<Status> {
+    transform_rules
+        .delete(id)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| not_found("Transform rule", id))
+}
+
+// Invoices
+
+#[derive(Responder)]
+pub enum InvoiceResponse {
+    Json(Json<Vec<Invoice>>),
+    Csv((ContentType, String)),
+}
+
+#[get("/invoices?<period>&<subscriber>&<format>")]
+#[allow(clippy::too_many_arguments)]
+fn list_invoices(
+    _admin: AdminKey,
+    subscribers: &State<FlatSubscriberList>,
+    ledger: &State<FlatLedgerList>,
+    requests: &State<FlatRequestList>,
+    overages: &State<FlatOverageList>,
+    period: &str,
+    subscriber: Option<u128>,
+    format: Option<&str>,
+) -> AdminResult<InvoiceResponse> {
+    let month = BillingMonth::parse(period)
+        .ok_or_else(|| unprocessable("`period` should look like `2024-01`".to_string()))?;
+    let subscribers = match subscriber {
+        Some(id) => vec![subscribers
+            .get_by_id(id)
+            .ok_or_else(|| not_found("Subscriber", id))?],
+        None => subscribers.list(),
+    };
+    let invoices = invoice::generate_all(
+        &subscribers,
+        &ledger.list(),
+        &requests.list(),
+        &overages.list(),
+        &month,
+    );
+
+    match format.unwrap_or("json") {
+        "json" => Ok(InvoiceResponse::Json(Json(invoices))),
+        "csv" => Ok(InvoiceResponse::Csv((
+            ContentType::CSV,
+            invoice::to_csv(&invoices),
+        ))),
+        _ => Err(unprocessable(
+            "`format` should be `json` or `csv`".to_string(),
+        )),
+    }
+}
+
+// Ledger entries, renewals, overages, alerts and requests are append-only logs, so they can only be read.
+
+#[get("/ledger?<subscription>&<page>&<per_page>")]
+fn list_ledger(
+    _admin: AdminKey,
+    ledger: &State<FlatLedgerList>,
+    subscription: Option<u128>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Entry>> {
+    Json(search_page(
+        equal_to(&[("subscription", subscription.map(|id| id.to_string()))]),
+        page,
+        per_page,
+        |query| ledger.filter(query),
+        |filter| ledger.count(filter),
+    ))
+}
+
+#[get("/ledger/reconcile")]
+fn reconcile_ledger(
+    _admin: AdminKey,
+    subscriptions: &State<FlatSubscriptionList>,
+    ledger: &State<FlatLedgerList>,
+) -> Json<Vec<Mismatch>> {
+    Json(ledger::reconcile(&subscriptions.list(), &ledger.list()))
+}
+
+#[get("/renewals?<page>&<per_page>")]
+fn list_renewals(
+    _admin: AdminKey,
+    renewals: &State<FlatRenewalList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Renewal>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| renewals.filter(query),
+        |filter| renewals.count(filter),
+    ))
+}
+
+#[get("/overages?<subscription>&<page>&<per_page>")]
+fn list_overages(
+    _admin: AdminKey,
+    overages: &State<FlatOverageList>,
+    subscription: Option<u128>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Overage>> {
+    Json(search_page(
+        equal_to(&[("subscription", subscription.map(|id| id.to_string()))]),
+        page,
+        per_page,
+        |query| overages.filter(query),
+        |filter| overages.count(filter),
+    ))
+}
+
+#[get("/alerts?<page>&<per_page>")]
+fn list_alerts(
+    _admin: AdminKey,
+    alerts: &State<FlatAlertList>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Alert>> {
+    Json(search_page(
+        Filter::All(vec![]),
+        page,
+        per_page,
+        |query| alerts.filter(query),
+        |filter| alerts.count(filter),
+    ))
+}
+
+#[get("/requests?<consumer>&<service>&<status>&<page>&<per_page>")]
+fn list_requests(
+    _admin: AdminKey,
+    requests: &State<FlatRequestList>,
+    consumer: Option<u128>,
+    service: Option<u128>,
+    status: Option<u32>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Request>> {
+    let filter = equal_to(&[
+        ("consumer", consumer.map(|id| id.to_string())),
+        ("service", service.map(|id| id.to_string())),
+        ("status", status.map(|status| status.to_string())),
+    ]);
+    Json(search_page(
+        filter,
+        page,
+        per_page,
+        |query| requests.filter(query),
+        |filter| requests.count(filter),
+    ))
+}
+
+#[get("/sandbox/requests?<consumer>&<service>&<status>&<page>&<per_page>")]
+fn list_sandbox_requests(
+    _admin: AdminKey,
+    requests: &State<SandboxRequestList>,
+    consumer: Option<u128>,
+    service: Option<u128>,
+    status: Option<u32>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<Page<Request>> {
+    let filter = equal_to(&[
+        ("consumer", consumer.map(|id| id.to_string())),
+        ("service", service.map(|id| id.to_string())),
+        ("status", status.map(|status| status.to_string())),
+    ]);
+    Json(search_page(
+        filter,
+        page,
+        per_page,
+        |query| requests.filter(query),
+        |filter| requests.count(filter),
+    ))
+}
+
+#[get("/requests/<id>")]
+fn get_request(
+    _admin: AdminKey,
+    requests: &State<FlatRequestList>,
+    id: &str,
+) -> AdminResult<Json<Request>> {
+    requests.get_by_id(id).map(Json).ok_or_else(|| {
+        error(
+            Status::NotFound,
+            &format!("Request with id:{id} is not found"),
+        )
+    })
+}
+
+/// Lists every problem in the stored tables, an empty list when they are
+/// consistent.
+#[get("/integrity")]
+fn check_integrity(_admin: AdminKey) -> Json<Vec<Problem>> {
+    Json(integrity::check_storage())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_plans,
+        get_plan,
+        create_plan,
+        update_plan,
+        delete_plan,
+        list_subscriptions,
+        get_subscription,
+        create_subscription,
+        update_subscription,
+        refund_requests,
+        adjust_quota,
+        delete_subscription,
+        list_subscribers,
+        get_subscriber,
+        create_subscriber,
+        update_subscriber,
+        delete_subscriber,
+        list_consumers,
+        get_consumer,
+        create_consumer,
+        update_consumer,
+        delete_consumer,
+        list_products,
+        get_product,
+        create_product,
+        update_product,
+        delete_product,
+        list_services,
+        get_service,
+        create_service,
+        update_service,
+        delete_service,
+        list_routes,
+        reload_routes,
+        list_pricing_rules,
+        create_pricing_rule,
+        delete_pricing_rule,
+        list_transform_rules,
+        create_transform_rule,
+        delete_transform_rule,
+        list_invoices,
+        list_ledger,
+        reconcile_ledger,
+        list_renewals,
+        list_overages,
+        list_alerts,
+        list_requests,
+        list_sandbox_requests,
+        get_request,
+        check_integrity,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::admin::admin_list::AdminList;
+    use crate::alert::alert_list::AlertList;
+    use crate::budget::budget_list::BudgetUsageList;
+    use crate::consumer::consumer_list::ConsumerList;
+    use crate::db::file_db::{read_from_string, FlatTable};
+    use crate::idempotency::idempotency_list::IdempotencyList;
+    use crate::job::job_list::JobList;
+    use crate::ledger::ledger_list::LedgerList;
+    use crate::overage::overage_list::OverageList;
+    use crate::plan::plan_list::PlanList;
+    use crate::pricing::pricing_list::PricingRuleList;
+    use crate::product::product_list::ProductList;
+    use crate::renewal::renewal_list::RenewalList;
+    use crate::request::request_list::RequestList;
+    use crate::router::table::RoutingTable;
+    use crate::service::service_list::ServiceList;
+    use crate::subscriber::subscriber_list::{SubscriberList, SubscriptionList};
+    use crate::transform::transform_list::TransformRuleList;
+
+    /// Tables where the records with id 1 are referenced and those with id 2
+    /// are not.
+    fn tables() -> Vec<(&'static str, String)> {
+        [
+            (
+                "plans",
+                "id, name, price, quota, period\n\
+                 1, Basic, 100, 10, monthly\n\
+                 2, Spare, 100, 10, monthly",
+            ),
+            (
+                "subscriptions",
+                "id, name, status, price, quota, expiry_date, auto_renew, plan\n\
+                 1, Basic, 1, 100, 10, 2030-01-01 00:00:00, false, 1\n\
+                 2, Spare, 1, 100, 10, 2030-01-01 00:00:00, false, 1",
+            ),
+            (
+                "subscribers",
+                "id, name, subscription, access_token\n\
+                 1, Subscriber A, 1, sub-1\n\
+                 2, Subscriber B, 1, sub-2",
+            ),
+            (
+                "consumers",
+                "id, subscriber, access_token\n1, 1, key-1\n2, 1, key-2",
+            ),
+            (
+                "products",
+                "id, slug, requests\n1, product_a, 0\n2, product_b, 0",
+            ),
+            (
+                "services",
+                "id, name, slug, version, status, base_url, price, requests, product\n\
+                 1, Service A, service_a, v1, 1, http://a, 1, 0, 1\n\
+                 2, Service B, service_b, v1, 1, http://b, 1, 0, 1",
+            ),
+            ("pricing_rules", "id, service, kind, param, price"),
+            ("transform_rules", "id, service, kind, param, value"),
+            (
+                "requests",
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, \
+                 service",
+            ),
+            (
+                "sandbox_requests",
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, \
+                 service",
+            ),
+            (
+                "ledger",
+                "id, subscription, kind, amount, reason, request, created_at",
+            ),
+            (
+                "renewals",
+                "id, subscription, plan, quota, renewed_at, expiry_date",
+            ),
+            (
+                "overages",
+                "id, subscription, request, tokens, price, created_at",
+            ),
+            (
+                "alerts",
+                "id, subscriber, subscription, kind, period, status, attempts, \
+                 next_attempt_at, created_at",
+            ),
+            ("budget_usage", "id, consumer, period, tokens"),
+            (
+                "idempotency_keys",
+                "id, consumer, key, fingerprint, status, headers, body, created_at",
+            ),
+            (
+                "jobs",
+                "id, consumer, service, request, method, url, headers, body, callback_url, \
+                 status, response_status, response_headers, response_body, price, reserved, \
+                 reserved_overage, created_at, completed_at",
+            ),
+        ]
+        .into_iter()
+        .map(|(table, content)| (table, content.to_string()))
+        .collect()
+    }
+
+    /// The lists whose records can be deleted, to read them back.
+    struct Deletable {
+        plans: FlatPlanList,
+        subscriptions: FlatSubscriptionList,
+        subscribers: FlatSubscriberList,
+        consumers: FlatConsumerList,
+        products: FlatProductList,
+        services: FlatServiceList,
+    }
+
+    impl Deletable {
+        /// `tables` with the deletable ones as they are now.
+        fn contents(&self, tables: &[(&'static str, String)]) -> Vec<(&'static str, String)> {
+            tables
+                .iter()
+                .map(|(table, content)| {
+                    let content = match *table {
+                        "plans" => self.plans.contents(),
+                        "subscriptions" => self.subscriptions.contents(),
+                        "subscribers" => self.subscribers.contents(),
+                        "consumers" => self.consumers.contents(),
+                        "products" => self.products.contents(),
+                        "services" => self.services.contents(),
+                        _ => content.clone(),
+                    };
+                    (*table, content)
+                })
+                .collect()
+        }
+    }
+
+    fn admin(tables: &[(&'static str, String)]) -> (Client, Deletable) {
+        let content = |name: &str| {
+            let (_, content) = tables.iter().find(|(table, _)| *table == name).unwrap();
+            content.clone()
+        };
+        let table = |name: &str| Mutex::new(FlatTable::new_from_string(content(name)));
+        let routing = RoutingTable::build(
+            &read_from_string(&content("products")),
+            &read_from_string(&content("services")),
+        )
+        .unwrap();
+        let deletable = Deletable {
+            plans: PlanList::new(table("plans")),
+            subscriptions: SubscriptionList::new(table("subscriptions")),
+            subscribers: SubscriberList::new(table("subscribers")),
+            consumers: ConsumerList::new(table("consumers")),
+            products: ProductList::new(table("products")),
+            services: ServiceList::new(table("services")),
+        };
+        let rocket = rocket::build()
+            .mount(
+                "/admin",
+                routes![
+                    list_plans,
+                    list_ledger,
+                    delete_plan,
+                    delete_subscription,
+                    delete_subscriber,
+                    delete_consumer,
+                    delete_product,
+                    delete_service,
+                ],
+            )
+            .manage(AdminList::new(Mutex::new(FlatTable::new_from_string(
+                "id, name, access_token\n1, Admin, admin-1".to_string(),
+            ))))
+            .manage(deletable.plans.clone())
+            .manage(deletable.subscriptions.clone())
+            .manage(deletable.subscribers.clone())
+            .manage(deletable.consumers.clone())
+            .manage(deletable.products.clone())
+            .manage(deletable.services.clone())
+            .manage(Routing::new(routing))
+            .manage(PricingRuleList::new(table("pricing_rules")))
+            .manage(TransformRuleList::new(table("transform_rules")))
+            .manage(RequestList::new(table("requests")))
+            .manage(SandboxRequestList::new(table("sandbox_requests")))
+            .manage(LedgerList::new(table("ledger")))
+            .manage(RenewalList::new(table("renewals")))
+            .manage(OverageList::new(table("overages")))
+            .manage(AlertList::new(table("alerts")))
+            .manage(BudgetUsageList::new(table("budget_usage")))
+            .manage(IdempotencyList::new(table("idempotency_keys")))
+            .manage(JobList::new(table("jobs")));
+        (Client::tracked(rocket).expect("valid rocket"), deletable)
+    }
+
+    fn delete(client: &Client, path: &str) -> Status {
+        client
+            .delete(format!("/admin/{path}"))
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch()
+            .status()
+    }
+
+    #[test]
+    fn list_pages_from_storage() {
+        let mut tables = tables();
+        let (_, ledger) = tables
+            .iter_mut()
+            .find(|(table, _)| *table == "ledger")
+            .unwrap();
+        *ledger = format!(
+            "{ledger}\n\
+             1, 1, credit, 10, opening balance, , 2024-01-01 00:00:00\n\
+             2, 2, credit, 10, opening balance, , 2024-01-01 00:00:00\n\
+             3, 1, debit, -1, service_a, r-1, 2024-01-02 00:00:00"
+        );
+        let (client, _) = admin(&tables);
+        let get = |path: &str| {
+            client
+                .get(format!("/admin/{path}"))
+                .header(Header::new("x-admin-key", "admin-1"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+
+        let plans = get("plans?page=2&per_page=1");
+        assert!(plans.contains("\"name\":\"Spare\""));
+        assert!(!plans.contains("\"name\":\"Basic\""));
+        assert!(plans.contains("\"total\":2"));
+        let entries = get("ledger?subscription=1&page=2&per_page=1");
+        assert!(entries.contains("\"request_id\":\"r-1\""));
+        assert!(entries.contains("\"total\":2"));
+    }
+
+    #[test]
+    fn delete_unreferenced_records() {
+        let tables = tables();
+        assert_eq!(integrity::check(&tables), vec![]);
+        let (client, deletable) = admin(&tables);
+
+        for path in [
+            "plans/2",
+            "subscriptions/2",
+            "subscribers/2",
+            "consumers/2",
+            "services/2",
+            "products/2",
+        ] {
+            assert_eq!(delete(&client, path), Status::NoContent, "{path}");
+        }
+
+        assert!(deletable.plans.get_by_id(2).is_none());
+        assert_eq!(integrity::check(&deletable.contents(&tables)), vec![]);
+    }
+
+    #[test]
+    fn refuse_to_delete_referenced_records() {
+        let cases = [
+            ("plans/1", None),
+            ("plans/2", Some(("renewals", "1, 2, 2, 10, 2024-01-01 00:00:00, 2030-01-01 00:00:00"))),
+            ("subscriptions/1", None),
+            ("subscriptions/2", Some(("ledger", "1, 2, credit, 10, opening balance, , 2024-01-01 00:00:00"))),
+            ("subscriptions/2", Some(("renewals", "1, 2, 1, 10, 2024-01-01 00:00:00, 2030-01-01 00:00:00"))),
+            ("subscriptions/2", Some(("overages", "1, 2, r-1, 1, 1, 2024-01-01 00:00:00"))),
+            (
+                "subscriptions/2",
+                Some(("alerts", "1, 1, 2, quota_80, 2024-01-01 00:00:00, sent, 1, 2024-01-01 00:00:00, 2024-01-01 00:00:00")),
+            ),
+            ("subscribers/1", None),
+            (
+                "subscribers/2",
+                Some(("alerts", "1, 2, , quota_80, 2024-01-01 00:00:00, sent, 1, 2024-01-01 00:00:00, 2024-01-01 00:00:00")),
+            ),
+            ("consumers/2", Some(("requests", "r-1, product_a, service_a, v1, /, 200, 1, 2, 1"))),
+            ("consumers/2", Some(("sandbox_requests", "r-1, product_a, service_a, v1, /, 200, 0, 2, 1"))),
+            ("consumers/2", Some(("budget_usage", "1, 2, 2024-01-01 00:00:00, 5"))),
+            ("consumers/2", Some(("idempotency_keys", "1, 2, k-1, f, 200, , , 2024-01-01 00:00:00"))),
+            (
+                "consumers/2",
+                Some(("jobs", "1, 2, 1, r-1, GET, , , , , queued, , , , 1, 1, 0, 2024-01-01 00:00:00, ")),
+            ),
+            ("services/2", Some(("pricing_rules", "1, 2, method, POST, 1"))),
+            ("services/2", Some(("transform_rules", "1, 2, remove_request_header, Cookie, "))),
+            ("services/2", Some(("requests", "r-1, product_a, service_b, v1, /, 200, 1, 1, 2"))),
+            ("services/2", Some(("sandbox_requests", "r-1, product_a, service_b, v1, /, 200, 0, 1, 2"))),
+            (
+                "services/2",
+                Some(("jobs", "1, 1, 2, r-1, GET, , , , , queued, , , , 1, 1, 0, 2024-01-01 00:00:00, ")),
+            ),
+            ("products/1", None),
+        ];
+
+        for (path, reference) in cases {
+            let mut tables = tables();
+            if let Some((name, row)) = reference {
+                let (_, content) = tables.iter_mut().find(|(table, _)| *table == name).unwrap();
+                *content = format!("{content}\n{row}");
+            }
+            assert_eq!(integrity::check(&tables), vec![], "{path} {reference:?}");
+            let (client, deletable) = admin(&tables);
+
+            assert_eq!(
+                delete(&client, path),
+                Status::Conflict,
+                "{path} {reference:?}"
+            );
+            assert_eq!(integrity::check(&deletable.contents(&tables)), vec![]);
+        }
+    }
+
+    #[test]
+    fn paginate_items() {
+        let page = paginate((1..=45).collect::<Vec<u32>>(), Some(3), Some(20));
+
+        assert_eq!(page.items, vec![41, 42, 43, 44, 45]);
+        assert_eq!(page.total, 45);
+    }
+
+    #[test]
+    fn paginate_with_defaults_and_bounds() {
+        let page = paginate((1..=5).collect::<Vec<u32>>(), Some(0), Some(1000));
+
+        assert_eq!(page.page, 1);
+        assert_eq!(page.per_page, MAX_PER_PAGE);
+        assert_eq!(page.items.len(), 5);
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+use crate::subscriber::{format_date, parse_date};
+
+use super::{Alert, AlertKind, DeliveryStatus};
+
+#[derive(Clone)]
+pub struct AlertList<D> {
+    db: Arc<Mutex<D>>,
+    pub alerts: Vec<Alert>,
+}
+
+pub type FlatAlertList = AlertList<FlatTable<String, String>>;
+
+impl FlatAlertList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        AlertList {
+            db: Arc::new(db),
+            alerts: vec![],
+        }
+    }
+
+    pub fn list(&self) -> Vec<Alert> {
+        AlertList::get_all::<FlatTable<String, String>, Alert>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Alert> {
+        AlertList::search_records::<FlatTable<String, String>, Alert>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        AlertList::count_records(&self.db, filter)
+    }
+
+    pub fn list_by_subscriber(&self, subscriber_id: u128) -> Vec<Alert> {
+        self.list()
+            .into_iter()
+            .filter(|alert| alert.subscriber_id == subscriber_id)
+            .collect()
+    }
+
+    /// Queues a new alert for delivery right away.
+    pub fn create(
+        &self,
+        subscriber_id: u128,
+        subscription_id: u128,
+        kind: AlertKind,
+        period: NaiveDateTime,
+        created_at: NaiveDateTime,
+    ) -> Alert {
+        AlertList::insert_next::<FlatTable<String, String>, Alert>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("subscriber".to_string(), subscriber_id.to_string()),
+                ("subscription".to_string(), subscription_id.to_string()),
+                ("kind".to_string(), kind.as_str()),
+                ("period".to_string(), format_date(&period)),
+                (
+                    "status".to_string(),
+                    DeliveryStatus::Pending.as_str().to_string(),
+                ),
+                ("attempts".to_string(), "0".to_string()),
+                ("next_attempt_at".to_string(), format_date(&created_at)),
+                ("created_at".to_string(), format_date(&created_at)),
+            ])
+        })
+    }
+
+    /// Records the outcome of a delivery attempt.
+    pub fn update_delivery(
+        &self,
+        id: u128,
+        status: DeliveryStatus,
+        attempts: u32,
+        next_attempt_at: NaiveDateTime,
+    ) -> Option<Alert> {
+        AlertList::update_by_attr::<FlatTable<String, String>, Alert>(
+            &self.db,
+            "id",
+            id.to_string(),
+            Record::from([
+                ("status".to_string(), status.as_str().to_string()),
+                ("attempts".to_string(), attempts.to_string()),
+                ("next_attempt_at".to_string(), format_date(&next_attempt_at)),
+            ]),
+        )
+    }
+}
+
+impl ModelAble<String, String> for FlatAlertList {}
+
+impl From<Record<String, String>> for Alert {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("subscriber"),
+            map.get("subscription"),
+            map.get("kind"),
+            map.get("period"),
+            map.get("status"),
+            map.get("attempts"),
+            map.get("next_attempt_at"),
+            map.get("created_at"),
+        ) {
+            (
+                Some(id),
+                Some(subscriber_id),
+                Some(subscription_id),
+                Some(kind),
+                Some(period),
+                Some(status),
+                Some(attempts),
+                Some(next_attempt_at),
+                Some(created_at),
+            ) => Alert {
+                id: id.parse::<u128>().unwrap(),
+                subscriber_id: subscriber_id.parse::<u128>().unwrap(),
+                subscription_id: subscription_id.parse::<u128>().unwrap(),
+                kind: AlertKind::parse(kind).expect("Invalid alert kind"),
+                period: parse_date(period).expect("Invalid alert period"),
+                status: DeliveryStatus::parse(status).expect("Invalid delivery status"),
+                attempts: attempts.parse::<u32>().unwrap(),
+                next_attempt_at: parse_date(next_attempt_at).expect("Invalid alert date"),
+                created_at: parse_date(created_at).expect("Invalid alert date"),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_and_deliver_alert() {
+        let table = "\
+        id, subscriber, subscription, kind, period, status, attempts, next_attempt_at, created_at
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let alert_list = AlertList::new(db);
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+
+        let alert = alert_list.create(1, 1, AlertKind::Quota(80), now, now);
+        alert_list.update_delivery(alert.id, DeliveryStatus::Sent, 1, now);
+
+        let alerts = alert_list.list_by_subscriber(1);
+        assert_eq!(alerts[0].kind, AlertKind::Quota(80));
+        assert_eq!(alerts[0].status, DeliveryStatus::Sent);
+    }
+}
@@ -0,0 +1,549 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use chrono::{Duration, NaiveDateTime};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::biller;
+use crate::overage::Overage;
+use crate::subscriber::subscriber_list::FlatSubscriberList;
+use crate::subscriber::{format_date, parse_date, Subscriber, Subscription, SubscriptionStatus};
+
+use self::alert_list::FlatAlertList;
+
+pub mod alert_list;
+
+/// What a subscriber is warned about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertKind {
+    /// The given percentage of the period's quota was used.
+    Quota(u8),
+    /// The subscription expires soon.
+    Expiry,
+}
+
+impl AlertKind {
+    pub fn parse(value: &str) -> Option<AlertKind> {
+        match value {
+            "expiry" => Some(AlertKind::Expiry),
+            _ => value
+                .strip_prefix("quota_")
+                .and_then(|threshold| threshold.parse::<u8>().ok())
+                .map(AlertKind::Quota),
+        }
+    }
+
+    pub fn as_str(&self) -> String {
+        match self {
+            AlertKind::Quota(threshold) => format!("quota_{threshold}"),
+            AlertKind::Expiry => "expiry".to_string(),
+        }
+    }
+
+    /// Returns the event name sent to webhooks.
+    pub fn event(&self) -> &'static str {
+        match self {
+            AlertKind::Quota(_) => "quota.threshold",
+            AlertKind::Expiry => "subscription.expiring",
+        }
+    }
+}
+
+impl Serialize for AlertKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn parse(value: &str) -> Option<DeliveryStatus> {
+        match value {
+            "pending" => Some(DeliveryStatus::Pending),
+            "sent" => Some(DeliveryStatus::Sent),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+/// An alert raised once per billing period, and its webhook delivery.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub id: u128,
+    pub subscriber_id: u128,
+    pub subscription_id: u128,
+    pub kind: AlertKind,
+    /// Start of the billing period the alert was raised in.
+    pub period: NaiveDateTime,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl Alert {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Alert {
+        let date = |name| parse_date(attr.get(name).unwrap_or(&"2001-01-01 00:00:00")).unwrap();
+        Alert {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            subscriber_id: attr
+                .get("subscriber")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            subscription_id: attr
+                .get("subscription")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            kind: AlertKind::parse(attr.get("kind").unwrap_or(&"quota_50")).unwrap(),
+            period: date("period"),
+            status: DeliveryStatus::parse(attr.get("status").unwrap_or(&"pending")).unwrap(),
+            attempts: attr.get("attempts").unwrap_or(&"0").parse::<u32>().unwrap(),
+            next_attempt_at: date("next_attempt_at"),
+            created_at: date("created_at"),
+        }
+    }
+}
+
+/// When alerts are raised and how their delivery is retried.
+#[derive(Debug, Clone)]
+pub struct AlertSettings {
+    /// Percentages of the quota that raise an alert once used.
+    pub thresholds: Vec<u8>,
+    /// How long before `expiry_date` the expiry alert is raised.
+    pub expiry_notice: Duration,
+    /// Deliveries are given up after this many failed attempts.
+    pub max_attempts: u32,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        AlertSettings {
+            thresholds: vec![50, 80, 100],
+            expiry_notice: Duration::days(7),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Returns the alerts `subscription` should raise that were not raised in its
+/// current period yet.
+pub fn due_alerts(
+    subscription: &Subscription,
+    overage_used: u128,
+    raised: &[Alert],
+    settings: &AlertSettings,
+    now: NaiveDateTime,
+) -> Vec<AlertKind> {
+    if subscription.status != SubscriptionStatus::Active {
+        return vec![];
+    }
+    let period = subscription.period_start();
+    let is_new = |kind: &AlertKind| {
+        !raised.iter().any(|alert| {
+            alert.subscription_id == subscription.id
+                && alert.period == period
+                && &alert.kind == kind
+        })
+    };
+
+    let mut due = vec![];
+    let limit = subscription.plan.quota;
+    if limit > 0 {
+        let used = limit.saturating_sub(subscription.quota) + overage_used;
+        let percent = used * 100 / limit;
+        due.extend(
+            settings
+                .thresholds
+                .iter()
+                .filter(|threshold| percent >= **threshold as u128)
+                .map(|threshold| AlertKind::Quota(*threshold)),
+        );
+    }
+    if subscription.expiry_date - now <= settings.expiry_notice {
+        due.push(AlertKind::Expiry);
+    }
+    due.into_iter().filter(is_new).collect()
+}
+
+/// Returns how long to wait before retrying a delivery that failed `attempts` times.
+pub fn backoff(attempts: u32) -> Duration {
+    Duration::minutes(1 << attempts.min(10))
+}
+
+/// The JSON body posted to a subscriber's webhook.
+#[derive(Debug, Serialize)]
+pub struct Event {
+    pub id: u128,
+    pub event: &'static str,
+    pub kind: AlertKind,
+    pub subscriber: u128,
+    pub subscription: u128,
+    pub quota: u128,
+    pub limit: u128,
+    pub expiry_date: String,
+    pub created_at: String,
+}
+
+impl Event {
+    pub fn new(alert: &Alert, subscriber: &Subscriber) -> Event {
+        let subscription = &subscriber.subscription;
+        Event {
+            id: alert.id,
+            event: alert.kind.event(),
+            kind: alert.kind,
+            subscriber: subscriber.id,
+            subscription: subscription.id,
+            quota: subscription.quota,
+            limit: subscription.plan.quota,
+            expiry_date: format_date(&subscription.expiry_date),
+            created_at: format_date(&alert.created_at),
+        }
+    }
+}
+
+/// Signs `body` sent at `timestamp` with HMAC-SHA256, hex encoded.
+pub fn sign(secret: &str, timestamp: i64, body: impl AsRef<[u8]>) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body.as_ref());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Returns true for addresses the gateway must not post to on a subscriber's
+/// behalf: its own host, private and link-local networks, and other ranges
+/// that aren't public.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7, and link-local, fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Checks a URL subscribers or consumers ask the gateway to post to, returning
+/// what is wrong with it.
+pub fn check_webhook_url(url: &str) -> Result<(), &'static str> {
+    let is_http = url.starts_with("http://") || url.starts_with("https://");
+    if !is_http || url.contains(',') || url.contains(char::is_whitespace) {
+        return Err("should be an http(s) URL without commas");
+    }
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_lowercase))
+        .ok_or("should be an http(s) URL without commas")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let is_local = match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal(ip),
+        Err(_) => {
+            let name = host.trim_end_matches('.');
+            name == "localhost" || name.ends_with(".localhost")
+        }
+    };
+    match is_local {
+        true => Err("should not point to a local or private address"),
+        false => Ok(()),
+    }
+}
+
+/// Resolves host names to their public addresses only, so that a webhook
+/// can't be pointed at the gateway's network through DNS.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = rocket::tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Returns a client for posting to webhooks. It doesn't follow redirects and
+/// only connects to public addresses.
+pub fn webhook_client(timeout: std::time::Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(timeout)
+        .read_timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("build webhook client")
+}
+
+/// Posts `event` to the subscriber's webhook, returning true if it was accepted.
+pub async fn post(
+    client: &reqwest::Client,
+    subscriber: &Subscriber,
+    event: &Event,
+    now: NaiveDateTime,
+) -> bool {
+    let body = rocket::serde::json::to_string(event).expect("serialize event");
+    let timestamp = now.and_utc().timestamp();
+    let signature = sign(&subscriber.webhook_secret, timestamp, &body);
+
+    client
+        .post(&subscriber.webhook_url)
+        .header("Content-Type", "application/json")
+        .header("X-UWS-Event", event.event)
+        .header("X-UWS-Timestamp", timestamp.to_string())
+        .header("X-UWS-Signature", format!("sha256={signature}"))
+        .body(body)
+        .send()
+        .await
+        .map(|response| response.status().is_success())
+        .unwrap_or(false)
+}
+
+/// Queues the alerts due for subscribers that have a webhook.
+pub fn queue_alerts(
+    subscribers: &FlatSubscriberList,
+    alerts: &FlatAlertList,
+    overages: &[Overage],
+    settings: &AlertSettings,
+    now: NaiveDateTime,
+) -> Vec<Alert> {
+    let raised = alerts.list();
+    let mut queued = vec![];
+    for subscriber in subscribers.list() {
+        if subscriber.webhook_url.is_empty() {
+            continue;
+        }
+        let subscription = &subscriber.subscription;
+        let overage_used = biller::overage_used(subscription, overages);
+        for kind in due_alerts(subscription, overage_used, &raised, settings, now) {
+            queued.push(alerts.create(
+                subscriber.id,
+                subscription.id,
+                kind,
+                subscription.period_start(),
+                now,
+            ));
+        }
+    }
+    queued
+}
+
+/// Posts the pending alerts whose next attempt is due, retrying failed ones
+/// later with an exponential backoff.
+pub async fn deliver_alerts(
+    client: &reqwest::Client,
+    subscribers: &FlatSubscriberList,
+    alerts: &FlatAlertList,
+    settings: &AlertSettings,
+    now: NaiveDateTime,
+) -> Vec<Alert> {
+    let mut delivered = vec![];
+    for alert in alerts.list() {
+        if alert.status != DeliveryStatus::Pending || alert.next_attempt_at > now {
+            continue;
+        }
+        let accepted = match subscribers.get_by_id(alert.subscriber_id) {
+            Some(subscriber) if !subscriber.webhook_url.is_empty() => {
+                post(client, &subscriber, &Event::new(&alert, &subscriber), now).await
+            }
+            _ => false,
+        };
+        let attempts = alert.attempts + 1;
+        let status = match accepted {
+            true => DeliveryStatus::Sent,
+            false if attempts >= settings.max_attempts => DeliveryStatus::Failed,
+            false => DeliveryStatus::Pending,
+        };
+        delivered.extend(alerts.update_delivery(
+            alert.id,
+            status,
+            attempts,
+            now + backoff(attempts),
+        ));
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::alert_list::AlertList;
+    use super::*;
+    use crate::db::file_db::FlatTable;
+    use crate::router::stub;
+    use crate::subscriber::subscriber_list::SubscriberList;
+
+    fn lists(webhook_url: &str) -> (FlatSubscriberList, FlatAlertList) {
+        let subscribers = format!(
+            "id, name, subscription, access_token, webhook_url, webhook_secret\n\
+             2, Subscriber B, 2, sub-2, {webhook_url}, whsec"
+        );
+        let alerts = "\
+        id, subscriber, subscription, kind, period, status, attempts, next_attempt_at, created_at
+        1, 2, 2, quota_80, 2022-10-01 00:00:00, pending, 0, 2023-01-01 00:00:00, 2023-01-01 00:00:00
+        "
+        .to_string();
+        (
+            SubscriberList::new(Mutex::new(FlatTable::new_from_string(subscribers))),
+            AlertList::new(Mutex::new(FlatTable::new_from_string(alerts))),
+        )
+    }
+
+    #[rocket::async_test]
+    async fn deliver_signed_alert() {
+        let (url, received) = stub::serve_once("HTTP/1.1 204 No Content\r\n\r\n");
+        let (subscribers, alerts) = lists(&url);
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+
+        let delivered = deliver_alerts(
+            &reqwest::Client::new(),
+            &subscribers,
+            &alerts,
+            &AlertSettings::default(),
+            now,
+        )
+        .await;
+
+        assert_eq!(delivered[0].status, DeliveryStatus::Sent);
+        let request = received.recv().unwrap();
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        let signature = sign("whsec", now.and_utc().timestamp(), body);
+        assert!(request.contains(&format!("x-uws-signature: sha256={signature}")));
+        assert!(body.contains("\"kind\":\"quota_80\""));
+    }
+
+    #[rocket::async_test]
+    async fn retry_failed_delivery_later() {
+        let (url, _received) = stub::serve_once("HTTP/1.1 500 Internal Server Error\r\n\r\n");
+        let (subscribers, alerts) = lists(&url);
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+
+        let delivered = deliver_alerts(
+            &reqwest::Client::new(),
+            &subscribers,
+            &alerts,
+            &AlertSettings::default(),
+            now,
+        )
+        .await;
+
+        assert_eq!(delivered[0].status, DeliveryStatus::Pending);
+        assert_eq!(delivered[0].attempts, 1);
+        assert_eq!(delivered[0].next_attempt_at, now + Duration::minutes(2));
+    }
+
+    fn subscription(quota: &str) -> Subscription {
+        let mut subscription = Subscription::fake(&HashMap::from([
+            ("quota", quota),
+            ("expiry_date", "2023-02-01 00:00:00"),
+        ]));
+        subscription.plan.quota = 100;
+        subscription
+    }
+
+    #[test]
+    fn raise_crossed_thresholds_once_per_period() {
+        let settings = AlertSettings::default();
+        let now = parse_date("2023-01-10 00:00:00").unwrap();
+        let raised = vec![Alert::fake(&HashMap::from([
+            ("kind", "quota_50"),
+            ("period", "2023-01-01 00:00:00"),
+        ]))];
+
+        let due = due_alerts(&subscription("15"), 0, &raised, &settings, now);
+
+        assert_eq!(due, vec![AlertKind::Quota(80)]);
+        assert_eq!(
+            due_alerts(&subscription("0"), 4, &raised, &settings, now),
+            vec![AlertKind::Quota(80), AlertKind::Quota(100)]
+        );
+    }
+
+    #[test]
+    fn raise_expiry_alert() {
+        let settings = AlertSettings::default();
+        let now = parse_date("2023-01-26 00:00:00").unwrap();
+
+        let due = due_alerts(&subscription("90"), 0, &[], &settings, now);
+
+        assert_eq!(due, vec![AlertKind::Expiry]);
+    }
+
+    #[test]
+    fn sign_payload() {
+        assert_eq!(
+            sign("secret", 1700000000, "{}"),
+            "b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+    }
+
+    #[test]
+    fn refuse_webhooks_to_internal_hosts() {
+        assert_eq!(check_webhook_url("https://hooks.example.com/uws"), Ok(()));
+        assert_eq!(check_webhook_url("http://93.184.216.34:8080/uws"), Ok(()));
+        for url in [
+            "http://127.0.0.1:8000/admin",
+            "http://localhost/",
+            "http://api.localhost./",
+            "http://10.0.0.7/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:192.168.1.1]/",
+            "http://2130706433/",
+        ] {
+            assert_eq!(
+                check_webhook_url(url),
+                Err("should not point to a local or private address"),
+                "{url}"
+            );
+        }
+        assert!(check_webhook_url("ftp://hooks.example.com/").is_err());
+    }
+}
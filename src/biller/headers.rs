@@ -0,0 +1,112 @@
+use std::sync::Mutex;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Request, Response};
+
+use crate::plan::OveragePolicy;
+use crate::subscriber::Subscription;
+
+/// Quota of the subscription billed for a request, cached on the request for
+/// the response headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaState {
+    pub limit: u128,
+    pub remaining: u128,
+    pub overage_used: u128,
+    pub policy: OveragePolicy,
+}
+
+impl QuotaState {
+    pub fn new(subscription: &Subscription, overage_used: u128) -> Self {
+        QuotaState {
+            limit: subscription.plan.quota,
+            remaining: subscription.quota,
+            overage_used,
+            policy: subscription.plan.overage_policy,
+        }
+    }
+
+    /// Returns a warning once 80% of the quota is used or it is exhausted.
+    pub fn warning(&self) -> Option<&'static str> {
+        if self.remaining == 0 {
+            return Some(match self.policy {
+                OveragePolicy::Hard => "quota exhausted",
+                _ => "quota exhausted, billing overage",
+            });
+        }
+        if self.remaining * 5 <= self.limit {
+            return Some("quota almost exhausted");
+        }
+        None
+    }
+}
+
+#[derive(Default)]
+struct CachedQuota(Mutex<Option<QuotaState>>);
+
+/// Records `state` so [`QuotaHeaders`] can report it on the response. Later
+/// calls replace it, e.g. once a request has been charged.
+pub fn set_quota_state(req: &Request<'_>, state: QuotaState) {
+    *req.local_cache(CachedQuota::default)
+        .0
+        .lock()
+        .expect("lock quota state") = Some(state);
+}
+
+/// Adds `X-Quota-*` headers to responses of billed requests.
+pub struct QuotaHeaders;
+
+#[rocket::async_trait]
+impl Fairing for QuotaHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Quota headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let cached = req.local_cache(CachedQuota::default);
+        let state = match cached.0.lock().expect("lock quota state").clone() {
+            Some(state) => state,
+            None => return,
+        };
+        res.set_raw_header("X-Quota-Limit", state.limit.to_string());
+        res.set_raw_header("X-Quota-Remaining", state.remaining.to_string());
+        res.set_raw_header("X-Quota-Overage", state.overage_used.to_string());
+        if let Some(warning) = state.warning() {
+            res.set_raw_header("X-Quota-Warning", warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(remaining: u128, policy: OveragePolicy) -> QuotaState {
+        QuotaState {
+            limit: 100,
+            remaining,
+            overage_used: 0,
+            policy,
+        }
+    }
+
+    #[test]
+    fn warn_near_quota_limit() {
+        assert_eq!(state(21, OveragePolicy::Hard).warning(), None);
+        assert_eq!(
+            state(20, OveragePolicy::Hard).warning(),
+            Some("quota almost exhausted")
+        );
+        assert_eq!(
+            state(0, OveragePolicy::Hard).warning(),
+            Some("quota exhausted")
+        );
+        assert_eq!(
+            state(0, OveragePolicy::Soft).warning(),
+            Some("quota exhausted, billing overage")
+        );
+    }
+}
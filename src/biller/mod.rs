@@ -0,0 +1,1021 @@
+use std::fmt;
+
+use chrono::NaiveDateTime;
+
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::consumer::Consumer;
+use crate::ledger::{self, ledger_list::FlatLedgerList, Entry, EntryKind};
+use crate::overage::{self, overage_list::FlatOverageList, Overage};
+use crate::plan::OveragePolicy;
+use crate::subscriber::subscriber_list::FlatSubscriptionList;
+use crate::subscriber::{format_date, Subscription, SubscriptionStatus};
+
+pub mod headers;
+
+#[derive(Debug, PartialEq)]
+pub enum BillingError {
+    Inactive(SubscriptionStatus),
+    Expired(NaiveDateTime),
+    InsufficientQuota {
+        quota: u128,
+        amount: u128,
+    },
+    OverageCapReached {
+        cap: u128,
+    },
+    /// The consumer's own budget can't cover `amount` tokens, even though
+    /// the subscription may still have quota.
+    BudgetExceeded {
+        budget: u128,
+        left: u128,
+        amount: u128,
+    },
+    /// A refund of `request` that can't be made, and why.
+    NotRefundable {
+        request: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for BillingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BillingError::Inactive(status) => {
+                write!(f, "Subscription is not active (status: {status:?})")
+            }
+            BillingError::Expired(date) => {
+                write!(f, "Subscription expired on {}", format_date(date))
+            }
+            BillingError::InsufficientQuota { quota, amount } => {
+                write!(f, "Not enough quota: {amount} tokens needed, {quota} left")
+            }
+            BillingError::OverageCapReached { cap } => {
+                write!(f, "Overage cap of {cap} tokens reached for this period")
+            }
+            BillingError::BudgetExceeded {
+                budget, left: 0, ..
+            } => {
+                write!(
+                    f,
+                    "Consumer budget of {budget} tokens is spent for this period"
+                )
+            }
+            BillingError::NotRefundable { request, reason } => {
+                write!(f, "Request {request} can't be refunded: {reason}")
+            }
+            BillingError::BudgetExceeded { left, amount, .. } => {
+                write!(
+                    f,
+                    "Not enough consumer budget: {amount} tokens needed, {left} left"
+                )
+            }
+        }
+    }
+}
+
+/// Checks that `subscription` can be billed for a request made at `now`,
+/// given the `overage_used` tokens of its current period.
+pub fn authorize(
+    subscription: &Subscription,
+    overage_used: u128,
+    now: NaiveDateTime,
+) -> Result<(), BillingError> {
+    if subscription.status != SubscriptionStatus::Active {
+        return Err(BillingError::Inactive(subscription.status));
+    }
+    if subscription.is_expired(now) {
+        return Err(BillingError::Expired(subscription.expiry_date));
+    }
+    if subscription.quota > 0 {
+        return Ok(());
+    }
+    match subscription.plan.overage_policy {
+        OveragePolicy::Hard => Err(BillingError::InsufficientQuota {
+            quota: 0,
+            amount: 1,
+        }),
+        OveragePolicy::Capped if overage_used >= subscription.plan.overage_cap => {
+            Err(BillingError::OverageCapReached {
+                cap: subscription.plan.overage_cap,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `consumer`, having `used` tokens of its budget this period,
+/// may spend `amount` more.
+pub fn authorize_budget(consumer: &Consumer, used: u128, amount: u128) -> Result<(), BillingError> {
+    match consumer.budget {
+        Some(budget) if used >= budget || used + amount > budget => {
+            Err(BillingError::BudgetExceeded {
+                budget,
+                left: budget.saturating_sub(used),
+                amount,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Counts `tokens` against the budget of `consumer` for the period starting
+/// at `period`, refusing them if they would exceed it. The check and the count
+/// happen together, so concurrent requests can't overspend the budget.
+pub fn reserve_budget(
+    usages: &FlatBudgetUsageList,
+    consumer: &Consumer,
+    period: NaiveDateTime,
+    tokens: u128,
+) -> Result<(), BillingError> {
+    usages
+        .add_checked(consumer.id, period, tokens as i128, |used| {
+            authorize_budget(consumer, used, tokens)
+        })
+        .map(|_| ())
+}
+
+/// Replaces the `reserved` tokens counted against the budget of
+/// `consumer_id` with the final `tokens`. The request was already served, so
+/// the budget may end up exceeded.
+pub fn settle_budget(
+    usages: &FlatBudgetUsageList,
+    consumer_id: u128,
+    period: NaiveDateTime,
+    reserved: u128,
+    tokens: u128,
+) {
+    if tokens != reserved {
+        usages.add(consumer_id, period, tokens as i128 - reserved as i128);
+    }
+}
+
+/// Returns the overage tokens spent in the current period of `subscription`.
+pub fn overage_used(subscription: &Subscription, overages: &[Overage]) -> u128 {
+    overage::used_since(overages, subscription.id, subscription.period_start())
+}
+
+/// Tokens spent by a request.
+pub struct Usage<'a> {
+    pub tokens: u128,
+    pub reason: &'a str,
+    pub request_id: Option<&'a str>,
+}
+
+/// How a charge was covered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Charge {
+    pub debited: u128,
+    pub overage: u128,
+}
+
+/// Runs `f` on `subscription` as it stands with its current quota. The
+/// subscriptions table stays locked until the quota `f` leaves is stored, so
+/// whatever `f` records is recorded against that quota alone.
+fn with_current<T>(
+    subscriptions: &FlatSubscriptionList,
+    subscription: &Subscription,
+    f: impl FnOnce(&mut Subscription) -> T,
+) -> Option<T> {
+    subscriptions.update_quota(subscription.id, |quota| {
+        let mut current = Subscription {
+            quota: *quota,
+            ..subscription.clone()
+        };
+        let result = f(&mut current);
+        *quota = current.quota;
+        result
+    })
+}
+
+/// Charges `usage` to the quota, spilling into overage when the plan allows it.
+pub fn charge(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    overages: &FlatOverageList,
+    subscription: &Subscription,
+    usage: Usage,
+    now: NaiveDateTime,
+) -> Result<Charge, BillingError> {
+    let amount = usage.tokens;
+    with_current(subscriptions, subscription, |current| {
+        charge_current(current, ledger, overages, usage, now)
+    })
+    .unwrap_or(Err(BillingError::InsufficientQuota { quota: 0, amount }))
+}
+
+fn charge_current(
+    current: &mut Subscription,
+    ledger: &FlatLedgerList,
+    overages: &FlatOverageList,
+    usage: Usage,
+    now: NaiveDateTime,
+) -> Result<Charge, BillingError> {
+    let plan = current.plan.clone();
+    let debited = match plan.overage_policy {
+        OveragePolicy::Hard => usage.tokens,
+        _ => usage.tokens.min(current.quota),
+    };
+    let extra = usage.tokens - debited;
+
+    if plan.overage_policy == OveragePolicy::Capped
+        && overage_used(current, &overages.list()) + extra > plan.overage_cap
+    {
+        return Err(BillingError::OverageCapReached {
+            cap: plan.overage_cap,
+        });
+    }
+
+    if debited > 0 || extra == 0 {
+        let transaction = Transaction {
+            kind: EntryKind::Debit,
+            amount: -(debited as i128),
+            reason: usage.reason,
+            request_id: usage.request_id,
+            operator: None,
+        };
+        apply_current(current, ledger, transaction, now)?;
+    }
+    if extra > 0 {
+        overages.create(current.id, usage.request_id, extra, plan.overage_price, now);
+    }
+
+    Ok(Charge {
+        debited,
+        overage: extra,
+    })
+}
+
+/// Reconciles the tokens `reserved` for a request with its final price in
+/// `usage`, charging the difference or giving it back.
+pub fn settle(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    overages: &FlatOverageList,
+    subscription_id: u128,
+    reserved: &Charge,
+    usage: Usage,
+    now: NaiveDateTime,
+) -> Result<(), BillingError> {
+    let subscription = match subscriptions.get_by_id(subscription_id) {
+        Some(subscription) => subscription,
+        None => return Ok(()),
+    };
+    let reserved_tokens = reserved.debited + reserved.overage;
+
+    if usage.tokens > reserved_tokens {
+        let extra = Usage {
+            tokens: usage.tokens - reserved_tokens,
+            ..usage
+        };
+        let tokens = extra.tokens;
+        let settled = with_current(subscriptions, &subscription, |current| {
+            match charge_current(current, ledger, overages, extra, now) {
+                Ok(_) => Ok(()),
+                // the upstream has already served the request, so take what is left
+                Err(_) if current.quota > 0 => {
+                    let transaction = Transaction {
+                        kind: EntryKind::Debit,
+                        amount: -(tokens.min(current.quota) as i128),
+                        reason: usage.reason,
+                        request_id: usage.request_id,
+                        operator: None,
+                    };
+                    apply_current(current, ledger, transaction, now).map(|_| ())
+                }
+                Err(_) => Ok(()),
+            }
+        });
+        return settled.unwrap_or(Ok(()));
+    }
+
+    let mut returned = reserved_tokens - usage.tokens;
+    if let (Some(request_id), true) = (usage.request_id, reserved.overage > 0) {
+        returned -= overages.release(request_id, returned.min(reserved.overage));
+    }
+    if returned == 0 {
+        return Ok(());
+    }
+    let transaction = Transaction {
+        kind: EntryKind::Refund,
+        amount: returned as i128,
+        reason: usage.reason,
+        request_id: usage.request_id,
+        operator: None,
+    };
+    apply(subscriptions, ledger, &subscription, transaction, now).map(|_| ())
+}
+
+/// A quota change to record in the ledger.
+pub struct Transaction<'a> {
+    pub kind: EntryKind,
+    pub amount: i128,
+    pub reason: &'a str,
+    pub request_id: Option<&'a str>,
+    /// Who made a manual change.
+    pub operator: Option<&'a str>,
+}
+
+/// Applies `transaction` to the subscription's quota and appends it to the
+/// ledger, both while the quota is locked.
+pub fn apply(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    subscription: &Subscription,
+    transaction: Transaction,
+    now: NaiveDateTime,
+) -> Result<Entry, BillingError> {
+    let amount = transaction.amount.unsigned_abs();
+    with_current(subscriptions, subscription, |current| {
+        apply_current(current, ledger, transaction, now)
+    })
+    .unwrap_or(Err(BillingError::InsufficientQuota { quota: 0, amount }))
+}
+
+fn apply_current(
+    current: &mut Subscription,
+    ledger: &FlatLedgerList,
+    transaction: Transaction,
+    now: NaiveDateTime,
+) -> Result<Entry, BillingError> {
+    let quota = current.quota;
+    let amount = transaction.amount.unsigned_abs();
+    let applied = match transaction.amount < 0 {
+        true => current.decrease_quota(amount),
+        false => current.add_quota(amount),
+    };
+    if applied.is_none() {
+        return Err(BillingError::InsufficientQuota { quota, amount });
+    }
+
+    Ok(ledger.append(
+        current.id,
+        transaction.kind,
+        transaction.amount,
+        transaction.reason,
+        transaction.request_id,
+        transaction.operator,
+        now,
+    ))
+}
+
+/// Charges `amount` tokens for a request.
+pub fn debit(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    subscription: &Subscription,
+    amount: u128,
+    reason: &str,
+    request_id: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<Entry, BillingError> {
+    let transaction = Transaction {
+        kind: EntryKind::Debit,
+        amount: -(amount as i128),
+        reason,
+        request_id,
+        operator: None,
+    };
+    apply(subscriptions, ledger, subscription, transaction, now)
+}
+
+/// Sets the quota to `quota`, recording the difference as a `kind` entry
+/// made by `operator`.
+#[allow(clippy::too_many_arguments)]
+pub fn reset_quota(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    subscription: &Subscription,
+    quota: u128,
+    kind: EntryKind,
+    reason: &str,
+    operator: Option<&str>,
+    now: NaiveDateTime,
+) -> Result<Entry, BillingError> {
+    with_current(subscriptions, subscription, |current| {
+        let transaction = Transaction {
+            kind,
+            amount: quota as i128 - current.quota as i128,
+            reason,
+            request_id: None,
+            operator,
+        };
+        apply_current(current, ledger, transaction, now)
+    })
+    .unwrap_or(Err(BillingError::InsufficientQuota {
+        quota: 0,
+        amount: quota,
+    }))
+}
+
+/// Gives back what `subscription_id` is still charged for each of
+/// `request_ids`: the quota they took, as `refund` entries made by `operator`,
+/// and the overage they were billed, which is released. Nothing is refunded
+/// unless every request can be. What is left to refund is read while the
+/// quota is locked, so concurrent refunds can't give a request back twice.
+#[allow(clippy::too_many_arguments)]
+pub fn refund(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    overages: &FlatOverageList,
+    subscription_id: u128,
+    request_ids: &[String],
+    reason: &str,
+    operator: &str,
+    now: NaiveDateTime,
+) -> Result<Vec<Entry>, BillingError> {
+    let not_refundable = |request: &str, reason: &str| BillingError::NotRefundable {
+        request: request.to_string(),
+        reason: reason.to_string(),
+    };
+    let not_charged = |request: &str| {
+        not_refundable(
+            request,
+            &format!("it was not charged to subscription {subscription_id}"),
+        )
+    };
+    let first = request_ids.first().map_or("", String::as_str);
+    let subscription = match subscriptions.get_by_id(subscription_id) {
+        Some(subscription) => subscription,
+        None => return Err(not_charged(first)),
+    };
+
+    with_current(subscriptions, &subscription, |current| {
+        let entries = ledger.list_by_subscription(subscription_id);
+        let mut refunds = vec![];
+        for (i, request_id) in request_ids.iter().enumerate() {
+            if request_ids[..i].contains(request_id) {
+                return Err(not_refundable(request_id, "it is listed twice"));
+            }
+            let charged = ledger::charged_for(&entries, subscription_id, request_id);
+            let overage = overages
+                .get_by_request(request_id)
+                .filter(|overage| overage.subscription_id == subscription_id);
+            if charged.is_none() && overage.is_none() {
+                return Err(not_charged(request_id));
+            }
+            let charged = charged.unwrap_or_default().max(0);
+            let overage = overage.map_or(0, |overage| overage.tokens);
+            if charged == 0 && overage == 0 {
+                return Err(not_refundable(request_id, "nothing is left to refund"));
+            }
+            refunds.push((request_id.as_str(), charged, overage));
+        }
+
+        let mut applied = vec![];
+        for (request_id, charged, overage) in refunds {
+            if charged > 0 {
+                let transaction = Transaction {
+                    kind: EntryKind::Refund,
+                    amount: charged,
+                    reason,
+                    request_id: Some(request_id),
+                    operator: Some(operator),
+                };
+                applied.push(apply_current(current, ledger, transaction, now)?);
+            }
+            if overage > 0 {
+                overages.release(request_id, overage);
+            }
+        }
+        Ok(applied)
+    })
+    .unwrap_or_else(|| Err(not_charged(first)))
+}
+
+/// Credits or, when `amount` is negative, debits the quota of `subscription`
+/// as an `adjustment` entry made by `operator`.
+pub fn adjust(
+    subscriptions: &FlatSubscriptionList,
+    ledger: &FlatLedgerList,
+    subscription: &Subscription,
+    amount: i128,
+    reason: &str,
+    operator: &str,
+    now: NaiveDateTime,
+) -> Result<Entry, BillingError> {
+    let transaction = Transaction {
+        kind: EntryKind::Adjustment,
+        amount,
+        reason,
+        request_id: None,
+        operator: Some(operator),
+    };
+    apply(subscriptions, ledger, subscription, transaction, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use crate::budget::budget_list::BudgetUsageList;
+    use crate::db::file_db::FlatTable;
+    use crate::ledger::ledger_list::LedgerList;
+    use crate::overage::overage_list::OverageList;
+    use crate::subscriber::{parse_date, subscriber_list::SubscriptionList};
+
+    use super::*;
+
+    fn overages() -> FlatOverageList {
+        let table = "id, subscription, request, tokens, price, created_at".to_string();
+        OverageList::new(Mutex::new(FlatTable::new_from_string(table)))
+    }
+
+    fn lists() -> (FlatSubscriptionList, FlatLedgerList) {
+        let subscriptions = "\
+        id, name, status, price, quota, expiry_date, auto_renew, plan
+        1, Startup 500, 1, 10000, 5, 2030-01-01 00:00:00, true, 1
+        "
+        .to_string();
+        let ledger = "\
+        id, subscription, kind, amount, reason, request, created_at
+        1, 1, credit, 5, opening balance, , 2023-01-01 00:00:00
+        "
+        .to_string();
+
+        (
+            SubscriptionList::new(Mutex::new(FlatTable::new_from_string(subscriptions))),
+            LedgerList::new(Mutex::new(FlatTable::new_from_string(ledger))),
+        )
+    }
+
+    #[test]
+    fn reject_exhausted_quota_by_policy() {
+        let now = parse_date("2023-01-15 00:00:00").unwrap();
+        let hard = Subscription::fake(&HashMap::from([
+            ("quota", "0"),
+            ("expiry_date", "2023-02-01 00:00:00"),
+        ]));
+        let mut capped = hard.clone();
+        capped.plan.overage_policy = OveragePolicy::Capped;
+        capped.plan.overage_cap = 10;
+
+        assert!(matches!(
+            authorize(&hard, 0, now),
+            Err(BillingError::InsufficientQuota { .. })
+        ));
+        assert_eq!(authorize(&capped, 9, now), Ok(()));
+        assert_eq!(
+            authorize(&capped, 10, now),
+            Err(BillingError::OverageCapReached { cap: 10 })
+        );
+    }
+
+    #[test]
+    fn charge_overage_on_soft_limit() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let mut subscription = subscriptions.get_by_id(1).unwrap();
+        subscription.plan.overage_policy = OveragePolicy::Soft;
+        subscription.plan.overage_price = 30;
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+        let usage = Usage {
+            tokens: 8,
+            reason: "service_a",
+            request_id: Some("UUID-1"),
+        };
+
+        let charge = charge(
+            &subscriptions,
+            &ledger,
+            &overages,
+            &subscription,
+            usage,
+            now,
+        );
+
+        assert_eq!(
+            charge,
+            Ok(Charge {
+                debited: 5,
+                overage: 3
+            })
+        );
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 0);
+        assert_eq!(overages.list()[0].cost(), 90);
+    }
+
+    #[test]
+    fn refuse_charge_beyond_overage_cap() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let mut subscription = subscriptions.get_by_id(1).unwrap();
+        subscription.plan.overage_policy = OveragePolicy::Capped;
+        subscription.plan.overage_cap = 2;
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+        let usage = Usage {
+            tokens: 8,
+            reason: "service_a",
+            request_id: None,
+        };
+
+        let charge = charge(
+            &subscriptions,
+            &ledger,
+            &overages,
+            &subscription,
+            usage,
+            now,
+        );
+
+        assert_eq!(charge, Err(BillingError::OverageCapReached { cap: 2 }));
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 5);
+        assert!(overages.list().is_empty());
+    }
+
+    #[test]
+    fn settle_reservation_against_final_price() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+        let usage = |tokens| Usage {
+            tokens,
+            reason: "service_a",
+            request_id: Some("UUID-1"),
+        };
+
+        let reserved = charge(
+            &subscriptions,
+            &ledger,
+            &overages,
+            &subscription,
+            usage(2),
+            now,
+        )
+        .unwrap();
+        settle(
+            &subscriptions,
+            &ledger,
+            &overages,
+            1,
+            &reserved,
+            usage(3),
+            now,
+        )
+        .unwrap();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        assert_eq!(subscription.quota, 2);
+
+        let reserved = charge(
+            &subscriptions,
+            &ledger,
+            &overages,
+            &subscription,
+            usage(2),
+            now,
+        )
+        .unwrap();
+        settle(
+            &subscriptions,
+            &ledger,
+            &overages,
+            1,
+            &reserved,
+            usage(0),
+            now,
+        )
+        .unwrap();
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 2);
+        assert_eq!(ledger.list().last().unwrap().kind, EntryKind::Refund);
+    }
+
+    #[test]
+    fn debit_quota_through_ledger() {
+        let (subscriptions, ledger) = lists();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+
+        let entry = debit(
+            &subscriptions,
+            &ledger,
+            &subscription,
+            2,
+            "service_a",
+            Some("UUID-1"),
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(entry.amount, -2);
+        assert_eq!(entry.request_id.as_deref(), Some("UUID-1"));
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 3);
+        assert!(crate::ledger::reconcile(&subscriptions.list(), &ledger.list()).is_empty());
+    }
+
+    #[test]
+    fn debit_current_quota_under_concurrent_charges() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let mut stale = subscriptions.get_by_id(1).unwrap();
+        stale.plan.overage_policy = OveragePolicy::Hard;
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+
+        let charged = std::thread::scope(|scope| {
+            let charges = (0..6)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let usage = Usage {
+                            tokens: 1,
+                            reason: "service_a",
+                            request_id: None,
+                        };
+                        charge(&subscriptions, &ledger, &overages, &stale, usage, now)
+                    })
+                })
+                .collect::<Vec<_>>();
+            charges
+                .into_iter()
+                .map(|charge| charge.join().unwrap())
+                .filter(Result::is_ok)
+                .count()
+        });
+
+        assert_eq!(charged, 5);
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 0);
+        assert!(crate::ledger::reconcile(&subscriptions.list(), &ledger.list()).is_empty());
+    }
+
+    #[test]
+    fn do_not_debit_beyond_quota() {
+        let (subscriptions, ledger) = lists();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-01-02 00:00:00").unwrap();
+
+        let result = debit(
+            &subscriptions,
+            &ledger,
+            &subscription,
+            6,
+            "service_a",
+            None,
+            now,
+        );
+
+        assert_eq!(
+            result.unwrap_err(),
+            BillingError::InsufficientQuota {
+                quota: 5,
+                amount: 6
+            }
+        );
+        assert_eq!(ledger.list().len(), 1);
+    }
+
+    #[test]
+    fn reset_quota_records_difference() {
+        let (subscriptions, ledger) = lists();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-02-01 00:00:00").unwrap();
+
+        let entry = reset_quota(
+            &subscriptions,
+            &ledger,
+            &subscription,
+            500,
+            EntryKind::Renewal,
+            "renewal",
+            None,
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(entry.amount, 495);
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 500);
+    }
+
+    #[test]
+    fn refund_charged_requests_once() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-02-01 00:00:00").unwrap();
+        debit(
+            &subscriptions,
+            &ledger,
+            &subscription,
+            3,
+            "service_a",
+            Some("r-1"),
+            now,
+        )
+        .unwrap();
+        let requests = vec!["r-1".to_string()];
+        let refund = |requests: &[String]| {
+            refund(
+                &subscriptions,
+                &ledger,
+                &overages,
+                1,
+                requests,
+                "outage",
+                "alice",
+                now,
+            )
+        };
+
+        let refunds = refund(&requests).unwrap();
+
+        assert_eq!(refunds[0].amount, 3);
+        assert_eq!(refunds[0].operator.as_deref(), Some("alice"));
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 5);
+        assert_eq!(
+            refund(&requests).unwrap_err().to_string(),
+            "Request r-1 can't be refunded: nothing is left to refund"
+        );
+        let unknown = vec!["r-1".to_string(), "r-2".to_string()];
+        assert!(refund(&unknown).is_err());
+    }
+
+    #[test]
+    fn refund_overage_along_with_quota() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let mut subscription = subscriptions.get_by_id(1).unwrap();
+        subscription.plan.overage_policy = OveragePolicy::Soft;
+        let now = parse_date("2023-02-01 00:00:00").unwrap();
+        for (tokens, request_id) in [(8, "r-1"), (2, "r-2")] {
+            let usage = Usage {
+                tokens,
+                reason: "service_a",
+                request_id: Some(request_id),
+            };
+            charge(
+                &subscriptions,
+                &ledger,
+                &overages,
+                &subscription,
+                usage,
+                now,
+            )
+            .unwrap();
+        }
+        let requests = vec!["r-1".to_string(), "r-2".to_string()];
+
+        let refunds = refund(
+            &subscriptions,
+            &ledger,
+            &overages,
+            1,
+            &requests,
+            "outage",
+            "alice",
+            now,
+        )
+        .unwrap();
+
+        // r-2 went entirely to overage, so only r-1 gives back quota
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].amount, 5);
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 5);
+        assert!(overages.list().iter().all(|overage| overage.tokens == 0));
+    }
+
+    #[test]
+    fn refund_concurrently_only_once() {
+        let (subscriptions, ledger) = lists();
+        let overages = overages();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-02-01 00:00:00").unwrap();
+        debit(
+            &subscriptions,
+            &ledger,
+            &subscription,
+            3,
+            "service_a",
+            Some("r-1"),
+            now,
+        )
+        .unwrap();
+        let requests = vec!["r-1".to_string()];
+
+        let refunded = std::thread::scope(|scope| {
+            let refunds = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        refund(
+                            &subscriptions,
+                            &ledger,
+                            &overages,
+                            1,
+                            &requests,
+                            "outage",
+                            "alice",
+                            now,
+                        )
+                    })
+                })
+                .collect::<Vec<_>>();
+            refunds
+                .into_iter()
+                .map(|refund| refund.join().unwrap())
+                .filter(Result::is_ok)
+                .count()
+        });
+
+        assert_eq!(refunded, 1);
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 5);
+    }
+
+    #[test]
+    fn adjust_quota_both_ways() {
+        let (subscriptions, ledger) = lists();
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        let now = parse_date("2023-02-01 00:00:00").unwrap();
+
+        let entry = adjust(
+            &subscriptions,
+            &ledger,
+            &subscription,
+            -2,
+            "typo",
+            "bob",
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(entry.kind, EntryKind::Adjustment);
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 3);
+        let subscription = subscriptions.get_by_id(1).unwrap();
+        assert_eq!(
+            adjust(
+                &subscriptions,
+                &ledger,
+                &subscription,
+                -4,
+                "typo",
+                "bob",
+                now
+            )
+            .unwrap_err(),
+            BillingError::InsufficientQuota {
+                quota: 3,
+                amount: 4
+            }
+        );
+    }
+
+    #[test]
+    fn authorize_active_subscription() {
+        let subscription =
+            Subscription::fake(&HashMap::from([("expiry_date", "2023-02-01 00:00:00")]));
+
+        assert_eq!(
+            authorize(&subscription, 0, parse_date("2023-01-15 00:00:00").unwrap()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reject_expired_subscription() {
+        let subscription =
+            Subscription::fake(&HashMap::from([("expiry_date", "2023-02-01 00:00:00")]));
+
+        assert_eq!(
+            authorize(&subscription, 0, parse_date("2023-02-02 00:00:00").unwrap()),
+            Err(BillingError::Expired(subscription.expiry_date))
+        );
+    }
+
+    #[test]
+    fn reject_suspended_subscription() {
+        let subscription = Subscription::fake(&HashMap::from([
+            ("status", "2"),
+            ("expiry_date", "2023-02-01 00:00:00"),
+        ]));
+
+        assert_eq!(
+            authorize(&subscription, 0, parse_date("2023-01-15 00:00:00").unwrap()),
+            Err(BillingError::Inactive(SubscriptionStatus::Suspended))
+        );
+    }
+
+    #[test]
+    fn refuse_consumer_over_budget() {
+        let usages = BudgetUsageList::new(Mutex::new(FlatTable::new_from_string(
+            "id, consumer, period, tokens".to_string(),
+        )));
+        let consumer = Consumer::fake(&HashMap::from([("budget", "10")]));
+        let period = parse_date("2023-01-01 00:00:00").unwrap();
+
+        assert_eq!(reserve_budget(&usages, &consumer, period, 6), Ok(()));
+        assert_eq!(
+            reserve_budget(&usages, &consumer, period, 6),
+            Err(BillingError::BudgetExceeded {
+                budget: 10,
+                left: 4,
+                amount: 6
+            })
+        );
+
+        settle_budget(&usages, consumer.id, period, 6, 12);
+
+        let spent = authorize_budget(&consumer, usages.used(consumer.id, period), 0);
+        assert_eq!(
+            spent.unwrap_err().to_string(),
+            "Consumer budget of 10 tokens is spent for this period"
+        );
+        let unlimited = Consumer::fake(&HashMap::from([("id", "2")]));
+        assert_eq!(reserve_budget(&usages, &unlimited, period, 100), Ok(()));
+    }
+}
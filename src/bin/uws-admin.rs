@@ -0,0 +1,24 @@
+use std::process::exit;
+
+use uws_gateway::cli::{self, CliError, Tables, USAGE};
+use uws_gateway::config::GatewayConfig;
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if let Err(e) = GatewayConfig::load().map(|config| config.apply()) {
+        eprintln!("Invalid gateway config: {e}");
+        exit(1)
+    }
+
+    match cli::run(&args, &Tables::open()) {
+        Ok(output) => print!("{output}"),
+        Err(CliError::Usage(message)) => {
+            eprintln!("{message}\n\n{USAGE}");
+            exit(2)
+        }
+        Err(CliError::Failed(message)) => {
+            eprintln!("{message}");
+            exit(1)
+        }
+    }
+}
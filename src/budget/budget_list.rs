@@ -0,0 +1,178 @@
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record, Searchable, Writable};
+use crate::subscriber::{format_date, parse_date};
+
+use super::BudgetUsage;
+
+#[derive(Clone)]
+pub struct BudgetUsageList<D> {
+    db: Arc<Mutex<D>>,
+    pub usages: Vec<BudgetUsage>,
+}
+
+pub type FlatBudgetUsageList = BudgetUsageList<FlatTable<String, String>>;
+
+fn period_filter(consumer_id: u128, period: NaiveDateTime) -> Filter {
+    Filter::All(vec![
+        Filter::eq("consumer", &consumer_id.to_string()),
+        Filter::eq("period", &format_date(&period)),
+    ])
+}
+
+impl FlatBudgetUsageList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        BudgetUsageList {
+            db: Arc::new(db),
+            usages: vec![],
+        }
+    }
+
+    pub fn list(&self) -> Vec<BudgetUsage> {
+        BudgetUsageList::get_all::<FlatTable<String, String>, BudgetUsage>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<BudgetUsage> {
+        BudgetUsageList::search_records::<FlatTable<String, String>, BudgetUsage>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        BudgetUsageList::count_records(&self.db, filter)
+    }
+
+    pub fn get(&self, consumer_id: u128, period: NaiveDateTime) -> Option<BudgetUsage> {
+        let query = Query::default().filter(period_filter(consumer_id, period));
+        self.filter(&query.limit(1)).pop()
+    }
+
+    /// Returns the tokens `consumer_id` spent in the period starting at `period`.
+    pub fn used(&self, consumer_id: u128, period: NaiveDateTime) -> u128 {
+        self.get(consumer_id, period)
+            .map(|usage| usage.tokens)
+            .unwrap_or_default()
+    }
+
+    /// Adds `tokens` to the counter of `consumer_id` for `period`, taking
+    /// them back when negative. The counter never goes below zero.
+    pub fn add(&self, consumer_id: u128, period: NaiveDateTime, tokens: i128) -> BudgetUsage {
+        match self.add_checked(consumer_id, period, tokens, |_| Ok::<(), Infallible>(())) {
+            Ok(usage) => usage,
+        }
+    }
+
+    /// Adds `tokens` like `add`, unless `check` refuses the tokens already
+    /// counted. Checking and counting happen under one lock, so concurrent
+    /// requests can't all pass the check on the same count.
+    pub fn add_checked<E>(
+        &self,
+        consumer_id: u128,
+        period: NaiveDateTime,
+        tokens: i128,
+        check: impl FnOnce(u128) -> Result<(), E>,
+    ) -> Result<BudgetUsage, E> {
+        let mut db = self.db.lock().expect("lock db");
+        let query = Query::default().filter(period_filter(consumer_id, period));
+        let usage = db
+            .search(&query.limit(1))
+            .pop()
+            .map(|record| BudgetUsage::from(record.clone()));
+        check(usage.as_ref().map(|usage| usage.tokens).unwrap_or_default())?;
+
+        let record = match usage {
+            Some(usage) => {
+                let total = (usage.tokens as i128 + tokens).max(0);
+                let changes = Record::from([("tokens".to_string(), total.to_string())]);
+                db.update_by("id", &usage.id.to_string(), changes)
+                    .expect("budget usage exists")
+                    .clone()
+            }
+            None => {
+                let id = db.next_id();
+                db.insert(Record::from([
+                    ("id".to_string(), id.to_string()),
+                    ("consumer".to_string(), consumer_id.to_string()),
+                    ("period".to_string(), format_date(&period)),
+                    ("tokens".to_string(), tokens.max(0).to_string()),
+                ]))
+                .clone()
+            }
+        };
+        Ok(BudgetUsage::from(record))
+    }
+}
+
+impl ModelAble<String, String> for FlatBudgetUsageList {}
+
+impl From<Record<String, String>> for BudgetUsage {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("consumer"),
+            map.get("period"),
+            map.get("tokens"),
+        ) {
+            (Some(id), Some(consumer_id), Some(period), Some(tokens)) => BudgetUsage {
+                id: id.parse::<u128>().unwrap(),
+                consumer_id: consumer_id.parse::<u128>().unwrap(),
+                period: parse_date(period).expect("Invalid budget period"),
+                tokens: tokens.parse::<u128>().unwrap(),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_tokens_per_consumer_and_period() {
+        let table = "id, consumer, period, tokens".to_string();
+        let usage_list = BudgetUsageList::new(Mutex::new(FlatTable::new_from_string(table)));
+        let january = parse_date("2023-01-01 00:00:00").unwrap();
+        let february = parse_date("2023-02-01 00:00:00").unwrap();
+
+        usage_list.add(1, january, 5);
+        usage_list.add(1, january, -2);
+        usage_list.add(1, february, 4);
+        usage_list.add(2, january, 7);
+
+        assert_eq!(usage_list.used(1, january), 3);
+        assert_eq!(usage_list.used(1, february), 4);
+        assert_eq!(usage_list.used(3, january), 0);
+        assert_eq!(usage_list.add(1, january, -10).tokens, 0);
+        assert_eq!(usage_list.list().len(), 3);
+    }
+
+    #[test]
+    fn check_and_count_concurrent_tokens_together() {
+        let table = "id, consumer, period, tokens".to_string();
+        let usage_list = BudgetUsageList::new(Mutex::new(FlatTable::new_from_string(table)));
+        let january = parse_date("2023-01-01 00:00:00").unwrap();
+
+        let counted = std::thread::scope(|scope| {
+            let adds = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        usage_list.add_checked(1, january, 1, |used| match used < 5 {
+                            true => Ok(()),
+                            false => Err(used),
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            adds.into_iter()
+                .map(|add| add.join().unwrap())
+                .filter(Result::is_ok)
+                .count()
+        });
+
+        assert_eq!(counted, 5);
+        assert_eq!(usage_list.used(1, january), 5);
+        assert_eq!(usage_list.list().len(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::subscriber::parse_date;
+
+pub mod budget_list;
+
+/// Tokens a consumer spent in one billing period, counted against its budget.
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetUsage {
+    pub id: u128,
+    pub consumer_id: u128,
+    /// Start of the billing period the tokens were spent in.
+    pub period: NaiveDateTime,
+    pub tokens: u128,
+}
+
+impl BudgetUsage {
+    pub fn fake(attr: &HashMap<&str, &str>) -> BudgetUsage {
+        BudgetUsage {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            consumer_id: attr
+                .get("consumer")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            period: parse_date(attr.get("period").unwrap_or(&"2001-01-01 00:00:00")).unwrap(),
+            tokens: attr.get("tokens").unwrap_or(&"0").parse::<u128>().unwrap(),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{CacheConfig, CacheStoreKind};
+use crate::router::Upstream;
+use crate::service::Service;
+use crate::transform;
+
+pub mod store;
+
+use store::{DiskStore, MemoryStore};
+
+/// Response header telling whether a cacheable call was served from the
+/// cache (`HIT`) or by the service (`MISS`).
+pub const CACHE_HEADER: &str = "X-Cache";
+
+/// `Cache-Control` directives that keep a response out of the cache.
+const UNCACHEABLE: [&str; 3] = ["no-store", "no-cache", "private"];
+
+/// A response kept to answer identical calls until it expires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+    /// What the call that filled the cache was charged.
+    pub price: u128,
+    pub expires_at: NaiveDateTime,
+}
+
+impl CachedResponse {
+    /// Rebuilds the kept response, marked as a hit.
+    pub fn hit(&self) -> Upstream {
+        let mut headers = self.headers.clone();
+        headers.push((CACHE_HEADER.to_string(), "HIT".to_string()));
+        Upstream {
+            status: self.status,
+            headers,
+            body: self.body.clone(),
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+/// Somewhere cached responses are kept.
+pub trait CacheStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<CachedResponse>;
+    fn put(&self, key: &str, response: CachedResponse);
+    fn remove(&self, key: &str);
+}
+
+/// Reads a service's `cache_headers`, header names separated by spaces.
+pub fn parse_headers(value: &str) -> Result<Vec<String>, String> {
+    value
+        .split_whitespace()
+        .map(|name| match transform::is_header_name(name) {
+            true => Ok(name.to_string()),
+            false => Err("`cache_headers` should be header names separated by spaces".to_string()),
+        })
+        .collect()
+}
+
+/// Returns the key responses to a call to `service` are cached under: the
+/// calling subscriber, so that no response is served to another tenant, the
+/// path and query, along with the values of the service's `cache_headers`.
+pub fn key(
+    service: &Service,
+    subscriber: u128,
+    path: &str,
+    query: Option<&str>,
+    headers: &[(String, String)],
+) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        &subscriber.to_string(),
+        &service.product.slug,
+        &service.slug,
+        &service.version,
+        path,
+        query.unwrap_or_default(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\n");
+    }
+    for name in &service.cache_headers {
+        let values = headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        hasher.update(format!("{}: {values}\n", name.to_lowercase()).as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Returns how long a response with `headers` may be cached: as long as its
+/// `Cache-Control` says, else `service_ttl` seconds. `None` if it may not.
+pub fn ttl(service_ttl: u64, headers: &[(String, String)]) -> Option<Duration> {
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for (_, value) in headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("cache-control"))
+    {
+        for directive in value
+            .split(',')
+            .map(|directive| directive.trim().to_lowercase())
+        {
+            let (name, seconds) = match directive.split_once('=') {
+                Some((name, argument)) => (name, argument.trim_matches('"').parse::<u64>().ok()),
+                None => (directive.as_str(), None),
+            };
+            match name {
+                name if UNCACHEABLE.contains(&name) => return None,
+                "max-age" => max_age = seconds,
+                "s-maxage" => shared_max_age = seconds,
+                _ => {}
+            }
+        }
+    }
+    match shared_max_age.or(max_age).unwrap_or(service_ttl) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+/// The responses kept for services with a `cache_ttl`.
+pub struct ResponseCache {
+    store: Box<dyn CacheStore>,
+    hit_price_percent: u128,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> ResponseCache {
+        let store: Box<dyn CacheStore> = match config.store {
+            CacheStoreKind::Memory => Box::new(MemoryStore::new(config.capacity)),
+            CacheStoreKind::Disk => Box::new(DiskStore::new(&config.dir, config.capacity)),
+        };
+        ResponseCache {
+            store,
+            hit_price_percent: config.hit_price_percent.into(),
+        }
+    }
+
+    /// Returns the response kept under `key` unless it expired by `now`.
+    pub fn lookup(&self, key: &str, now: NaiveDateTime) -> Option<CachedResponse> {
+        let cached = self.store.get(key)?;
+        if cached.expires_at <= now {
+            self.store.remove(key);
+            return None;
+        }
+        Some(cached)
+    }
+
+    /// Keeps `upstream`, which cost `price`, under `key` for `ttl`.
+    pub fn keep(
+        &self,
+        key: &str,
+        upstream: &Upstream,
+        price: u128,
+        ttl: Duration,
+        now: NaiveDateTime,
+    ) {
+        let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+        self.store.put(
+            key,
+            CachedResponse {
+                status: upstream.status,
+                headers: upstream.headers.clone(),
+                body: upstream.body.clone(),
+                price,
+                expires_at: now.checked_add_signed(ttl).unwrap_or(NaiveDateTime::MAX),
+            },
+        );
+    }
+
+    /// Returns what a hit on `cached` is charged, a share of the first call's
+    /// price.
+    pub fn hit_price(&self, cached: &CachedResponse) -> u128 {
+        cached.price * self.hit_price_percent / 100
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn header(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn take_ttl_from_cache_control_over_service() {
+        assert_eq!(ttl(60, &[]), Some(Duration::from_secs(60)));
+        assert_eq!(
+            ttl(60, &[header("Cache-Control", "public, max-age=300")]),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            ttl(60, &[header("cache-control", "max-age=300, s-maxage=30")]),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(ttl(60, &[header("Cache-Control", "no-store")]), None);
+        assert_eq!(
+            ttl(60, &[header("Cache-Control", "private, max-age=300")]),
+            None
+        );
+        assert_eq!(ttl(0, &[]), None);
+    }
+
+    #[test]
+    fn key_calls_by_subscriber_path_query_and_selected_headers() {
+        let service = Service::fake(&HashMap::from([("cache_headers", "Accept-Language")]));
+        let french = [header("accept-language", "fr"), header("X-Trace", "1")];
+        let english = [header("Accept-Language", "en"), header("X-Trace", "1")];
+
+        let key_1 = key(&service, 1, "/users/1", Some("page=2"), &french);
+
+        assert_eq!(
+            key_1,
+            key(
+                &service,
+                1,
+                "/users/1",
+                Some("page=2"),
+                &[header("Accept-Language", "fr"), header("X-Trace", "2")]
+            )
+        );
+        assert_ne!(
+            key_1,
+            key(&service, 1, "/users/1", Some("page=2"), &english)
+        );
+        assert_ne!(key_1, key(&service, 1, "/users/1", None, &french));
+        assert_ne!(key_1, key(&service, 1, "/users/2", Some("page=2"), &french));
+        assert_ne!(key_1, key(&service, 2, "/users/1", Some("page=2"), &french));
+    }
+
+    #[test]
+    fn charge_share_of_first_price_for_hits() {
+        let cache = ResponseCache::new(&CacheConfig {
+            hit_price_percent: 25,
+            ..CacheConfig::default()
+        });
+        let now = chrono::Utc::now().naive_utc();
+        let upstream = Upstream {
+            status: 200,
+            headers: vec![header("Content-Type", "text/plain")],
+            body: b"ok".to_vec(),
+            duration: Duration::ZERO,
+        };
+
+        cache.keep("key-1", &upstream, 8, Duration::from_secs(60), now);
+        let cached = cache.lookup("key-1", now).unwrap();
+
+        assert_eq!(cache.hit_price(&cached), 2);
+        assert_eq!(cached.hit().body, b"ok");
+        assert!(cache
+            .lookup("key-1", now + chrono::Duration::seconds(60))
+            .is_none());
+    }
+}
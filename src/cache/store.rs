@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rocket::serde::json;
+
+use crate::logger;
+
+use super::{CacheStore, CachedResponse};
+
+struct Entries {
+    clock: u64,
+    /// Responses along with when they were last used.
+    responses: HashMap<String, (u64, CachedResponse)>,
+}
+
+/// Keeps responses in memory, dropping the least recently used once it
+/// holds `capacity` of them.
+pub struct MemoryStore {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        MemoryStore {
+            capacity,
+            entries: Mutex::new(Entries {
+                clock: 0,
+                responses: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().expect("lock cache");
+        entries.clock += 1;
+        let clock = entries.clock;
+        let (used, response) = entries.responses.get_mut(key)?;
+        *used = clock;
+        Some(response.clone())
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        let mut entries = self.entries.lock().expect("lock cache");
+        entries.clock += 1;
+        let clock = entries.clock;
+        entries.responses.insert(key.to_string(), (clock, response));
+        while entries.responses.len() > self.capacity {
+            let oldest = entries
+                .responses
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.responses.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries
+            .lock()
+            .expect("lock cache")
+            .responses
+            .remove(key);
+    }
+}
+
+/// Keeps responses as `<key>.cache` files in `dir`, so they outlive
+/// restarts, dropping the oldest once it holds `capacity` of them. Each file
+/// holds the response's status and headers as a JSON line, then its body.
+pub struct DiskStore {
+    dir: PathBuf,
+    capacity: usize,
+}
+
+impl DiskStore {
+    pub fn new(dir: &Path, capacity: usize) -> Self {
+        DiskStore {
+            dir: dir.to_path_buf(),
+            capacity,
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.cache"))
+    }
+
+    fn evict(&self) -> std::io::Result<()> {
+        let mut files = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "cache"))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect::<Vec<_>>();
+        if files.len() > self.capacity {
+            files.sort();
+            for (_, path) in &files[..files.len() - self.capacity] {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, key: &str, response: &CachedResponse) -> std::io::Result<()> {
+        let mut contents = json::to_string(response)
+            .expect("serialize cached response")
+            .into_bytes();
+        contents.push(b'\n');
+        contents.extend_from_slice(&response.body);
+        fs::create_dir_all(&self.dir)?;
+        // written aside first, so a reader never sees half a response
+        let partial = self.dir.join(format!("{key}.partial"));
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, self.path(key))?;
+        self.evict()
+    }
+}
+
+impl CacheStore for DiskStore {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let contents = fs::read(self.path(key)).ok()?;
+        let end = contents.iter().position(|byte| *byte == b'\n')?;
+        let mut response = json::from_slice::<CachedResponse>(&contents[..end]).ok()?;
+        response.body = contents[end + 1..].to_vec();
+        Some(response)
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        if let Err(e) = self.write(key, &response) {
+            logger::log(&format!("Response could not be cached: {e}"));
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.path(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    fn response(body: &str) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: body.as_bytes().to_vec(),
+            price: 4,
+            expires_at: NaiveDateTime::MAX,
+        }
+    }
+
+    #[test]
+    fn drop_least_recently_used_response() {
+        let store = MemoryStore::new(2);
+
+        store.put("a", response("a"));
+        store.put("b", response("b"));
+        store.get("a");
+        store.put("c", response("c"));
+
+        assert!(store.get("a").is_some());
+        assert!(store.get("b").is_none());
+        assert!(store.get("c").is_some());
+    }
+
+    #[test]
+    fn keep_responses_on_disk() {
+        let dir = std::env::temp_dir().join(format!("uws-cache-{}", std::process::id()));
+        let store = DiskStore::new(&dir, 1);
+
+        store.put("a", response("first\nline"));
+        let kept = store.get("a");
+        store.put("b", response("b"));
+        let dropped = store.get("a");
+        store.remove("b");
+        let removed = store.get("b");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(kept, Some(response("first\nline")));
+        assert!(dropped.is_none());
+        assert!(removed.is_none());
+    }
+}
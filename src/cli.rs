@@ -0,0 +1,1171 @@
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+use chrono::Utc;
+use rocket::serde::json::{self, serde_json::Map, Value};
+use serde::Serialize;
+
+use crate::admin::{changes, validate_text};
+use crate::biller::{self, Transaction};
+use crate::cache;
+use crate::consumer::consumer_list::FlatConsumerList;
+use crate::db::file_db::get_table_instance;
+use crate::db::{Filter, Query, Record};
+use crate::entitlement::Entitlements;
+use crate::guards::generate_key;
+use crate::integrity;
+use crate::invoice::{self, BillingMonth};
+use crate::ledger::{self, ledger_list::FlatLedgerList, EntryKind};
+use crate::migration::{self, FlatStore, MIGRATIONS};
+use crate::overage::overage_list::FlatOverageList;
+use crate::plan::plan_list::FlatPlanList;
+use crate::product::product_list::FlatProductList;
+use crate::request::request_list::FlatRequestList;
+use crate::router::table::RoutingTable;
+use crate::sandbox::{self, SandboxRequestList};
+use crate::service::{service_list::FlatServiceList, WebSocketBilling};
+use crate::snapshot::{self, ExportOptions, Snapshot};
+use crate::subscriber::subscriber_list::{FlatSubscriberList, FlatSubscriptionList};
+use crate::subscriber::{format_date, parse_date, SubscriptionStatus};
+
+pub const USAGE: &str = "\
+Usage: uws-admin <command> [options]
+
+Commands:
+  <entity> list [--format table|json]
+  <entity> show <id> [--format table|json]
+  <entity> create --<field> <value>...
+  <entity> update <id> --<field> <value>...
+      Manage subscribers, subscriptions, consumers, products or services
+  subscribers issue-key <id>
+  subscribers revoke-key <id>
+      Issue or revoke a subscriber's portal key
+  consumers issue-key <id>
+  consumers revoke-key <id>
+      Issue or revoke a consumer's API key
+  subscriptions top-up <id> --tokens <n> [--reason <text>]
+      Add tokens to a subscription's quota
+  subscriptions refund <id> --requests \"<id>...\" --reason <text> [--operator <name>]
+      Return the tokens charged for requests to a subscription's quota
+  subscriptions adjust <id> --amount <n> --reason <text> [--operator <name>]
+      Credit (positive) or debit (negative) a subscription's quota
+  requests [--consumer <id>] [--service <id>] [--limit <n>] [--sandbox true] [--format table|json]
+      Print the latest logged requests, or those made with sandbox keys
+  check
+      Validate the integrity of the tables
+  migrate [--dry-run true]
+      Apply the pending schema migrations, or only list them
+  export [--logs true] [--redact true]
+      Print the gateway data as JSON, with the logs or without keys
+  import <file> [--dry-run true]
+      Replace the gateway data with an export, or only validate it
+  invoice --period <YYYY-MM> [--subscriber <id>] [--format json|csv]
+      Print the statements of a billing month
+
+Fields:
+  subscribers    --name, --subscription
+  subscriptions  --plan, --name, --status, --price, --quota, --expiry-date, --auto-renew
+  consumers      --subscriber, --access-token (issued if missing), --scopes,
+                 --budget (tokens per period, `none` to lift it), --sandbox true|false
+  products       --slug, --requests
+  services       --name, --slug, --version, --status, --base-url, --price, --requests, --product,
+                 --sandbox-url (`none` to serve fixtures),
+                 --websocket-billing connection|message|minute,
+                 --cache-ttl (seconds, `none` to stop caching), --cache-headers";
+
+/// Who quota changes made from the CLI are attributed to in the ledger.
+const OPERATOR: &str = "uws-admin";
+
+const DEFAULT_LIMIT: usize = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    /// The command was misused; the usage should be shown.
+    Usage(String),
+    /// The command could not be carried out.
+    Failed(String),
+}
+
+type CliResult<T> = Result<T, CliError>;
+
+fn failed(message: String) -> CliError {
+    CliError::Failed(message)
+}
+
+/// The tables the commands work on.
+pub struct Tables {
+    pub plans: FlatPlanList,
+    pub subscriptions: FlatSubscriptionList,
+    pub subscribers: FlatSubscriberList,
+    pub consumers: FlatConsumerList,
+    pub products: FlatProductList,
+    pub services: FlatServiceList,
+    pub ledger: FlatLedgerList,
+    pub requests: FlatRequestList,
+    pub sandbox_requests: SandboxRequestList,
+    pub overages: FlatOverageList,
+}
+
+impl Tables {
+    pub fn open() -> Tables {
+        Tables {
+            plans: FlatPlanList::new(get_table_instance("plans")),
+            subscriptions: FlatSubscriptionList::new(get_table_instance("subscriptions")),
+            subscribers: FlatSubscriberList::new(get_table_instance("subscribers")),
+            consumers: FlatConsumerList::new(get_table_instance("consumers")),
+            products: FlatProductList::new(get_table_instance("products")),
+            services: FlatServiceList::new(get_table_instance("services")),
+            ledger: FlatLedgerList::new(get_table_instance("ledger")),
+            requests: FlatRequestList::new(get_table_instance("requests")),
+            sandbox_requests: SandboxRequestList::new(get_table_instance("sandbox_requests")),
+            overages: FlatOverageList::new(get_table_instance("overages")),
+        }
+    }
+}
+
+/// Positional arguments and `--name value` options of a command.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub positional: Vec<String>,
+    /// Option values by name, with dashes turned into underscores.
+    pub options: HashMap<String, String>,
+}
+
+impl Args {
+    pub fn parse(args: &[String]) -> CliResult<Args> {
+        let mut parsed = Args::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage(format!("`--{name}` needs a value")))?;
+                    parsed
+                        .options
+                        .insert(name.replace('-', "_"), value.to_string());
+                }
+                None => parsed.positional.push(arg.to_string()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Refuses options other than `allowed` and `format`.
+    fn allow(&self, allowed: &[&str]) -> CliResult<()> {
+        match self
+            .options
+            .keys()
+            .find(|name| *name != "format" && !allowed.contains(&name.as_str()))
+        {
+            Some(name) => Err(CliError::Usage(format!(
+                "Unknown option `--{}`",
+                name.replace('_', "-")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.options.get(name).cloned()
+    }
+
+    fn required(&self, name: &str) -> CliResult<String> {
+        self.text(name)
+            .ok_or_else(|| CliError::Usage(format!("`--{}` is required", name.replace('_', "-"))))
+    }
+
+    /// Parses a numeric or boolean option.
+    fn number<T: FromStr>(&self, name: &str) -> CliResult<Option<T>> {
+        self.options
+            .get(name)
+            .map(|value| {
+                value.trim().parse::<T>().map_err(|_| {
+                    failed(format!(
+                        "`--{}` has an invalid value",
+                        name.replace('_', "-")
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    fn id(&self) -> CliResult<u128> {
+        match self.positional.first().map(|id| id.parse::<u128>()) {
+            Some(Ok(id)) => Ok(id),
+            _ => Err(CliError::Usage("An `<id>` is required".to_string())),
+        }
+    }
+
+    fn format(&self) -> CliResult<Format> {
+        match self.options.get("format").map(|format| format.as_str()) {
+            None | Some("table") => Ok(Format::Table),
+            Some("json") => Ok(Format::Json),
+            Some(_) => Err(CliError::Usage(
+                "`--format` should be `table` or `json`".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        // nested records are shown by id
+        Value::Object(record) => record.get("id").map(cell).unwrap_or_default(),
+        other => other.to_string(),
+    }
+}
+
+/// Lays `items` out as aligned columns, `id` first.
+pub fn to_table<T: Serialize>(items: &[T]) -> String {
+    let rows = items
+        .iter()
+        // `to_value` refuses `u128`, which the JSON text supports
+        .map(
+            |item| match json::from_str(&json::to_string(item).unwrap_or_default()) {
+                Ok(Value::Object(record)) => record,
+                _ => Map::new(),
+            },
+        )
+        .collect::<Vec<Map<String, Value>>>();
+    let mut columns = rows
+        .first()
+        .map(|row| row.keys().cloned().collect::<Vec<String>>())
+        .unwrap_or_default();
+    columns.sort_by_key(|column| column != "id");
+
+    let cells = rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| row.get(column).map(cell).unwrap_or_default())
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            cells
+                .iter()
+                .map(|row| row[i].len())
+                .chain([column.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<usize>>();
+
+    let line = |values: &[String]| {
+        values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{value:width$}"))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    let mut lines = vec![line(&columns)];
+    lines.extend(cells.iter().map(|row| line(row)));
+    lines.join("\n") + "\n"
+}
+
+fn render<T: Serialize>(items: &[T], format: Format) -> String {
+    match format {
+        Format::Table => to_table(items),
+        Format::Json => json::to_pretty_string(&items).expect("serialize records") + "\n",
+    }
+}
+
+fn render_one<T: Serialize>(item: T, format: Format) -> String {
+    match format {
+        Format::Table => to_table(&[item]),
+        Format::Json => json::to_pretty_string(&item).expect("serialize record") + "\n",
+    }
+}
+
+fn fields(args: &Args, names: &[&str]) -> CliResult<Record<String, String>> {
+    changes(names.iter().map(|name| (*name, args.text(name))).collect()).map_err(failed)
+}
+
+fn not_found(entity: &str, id: u128) -> CliError {
+    failed(format!("{entity} with id:{id} is not found"))
+}
+
+fn check_subscription(tables: &Tables, id: u128) -> CliResult<()> {
+    match tables.subscriptions.get_by_id(id) {
+        Some(_) => Ok(()),
+        None => Err(failed(format!("Subscription with id:{id} does not exist"))),
+    }
+}
+
+fn check_status(args: &Args) -> CliResult<()> {
+    match args
+        .number::<u8>("status")?
+        .map(SubscriptionStatus::from_u8)
+    {
+        Some(None) => Err(failed("`--status` is not a valid status".to_string())),
+        _ => Ok(()),
+    }
+}
+
+fn check_date(args: &Args, name: &str) -> CliResult<()> {
+    match args.text(name).as_deref().map(parse_date) {
+        Some(None) => Err(failed(format!(
+            "`--{}` should look like `2024-01-31 00:00:00`",
+            name.replace('_', "-")
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn subscribers(action: &str, args: &Args, tables: &Tables) -> CliResult<String> {
+    let format = args.format()?;
+    let list = &tables.subscribers;
+    match action {
+        "list" => Ok(render(&list.list(), format)),
+        "show" => {
+            let id = args.id()?;
+            let subscriber = list
+                .get_by_id(id)
+                .ok_or_else(|| not_found("Subscriber", id))?;
+            Ok(render_one(subscriber, format))
+        }
+        "create" => {
+            args.allow(&["name", "subscription"])?;
+            let name = args.required("name")?;
+            validate_text("name", &name).map_err(failed)?;
+            let subscription_id = args
+                .number::<u128>("subscription")?
+                .ok_or_else(|| CliError::Usage("`--subscription` is required".to_string()))?;
+            check_subscription(tables, subscription_id)?;
+            let subscriber = list.create(
+                name.trim().to_string(),
+                generate_key("sub"),
+                subscription_id,
+            );
+            Ok(render_one(subscriber, format))
+        }
+        "update" => {
+            args.allow(&["name", "subscription"])?;
+            let id = args.id()?;
+            if let Some(subscription_id) = args.number::<u128>("subscription")? {
+                check_subscription(tables, subscription_id)?;
+            }
+            let subscriber = list
+                .update(id, fields(args, &["name", "subscription"])?)
+                .ok_or_else(|| not_found("Subscriber", id))?;
+            Ok(render_one(subscriber, format))
+        }
+        "issue-key" | "revoke-key" => {
+            let id = args.id()?;
+            let key = match action {
+                "issue-key" => generate_key("sub"),
+                _ => String::new(),
+            };
+            list.update(
+                id,
+                Record::from([("access_token".to_string(), key.clone())]),
+            )
+            .ok_or_else(|| not_found("Subscriber", id))?;
+            Ok(issued(&key))
+        }
+        _ => Err(unknown_action("subscribers", action)),
+    }
+}
+
+fn subscriptions(action: &str, args: &Args, tables: &Tables) -> CliResult<String> {
+    let format = args.format()?;
+    let list = &tables.subscriptions;
+    let now = Utc::now().naive_utc();
+    let set_quota = |subscription, quota, kind, reason: &str| {
+        biller::reset_quota(
+            list,
+            &tables.ledger,
+            subscription,
+            quota,
+            kind,
+            reason,
+            Some(OPERATOR),
+            now,
+        )
+        .map_err(|e| failed(e.to_string()))
+    };
+    let editable = [
+        "plan",
+        "name",
+        "status",
+        "price",
+        "quota",
+        "expiry_date",
+        "auto_renew",
+    ];
+    match action {
+        "list" => Ok(render(&list.list(), format)),
+        "show" => {
+            let id = args.id()?;
+            let subscription = list
+                .get_by_id(id)
+                .ok_or_else(|| not_found("Subscription", id))?;
+            Ok(render_one(subscription, format))
+        }
+        "create" => {
+            args.allow(&editable)?;
+            let plan_id = args
+                .number::<u128>("plan")?
+                .ok_or_else(|| CliError::Usage("`--plan` is required".to_string()))?;
+            let plan = tables
+                .plans
+                .get_by_id(plan_id)
+                .ok_or_else(|| failed(format!("Plan with id:{plan_id} does not exist")))?;
+            check_status(args)?;
+            check_date(args, "expiry_date")?;
+            let quota = args.number::<u128>("quota")?.unwrap_or(plan.quota);
+            let auto_renew = args.number::<bool>("auto_renew")?.unwrap_or(false);
+            let record = changes(vec![
+                ("plan", Some(plan.id.to_string())),
+                ("name", Some(args.text("name").unwrap_or(plan.name))),
+                (
+                    "status",
+                    Some(
+                        args.text("status")
+                            .unwrap_or((SubscriptionStatus::Active as u8).to_string()),
+                    ),
+                ),
+                (
+                    "price",
+                    Some(args.text("price").unwrap_or(plan.price.to_string())),
+                ),
+                ("quota", Some("0".to_string())),
+                (
+                    "expiry_date",
+                    Some(
+                        args.text("expiry_date")
+                            .unwrap_or_else(|| format_date(&plan.period.next(now))),
+                    ),
+                ),
+                ("auto_renew", Some(auto_renew.to_string())),
+            ])
+            .map_err(failed)?;
+
+            // the opening quota goes through the ledger like any other credit
+            let subscription = list.create(record);
+            set_quota(
+                &subscription,
+                quota,
+                EntryKind::Credit,
+                &format!("opening balance by {OPERATOR}"),
+            )?;
+            Ok(render_one(list.get_by_id(subscription.id).unwrap(), format))
+        }
+        "update" => {
+            args.allow(&editable)?;
+            let id = args.id()?;
+            let subscription = list
+                .get_by_id(id)
+                .ok_or_else(|| not_found("Subscription", id))?;
+            if let Some(plan_id) = args.number::<u128>("plan")? {
+                if tables.plans.get_by_id(plan_id).is_none() {
+                    return Err(failed(format!("Plan with id:{plan_id} does not exist")));
+                }
+            }
+            check_status(args)?;
+            check_date(args, "expiry_date")?;
+            args.number::<u128>("price")?;
+            args.number::<bool>("auto_renew")?;
+            let changes = fields(
+                args,
+                &[
+                    "plan",
+                    "name",
+                    "status",
+                    "price",
+                    "expiry_date",
+                    "auto_renew",
+                ],
+            )?;
+            if let Some(quota) = args.number::<u128>("quota")? {
+                set_quota(
+                    &subscription,
+                    quota,
+                    EntryKind::Adjustment,
+                    &format!("quota set by {OPERATOR}"),
+                )?;
+            }
+            let subscription = list
+                .update(id, changes)
+                .ok_or_else(|| not_found("Subscription", id))?;
+            Ok(render_one(subscription, format))
+        }
+        "top-up" => {
+            args.allow(&["tokens", "reason"])?;
+            let id = args.id()?;
+            let subscription = list
+                .get_by_id(id)
+                .ok_or_else(|| not_found("Subscription", id))?;
+            let tokens = match args.number::<u128>("tokens")? {
+                Some(tokens) if tokens > 0 => tokens,
+                _ => return Err(failed("`--tokens` should be positive".to_string())),
+            };
+            let reason = args
+                .text("reason")
+                .unwrap_or_else(|| format!("top-up by {OPERATOR}"));
+            validate_text("reason", &reason).map_err(failed)?;
+            let transaction = Transaction {
+                kind: EntryKind::Credit,
+                amount: tokens as i128,
+                reason: reason.trim(),
+                request_id: None,
+                operator: Some(OPERATOR),
+            };
+            biller::apply(list, &tables.ledger, &subscription, transaction, now)
+                .map_err(|e| failed(e.to_string()))?;
+            Ok(render_one(list.get_by_id(id).unwrap(), format))
+        }
+        "refund" => {
+            args.allow(&["requests", "reason", "operator"])?;
+            let id = args.id()?;
+            list.get_by_id(id)
+                .ok_or_else(|| not_found("Subscription", id))?;
+            let requests: Vec<String> = args
+                .required("requests")?
+                .split_whitespace()
+                .map(str::to_string)
+                .collect();
+            if requests.is_empty() {
+                return Err(failed("`--requests` should list request ids".to_string()));
+            }
+            let (reason, operator) = audit(args)?;
+            let entries = biller::refund(
+                list,
+                &tables.ledger,
+                &tables.overages,
+                id,
+                &requests,
+                &reason,
+                &operator,
+                now,
+            )
+            .map_err(|e| failed(e.to_string()))?;
+            Ok(render(&entries, format))
+        }
+        "adjust" => {
+            args.allow(&["amount", "reason", "operator"])?;
+            let id = args.id()?;
+            let subscription = list
+                .get_by_id(id)
+                .ok_or_else(|| not_found("Subscription", id))?;
+            let amount = match args.number::<i128>("amount")? {
+                Some(amount) if amount != 0 => amount,
+                _ => return Err(failed("`--amount` should not be zero".to_string())),
+            };
+            let (reason, operator) = audit(args)?;
+            let entry = biller::adjust(
+                list,
+                &tables.ledger,
+                &subscription,
+                amount,
+                &reason,
+                &operator,
+                now,
+            )
+            .map_err(|e| failed(e.to_string()))?;
+            Ok(render_one(entry, format))
+        }
+        _ => Err(unknown_action("subscriptions", action)),
+    }
+}
+
+/// Reads the reason and operator every refund or adjustment is recorded with.
+fn audit(args: &Args) -> CliResult<(String, String)> {
+    let reason = args.required("reason")?;
+    validate_text("reason", &reason).map_err(failed)?;
+    let operator = args
+        .text("operator")
+        .unwrap_or_else(|| OPERATOR.to_string());
+    validate_text("operator", &operator).map_err(failed)?;
+    Ok((reason.trim().to_string(), operator.trim().to_string()))
+}
+
+fn check_access_token(
+    tables: &Tables,
+    access_token: &str,
+    consumer_id: Option<u128>,
+) -> CliResult<()> {
+    match tables.consumers.get_by_access_token(access_token.trim()) {
+        Some(consumer) if Some(consumer.id) != consumer_id => Err(failed(
+            "`--access-token` is already used by another consumer".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_subscriber(tables: &Tables, id: u128) -> CliResult<()> {
+    match tables.subscribers.get_by_id(id) {
+        Some(_) => Ok(()),
+        None => Err(failed(format!("Subscriber with id:{id} does not exist"))),
+    }
+}
+
+/// Parses grants separated by spaces, such as `product_a product_b/service_b`.
+fn check_scopes(scopes: &str) -> CliResult<Entitlements> {
+    match Entitlements::parse(scopes).map_err(failed)? {
+        scopes if scopes.0.is_empty() => Err(failed(
+            "`--scopes` should list at least one grant".to_string(),
+        )),
+        scopes => Ok(scopes),
+    }
+}
+
+/// Parses `--budget`, where `none` lifts the budget.
+fn budget(args: &Args) -> CliResult<Option<Option<u128>>> {
+    match args.text("budget").as_deref().map(str::trim) {
+        Some("none") => Ok(Some(None)),
+        _ => Ok(args.number::<u128>("budget")?.map(Some)),
+    }
+}
+
+fn consumers(action: &str, args: &Args, tables: &Tables) -> CliResult<String> {
+    let format = args.format()?;
+    let list = &tables.consumers;
+    match action {
+        "list" => Ok(render(&list.list(), format)),
+        "show" => {
+            let id = args.id()?;
+            let consumer = list
+                .get_by_id(id)
+                .ok_or_else(|| not_found("Consumer", id))?;
+            Ok(render_one(consumer, format))
+        }
+        "create" => {
+            args.allow(&["subscriber", "access_token", "scopes", "budget", "sandbox"])?;
+            let subscriber_id = args
+                .number::<u128>("subscriber")?
+                .ok_or_else(|| CliError::Usage("`--subscriber` is required".to_string()))?;
+            check_subscriber(tables, subscriber_id)?;
+            let access_token = args
+                .text("access_token")
+                .unwrap_or_else(|| generate_key("key"));
+            validate_text("access_token", &access_token).map_err(failed)?;
+            check_access_token(tables, &access_token, None)?;
+            let scopes = match args.text("scopes") {
+                Some(scopes) => check_scopes(&scopes)?,
+                None => Entitlements::all(),
+            };
+            let consumer = list.create(
+                access_token.trim().to_string(),
+                subscriber_id,
+                &scopes,
+                budget(args)?.flatten(),
+                args.number::<bool>("sandbox")?.unwrap_or(false),
+            );
+            Ok(render_one(consumer, format))
+        }
+        "update" => {
+            args.allow(&["subscriber", "access_token", "scopes", "budget", "sandbox"])?;
+            let id = args.id()?;
+            if let Some(subscriber_id) = args.number::<u128>("subscriber")? {
+                check_subscriber(tables, subscriber_id)?;
+            }
+            if let Some(access_token) = args.text("access_token") {
+                check_access_token(tables, &access_token, Some(id))?;
+            }
+            let mut changes = fields(args, &["subscriber", "access_token"])?;
+            if let Some(scopes) = args.text("scopes") {
+                changes.insert("scopes".to_string(), check_scopes(&scopes)?.to_string());
+            }
+            if let Some(budget) = budget(args)? {
+                let budget = budget.map(|budget| budget.to_string());
+                changes.insert("budget".to_string(), budget.unwrap_or_default());
+            }
+            if let Some(sandbox) = args.number::<bool>("sandbox")? {
+                changes.insert("sandbox".to_string(), sandbox.to_string());
+            }
+            let consumer = list
+                .update(id, changes)
+                .ok_or_else(|| not_found("Consumer", id))?;
+            Ok(render_one(consumer, format))
+        }
+        "issue-key" | "revoke-key" => {
+            let id = args.id()?;
+            let key = match action {
+                "issue-key" => generate_key("key"),
+                _ => String::new(),
+            };
+            list.update(
+                id,
+                Record::from([("access_token".to_string(), key.clone())]),
+            )
+            .ok_or_else(|| not_found("Consumer", id))?;
+            Ok(issued(&key))
+        }
+        _ => Err(unknown_action("consumers", action)),
+    }
+}
+
+fn products(action: &str, args: &Args, tables: &Tables) -> CliResult<String> {
+    let format = args.format()?;
+    let list = &tables.products;
+    let check_slug = |slug: &str, id: Option<u128>| match list.get_by_slug(slug.trim()) {
+        Some(product) if Some(product.id) != id => {
+            Err(failed("`--slug` is already used".to_string()))
+        }
+        _ => Ok(()),
+    };
+    match action {
+        "list" => Ok(render(&list.list(), format)),
+        "show" => {
+            let id = args.id()?;
+            let product = list.get_by_id(id).ok_or_else(|| not_found("Product", id))?;
+            Ok(render_one(product, format))
+        }
+        "create" => {
+            args.allow(&["slug", "requests"])?;
+            let slug = args.required("slug")?;
+            validate_text("slug", &slug).map_err(failed)?;
+            check_slug(&slug, None)?;
+            let requests = args.number::<u128>("requests")?.unwrap_or(0);
+            Ok(render_one(
+                list.create(slug.trim().to_string(), requests),
+                format,
+            ))
+        }
+        "update" => {
+            args.allow(&["slug", "requests"])?;
+            let id = args.id()?;
+            if let Some(slug) = args.text("slug") {
+                check_slug(&slug, Some(id))?;
+            }
+            args.number::<u128>("requests")?;
+            let product = list
+                .update(id, fields(args, &["slug", "requests"])?)
+                .ok_or_else(|| not_found("Product", id))?;
+            Ok(render_one(product, format))
+        }
+        _ => Err(unknown_action("products", action)),
+    }
+}
+
+/// Reads a service's fields, where `--sandbox-url none` clears the sandbox
+/// and `--cache-ttl none` stops caching.
+fn service_fields(args: &Args, names: &[&str]) -> CliResult<Record<String, String>> {
+    let mut record = fields(args, names)?;
+    for name in ["sandbox_url", "cache_ttl"] {
+        if record.get(name).is_some_and(|value| value == "none") {
+            record.insert(name.to_string(), String::new());
+        }
+    }
+    Ok(record)
+}
+
+fn services(action: &str, args: &Args, tables: &Tables) -> CliResult<String> {
+    let format = args.format()?;
+    let list = &tables.services;
+    let editable = [
+        "name",
+        "slug",
+        "version",
+        "status",
+        "base_url",
+        "price",
+        "requests",
+        "product",
+        "sandbox_url",
+        "websocket_billing",
+        "cache_ttl",
+        "cache_headers",
+    ];
+    let check = |args: &Args| -> CliResult<()> {
+        args.allow(&editable)?;
+        args.number::<u32>("status")?;
+        args.number::<u128>("price")?;
+        args.number::<u128>("requests")?;
+        if let Some(url) = args.text("sandbox_url").filter(|url| url.trim() != "none") {
+            sandbox::check_url(&url).map_err(failed)?;
+        }
+        if let Some(billing) = args.text("websocket_billing") {
+            WebSocketBilling::parse(&billing).ok_or_else(|| {
+                failed(
+                    "`websocket_billing` should be `connection`, `message` or `minute`".to_string(),
+                )
+            })?;
+        }
+        if args
+            .text("cache_ttl")
+            .filter(|ttl| ttl.trim() != "none")
+            .is_some()
+        {
+            args.number::<u64>("cache_ttl")?;
+        }
+        if let Some(headers) = args.text("cache_headers") {
+            cache::parse_headers(&headers).map_err(failed)?;
+        }
+        if let Some(product_id) = args.number::<u128>("product")? {
+            if tables.products.get_by_id(product_id).is_none() {
+                return Err(failed(format!(
+                    "Product with id:{product_id} does not exist"
+                )));
+            }
+        }
+        Ok(())
+    };
+    match action {
+        "list" => Ok(render(&list.list(), format)),
+        "show" => {
+            let id = args.id()?;
+            let service = list.get_by_id(id).ok_or_else(|| not_found("Service", id))?;
+            Ok(render_one(service, format))
+        }
+        "create" => {
+            check(args)?;
+            for name in editable.iter().filter(|name| {
+                ![
+                    "requests",
+                    "sandbox_url",
+                    "websocket_billing",
+                    "cache_ttl",
+                    "cache_headers",
+                ]
+                .contains(name)
+            }) {
+                args.required(name)?;
+            }
+            let mut record = service_fields(args, &editable)?;
+            record
+                .entry("requests".to_string())
+                .or_insert_with(|| "0".to_string());
+            record
+                .entry("websocket_billing".to_string())
+                .or_insert_with(|| WebSocketBilling::Connection.as_str().to_string());
+            Ok(render_one(list.create(record), format))
+        }
+        "update" => {
+            check(args)?;
+            let id = args.id()?;
+            let service = list
+                .update(id, service_fields(args, &editable)?)
+                .ok_or_else(|| not_found("Service", id))?;
+            Ok(render_one(service, format))
+        }
+        _ => Err(unknown_action("services", action)),
+    }
+}
+
+fn issued(key: &str) -> String {
+    match key.is_empty() {
+        true => "Key revoked\n".to_string(),
+        false => format!("{key}\n"),
+    }
+}
+
+fn unknown_action(entity: &str, action: &str) -> CliError {
+    CliError::Usage(format!("Unknown action `{action}` for `{entity}`"))
+}
+
+fn requests(args: &Args, tables: &Tables) -> CliResult<String> {
+    args.allow(&["consumer", "service", "limit", "sandbox"])?;
+    let requests = match args.number::<bool>("sandbox")? {
+        Some(true) => &tables.sandbox_requests.0,
+        _ => &tables.requests,
+    };
+    let consumer = args.number::<u128>("consumer")?;
+    let service = args.number::<u128>("service")?;
+    let limit = args.number::<usize>("limit")?.unwrap_or(DEFAULT_LIMIT);
+    let filter = Filter::All(
+        [("consumer", consumer), ("service", service)]
+            .into_iter()
+            .filter_map(|(attr, id)| Some(Filter::eq(attr, &id?.to_string())))
+            .collect(),
+    );
+    let total = requests.count(Some(&filter));
+    let query = Query::default()
+        .filter(filter)
+        .offset(total.saturating_sub(limit));
+    Ok(render(&requests.filter(&query), args.format()?))
+}
+
+/// Reports the problems in the stored tables and those that would break
+/// routing or billing. Routing and the ledger are only checked on tables
+/// without problems, as reading broken rows would panic.
+fn check(tables: &Tables) -> CliResult<String> {
+    let mut problems = integrity::check_storage()
+        .iter()
+        .map(|problem| problem.to_string())
+        .collect::<Vec<String>>();
+    if !problems.is_empty() {
+        return Err(failed(problems.join("\n")));
+    }
+    if let Err(e) = RoutingTable::build(&tables.products.records(), &tables.services.records()) {
+        problems.extend(e.0);
+    }
+    for mismatch in ledger::reconcile(&tables.subscriptions.list(), &tables.ledger.list()) {
+        problems.push(format!(
+            "subscription with id:{} has a quota of {} but its ledger sums to {}",
+            mismatch.subscription_id, mismatch.cached, mismatch.derived
+        ));
+    }
+    match problems.is_empty() {
+        true => Ok("No problems found\n".to_string()),
+        false => Err(failed(problems.join("\n"))),
+    }
+}
+
+fn migrate(args: &Args) -> CliResult<String> {
+    args.allow(&["dry_run"])?;
+    let dry_run = args.number::<bool>("dry_run")?.unwrap_or(false);
+    let mut store = FlatStore::open();
+    let applied =
+        migration::migrate(&mut store, MIGRATIONS, dry_run).map_err(|e| failed(e.to_string()))?;
+    if applied.is_empty() {
+        return Ok("No pending migrations\n".to_string());
+    }
+
+    let verb = match dry_run {
+        true => "Would apply",
+        false => "Applied",
+    };
+    let mut output = String::new();
+    for migration in applied {
+        output.push_str(&format!(
+            "{verb} migration {}: {}\n",
+            migration.version, migration.name
+        ));
+        for step in migration.steps {
+            output.push_str(&format!("  {step}\n"));
+        }
+    }
+    Ok(output)
+}
+
+fn export(args: &Args) -> CliResult<String> {
+    args.allow(&["logs", "redact"])?;
+    let options = ExportOptions {
+        logs: args.number::<bool>("logs")?.unwrap_or(false),
+        redact: args.number::<bool>("redact")?.unwrap_or(false),
+    };
+    let snapshot = snapshot::export_storage(options).map_err(|e| failed(e.to_string()))?;
+    Ok(json::to_pretty_string(&snapshot).expect("serialize snapshot") + "\n")
+}
+
+fn import(args: &Args) -> CliResult<String> {
+    args.allow(&["dry_run"])?;
+    let dry_run = args.number::<bool>("dry_run")?.unwrap_or(false);
+    let path = args
+        .positional
+        .first()
+        .ok_or_else(|| CliError::Usage("`import` needs a file".to_string()))?;
+    let content = fs::read_to_string(path).map_err(|e| failed(format!("{path}: {e}")))?;
+    let snapshot =
+        json::from_str::<Snapshot>(&content).map_err(|e| failed(format!("{path}: {e}")))?;
+
+    let restored =
+        snapshot::restore_storage(&snapshot, dry_run).map_err(|e| failed(e.to_string()))?;
+    let verb = match dry_run {
+        true => "Would restore",
+        false => "Restored",
+    };
+    Ok(format!("{verb} {}\n", restored.join(", ")))
+}
+
+fn invoice(args: &Args, tables: &Tables) -> CliResult<String> {
+    args.allow(&["period", "subscriber"])?;
+    let month = match args.text("period").as_deref().map(BillingMonth::parse) {
+        Some(Some(month)) => month,
+        _ => {
+            return Err(CliError::Usage(
+                "`--period` should look like `2024-01`".to_string(),
+            ))
+        }
+    };
+    let subscribers = match args.text("subscriber") {
+        Some(id) => match id
+            .parse::<u128>()
+            .ok()
+            .and_then(|id| tables.subscribers.get_by_id(id))
+        {
+            Some(subscriber) => vec![subscriber],
+            None => return Err(failed(format!("Subscriber `{id}` is not found"))),
+        },
+        None => tables.subscribers.list(),
+    };
+    let invoices = invoice::generate_all(
+        &subscribers,
+        &tables.ledger.list(),
+        &tables.requests.list(),
+        &tables.overages.list(),
+        &month,
+    );
+
+    match args.text("format").as_deref().unwrap_or("json") {
+        "json" => Ok(json::to_pretty_string(&invoices).expect("serialize invoices") + "\n"),
+        "csv" => Ok(invoice::to_csv(&invoices)),
+        _ => Err(CliError::Usage(
+            "`--format` should be `json` or `csv`".to_string(),
+        )),
+    }
+}
+
+/// Runs the command in `args`, returning what it prints.
+pub fn run(args: &[String], tables: &Tables) -> CliResult<String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Ok(format!("{USAGE}\n")),
+    };
+    let entity = |handle: fn(&str, &Args, &Tables) -> CliResult<String>| match rest.split_first() {
+        Some((action, rest)) => handle(action, &Args::parse(rest)?, tables),
+        None => Err(CliError::Usage(format!("`{command}` needs an action"))),
+    };
+
+    match command {
+        "subscribers" => entity(subscribers),
+        "subscriptions" => entity(subscriptions),
+        "consumers" => entity(consumers),
+        "products" => entity(products),
+        "services" => entity(services),
+        "requests" => requests(&Args::parse(rest)?, tables),
+        "check" => check(tables),
+        "migrate" => migrate(&Args::parse(rest)?),
+        "export" => export(&Args::parse(rest)?),
+        "import" => import(&Args::parse(rest)?),
+        "invoice" => invoice(&Args::parse(rest)?, tables),
+        "help" | "--help" => Ok(format!("{USAGE}\n")),
+        _ => Err(CliError::Usage(format!("Unknown command `{command}`"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::db::file_db::FlatTable;
+
+    fn table(contents: &str) -> Mutex<FlatTable<String, String>> {
+        Mutex::new(FlatTable::new_from_string(contents.to_string()))
+    }
+
+    fn tables() -> Tables {
+        Tables {
+            plans: FlatPlanList::new(table(
+                "id, name, price, quota, period, overage_policy, overage_price, overage_cap",
+            )),
+            subscriptions: FlatSubscriptionList::new(table(
+                "id, name, status, price, quota, expiry_date, auto_renew, plan\n\
+                 1, Startup 500, 1, 10000, 5, 2030-01-01 00:00:00, true, 1",
+            )),
+            subscribers: FlatSubscriberList::new(table("id, name, subscription, access_token")),
+            consumers: FlatConsumerList::new(table("id, subscriber, access_token")),
+            products: FlatProductList::new(table("id, slug, requests\n1, product_a, 0")),
+            services: FlatServiceList::new(table(
+                "id, name, slug, version, status, base_url, price, requests, product",
+            )),
+            ledger: FlatLedgerList::new(table(
+                "id, subscription, kind, amount, reason, request, created_at\n\
+                 1, 1, credit, 5, opening balance, , 2023-01-01 00:00:00",
+            )),
+            requests: FlatRequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )),
+            sandbox_requests: SandboxRequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )),
+            overages: FlatOverageList::new(table(
+                "id, subscription, request, tokens, price, created_at",
+            )),
+        }
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn create_and_list_products() {
+        let tables = tables();
+
+        run(&args("products create --slug product_b"), &tables).unwrap();
+        let output = run(&args("products list"), &tables).unwrap();
+
+        assert_eq!(
+            output,
+            "id  requests  slug\n1   0         product_a\n2   0         product_b\n"
+        );
+        assert_eq!(
+            run(&args("products create --slug product_b"), &tables),
+            Err(CliError::Failed("`--slug` is already used".to_string()))
+        );
+    }
+
+    #[test]
+    fn top_up_subscription_through_ledger() {
+        let tables = tables();
+
+        run(&args("subscriptions top-up 1 --tokens 20"), &tables).unwrap();
+
+        assert_eq!(tables.subscriptions.get_by_id(1).unwrap().quota, 25);
+        let entries = tables.ledger.list();
+        assert_eq!(entries[1].amount, 20);
+        assert_eq!(entries[1].reason, "top-up by uws-admin");
+        assert_eq!(
+            run(&args("check"), &tables),
+            Ok("No problems found\n".to_string())
+        );
+    }
+
+    #[test]
+    fn adjust_subscription_with_reason() {
+        let tables = tables();
+
+        assert_eq!(
+            run(&args("subscriptions adjust 1 --amount -2"), &tables),
+            Err(CliError::Usage("`--reason` is required".to_string()))
+        );
+        run(
+            &args("subscriptions adjust 1 --amount -2 --reason outage --operator ana"),
+            &tables,
+        )
+        .unwrap();
+
+        assert_eq!(tables.subscriptions.get_by_id(1).unwrap().quota, 3);
+        let entries = tables.ledger.list();
+        assert_eq!(entries[1].kind, EntryKind::Adjustment);
+        assert_eq!(entries[1].operator.as_deref(), Some("ana"));
+        assert_eq!(
+            run(
+                &args("subscriptions refund 1 --requests r-1 --reason outage"),
+                &tables
+            ),
+            Err(CliError::Failed(
+                "Request r-1 can't be refunded: it was not charged to subscription 1".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_misused_commands() {
+        let tables = tables();
+
+        assert_eq!(
+            run(&args("products create --name product_b"), &tables),
+            Err(CliError::Usage("Unknown option `--name`".to_string()))
+        );
+        assert_eq!(
+            run(&args("services update"), &tables),
+            Err(CliError::Usage("An `<id>` is required".to_string()))
+        );
+        assert!(matches!(
+            run(&args("plans list"), &tables),
+            Err(CliError::Usage(_))
+        ));
+    }
+}
@@ -0,0 +1,501 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rocket::config::LogLevel;
+use rocket::data::{ByteUnit, ToByteUnit};
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
+use rocket::figment::Figment;
+use serde::{Deserialize, Serialize};
+
+use crate::alert::AlertSettings;
+use crate::db::file_db;
+use crate::logger;
+use crate::router::UPSTREAM_TIMEOUT;
+
+/// Environment variable naming the config file, `uws.toml` by default.
+pub const CONFIG_PATH_ENV: &str = "UWS_CONFIG";
+
+/// Environment variables starting with this override the config file, with
+/// `__` between section and key, e.g. `UWS_SERVER__PORT=8080`.
+pub const ENV_PREFIX: &str = "UWS_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// Comma separated `<table>_table.txt` files in `storage.dir`.
+    #[default]
+    Flat,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub dir: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Flat,
+            dir: PathBuf::from("db"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain.
+    pub certs: PathBuf,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Serves HTTPS when set.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8000,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Requests a consumer can make per minute, `0` for no limit.
+    pub requests_per_minute: u32,
+    /// Largest request body forwarded to a service, in mebibytes.
+    pub body_mib: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            requests_per_minute: 0,
+            body_mib: 10,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn body(&self) -> ByteUnit {
+        self.body_mib.mebibytes()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// How long the gateway waits for a service, in seconds, both for its
+    /// response and between the chunks of a streamed one.
+    pub timeout_secs: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            timeout_secs: UPSTREAM_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    /// How long responses are kept for retries with the same
+    /// `Idempotency-Key`, in hours.
+    pub retention_hours: u64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            retention_hours: 24,
+        }
+    }
+}
+
+impl IdempotencyConfig {
+    pub fn retention(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.retention_hours as i64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// How long a job waits for its service, in seconds, as jobs are meant
+    /// for calls too long to wait for.
+    pub timeout_secs: u64,
+    /// How long finished jobs keep their result, in hours.
+    pub retention_hours: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            timeout_secs: 600,
+            retention_hours: 24,
+        }
+    }
+}
+
+impl JobsConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn retention(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.retention_hours as i64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    /// Percentages of the quota that raise an alert once used.
+    pub thresholds: Vec<u8>,
+    /// How many days before expiry the expiry alert is raised.
+    pub expiry_notice_days: u32,
+    /// Deliveries are given up after this many failed attempts.
+    pub max_attempts: u32,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            thresholds: vec![50, 80, 100],
+            expiry_notice_days: 7,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl AlertsConfig {
+    pub fn settings(&self) -> AlertSettings {
+        AlertSettings {
+            thresholds: self.thresholds.clone(),
+            expiry_notice: chrono::Duration::days(self.expiry_notice_days.into()),
+            max_attempts: self.max_attempts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Where the fixtures answering sandbox keys are read from, as
+    /// `<service slug>/<version>.json`.
+    pub fixtures_dir: PathBuf,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            fixtures_dir: PathBuf::from("fixtures"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStoreKind {
+    /// In the gateway's memory, dropping the least recently used responses.
+    #[default]
+    Memory,
+    /// As files in `cache.dir`, dropping the oldest responses.
+    Disk,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub store: CacheStoreKind,
+    /// Most responses kept at once.
+    pub capacity: usize,
+    /// Where the `disk` store keeps responses.
+    pub dir: PathBuf,
+    /// Share of the first call's price charged for a cache hit, in percent.
+    pub hit_price_percent: u8,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            store: CacheStoreKind::Memory,
+            capacity: 1000,
+            dir: PathBuf::from("cache"),
+            hit_price_percent: 10,
+        }
+    }
+}
+
+/// Where the gateway's own messages are written.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LogSink {
+    Stdout,
+    /// Appends timestamped lines to `path`.
+    File {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Rocket's own log level: `off`, `critical`, `normal` or `debug`.
+    pub level: LogLevel,
+    pub sinks: Vec<LogSink>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: LogLevel::Normal,
+            sinks: vec![LogSink::Stdout],
+        }
+    }
+}
+
+/// Gateway settings, read from a TOML file and overridden by the environment.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    pub storage: StorageConfig,
+    pub server: ServerConfig,
+    pub limits: LimitsConfig,
+    pub upstream: UpstreamConfig,
+    pub idempotency: IdempotencyConfig,
+    pub sandbox: SandboxConfig,
+    pub cache: CacheConfig,
+    pub jobs: JobsConfig,
+    pub alerts: AlertsConfig,
+    pub logging: LoggingConfig,
+}
+
+/// Every problem found in a config.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("; "))
+    }
+}
+
+fn check_file(errors: &mut Vec<String>, field: &str, path: &Path) {
+    if !path.is_file() {
+        errors.push(format!("`{field}` {} is not a file", path.display()));
+    }
+}
+
+impl GatewayConfig {
+    /// Layers the defaults, the config file and the environment.
+    pub fn figment() -> Figment {
+        let path = std::env::var(CONFIG_PATH_ENV).unwrap_or_else(|_| "uws.toml".to_string());
+        Figment::from(Serialized::defaults(GatewayConfig::default()))
+            .merge(Toml::file(path))
+            .merge(Env::prefixed(ENV_PREFIX).ignore(&["CONFIG"]).split("__"))
+    }
+
+    pub fn load() -> Result<GatewayConfig, ConfigError> {
+        GatewayConfig::from_figment(&GatewayConfig::figment())
+    }
+
+    pub fn from_figment(figment: &Figment) -> Result<GatewayConfig, ConfigError> {
+        let config = figment
+            .extract::<GatewayConfig>()
+            .map_err(|e| ConfigError(e.into_iter().map(|e| e.to_string()).collect()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = vec![];
+        if !self.storage.dir.is_dir() {
+            errors.push(format!(
+                "`storage.dir` {} is not a directory",
+                self.storage.dir.display()
+            ));
+        }
+        if let Some(tls) = &self.server.tls {
+            check_file(&mut errors, "server.tls.certs", &tls.certs);
+            check_file(&mut errors, "server.tls.key", &tls.key);
+        }
+        if self.limits.body_mib == 0 {
+            errors.push("`limits.body_mib` should be positive".to_string());
+        }
+        if self.upstream.timeout_secs == 0 {
+            errors.push("`upstream.timeout_secs` should be positive".to_string());
+        }
+        if self.idempotency.retention_hours == 0 {
+            errors.push("`idempotency.retention_hours` should be positive".to_string());
+        }
+        if self.cache.capacity == 0 {
+            errors.push("`cache.capacity` should be positive".to_string());
+        }
+        if self.cache.hit_price_percent > 100 {
+            errors.push("`cache.hit_price_percent` should be at most 100".to_string());
+        }
+        if self.jobs.timeout_secs == 0 {
+            errors.push("`jobs.timeout_secs` should be positive".to_string());
+        }
+        if self.jobs.retention_hours == 0 {
+            errors.push("`jobs.retention_hours` should be positive".to_string());
+        }
+        if self
+            .alerts
+            .thresholds
+            .iter()
+            .any(|threshold| !(1..=100).contains(threshold))
+        {
+            errors.push("`alerts.thresholds` should be between 1 and 100".to_string());
+        }
+        if self.alerts.max_attempts == 0 {
+            errors.push("`alerts.max_attempts` should be positive".to_string());
+        }
+        for sink in &self.logging.sinks {
+            if let LogSink::File { path } = sink {
+                if path
+                    .parent()
+                    .is_some_and(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+                {
+                    errors.push(format!(
+                        "`logging.sinks` {} is not in a directory",
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError(errors)),
+        }
+    }
+
+    /// Points the tables and the log at the configured places.
+    pub fn apply(&self) {
+        file_db::set_storage_dir(&self.storage.dir);
+        logger::set_sinks(self.logging.sinks.clone());
+    }
+
+    /// Returns Rocket's own config with the server and logging settings.
+    pub fn rocket_figment(&self) -> Figment {
+        let figment = rocket::Config::figment()
+            .merge(("address", self.server.address))
+            .merge(("port", self.server.port))
+            .merge(("log_level", self.logging.level));
+        match &self.server.tls {
+            Some(tls) => figment
+                .merge(("tls.certs", &tls.certs))
+                .merge(("tls.key", &tls.key)),
+            None => figment,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn figment(toml: &str) -> Figment {
+        Figment::from(Serialized::defaults(GatewayConfig::default())).merge(Toml::string(toml))
+    }
+
+    #[test]
+    fn read_config_over_defaults() {
+        let config = GatewayConfig::from_figment(&figment(
+            r#"
+            [server]
+            port = 9000
+
+            [limits]
+            requests_per_minute = 120
+
+            [[logging.sinks]]
+            kind = "file"
+            path = "uws.log"
+            "#,
+        ))
+        .unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.limits.requests_per_minute, 120);
+        assert_eq!(config.limits.body_mib, 10);
+        assert_eq!(config.storage.dir, PathBuf::from("db"));
+        assert_eq!(
+            config.logging.sinks,
+            vec![LogSink::File {
+                path: PathBuf::from("uws.log")
+            }]
+        );
+    }
+
+    #[test]
+    fn override_config_with_later_layers() {
+        let config = GatewayConfig::from_figment(
+            &figment("[upstream]\ntimeout_secs = 5").merge(("upstream.timeout_secs", 10)),
+        )
+        .unwrap();
+
+        assert_eq!(config.upstream.timeout(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let errors = GatewayConfig::from_figment(&figment(
+            r#"
+            [storage]
+            dir = "missing"
+
+            [server.tls]
+            certs = "missing.pem"
+            key = "missing.key"
+
+            [upstream]
+            timeout_secs = 0
+
+            [cache]
+            hit_price_percent = 150
+
+            [alerts]
+            thresholds = [50, 120]
+            "#,
+        ))
+        .unwrap_err();
+
+        assert_eq!(
+            errors.0,
+            vec![
+                "`storage.dir` missing is not a directory",
+                "`server.tls.certs` missing.pem is not a file",
+                "`server.tls.key` missing.key is not a file",
+                "`upstream.timeout_secs` should be positive",
+                "`cache.hit_price_percent` should be at most 100",
+                "`alerts.thresholds` should be between 1 and 100",
+            ]
+        );
+        assert!(GatewayConfig::from_figment(&figment("[storage]\nbackend = \"sql\"")).is_err());
+    }
+}
@@ -1,15 +1,17 @@
 use std::convert::From;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use crate::db::file_db::get_table_instance;
 use crate::db::Record;
-use crate::db::{file_db::FlatTable, ModelAble};
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query};
+use crate::entitlement::Entitlements;
 
 use super::Consumer;
 
+#[derive(Clone)]
 pub struct ConsumerList<D> {
-    db: Mutex<D>,
+    db: Arc<Mutex<D>>,
     pub consumers: Vec<Consumer>,
 }
 
@@ -18,7 +20,7 @@ pub type FlatConsumerList = ConsumerList<FlatTable<String, String>>;
 impl FlatConsumerList {
     pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
         ConsumerList {
-            db,
+            db: Arc::new(db),
             consumers: vec![],
         }
     }
@@ -38,13 +40,74 @@ impl FlatConsumerList {
             access_token.to_string(),
         )
     }
+
+    pub fn get_by_subscriber(&self, subscriber_id: u128) -> Option<Consumer> {
+        ConsumerList::get_by_attr::<FlatTable<String, String>, Consumer>(
+            &self.db,
+            "subscriber",
+            subscriber_id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<Consumer> {
+        ConsumerList::get_all::<FlatTable<String, String>, Consumer>(&self.db)
+    }
+
+    /// The table as it would be written to its file.
+    pub fn contents(&self) -> String {
+        self.db.lock().expect("lock db").contents()
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Consumer> {
+        ConsumerList::search_records::<FlatTable<String, String>, Consumer>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        ConsumerList::count_records(&self.db, filter)
+    }
+
+    pub fn create(
+        &self,
+        access_token: String,
+        subscriber_id: u128,
+        scopes: &Entitlements,
+        budget: Option<u128>,
+        sandbox: bool,
+    ) -> Consumer {
+        ConsumerList::insert_next::<FlatTable<String, String>, Consumer>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("subscriber".to_string(), subscriber_id.to_string()),
+                ("access_token".to_string(), access_token),
+                ("scopes".to_string(), scopes.to_string()),
+                (
+                    "budget".to_string(),
+                    budget.map(|budget| budget.to_string()).unwrap_or_default(),
+                ),
+                ("sandbox".to_string(), sandbox.to_string()),
+            ])
+        })
+    }
+
+    pub fn update(&self, id: u128, changes: Record<String, String>) -> Option<Consumer> {
+        ConsumerList::update_by_attr::<FlatTable<String, String>, Consumer>(
+            &self.db,
+            "id",
+            id.to_string(),
+            changes,
+        )
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        ConsumerList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
 }
 
 impl ModelAble<String, String> for FlatConsumerList {}
 
 impl From<Record<String, String>> for Consumer {
     fn from(map: Record<String, String>) -> Self {
-        return match (
+        match (
             map.get("id"),
             map.get("access_token"),
             map.get("subscriber"),
@@ -56,9 +119,19 @@ impl From<Record<String, String>> for Consumer {
                     get_table_instance("subscribers"),
                     subscriber_id.parse::<u128>().unwrap(),
                 ),
+                // keys predating scopes keep everything the plan includes
+                scopes: map
+                    .get("scopes")
+                    .map(|scopes| Entitlements::parse(scopes).expect("Invalid scopes"))
+                    .unwrap_or_else(Entitlements::all),
+                budget: map
+                    .get("budget")
+                    .filter(|budget| !budget.is_empty())
+                    .map(|budget| budget.parse::<u128>().expect("Invalid budget")),
+                sandbox: map.get("sandbox").is_some_and(|sandbox| sandbox == "true"),
             },
             _ => panic!("Can't convert!"),
-        };
+        }
     }
 }
 #[cfg(test)]
@@ -1,17 +1,27 @@
 use std::{collections::HashMap, sync::Mutex};
 
+use serde::Serialize;
+
 use crate::{
     db::file_db::{get_table_instance, FlatTable},
+    entitlement::Entitlements,
     subscriber::{subscriber_list::SubscriberList, Subscriber},
 };
 
 pub mod consumer_list;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Consumer {
     pub id: u128,
     pub access_token: String,
     pub subscriber: Subscriber,
+    /// Narrows what the subscriber's plan includes for this key.
+    pub scopes: Entitlements,
+    /// Most tokens the consumer may spend per billing period, out of the
+    /// subscription's shared quota. `None` leaves it unlimited.
+    pub budget: Option<u128>,
+    /// Sandbox keys are served by mock upstreams and never charged.
+    pub sandbox: bool,
 }
 
 impl Consumer {
@@ -23,6 +33,9 @@ impl Consumer {
                 subscriber_id,
             ),
             access_token,
+            scopes: Entitlements::all(),
+            budget: None,
+            sandbox: false,
         }
     }
 
@@ -34,6 +47,13 @@ impl Consumer {
                 None => Subscriber::fake(&HashMap::new()),
             },
             access_token: attr.get("access_token").unwrap_or(&"A-B-C").to_string(),
+            scopes: Entitlements::parse(attr.get("scopes").unwrap_or(&"*")).unwrap(),
+            budget: attr
+                .get("budget")
+                .map(|budget| budget.parse::<u128>().unwrap()),
+            sandbox: attr
+                .get("sandbox")
+                .is_some_and(|sandbox| *sandbox == "true"),
         }
     }
 
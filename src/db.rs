@@ -1,8 +1,157 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::convert::From;
+use std::hash::Hash;
 use std::{collections::HashMap, sync::Mutex};
 
 pub type Record<K, V> = HashMap<K, V>;
 
+/// How a `Filter` compares an attribute with a value. Values that both parse
+/// as numbers are compared as numbers, others as text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// The attribute holds the value as a substring.
+    Contains,
+}
+
+/// Orders two attribute values, numerically when both are numbers.
+pub fn compare_values(a: &str, b: &str) -> Ordering {
+    match (a.parse::<i128>(), b.parse::<i128>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// A condition on records, combining attribute comparisons with AND and OR.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare { attr: String, op: Op, value: String },
+    All(Vec<Filter>),
+    Any(Vec<Filter>),
+}
+
+impl Filter {
+    pub fn compare(attr: &str, op: Op, value: &str) -> Filter {
+        Filter::Compare {
+            attr: attr.to_string(),
+            op,
+            value: value.to_string(),
+        }
+    }
+
+    pub fn eq(attr: &str, value: &str) -> Filter {
+        Filter::compare(attr, Op::Eq, value)
+    }
+
+    /// Records missing the attribute match no comparison.
+    pub fn matches<K, V>(&self, record: &Record<K, V>) -> bool
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        match self {
+            Filter::Compare { attr, op, value } => match record.get(attr.as_str()) {
+                Some(actual) => {
+                    let actual = actual.as_ref();
+                    let ordering = compare_values(actual, value);
+                    match op {
+                        Op::Eq => ordering == Ordering::Equal,
+                        Op::Ne => ordering != Ordering::Equal,
+                        Op::Lt => ordering == Ordering::Less,
+                        Op::Le => ordering != Ordering::Greater,
+                        Op::Gt => ordering == Ordering::Greater,
+                        Op::Ge => ordering != Ordering::Less,
+                        Op::Contains => actual.contains(value.as_str()),
+                    }
+                }
+                None => false,
+            },
+            Filter::All(filters) => filters.iter().all(|filter| filter.matches(record)),
+            Filter::Any(filters) => filters.iter().any(|filter| filter.matches(record)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    pub attr: String,
+    pub descending: bool,
+}
+
+/// Which records to return and in what order, built like
+/// `Query::default().filter(..).sort_by("price", true).limit(10)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pub filter: Option<Filter>,
+    /// Applied in order, later keys breaking ties of earlier ones.
+    pub sort: Vec<Sort>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn sort_by(mut self, attr: &str, descending: bool) -> Self {
+        self.sort.push(Sort {
+            attr: attr.to_string(),
+            descending,
+        });
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn matches<K, V>(&self, record: &Record<K, V>) -> bool
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(record))
+    }
+
+    /// Orders two records by the sort keys. Records missing a key come first.
+    fn compare<K, V>(&self, a: &Record<K, V>, b: &Record<K, V>) -> Ordering
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        for sort in &self.sort {
+            let ordering = match (a.get(sort.attr.as_str()), b.get(sort.attr.as_str())) {
+                (Some(a), Some(b)) => compare_values(a.as_ref(), b.as_ref()),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            };
+            let ordering = match sort.descending {
+                true => ordering.reverse(),
+                false => ordering,
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
 pub trait Searchable<K, V>
 where
     K: Clone,
@@ -10,7 +159,72 @@ where
 {
     fn find_by(&mut self, attr: &str, value: &str) -> Option<&Record<K, V>>;
 
+    fn all(&mut self) -> &Vec<Record<K, V>>;
+
     fn get_table_name(&self) -> &str;
+
+    fn find_all_by(&mut self, attr: &str, value: &str) -> Vec<&Record<K, V>>
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        let filter = Filter::eq(attr, value);
+        self.all()
+            .iter()
+            .filter(|record| filter.matches(*record))
+            .collect()
+    }
+
+    /// Returns the records matching `query`, sorted and paginated by it.
+    fn search(&mut self, query: &Query) -> Vec<&Record<K, V>>
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        let mut records = self
+            .all()
+            .iter()
+            .filter(|record| query.matches(*record))
+            .collect::<Vec<&Record<K, V>>>();
+        // stable, so equal records keep the table order
+        records.sort_by(|a, b| query.compare(a, b));
+        records
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    fn count(&mut self, filter: Option<&Filter>) -> usize
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        self.all()
+            .iter()
+            .filter(|record| filter.is_none_or(|filter| filter.matches(*record)))
+            .count()
+    }
+}
+
+pub trait Writable<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn insert(&mut self, record: Record<K, V>) -> &Record<K, V>;
+
+    fn update_by(
+        &mut self,
+        attr: &str,
+        value: &str,
+        changes: Record<K, V>,
+    ) -> Option<&Record<K, V>>;
+
+    fn delete_by(&mut self, attr: &str, value: &str) -> Option<Record<K, V>>;
+
+    /// Returns the id following the largest numeric `id` in the table.
+    fn next_id(&mut self) -> u128;
 }
 
 pub trait ModelAble<K, V>
@@ -24,7 +238,79 @@ where
         value: String,
     ) -> Option<S> {
         let mut lock = db.lock().expect("lock db");
-        lock.find_by(attr, value.as_str()).map(|record| S::from(record.clone()))
+        lock.find_by(attr, value.as_str())
+            .map(|record| S::from(record.clone()))
+    }
+
+    fn get_all<D: Searchable<K, V>, S: From<Record<K, V>>>(db: &Mutex<D>) -> Vec<S> {
+        let mut lock = db.lock().expect("lock db");
+        lock.all()
+            .iter()
+            .map(|record| S::from(record.clone()))
+            .collect()
+    }
+
+    fn search_records<D: Searchable<K, V>, S: From<Record<K, V>>>(
+        db: &Mutex<D>,
+        query: &Query,
+    ) -> Vec<S>
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        let mut lock = db.lock().expect("lock db");
+        lock.search(query)
+            .into_iter()
+            .map(|record| S::from(record.clone()))
+            .collect()
+    }
+
+    fn count_records<D: Searchable<K, V>>(db: &Mutex<D>, filter: Option<&Filter>) -> usize
+    where
+        K: Borrow<str> + Hash + Eq,
+        V: AsRef<str>,
+    {
+        let mut lock = db.lock().expect("lock db");
+        lock.count(filter)
+    }
+
+    fn insert_record<D: Writable<K, V>, S: From<Record<K, V>>>(
+        db: &Mutex<D>,
+        record: Record<K, V>,
+    ) -> S {
+        let mut lock = db.lock().expect("lock db");
+        S::from(lock.insert(record).clone())
+    }
+
+    /// Inserts the record `build` makes for the next free id. The id is taken
+    /// and used under one lock, so concurrent creates can't share it.
+    fn insert_next<D: Writable<K, V>, S: From<Record<K, V>>>(
+        db: &Mutex<D>,
+        build: impl FnOnce(u128) -> Record<K, V>,
+    ) -> S {
+        let mut lock = db.lock().expect("lock db");
+        let id = lock.next_id();
+        S::from(lock.insert(build(id)).clone())
+    }
+
+    fn update_by_attr<D: Writable<K, V>, S: From<Record<K, V>>>(
+        db: &Mutex<D>,
+        attr: &str,
+        value: String,
+        changes: Record<K, V>,
+    ) -> Option<S> {
+        let mut lock = db.lock().expect("lock db");
+        lock.update_by(attr, value.as_str(), changes)
+            .map(|record| S::from(record.clone()))
+    }
+
+    fn delete_by_attr<D: Writable<K, V>>(
+        db: &Mutex<D>,
+        attr: &str,
+        value: String,
+    ) -> Option<Record<K, V>> {
+        let mut lock = db.lock().expect("lock db");
+        lock.delete_by(attr, value.as_str())
     }
 }
 
@@ -34,27 +320,57 @@ pub trait ToStruct<T, K> {
 
 pub mod file_db {
     use super::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::RwLock;
     use std::{collections::HashMap, fs};
 
+    /// The directory holding the tables, `DEFAULT_DIR` until configured
+    /// otherwise.
+    static STORAGE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+    /// The tables shipped with the gateway, as the migrations leave them.
+    #[cfg(not(test))]
+    const DEFAULT_DIR: &str = "db";
+    /// Tests read the fixture tables, with keys and live subscriptions.
+    #[cfg(test)]
+    const DEFAULT_DIR: &str = "tests/fixtures/db";
+
+    pub fn set_storage_dir(dir: &Path) {
+        *STORAGE_DIR.write().expect("write storage dir") = Some(dir.to_path_buf());
+    }
+
+    pub fn table_path(table: &str) -> PathBuf {
+        let dir = STORAGE_DIR.read().expect("read storage dir");
+        dir.clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR))
+            .join(format!("{}_table.txt", table))
+    }
+
     fn read_from_file(table: &str) -> String {
-        fs::read_to_string(format!("db/{}_table.txt", table))
-            .expect("Should have been able to read the file")
+        fs::read_to_string(table_path(table)).expect("Should have been able to read the file")
     }
 
-    fn get_column_names(content: &str) -> Vec<String> {
-        return match content.lines().next() {
+    fn write_to_file(table: &str, content: &str) {
+        fs::write(table_path(table), content).expect("Should have been able to write the file")
+    }
+
+    pub(crate) fn get_column_names(content: &str) -> Vec<String> {
+        match content.lines().next() {
             Some(first_line) => first_line
                 .split(',')
                 .map(|f| String::from(f.trim()))
                 .collect::<Vec<String>>(),
             None => panic!("Table has no columns"),
-        };
+        }
     }
 
     fn get_records(content: &str) -> Vec<Vec<String>> {
         let mut rows = vec![];
         for line in content.lines().skip(1) {
             // skip header line (header)
+            if line.trim().is_empty() {
+                continue;
+            }
             let row = match Some(line) {
                 Some(l) => l
                     .split(',')
@@ -96,10 +412,23 @@ pub mod file_db {
         let rows = get_records(content);
         create_flat_table(columns, rows)
     }
+
+    pub(crate) fn to_table_string(columns: &[String], items: &[Record<String, String>]) -> String {
+        let mut lines = vec![columns.join(", ")];
+        for record in items.iter() {
+            let row = columns
+                .iter()
+                .map(|column| record.get(column).cloned().unwrap_or_default())
+                .collect::<Vec<String>>();
+            lines.push(row.join(", "));
+        }
+        lines.join("\n") + "\n"
+    }
     #[derive(Clone)]
     pub struct FlatTable<K, V> {
         pub table_name: String,
         pub items: Vec<Record<K, V>>,
+        columns: Vec<String>,
         source: u8,
         raw: String,
     }
@@ -111,6 +440,7 @@ pub mod file_db {
             FlatTable {
                 table_name,
                 items: vec![],
+                columns: vec![],
                 source: 1,
                 raw: String::new(),
             }
@@ -120,19 +450,49 @@ pub mod file_db {
             FlatTable {
                 table_name: "from_string".to_string(),
                 items: read_from_string(&contents),
+                columns: get_column_names(&contents),
                 source: 2,
                 raw: contents,
             }
         }
 
         pub fn refresh(&mut self) -> &Self {
-            self.items = match self.source {
-                1 => file_db::read(self.table_name.as_str()),
-                2 => read_from_string(&self.raw),
+            let content = match self.source {
+                1 => read_from_file(self.table_name.as_str()),
+                2 => self.raw.clone(),
                 _ => panic!("Invalid source!"),
             };
+            self.columns = get_column_names(&content);
+            self.items = read_from_string(&content);
             self
         }
+
+        /// The table as it would be written to its source.
+        pub(crate) fn contents(&mut self) -> String {
+            self.refresh();
+            to_table_string(&self.columns, &self.items)
+        }
+
+        /// Writes the in-memory items back to the table source.
+        fn persist(&mut self) {
+            let content = to_table_string(&self.columns, &self.items);
+            match self.source {
+                1 => write_to_file(self.table_name.as_str(), &content),
+                2 => self.raw = content,
+                _ => panic!("Invalid source!"),
+            }
+        }
+
+        /// Adds any column of `record` that the table doesn't have yet.
+        fn add_missing_columns(&mut self, record: &Record<String, String>) {
+            let mut missing = record
+                .keys()
+                .filter(|key| !self.columns.contains(key))
+                .cloned()
+                .collect::<Vec<String>>();
+            missing.sort();
+            self.columns.extend(missing);
+        }
     }
 
     impl super::Searchable<String, String> for FlatTable<String, String> {
@@ -144,11 +504,64 @@ pub mod file_db {
             })
         }
 
+        fn all(&mut self) -> &Vec<Record<String, String>> {
+            self.refresh();
+            &self.items
+        }
+
         fn get_table_name(&self) -> &str {
             &self.table_name
         }
     }
 
+    impl super::Writable<String, String> for FlatTable<String, String> {
+        fn insert(&mut self, record: Record<String, String>) -> &Record<String, String> {
+            self.refresh();
+            self.add_missing_columns(&record);
+            self.items.push(record);
+            self.persist();
+            self.items.last().expect("inserted record")
+        }
+
+        fn update_by(
+            &mut self,
+            attr: &str,
+            value: &str,
+            changes: Record<String, String>,
+        ) -> Option<&Record<String, String>> {
+            self.refresh();
+            self.add_missing_columns(&changes);
+            let index = self
+                .items
+                .iter()
+                .position(|record| record.get(attr).map(|a| a == value).unwrap_or(false))?;
+            self.items[index].extend(changes);
+            self.persist();
+            self.items.get(index)
+        }
+
+        fn delete_by(&mut self, attr: &str, value: &str) -> Option<Record<String, String>> {
+            self.refresh();
+            let index = self
+                .items
+                .iter()
+                .position(|record| record.get(attr).map(|a| a == value).unwrap_or(false))?;
+            let record = self.items.remove(index);
+            self.persist();
+            Some(record)
+        }
+
+        fn next_id(&mut self) -> u128 {
+            self.refresh();
+            self.items
+                .iter()
+                .filter_map(|record| record.get("id")?.parse::<u128>().ok())
+                .max()
+                .unwrap_or(0)
+                + 1
+        }
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -224,6 +637,7 @@ pub mod file_db {
                 raw: table.clone(),
                 table_name: "N\\A".to_string(),
                 items: read_from_string(&table),
+                columns: get_column_names(&table),
                 source: 2,
             };
 
@@ -238,5 +652,165 @@ pub mod file_db {
                 )
             }
         }
+
+        #[test]
+        fn test_inserting_record() {
+            let mut flat_table = FlatTable::new_from_string(
+                "\
+            column1, column2
+            row1_value1, row1_value2"
+                    .to_string(),
+            );
+
+            flat_table.insert(HashMap::from([
+                ("column1".to_string(), "row2_value1".to_string()),
+                ("column2".to_string(), "row2_value2".to_string()),
+            ]));
+
+            assert_eq!(flat_table.all().len(), 2);
+            assert!(flat_table.find_by("column1", "row2_value1").is_some());
+        }
+
+        #[test]
+        fn test_updating_record() {
+            let mut flat_table = FlatTable::new_from_string(
+                "\
+            column1, column2
+            row1_value1, row1_value2
+            row2_value1, row2_value2"
+                    .to_string(),
+            );
+
+            let updated = flat_table
+                .update_by(
+                    "column1",
+                    "row2_value1",
+                    HashMap::from([("column2".to_string(), "changed".to_string())]),
+                )
+                .cloned();
+
+            assert_eq!(updated.unwrap()["column2"], "changed");
+            assert_eq!(
+                flat_table.find_by("column1", "row2_value1").unwrap()["column2"],
+                "changed"
+            );
+            assert!(flat_table
+                .update_by("column1", "missing", HashMap::new())
+                .is_none());
+        }
+
+        #[test]
+        fn test_deleting_record() {
+            let mut flat_table = FlatTable::new_from_string(
+                "\
+            column1, column2
+            row1_value1, row1_value2
+            row2_value1, row2_value2"
+                    .to_string(),
+            );
+
+            let deleted = flat_table.delete_by("column1", "row1_value1");
+
+            assert!(deleted.is_some());
+            assert_eq!(flat_table.all().len(), 1);
+            assert!(flat_table.find_by("column1", "row1_value1").is_none());
+        }
+
+        #[test]
+        fn test_finding_all_by_attribute() {
+            let mut flat_table = FlatTable::new_from_string(
+                "\
+            id, kind
+            1, a
+            2, b
+            3, a"
+                    .to_string(),
+            );
+
+            let ids = flat_table
+                .find_all_by("kind", "a")
+                .iter()
+                .map(|record| record["id"].clone())
+                .collect::<Vec<String>>();
+
+            assert_eq!(ids, vec!["1", "3"]);
+        }
+
+        #[test]
+        fn test_searching_with_filters_sorting_and_pages() {
+            let mut flat_table = FlatTable::new_from_string(
+                "\
+            id, kind, price
+            1, a, 9
+            2, b, 10
+            3, a, 100
+            4, c, 2
+            5, a, 10"
+                    .to_string(),
+            );
+            let filter = Filter::Any(vec![
+                Filter::All(vec![
+                    Filter::eq("kind", "a"),
+                    Filter::compare("price", Op::Ge, "10"),
+                ]),
+                Filter::compare("kind", Op::Eq, "b"),
+            ]);
+            let query = Query::default()
+                .filter(filter.clone())
+                .sort_by("price", true)
+                .sort_by("id", false)
+                .offset(1)
+                .limit(2);
+
+            let ids = flat_table
+                .search(&query)
+                .iter()
+                .map(|record| record["id"].clone())
+                .collect::<Vec<String>>();
+
+            // prices compare as numbers: 100, 10 (id 2), 10 (id 5)
+            assert_eq!(ids, vec!["2", "5"]);
+            assert_eq!(flat_table.count(Some(&filter)), 3);
+            assert_eq!(flat_table.count(None), 5);
+        }
+
+        #[test]
+        fn test_inserting_next_ids_concurrently() {
+            struct Rows;
+            impl ModelAble<String, String> for Rows {}
+
+            let db = Mutex::new(FlatTable::new_from_string("id, name".to_string()));
+            std::thread::scope(|scope| {
+                for _ in 0..8 {
+                    scope.spawn(|| {
+                        Rows::insert_next::<FlatTable<String, String>, Record<String, String>>(
+                            &db,
+                            |id| HashMap::from([("id".to_string(), id.to_string())]),
+                        )
+                    });
+                }
+            });
+
+            let mut ids = db
+                .lock()
+                .unwrap()
+                .all()
+                .iter()
+                .map(|record| record["id"].parse::<u128>().unwrap())
+                .collect::<Vec<u128>>();
+            ids.sort();
+            assert_eq!(ids, (1..=8).collect::<Vec<u128>>());
+        }
+
+        #[test]
+        fn test_serializing_table() {
+            let table = "\
+            column1, column2
+            row1_value1, row1_value2";
+
+            let content = to_table_string(&get_column_names(table), &read_from_string(table));
+
+            assert_eq!(content, "column1, column2\nrow1_value1, row1_value2\n");
+        }
     }
 }
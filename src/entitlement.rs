@@ -0,0 +1,184 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+use crate::consumer::Consumer;
+use crate::service::Service;
+
+/// Matches any product, service or version.
+const ANY: &str = "*";
+
+/// Access to a product, to one of its services or to one version of it,
+/// written `product[/service[/version]]`. Each part can be `*`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub product: String,
+    pub service: Option<String>,
+    pub version: Option<String>,
+}
+
+impl Grant {
+    pub fn parse(value: &str) -> Option<Grant> {
+        let parts = value.split('/').collect::<Vec<&str>>();
+        let valid = |part: &&str| {
+            !part.is_empty() && !part.contains(|c: char| c == ',' || c.is_whitespace())
+        };
+        if parts.len() > 3 || !parts.iter().all(valid) {
+            return None;
+        }
+        Some(Grant {
+            product: parts[0].to_string(),
+            service: parts.get(1).map(|part| part.to_string()),
+            version: parts.get(2).map(|part| part.to_string()),
+        })
+    }
+
+    pub fn allows(&self, product: &str, service: &str, version: &str) -> bool {
+        let matches = |part: Option<&String>, value: &str| {
+            part.is_none_or(|part| part == ANY || part == value)
+        };
+        matches(Some(&self.product), product)
+            && matches(self.service.as_ref(), service)
+            && matches(self.version.as_ref(), version)
+    }
+}
+
+impl fmt::Display for Grant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.product)?;
+        for part in [&self.service, &self.version].into_iter().flatten() {
+            write!(f, "/{part}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What a plan includes or a consumer key is scoped to, stored as grants
+/// separated by spaces. No grant means no access.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entitlements(pub Vec<Grant>);
+
+impl Entitlements {
+    /// Grants every product, the default of plans and keys predating
+    /// entitlements.
+    pub fn all() -> Entitlements {
+        Entitlements(vec![Grant::parse(ANY).expect("wildcard grant")])
+    }
+
+    pub fn parse(value: &str) -> Result<Entitlements, String> {
+        Entitlements::from_list(value.split_whitespace())
+    }
+
+    pub fn from_list<'a>(
+        grants: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Entitlements, String> {
+        grants
+            .into_iter()
+            .map(|grant| {
+                Grant::parse(grant.trim()).ok_or_else(|| {
+                    format!("`{grant}` should look like `product[/service[/version]]`")
+                })
+            })
+            .collect::<Result<Vec<Grant>, String>>()
+            .map(Entitlements)
+    }
+
+    pub fn allows(&self, product: &str, service: &str, version: &str) -> bool {
+        self.0
+            .iter()
+            .any(|grant| grant.allows(product, service, version))
+    }
+}
+
+impl fmt::Display for Entitlements {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let grants = self.0.iter().map(Grant::to_string).collect::<Vec<String>>();
+        write!(f, "{}", grants.join(" "))
+    }
+}
+
+impl Serialize for Entitlements {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(Grant::to_string))
+    }
+}
+
+/// Checks that `consumer` may call `service`: its plan has to include it and
+/// its key has to be scoped for it.
+pub fn authorize(consumer: &Consumer, service: &Service) -> Result<(), String> {
+    let (product, slug, version) = (
+        service.product.slug.as_str(),
+        service.slug.as_str(),
+        service.version.as_str(),
+    );
+    let plan = &consumer.subscriber.subscription.plan;
+    if !plan.entitlements.allows(product, slug, version) {
+        return Err(format!(
+            "Plan {} does not include {product}/{slug}/{version}",
+            plan.name
+        ));
+    }
+    if !consumer.scopes.allows(product, slug, version) {
+        return Err(format!(
+            "API key is not scoped for {product}/{slug}/{version}"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn match_grants_by_product_service_and_version() {
+        let entitlements = Entitlements::parse("product_a product_b/service_b/v2.0.0").unwrap();
+
+        assert!(entitlements.allows("product_a", "service_a", "v1.0.0"));
+        assert!(entitlements.allows("product_b", "service_b", "v2.0.0"));
+        assert!(!entitlements.allows("product_b", "service_b", "v1.0.0"));
+        assert!(!entitlements.allows("product_c", "service_a", "v1.0.0"));
+        assert!(Entitlements::all().allows("product_c", "service_c", "v1.0.0"));
+        assert!(!Entitlements::parse("")
+            .unwrap()
+            .allows("product_a", "service_a", "v1.0.0"));
+        assert_eq!(
+            entitlements.to_string(),
+            "product_a product_b/service_b/v2.0.0"
+        );
+    }
+
+    #[test]
+    fn reject_malformed_grants() {
+        assert!(Entitlements::parse("product_a//v1").is_err());
+        assert!(Entitlements::parse("a/b/c/d").is_err());
+        assert!(Entitlements::from_list(["product_a,product_b"]).is_err());
+    }
+
+    #[test]
+    fn deny_by_plan_before_scope() {
+        let mut consumer = Consumer::fake(&HashMap::new());
+        consumer.subscriber.subscription.plan.entitlements =
+            Entitlements::parse("product_a").unwrap();
+        consumer.scopes = Entitlements::parse("product_b").unwrap();
+        let mut service = Service::fake(&HashMap::from([
+            ("slug", "service_a"),
+            ("version", "v1.0.0"),
+        ]));
+        service.product.slug = "product_b".to_string();
+
+        assert_eq!(
+            authorize(&consumer, &service),
+            Err("Plan default_plan does not include product_b/service_a/v1.0.0".to_string())
+        );
+
+        service.product.slug = "product_a".to_string();
+
+        assert_eq!(
+            authorize(&consumer, &service),
+            Err("API key is not scoped for product_a/service_a/v1.0.0".to_string())
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::{Catcher, Request};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+pub fn error(status: Status, message: &str) -> Custom<Json<ApiError>> {
+    Custom(
+        status,
+        Json(ApiError {
+            error: message.to_string(),
+        }),
+    )
+}
+
+/// Explains why a request guard failed, cached on the request for the catcher.
+#[derive(Debug, Default)]
+pub struct ErrorReason(pub Option<String>);
+
+/// Records `reason` so the catcher can return it instead of the status text.
+pub fn set_reason(req: &Request<'_>, reason: String) {
+    req.local_cache(|| ErrorReason(Some(reason)));
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, req: &Request) -> Json<ApiError> {
+    let reason = req.local_cache(ErrorReason::default);
+    Json(ApiError {
+        error: reason
+            .0
+            .clone()
+            .unwrap_or_else(|| status.reason_lossy().to_string()),
+    })
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
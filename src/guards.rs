@@ -1,4 +1,14 @@
-use crate::consumer::consumer_list::FlatConsumerList;
+use crate::admin::{admin_list::FlatAdminList, Admin};
+use crate::biller::headers::{set_quota_state, QuotaState};
+use crate::biller::{self, BillingError};
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::consumer::{consumer_list::FlatConsumerList, Consumer};
+use crate::errors::set_reason;
+use crate::limiter::RateLimiter;
+use crate::overage::overage_list::FlatOverageList;
+use crate::subscriber::{subscriber_list::FlatSubscriberList, Subscriber};
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, Rng};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket::State;
@@ -40,11 +50,172 @@ impl<'r> FromRequest<'r> for ApiKey<'r> {
 
         match req.headers().get_one("x-api-key") {
             None => Outcome::Error((Status::Unauthorized, ApiKeyError::Missing)),
+            // revoked consumers keep an empty access token
+            Some(key) if key.trim().is_empty() => {
+                Outcome::Error((Status::Unauthorized, ApiKeyError::Invalid))
+            }
             Some(key) if is_valid(req, key).await => Outcome::Success(ApiKey(key)),
             Some(_) => Outcome::Error((Status::Unauthorized, ApiKeyError::Invalid)),
         }
     }
 }
 
+/// A consumer authenticated by its `x-api-key` header and within its rate limit.
+#[derive(Debug)]
+pub struct Authenticated(pub Consumer);
+
+/// An authenticated consumer whose subscription can be billed.
+#[derive(Debug)]
+pub struct Billable(pub Consumer);
+
+#[derive(Debug)]
+pub enum BillableError {
+    Unauthorized(ApiKeyError),
+    RateLimited,
+    Billing(BillingError),
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+    type Error = BillableError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = match req.guard::<ApiKey>().await {
+            Outcome::Success(key) => key,
+            Outcome::Error((status, e)) => {
+                return Outcome::Error((status, BillableError::Unauthorized(e)))
+            }
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let consumer_list = req.guard::<&State<FlatConsumerList>>().await.unwrap();
+        let consumer = match consumer_list.get_by_access_token(key.0) {
+            Some(consumer) => consumer,
+            None => {
+                return Outcome::Error((
+                    Status::Unauthorized,
+                    BillableError::Unauthorized(ApiKeyError::Invalid),
+                ))
+            }
+        };
+
+        let now = Utc::now().naive_utc();
+        if let Some(limiter) = req.rocket().state::<RateLimiter>() {
+            if !limiter.allow(consumer.id, now) {
+                set_reason(
+                    req,
+                    format!(
+                        "Limit of {} requests per minute reached",
+                        limiter.per_minute
+                    ),
+                );
+                return Outcome::Error((Status::TooManyRequests, BillableError::RateLimited));
+            }
+        }
+        Outcome::Success(Authenticated(consumer))
+    }
+}
+
+/// Checks that the subscription of `consumer` can be billed and that its own
+/// budget isn't spent, setting the quota headers either way.
+pub async fn check_billable(req: &Request<'_>, consumer: &Consumer) -> Result<(), BillingError> {
+    let subscription = &consumer.subscriber.subscription;
+    let overage_list = req.guard::<&State<FlatOverageList>>().await.unwrap();
+    let overage_used = biller::overage_used(subscription, &overage_list.list());
+    set_quota_state(req, QuotaState::new(subscription, overage_used));
+
+    biller::authorize(subscription, overage_used, Utc::now().naive_utc())
+        .and_then(|_| match req.rocket().state::<FlatBudgetUsageList>() {
+            Some(usages) if consumer.budget.is_some() => {
+                let used = usages.used(consumer.id, subscription.period_start());
+                biller::authorize_budget(consumer, used, 0)
+            }
+            _ => Ok(()),
+        })
+        .inspect_err(|e| set_reason(req, e.to_string()))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Billable {
+    type Error = BillableError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let consumer = match req.guard::<Authenticated>().await {
+            Outcome::Success(authenticated) => authenticated.0,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        match check_billable(req, &consumer).await {
+            Ok(()) => Outcome::Success(Billable(consumer)),
+            Err(e) => Outcome::Error((Status::PaymentRequired, BillableError::Billing(e))),
+        }
+    }
+}
+
+/// The operator behind an `x-admin-key` header.
+#[derive(Debug)]
+pub struct AdminKey(pub Admin);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminKey {
+    type Error = ApiKeyError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let admin_list = req.guard::<&State<FlatAdminList>>().await.unwrap();
+
+        match req.headers().get_one("x-admin-key") {
+            None => Outcome::Error((Status::Unauthorized, ApiKeyError::Missing)),
+            Some(key) => match admin_list.get_by_access_token(key) {
+                Some(admin) => Outcome::Success(AdminKey(admin)),
+                None => Outcome::Error((Status::Unauthorized, ApiKeyError::Invalid)),
+            },
+        }
+    }
+}
+
+/// The subscriber behind an `x-subscriber-key` header.
+#[derive(Debug)]
+pub struct SubscriberKey(pub Subscriber);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SubscriberKey {
+    type Error = ApiKeyError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let subscriber_list = req.guard::<&State<FlatSubscriberList>>().await.unwrap();
+
+        match req.headers().get_one("x-subscriber-key") {
+            None => Outcome::Error((Status::Unauthorized, ApiKeyError::Missing)),
+            Some(key) if key.trim().is_empty() => {
+                Outcome::Error((Status::Unauthorized, ApiKeyError::Invalid))
+            }
+            Some(key) => match subscriber_list.get_by_access_token(key) {
+                Some(subscriber) => Outcome::Success(SubscriberKey(subscriber)),
+                None => Outcome::Error((Status::Unauthorized, ApiKeyError::Invalid)),
+            },
+        }
+    }
+}
+
+/// Returns a new random access token such as `uws-key-3fK9...`.
+pub fn generate_key(prefix: &str) -> String {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    format!("uws-{prefix}-{secret}")
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::generate_key;
+
+    #[test]
+    fn generate_unique_keys() {
+        let key = generate_key("key");
+
+        assert!(key.starts_with("uws-key-"));
+        assert_eq!(key.len(), "uws-key-".len() + 32);
+        assert_ne!(key, generate_key("key"));
+    }
+}
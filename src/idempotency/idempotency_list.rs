@@ -0,0 +1,250 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+use rocket::serde::json;
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Op, Query, Record, Searchable, Writable};
+use crate::router::Upstream;
+use crate::subscriber::{format_date, parse_date};
+
+use super::{Claim, IdempotentRequest};
+
+#[derive(Clone)]
+pub struct IdempotencyList<D> {
+    db: Arc<Mutex<D>>,
+    pub requests: Vec<IdempotentRequest>,
+}
+
+pub type FlatIdempotencyList = IdempotencyList<FlatTable<String, String>>;
+
+impl FlatIdempotencyList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        IdempotencyList {
+            db: Arc::new(db),
+            requests: vec![],
+        }
+    }
+
+    pub fn list(&self) -> Vec<IdempotentRequest> {
+        IdempotencyList::get_all::<FlatTable<String, String>, IdempotentRequest>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<IdempotentRequest> {
+        IdempotencyList::search_records::<FlatTable<String, String>, IdempotentRequest>(
+            &self.db, query,
+        )
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        IdempotencyList::count_records(&self.db, filter)
+    }
+
+    /// Claims `key` of `consumer_id` for a request with `fingerprint`, unless
+    /// a request made since `expired_before` already holds it. Checking and
+    /// claiming happen under one lock, so concurrent retries can't both win.
+    pub fn claim(
+        &self,
+        consumer_id: u128,
+        key: &str,
+        fingerprint: &str,
+        now: NaiveDateTime,
+        expired_before: NaiveDateTime,
+    ) -> Claim {
+        let mut db = self.db.lock().expect("lock db");
+        let filter = Filter::All(vec![
+            Filter::eq("consumer", &consumer_id.to_string()),
+            Filter::eq("key", key),
+        ]);
+        let held = db
+            .search(&Query::default().filter(filter))
+            .into_iter()
+            .map(|record| IdempotentRequest::from(record.clone()))
+            .collect::<Vec<IdempotentRequest>>();
+        for request in held {
+            if request.created_at < expired_before {
+                db.delete_by("id", &request.id.to_string());
+                continue;
+            }
+            return match (request.fingerprint == fingerprint, request.status) {
+                (false, _) => Claim::Mismatch,
+                (true, None) => Claim::InProgress,
+                (true, Some(_)) => Claim::Replay(request),
+            };
+        }
+
+        let id = db.next_id();
+        let record = db.insert(Record::from([
+            ("id".to_string(), id.to_string()),
+            ("consumer".to_string(), consumer_id.to_string()),
+            ("key".to_string(), key.to_string()),
+            ("fingerprint".to_string(), fingerprint.to_string()),
+            ("status".to_string(), String::new()),
+            ("headers".to_string(), String::new()),
+            ("body".to_string(), String::new()),
+            ("created_at".to_string(), format_date(&now)),
+        ]));
+        Claim::New(IdempotentRequest::from(record.clone()))
+    }
+
+    /// Stores the response to replay for the request claimed as `id`.
+    pub fn complete(&self, id: u128, upstream: &Upstream) -> Option<IdempotentRequest> {
+        let headers = json::to_string(&upstream.headers).expect("serialize headers");
+        IdempotencyList::update_by_attr::<FlatTable<String, String>, IdempotentRequest>(
+            &self.db,
+            "id",
+            id.to_string(),
+            Record::from([
+                ("status".to_string(), upstream.status.to_string()),
+                ("headers".to_string(), hex::encode(headers)),
+                ("body".to_string(), hex::encode(&upstream.body)),
+            ]),
+        )
+    }
+
+    /// Gives up the claim `id`, so that a retry is forwarded again.
+    pub fn release(&self, id: u128) -> Option<()> {
+        IdempotencyList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
+
+    /// Deletes the requests made before `expired_before`, returning how many.
+    pub fn purge(&self, expired_before: NaiveDateTime) -> usize {
+        let expired = self.filter(&Query::default().filter(Filter::compare(
+            "created_at",
+            Op::Lt,
+            &format_date(&expired_before),
+        )));
+        for request in &expired {
+            self.release(request.id);
+        }
+        expired.len()
+    }
+}
+
+impl ModelAble<String, String> for FlatIdempotencyList {}
+
+fn decode(value: &str) -> Vec<u8> {
+    hex::decode(value).expect("Invalid hex value")
+}
+
+impl From<Record<String, String>> for IdempotentRequest {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("consumer"),
+            map.get("key"),
+            map.get("fingerprint"),
+            map.get("status"),
+            map.get("headers"),
+            map.get("body"),
+            map.get("created_at"),
+        ) {
+            (
+                Some(id),
+                Some(consumer_id),
+                Some(key),
+                Some(fingerprint),
+                Some(status),
+                Some(headers),
+                Some(body),
+                Some(created_at),
+            ) => IdempotentRequest {
+                id: id.parse::<u128>().unwrap(),
+                consumer_id: consumer_id.parse::<u128>().unwrap(),
+                key: key.clone(),
+                fingerprint: fingerprint.clone(),
+                status: match status.is_empty() {
+                    true => None,
+                    false => Some(status.parse::<u16>().unwrap()),
+                },
+                headers: match headers.is_empty() {
+                    true => vec![],
+                    false => json::from_slice(&decode(headers)).expect("Invalid headers"),
+                },
+                body: decode(body),
+                created_at: parse_date(created_at).expect("Invalid idempotency date"),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn date(value: &str) -> NaiveDateTime {
+        parse_date(value).unwrap()
+    }
+
+    #[test]
+    fn replay_completed_request() {
+        let table = "id, consumer, key, fingerprint, status, headers, body, created_at";
+        let list = IdempotencyList::new(Mutex::new(FlatTable::new_from_string(table.to_string())));
+        let now = date("2023-01-02 00:00:00");
+        let expired_before = date("2023-01-01 00:00:00");
+
+        let id = match list.claim(1, "retry-1", "abc", now, expired_before) {
+            Claim::New(request) => request.id,
+            claim => panic!("unexpected {claim:?}"),
+        };
+        assert!(matches!(
+            list.claim(1, "retry-1", "abc", now, expired_before),
+            Claim::InProgress
+        ));
+
+        list.complete(
+            id,
+            &Upstream {
+                status: 201,
+                headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+                body: b"created, once".to_vec(),
+                duration: Duration::ZERO,
+            },
+        );
+
+        match list.claim(1, "retry-1", "abc", now, expired_before) {
+            Claim::Replay(request) => {
+                let replayed = request.replay();
+                assert_eq!(replayed.status, 201);
+                assert_eq!(replayed.body, b"created, once");
+                assert_eq!(replayed.headers.len(), 2);
+            }
+            claim => panic!("unexpected {claim:?}"),
+        }
+        assert!(matches!(
+            list.claim(1, "retry-1", "def", now, expired_before),
+            Claim::Mismatch
+        ));
+        // keys are scoped to the consumer
+        assert!(matches!(
+            list.claim(2, "retry-1", "def", now, expired_before),
+            Claim::New(_)
+        ));
+    }
+
+    #[test]
+    fn expire_old_requests() {
+        let table = "\
+        id, consumer, key, fingerprint, status, headers, body, created_at
+        1, 1, retry-1, abc, 200, , , 2023-01-01 00:00:00
+        2, 1, retry-2, abc, 200, , , 2023-01-03 00:00:00\
+        ";
+        let list = IdempotencyList::new(Mutex::new(FlatTable::new_from_string(table.to_string())));
+        let expired_before = date("2023-01-02 00:00:00");
+
+        assert!(matches!(
+            list.claim(
+                1,
+                "retry-1",
+                "def",
+                date("2023-01-03 00:00:00"),
+                expired_before
+            ),
+            Claim::New(_)
+        ));
+        assert_eq!(list.purge(date("2023-01-04 00:00:00")), 2);
+        assert!(list.list().is_empty());
+    }
+}
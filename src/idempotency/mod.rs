@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::router::Upstream;
+use crate::subscriber::parse_date;
+
+pub mod idempotency_list;
+
+/// Header a consumer sets to make retries of a request safe.
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Header marking a response replayed for a retried request.
+pub const REPLAYED: &str = "Idempotent-Replayed";
+
+/// The response given to the first request with an idempotency key, replayed
+/// to retries of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct IdempotentRequest {
+    pub id: u128,
+    pub consumer_id: u128,
+    pub key: String,
+    /// Identifies the request the key was first used for.
+    pub fingerprint: String,
+    /// `None` while the first request is still being served.
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
+impl IdempotentRequest {
+    pub fn fake(attr: &HashMap<&str, &str>) -> IdempotentRequest {
+        IdempotentRequest {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            consumer_id: attr
+                .get("consumer")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            key: attr.get("key").unwrap_or(&"key-1").to_string(),
+            fingerprint: attr.get("fingerprint").unwrap_or(&"abc").to_string(),
+            status: attr
+                .get("status")
+                .map(|status| status.parse::<u16>().unwrap()),
+            headers: vec![],
+            body: attr.get("body").unwrap_or(&"").as_bytes().to_vec(),
+            created_at: parse_date(attr.get("created_at").unwrap_or(&"2001-01-01 00:00:00"))
+                .unwrap(),
+        }
+    }
+
+    /// Rebuilds the stored response, marked as replayed.
+    pub fn replay(&self) -> Upstream {
+        let mut headers = self.headers.clone();
+        headers.push((REPLAYED.to_string(), "true".to_string()));
+        Upstream {
+            status: self.status.unwrap_or_default(),
+            headers,
+            body: self.body.clone(),
+            duration: Default::default(),
+        }
+    }
+}
+
+/// What to do with a request carrying an idempotency key.
+#[derive(Debug)]
+pub enum Claim {
+    /// The key is new, or expired, and now belongs to this request.
+    New(IdempotentRequest),
+    /// A retry of a request that was already answered.
+    Replay(IdempotentRequest),
+    /// A retry of a request that is still being served.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Returns true if `key` can be stored: 1 to 255 visible characters, without
+/// commas.
+pub fn is_valid_key(key: &str) -> bool {
+    (1..=255).contains(&key.len()) && key.chars().all(|c| c.is_ascii_graphic() && c != ',')
+}
+
+/// Identifies a request by its method, product, path, query and body.
+pub fn fingerprint(method: &str, product: &str, uri: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    for part in [method.as_bytes(), product.as_bytes(), uri.as_bytes()] {
+        hasher.update(part);
+        hasher.update(b"\n");
+    }
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_whole_request() {
+        let first = fingerprint("POST", "product_a", "/service_a/v1/orders", b"{}");
+
+        assert_eq!(
+            first,
+            fingerprint("POST", "product_a", "/service_a/v1/orders", b"{}")
+        );
+        assert_ne!(
+            first,
+            fingerprint("POST", "product_a", "/service_a/v1/orders", b"{\"id\":1}")
+        );
+        assert_ne!(
+            first,
+            fingerprint("PUT", "product_a", "/service_a/v1/orders", b"{}")
+        );
+    }
+
+    #[test]
+    fn accept_storable_keys() {
+        assert!(is_valid_key("9f1c2a34-retry"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("a,b"));
+        assert!(!is_valid_key("a b"));
+        assert!(!is_valid_key(&"k".repeat(256)));
+    }
+}
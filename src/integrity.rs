@@ -0,0 +1,581 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+
+use serde::Serialize;
+
+use crate::db::file_db::table_path;
+use crate::entitlement::Entitlements;
+use crate::subscriber::parse_date;
+
+/// What a column holds.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    /// A unique numeric id.
+    Id,
+    /// A unique non-empty id, such as a request's UUID.
+    TextId,
+    Number,
+    Signed,
+    Bool,
+    Date,
+    /// Like `Date`, or empty.
+    OptionalDate,
+    Text,
+    /// One of a few values.
+    Choice(&'static [&'static str]),
+    /// The id of a row of another table.
+    Ref(&'static str),
+    /// Like `Ref`, or empty.
+    OptionalRef(&'static str),
+    /// Like `Number`, or empty.
+    OptionalNumber,
+    /// A key that no other row of the table uses, or empty once revoked.
+    Token,
+    /// Product grants separated by spaces.
+    Grants,
+    /// Hex encoded bytes.
+    Hex,
+}
+
+struct Column {
+    name: &'static str,
+    kind: Kind,
+    /// Older tables may lack the column; readers fall back to a default.
+    optional: bool,
+}
+
+const fn col(name: &'static str, kind: Kind) -> Column {
+    Column {
+        name,
+        kind,
+        optional: false,
+    }
+}
+
+const fn opt(name: &'static str, kind: Kind) -> Column {
+    Column {
+        name,
+        kind,
+        optional: true,
+    }
+}
+
+use Kind::*;
+
+/// The columns of every table the gateway reads.
+const SCHEMA: &[(&str, &[Column])] = &[
+    (
+        "admins",
+        &[col("id", Id), col("name", Text), col("access_token", Token)],
+    ),
+    (
+        "plans",
+        &[
+            col("id", Id),
+            col("name", Text),
+            col("price", Number),
+            col("quota", Number),
+            col("period", Choice(&["monthly", "annual"])),
+            opt("overage_policy", Choice(&["hard", "soft", "capped"])),
+            opt("overage_price", Number),
+            opt("overage_cap", Number),
+            opt("entitlements", Grants),
+        ],
+    ),
+    (
+        "subscriptions",
+        &[
+            col("id", Id),
+            col("name", Text),
+            col("status", Choice(&["0", "1", "2", "3"])),
+            col("price", Number),
+            col("quota", Number),
+            col("expiry_date", Date),
+            col("auto_renew", Bool),
+            col("plan", Ref("plans")),
+        ],
+    ),
+    (
+        "subscribers",
+        &[
+            col("id", Id),
+            col("name", Text),
+            col("subscription", Ref("subscriptions")),
+            opt("access_token", Token),
+            opt("webhook_url", Text),
+            opt("webhook_secret", Text),
+        ],
+    ),
+    (
+        "consumers",
+        &[
+            col("id", Id),
+            col("subscriber", Ref("subscribers")),
+            col("access_token", Token),
+            opt("scopes", Grants),
+            opt("budget", OptionalNumber),
+            opt("sandbox", Bool),
+        ],
+    ),
+    (
+        "products",
+        &[col("id", Id), col("slug", Text), col("requests", Number)],
+    ),
+    (
+        "services",
+        &[
+            col("id", Id),
+            col("name", Text),
+            col("slug", Text),
+            col("version", Text),
+            col("status", Number),
+            col("base_url", Text),
+            col("price", Number),
+            col("requests", Number),
+            col("product", Ref("products")),
+            opt("sandbox_url", Text),
+            opt(
+                "websocket_billing",
+                Choice(&["connection", "message", "minute"]),
+            ),
+            opt("cache_ttl", OptionalNumber),
+            opt("cache_headers", Text),
+        ],
+    ),
+    (
+        "pricing_rules",
+        &[
+            col("id", Id),
+            col("service", Ref("services")),
+            col(
+                "kind",
+                Choice(&[
+                    "method",
+                    "request_bytes",
+                    "response_bytes",
+                    "duration",
+                    "usage_header",
+                ]),
+            ),
+            col("param", Text),
+            col("price", Number),
+        ],
+    ),
+    (
+        "transform_rules",
+        &[
+            col("id", Id),
+            col("service", Ref("services")),
+            col(
+                "kind",
+                Choice(&[
+                    "remove_request_header",
+                    "set_request_header",
+                    "rewrite_path_prefix",
+                    "rewrite_path_regex",
+                    "remove_response_header",
+                    "set_response_header",
+                ]),
+            ),
+            col("param", Text),
+            col("value", Text),
+        ],
+    ),
+    (
+        "requests",
+        &[
+            col("id", TextId),
+            col("product_slug", Text),
+            col("service_slug", Text),
+            col("service_version", Text),
+            col("url", Text),
+            col("status", Number),
+            col("price", Number),
+            col("consumer", Ref("consumers")),
+            col("service", Ref("services")),
+        ],
+    ),
+    (
+        "sandbox_requests",
+        &[
+            col("id", TextId),
+            col("product_slug", Text),
+            col("service_slug", Text),
+            col("service_version", Text),
+            col("url", Text),
+            col("status", Number),
+            col("price", Number),
+            col("consumer", Ref("consumers")),
+            col("service", Ref("services")),
+        ],
+    ),
+    (
+        "ledger",
+        &[
+            col("id", Id),
+            col("subscription", Ref("subscriptions")),
+            col(
+                "kind",
+                Choice(&["debit", "credit", "refund", "renewal", "adjustment"]),
+            ),
+            col("amount", Signed),
+            col("reason", Text),
+            col("request", Text),
+            col("created_at", Date),
+            opt("operator", Text),
+        ],
+    ),
+    (
+        "renewals",
+        &[
+            col("id", Id),
+            col("subscription", Ref("subscriptions")),
+            col("plan", Ref("plans")),
+            col("quota", Number),
+            col("renewed_at", Date),
+            col("expiry_date", Date),
+        ],
+    ),
+    (
+        "overages",
+        &[
+            col("id", Id),
+            col("subscription", Ref("subscriptions")),
+            col("request", Text),
+            col("tokens", Number),
+            col("price", Number),
+            col("created_at", Date),
+        ],
+    ),
+    (
+        "alerts",
+        &[
+            col("id", Id),
+            col("subscriber", Ref("subscribers")),
+            col("subscription", OptionalRef("subscriptions")),
+            col("kind", Text),
+            col("period", Date),
+            col("status", Choice(&["pending", "sent", "failed"])),
+            col("attempts", Number),
+            col("next_attempt_at", Date),
+            col("created_at", Date),
+        ],
+    ),
+    (
+        "budget_usage",
+        &[
+            col("id", Id),
+            col("consumer", Ref("consumers")),
+            col("period", Date),
+            col("tokens", Number),
+        ],
+    ),
+    (
+        "idempotency_keys",
+        &[
+            col("id", Id),
+            col("consumer", Ref("consumers")),
+            col("key", Text),
+            col("fingerprint", Text),
+            col("status", OptionalNumber),
+            col("headers", Hex),
+            col("body", Hex),
+            col("created_at", Date),
+        ],
+    ),
+    (
+        "jobs",
+        &[
+            col("id", Id),
+            col("consumer", Ref("consumers")),
+            col("service", Ref("services")),
+            col("request", Text),
+            col("method", Text),
+            col("url", Hex),
+            col("headers", Hex),
+            col("body", Hex),
+            col("callback_url", Text),
+            col(
+                "status",
+                Choice(&["queued", "running", "completed", "failed"]),
+            ),
+            col("response_status", OptionalNumber),
+            col("response_headers", Hex),
+            col("response_body", Hex),
+            col("price", Number),
+            col("reserved", Number),
+            col("reserved_overage", Number),
+            col("created_at", Date),
+            col("completed_at", OptionalDate),
+        ],
+    ),
+    (
+        "schema_migrations",
+        &[
+            col("version", Id),
+            col("name", Text),
+            col("applied_at", Date),
+        ],
+    ),
+];
+
+/// Something in a table that would break reading it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Problem {
+    pub table: String,
+    /// The line of the table file, the header being line 1.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} line {line}: {}", self.table, self.message),
+            None => write!(f, "{}: {}", self.table, self.message),
+        }
+    }
+}
+
+fn problem(table: &str, line: Option<usize>, message: String) -> Problem {
+    Problem {
+        table: table.to_string(),
+        line,
+        message,
+    }
+}
+
+fn split(line: &str) -> Vec<String> {
+    line.split(',')
+        .map(|cell| cell.trim().to_string())
+        .collect()
+}
+
+/// A table's header and its non-blank rows, with their line numbers.
+struct Parsed {
+    columns: Vec<String>,
+    rows: Vec<(usize, Vec<String>)>,
+}
+
+impl Parsed {
+    fn new(content: &str) -> Parsed {
+        let mut lines = content.lines().enumerate();
+        let columns = lines
+            .next()
+            .map(|(_, header)| split(header))
+            .unwrap_or_default();
+        let rows = lines
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| (i + 1, split(line)))
+            .collect();
+        Parsed { columns, rows }
+    }
+
+    fn values<'a>(&'a self, column: &str) -> Vec<(usize, &'a str)> {
+        match self.columns.iter().position(|name| name == column) {
+            Some(i) => self
+                .rows
+                .iter()
+                .filter_map(|(line, cells)| Some((*line, cells.get(i)?.as_str())))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+fn check_value(kind: Kind, value: &str, ids: &HashMap<&str, HashSet<String>>) -> Option<String> {
+    let is_ref = |table: &str| ids.get(table).is_some_and(|ids| ids.contains(value));
+    match kind {
+        Id | Number | Ref(_) if value.parse::<u128>().is_err() => {
+            Some(format!("`{value}` is not a number"))
+        }
+        OptionalNumber if !value.is_empty() && value.parse::<u128>().is_err() => {
+            Some(format!("`{value}` is not a number"))
+        }
+        TextId if value.is_empty() => Some("the id is empty".to_string()),
+        Signed if value.parse::<i128>().is_err() => Some(format!("`{value}` is not a number")),
+        Bool if value.parse::<bool>().is_err() => {
+            Some(format!("`{value}` is not `true` or `false`"))
+        }
+        Date if parse_date(value).is_none() => Some(format!("`{value}` is not a date")),
+        OptionalDate if !value.is_empty() && parse_date(value).is_none() => {
+            Some(format!("`{value}` is not a date"))
+        }
+        Choice(choices) if !choices.contains(&value) => {
+            Some(format!("`{value}` is not one of {}", choices.join(", ")))
+        }
+        Ref(table) if !is_ref(table) => Some(format!("{table} with id:{value} does not exist")),
+        OptionalRef(table) if !value.is_empty() && !is_ref(table) => {
+            Some(format!("{table} with id:{value} does not exist"))
+        }
+        Grants => Entitlements::parse(value).err(),
+        Hex if hex::decode(value).is_err() => Some("the value is not hex encoded".to_string()),
+        _ => None,
+    }
+}
+
+/// Checks the `contents` of tables by name, returning every problem found.
+pub fn check(contents: &[(&str, String)]) -> Vec<Problem> {
+    let mut problems = vec![];
+    let parsed = contents
+        .iter()
+        .map(|(table, content)| (*table, Parsed::new(content)))
+        .collect::<HashMap<&str, Parsed>>();
+    let ids = parsed
+        .iter()
+        .map(|(table, parsed)| {
+            let ids = parsed
+                .values("id")
+                .into_iter()
+                .map(|(_, id)| id.to_string())
+                .collect::<HashSet<String>>();
+            (*table, ids)
+        })
+        .collect::<HashMap<&str, HashSet<String>>>();
+
+    for (table, columns) in SCHEMA {
+        let parsed = match parsed.get(table) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        for column in columns.iter().filter(|column| !column.optional) {
+            if !parsed.columns.iter().any(|name| name == column.name) {
+                problems.push(problem(
+                    table,
+                    Some(1),
+                    format!("`{}` column is missing", column.name),
+                ));
+            }
+        }
+        for (line, cells) in &parsed.rows {
+            if cells.len() != parsed.columns.len() {
+                problems.push(problem(
+                    table,
+                    Some(*line),
+                    format!(
+                        "{} values for {} columns",
+                        cells.len(),
+                        parsed.columns.len()
+                    ),
+                ));
+            }
+        }
+
+        for column in columns.iter() {
+            let mut seen = HashMap::new();
+            for (line, value) in parsed.values(column.name) {
+                if let Some(message) = check_value(column.kind, value, &ids) {
+                    problems.push(problem(
+                        table,
+                        Some(line),
+                        format!("`{}`: {message}", column.name),
+                    ));
+                }
+                let unique = match column.kind {
+                    Id | TextId => true,
+                    Token => !value.is_empty(),
+                    _ => false,
+                };
+                if !unique {
+                    continue;
+                }
+                if let Some(first) = seen.insert(value, line) {
+                    problems.push(problem(
+                        table,
+                        Some(line),
+                        format!("`{}` is already used on line {first}", column.name),
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Checks every table of the configured storage.
+pub fn check_storage() -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut contents = vec![];
+    for (table, _) in SCHEMA {
+        let path = table_path(table);
+        match fs::read_to_string(&path) {
+            Ok(content) => contents.push((*table, content)),
+            Err(e) => problems.push(problem(
+                table,
+                None,
+                format!("{} can't be read: {e}", path.display()),
+            )),
+        }
+    }
+    problems.extend(check(&contents));
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(consumers: &str) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "subscribers",
+                "id, name, subscription, access_token\n1, Subscriber A, 1, sub-1\n".to_string(),
+            ),
+            ("consumers", consumers.to_string()),
+        ]
+    }
+
+    #[test]
+    fn accept_consistent_tables() {
+        let problems = check(&tables(
+            "id, subscriber, access_token\n1, 1, key-1\n2, 1, \n",
+        ));
+
+        // subscriptions aren't part of the check, so they all look missing
+        assert_eq!(
+            problems,
+            vec![problem(
+                "subscribers",
+                Some(2),
+                "`subscription`: subscriptions with id:1 does not exist".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn report_every_problem() {
+        let consumers = "\
+        id, subscriber, access_token
+        1, 1, key-1
+        1, 9, key-1
+        x, 1
+        ";
+        let problems = check(&tables(consumers))
+            .iter()
+            .filter(|problem| problem.table == "consumers")
+            .map(|problem| problem.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            problems,
+            vec![
+                "consumers line 4: 2 values for 3 columns",
+                "consumers line 3: `id` is already used on line 2",
+                "consumers line 4: `id`: `x` is not a number",
+                "consumers line 3: `subscriber`: subscribers with id:9 does not exist",
+                "consumers line 3: `access_token` is already used on line 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn report_missing_columns() {
+        let problems = check(&[("products", "id, slug\n1, product_a\n".to_string())]);
+
+        assert_eq!(
+            problems[0].to_string(),
+            "products line 1: `requests` column is missing"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use chrono::{Months, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+
+use crate::ledger::{Entry, EntryKind};
+use crate::overage::Overage;
+use crate::request::Request;
+use crate::subscriber::{format_date, Subscriber};
+
+/// A calendar month, written as `2023-01`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BillingMonth {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+impl BillingMonth {
+    pub fn parse(value: &str) -> Option<BillingMonth> {
+        let start = NaiveDate::parse_from_str(&format!("{}-01", value.trim()), "%Y-%m-%d")
+            .ok()?
+            .and_hms_opt(0, 0, 0)?;
+        let end = start.checked_add_months(Months::new(1))?;
+        Some(BillingMonth { start, end })
+    }
+
+    pub fn contains(&self, date: &NaiveDateTime) -> bool {
+        &self.start <= date && date < &self.end
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct InvoiceLine {
+    pub product: String,
+    pub service: String,
+    pub requests: u128,
+    pub tokens: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Invoice {
+    pub subscriber_id: u128,
+    pub subscriber: String,
+    pub plan: String,
+    pub period_start: String,
+    pub period_end: String,
+    pub plan_fee: u128,
+    pub lines: Vec<InvoiceLine>,
+    pub included_tokens: u128,
+    pub consumed_tokens: u128,
+    pub refunded_tokens: u128,
+    pub overage_tokens: u128,
+    pub overage_fee: u128,
+    pub total: u128,
+}
+
+/// Builds the statement of `subscriber` for `month` from its ledger entries
+/// and overages.
+pub fn generate(
+    subscriber: &Subscriber,
+    entries: &[Entry],
+    requests: &[Request],
+    overages: &[Overage],
+    month: &BillingMonth,
+) -> Invoice {
+    let subscription = &subscriber.subscription;
+    let entries = entries
+        .iter()
+        .filter(|entry| {
+            entry.subscription_id == subscription.id && month.contains(&entry.created_at)
+        })
+        .collect::<Vec<&Entry>>();
+
+    let mut lines: Vec<InvoiceLine> = vec![];
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Debit)
+    {
+        let request = entry
+            .request_id
+            .as_ref()
+            .and_then(|id| requests.iter().find(|request| &request.id == id));
+        let (product, service) = match request {
+            Some(request) => (request.product_slug.clone(), request.service_slug.clone()),
+            None => ("unknown".to_string(), "unknown".to_string()),
+        };
+        let tokens = entry.amount.unsigned_abs();
+        match lines
+            .iter_mut()
+            .find(|line| line.product == product && line.service == service)
+        {
+            Some(line) => {
+                line.requests += 1;
+                line.tokens += tokens;
+            }
+            None => lines.push(InvoiceLine {
+                product,
+                service,
+                requests: 1,
+                tokens,
+            }),
+        }
+    }
+
+    let consumed_tokens = lines.iter().map(|line| line.tokens).sum::<u128>();
+    let refunded_tokens = entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Refund)
+        .map(|entry| entry.amount.unsigned_abs())
+        .sum::<u128>();
+    let included_tokens = subscription.plan.quota;
+    let overages = overages
+        .iter()
+        .filter(|overage| {
+            overage.subscription_id == subscription.id && month.contains(&overage.created_at)
+        })
+        .collect::<Vec<&Overage>>();
+    let overage_fee = overages.iter().map(|overage| overage.cost()).sum::<u128>();
+
+    Invoice {
+        subscriber_id: subscriber.id,
+        subscriber: subscriber.name.clone(),
+        plan: subscription.plan.name.clone(),
+        period_start: format_date(&month.start),
+        period_end: format_date(&month.end),
+        plan_fee: subscription.price,
+        lines,
+        included_tokens,
+        consumed_tokens,
+        refunded_tokens,
+        overage_tokens: overages.iter().map(|overage| overage.tokens).sum(),
+        overage_fee,
+        total: subscription.price + overage_fee,
+    }
+}
+
+/// Builds the statements of `subscribers` for `month`.
+pub fn generate_all(
+    subscribers: &[Subscriber],
+    entries: &[Entry],
+    requests: &[Request],
+    overages: &[Overage],
+    month: &BillingMonth,
+) -> Vec<Invoice> {
+    subscribers
+        .iter()
+        .map(|subscriber| generate(subscriber, entries, requests, overages, month))
+        .collect()
+}
+
+/// Renders invoices as CSV, one row per invoice line followed by a total row.
+pub fn to_csv(invoices: &[Invoice]) -> String {
+    let mut rows = vec![
+        "subscriber_id,subscriber,plan,period_start,period_end,item,product,service,requests,tokens,amount"
+            .to_string(),
+    ];
+    for invoice in invoices {
+        let prefix = format!(
+            "{},{},{},{},{}",
+            invoice.subscriber_id,
+            csv_field(&invoice.subscriber),
+            csv_field(&invoice.plan),
+            invoice.period_start,
+            invoice.period_end
+        );
+        rows.push(format!(
+            "{prefix},plan_fee,,,,{},{}",
+            invoice.included_tokens, invoice.plan_fee
+        ));
+        for line in &invoice.lines {
+            rows.push(format!(
+                "{prefix},usage,{},{},{},{},",
+                csv_field(&line.product),
+                csv_field(&line.service),
+                line.requests,
+                line.tokens
+            ));
+        }
+        rows.push(format!("{prefix},refunds,,,,{},", invoice.refunded_tokens));
+        rows.push(format!(
+            "{prefix},overage,,,,{},{}",
+            invoice.overage_tokens, invoice.overage_fee
+        ));
+        rows.push(format!(
+            "{prefix},total,,,,{},{}",
+            invoice.consumed_tokens, invoice.total
+        ));
+    }
+    rows.join("\n") + "\n"
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains(',') || value.contains('"') {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::fake(&HashMap::from([
+                ("kind", "debit"),
+                ("amount", "-4"),
+                ("request", "UUID-1"),
+                ("created_at", "2023-01-10 00:00:00"),
+            ])),
+            Entry::fake(&HashMap::from([
+                ("kind", "debit"),
+                ("amount", "-8"),
+                ("request", "UUID-1"),
+                ("created_at", "2023-01-11 00:00:00"),
+            ])),
+            Entry::fake(&HashMap::from([
+                ("kind", "refund"),
+                ("amount", "1"),
+                ("created_at", "2023-01-12 00:00:00"),
+            ])),
+            Entry::fake(&HashMap::from([
+                ("kind", "debit"),
+                ("amount", "-100"),
+                ("created_at", "2023-02-01 00:00:00"),
+            ])),
+        ]
+    }
+
+    #[test]
+    fn parse_billing_month() {
+        let month = BillingMonth::parse("2023-12").unwrap();
+
+        assert_eq!(format_date(&month.end), "2024-01-01 00:00:00");
+        assert!(BillingMonth::parse("2023-13").is_none());
+    }
+
+    #[test]
+    fn generate_invoice_for_month() {
+        let subscriber = Subscriber::fake(&HashMap::new());
+        let requests = vec![Request::fake(&HashMap::from([
+            ("id", "UUID-1"),
+            ("product_slug", "product_a"),
+            ("service_slug", "service_a"),
+        ]))];
+        let overages = vec![
+            Overage::fake(&HashMap::from([
+                ("tokens", "3"),
+                ("price", "2"),
+                ("created_at", "2023-01-11 00:00:00"),
+            ])),
+            Overage::fake(&HashMap::from([
+                ("tokens", "5"),
+                ("created_at", "2023-02-11 00:00:00"),
+            ])),
+        ];
+        let month = BillingMonth::parse("2023-01").unwrap();
+
+        let invoice = generate(&subscriber, &entries(), &requests, &overages, &month);
+
+        assert_eq!(
+            invoice.lines,
+            vec![InvoiceLine {
+                product: "product_a".to_string(),
+                service: "service_a".to_string(),
+                requests: 2,
+                tokens: 12
+            }]
+        );
+        assert_eq!(invoice.consumed_tokens, 12);
+        assert_eq!(invoice.refunded_tokens, 1);
+        assert_eq!(invoice.overage_tokens, 3);
+        assert_eq!(invoice.overage_fee, 6);
+        assert_eq!(invoice.total, invoice.plan_fee + 6);
+    }
+
+    #[test]
+    fn render_invoice_as_csv() {
+        let subscriber = Subscriber::fake(&HashMap::from([("name", "Acme, Inc")]));
+        let month = BillingMonth::parse("2023-02").unwrap();
+
+        let csv = to_csv(&[generate(&subscriber, &entries(), &[], &[], &month)]);
+
+        assert!(csv.contains("1,\"Acme, Inc\",default_plan,2023-02-01 00:00:00,2023-03-01 00:00:00,usage,unknown,unknown,1,100,\n"));
+    }
+}
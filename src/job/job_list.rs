@@ -0,0 +1,278 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+use rocket::serde::json;
+
+use crate::biller::Charge;
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record, Searchable, Writable};
+use crate::router::Upstream;
+use crate::subscriber::{format_date, parse_date};
+
+use super::{Job, JobStatus};
+
+#[derive(Clone)]
+pub struct JobList<D> {
+    db: Arc<Mutex<D>>,
+    pub jobs: Vec<Job>,
+}
+
+pub type FlatJobList = JobList<FlatTable<String, String>>;
+
+fn encode_headers(headers: &[(String, String)]) -> String {
+    hex::encode(json::to_string(&headers).expect("serialize headers"))
+}
+
+impl FlatJobList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        JobList {
+            db: Arc::new(db),
+            jobs: vec![],
+        }
+    }
+
+    pub fn get_by_id(&self, id: u128) -> Option<Job> {
+        JobList::get_by_attr::<FlatTable<String, String>, Job>(&self.db, "id", id.to_string())
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        JobList::get_all::<FlatTable<String, String>, Job>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Job> {
+        JobList::search_records::<FlatTable<String, String>, Job>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        JobList::count_records(&self.db, filter)
+    }
+
+    /// Queues `job` for the runner, giving it an id.
+    pub fn queue(&self, job: Job) -> Job {
+        JobList::insert_next::<FlatTable<String, String>, Job>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("consumer".to_string(), job.consumer_id.to_string()),
+                ("service".to_string(), job.service_id.to_string()),
+                ("request".to_string(), job.request_id),
+                ("method".to_string(), job.method),
+                ("url".to_string(), hex::encode(job.url)),
+                ("headers".to_string(), encode_headers(&job.headers)),
+                ("body".to_string(), hex::encode(job.body)),
+                (
+                    "callback_url".to_string(),
+                    job.callback_url.unwrap_or_default(),
+                ),
+                ("status".to_string(), JobStatus::Queued.as_str().to_string()),
+                ("response_status".to_string(), String::new()),
+                ("response_headers".to_string(), String::new()),
+                ("response_body".to_string(), String::new()),
+                ("price".to_string(), "0".to_string()),
+                ("reserved".to_string(), job.reserved.debited.to_string()),
+                (
+                    "reserved_overage".to_string(),
+                    job.reserved.overage.to_string(),
+                ),
+                ("created_at".to_string(), format_date(&job.created_at)),
+                ("completed_at".to_string(), String::new()),
+            ])
+        })
+    }
+
+    /// Marks the queued jobs as running and returns them. Checking and marking
+    /// happen under one lock, so no job is started twice.
+    pub fn start_queued(&self) -> Vec<Job> {
+        let mut db = self.db.lock().expect("lock db");
+        let queued = db
+            .search(&Query::default().filter(Filter::eq("status", JobStatus::Queued.as_str())))
+            .into_iter()
+            .map(|record| Job::from(record.clone()))
+            .collect::<Vec<Job>>();
+        queued
+            .into_iter()
+            .filter_map(|job| {
+                let changes = Record::from([(
+                    "status".to_string(),
+                    JobStatus::Running.as_str().to_string(),
+                )]);
+                db.update_by("id", &job.id.to_string(), changes)
+                    .map(|record| Job::from(record.clone()))
+            })
+            .collect()
+    }
+
+    /// Queues again the jobs left running by a gateway that stopped, returning
+    /// how many.
+    pub fn requeue_running(&self) -> usize {
+        let running = self
+            .filter(&Query::default().filter(Filter::eq("status", JobStatus::Running.as_str())));
+        for job in &running {
+            JobList::update_by_attr::<FlatTable<String, String>, Job>(
+                &self.db,
+                "id",
+                job.id.to_string(),
+                Record::from([("status".to_string(), JobStatus::Queued.as_str().to_string())]),
+            );
+        }
+        running.len()
+    }
+
+    /// Stores the response job `id` ended with and what it was charged.
+    pub fn finish(
+        &self,
+        id: u128,
+        status: JobStatus,
+        upstream: &Upstream,
+        price: u128,
+        now: NaiveDateTime,
+    ) -> Option<Job> {
+        JobList::update_by_attr::<FlatTable<String, String>, Job>(
+            &self.db,
+            "id",
+            id.to_string(),
+            Record::from([
+                ("status".to_string(), status.as_str().to_string()),
+                ("response_status".to_string(), upstream.status.to_string()),
+                (
+                    "response_headers".to_string(),
+                    encode_headers(&upstream.headers),
+                ),
+                ("response_body".to_string(), hex::encode(&upstream.body)),
+                ("price".to_string(), price.to_string()),
+                ("completed_at".to_string(), format_date(&now)),
+            ]),
+        )
+    }
+
+    /// Deletes the jobs that were done before `expired_before`, returning how
+    /// many.
+    pub fn purge(&self, expired_before: NaiveDateTime) -> usize {
+        let expired = self
+            .list()
+            .into_iter()
+            .filter(|job| job.completed_at.is_some_and(|date| date < expired_before))
+            .collect::<Vec<Job>>();
+        for job in &expired {
+            JobList::delete_by_attr(&self.db, "id", job.id.to_string());
+        }
+        expired.len()
+    }
+}
+
+impl ModelAble<String, String> for FlatJobList {}
+
+fn decode(value: &str) -> Vec<u8> {
+    hex::decode(value).expect("Invalid hex value")
+}
+
+fn decode_headers(value: &str) -> Vec<(String, String)> {
+    match value.is_empty() {
+        true => vec![],
+        false => json::from_slice(&decode(value)).expect("Invalid headers"),
+    }
+}
+
+impl From<Record<String, String>> for Job {
+    fn from(map: Record<String, String>) -> Self {
+        let field = |name: &str| map.get(name).map(String::as_str);
+        match (
+            field("id"),
+            field("consumer"),
+            field("service"),
+            field("request"),
+            field("method"),
+            field("url"),
+            field("status"),
+            field("price"),
+            field("created_at"),
+        ) {
+            (
+                Some(id),
+                Some(consumer_id),
+                Some(service_id),
+                Some(request_id),
+                Some(method),
+                Some(url),
+                Some(status),
+                Some(price),
+                Some(created_at),
+            ) => Job {
+                id: id.parse::<u128>().unwrap(),
+                consumer_id: consumer_id.parse::<u128>().unwrap(),
+                service_id: service_id.parse::<u128>().unwrap(),
+                request_id: request_id.to_string(),
+                method: method.to_string(),
+                url: String::from_utf8(decode(url)).expect("Invalid job URL"),
+                headers: decode_headers(field("headers").unwrap_or_default()),
+                body: decode(field("body").unwrap_or_default()),
+                callback_url: field("callback_url")
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string),
+                status: JobStatus::parse(status).expect("Invalid job status"),
+                response_status: field("response_status")
+                    .filter(|status| !status.is_empty())
+                    .map(|status| status.parse::<u16>().unwrap()),
+                response_headers: decode_headers(field("response_headers").unwrap_or_default()),
+                response_body: decode(field("response_body").unwrap_or_default()),
+                price: price.parse::<u128>().unwrap(),
+                reserved: Charge {
+                    debited: field("reserved").unwrap_or("0").parse::<u128>().unwrap(),
+                    overage: field("reserved_overage")
+                        .unwrap_or("0")
+                        .parse::<u128>()
+                        .unwrap(),
+                },
+                created_at: parse_date(created_at).expect("Invalid job date"),
+                completed_at: field("completed_at")
+                    .filter(|date| !date.is_empty())
+                    .map(|date| parse_date(date).expect("Invalid job date")),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn date(value: &str) -> NaiveDateTime {
+        parse_date(value).unwrap()
+    }
+
+    #[test]
+    fn run_queued_job_once_and_keep_result() {
+        let table = "id, consumer, service, request, method, url, headers, body, callback_url, \
+                     status, response_status, response_headers, response_body, price, \
+                     reserved, reserved_overage, created_at, completed_at";
+        let list = JobList::new(Mutex::new(FlatTable::new_from_string(table.to_string())));
+        let job = list.queue(Job {
+            headers: vec![("X-Request-Id".to_string(), "r-1".to_string())],
+            ..Job::fake(&HashMap::from([("url", "http://127.0.0.1:8001/a?b=1,2")]))
+        });
+
+        let started = list.start_queued();
+        assert_eq!(started.len(), 1);
+        assert_eq!(started[0].url, "http://127.0.0.1:8001/a?b=1,2");
+        assert_eq!(started[0].headers.len(), 1);
+        assert!(list.start_queued().is_empty());
+
+        let upstream = Upstream {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"done, at last".to_vec(),
+            duration: Duration::ZERO,
+        };
+        let now = date("2023-01-02 00:00:00");
+        list.finish(job.id, JobStatus::Completed, &upstream, 6, now);
+
+        let done = list.get_by_id(job.id).unwrap();
+        assert_eq!(done.status, JobStatus::Completed);
+        assert_eq!(done.price, 6);
+        assert_eq!(done.result().unwrap().body, b"done, at last");
+        assert_eq!(list.purge(date("2023-01-02 00:00:00")), 0);
+        assert_eq!(list.purge(date("2023-01-03 00:00:00")), 1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::alert;
+use crate::biller::Charge;
+use crate::router::Upstream;
+use crate::subscriber::parse_date;
+
+pub mod job_list;
+pub mod routes;
+pub mod runner;
+
+/// Header whose `respond-async` preference turns a call into a job.
+pub const PREFER: &str = "Prefer";
+
+/// Header naming where a job's result is posted once it is done.
+pub const CALLBACK_URL: &str = "X-Callback-Url";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Accepted, waiting for the runner.
+    Queued,
+    /// Forwarded, waiting for the service.
+    Running,
+    /// Answered by the service and charged.
+    Completed,
+    /// Not answered, answered with a `5xx` or not paid for, so not charged.
+    Failed,
+}
+
+impl JobStatus {
+    pub fn parse(value: &str) -> Option<JobStatus> {
+        match value {
+            "queued" => Some(JobStatus::Queued),
+            "running" => Some(JobStatus::Running),
+            "completed" => Some(JobStatus::Completed),
+            "failed" => Some(JobStatus::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed)
+    }
+
+    /// Returns the event name sent to callbacks.
+    pub fn event(&self) -> &'static str {
+        match self {
+            JobStatus::Completed => "job.completed",
+            _ => "job.failed",
+        }
+    }
+}
+
+/// A call forwarded in the background, and its result once it is done.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u128,
+    pub consumer_id: u128,
+    pub service_id: u128,
+    pub request_id: String,
+    #[serde(skip)]
+    pub method: String,
+    /// The upstream URL, which the consumer doesn't get to see.
+    #[serde(skip)]
+    pub url: String,
+    #[serde(skip)]
+    pub headers: Vec<(String, String)>,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+    pub callback_url: Option<String>,
+    pub status: JobStatus,
+    /// `None` until the job is done.
+    pub response_status: Option<u16>,
+    #[serde(skip)]
+    pub response_headers: Vec<(String, String)>,
+    #[serde(skip)]
+    pub response_body: Vec<u8>,
+    pub price: u128,
+    /// The estimated price taken from the quota when the job was queued,
+    /// until it is settled.
+    #[serde(skip)]
+    pub reserved: Charge,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+impl Job {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Job {
+        Job {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            consumer_id: attr
+                .get("consumer")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            service_id: attr.get("service").unwrap_or(&"1").parse::<u128>().unwrap(),
+            request_id: attr.get("request").unwrap_or(&"r-1").to_string(),
+            method: attr.get("method").unwrap_or(&"POST").to_string(),
+            url: attr
+                .get("url")
+                .unwrap_or(&"http://127.0.0.1:8001/")
+                .to_string(),
+            headers: vec![],
+            body: attr.get("body").unwrap_or(&"").as_bytes().to_vec(),
+            callback_url: attr.get("callback_url").map(|url| url.to_string()),
+            status: JobStatus::parse(attr.get("status").unwrap_or(&"queued")).unwrap(),
+            response_status: attr
+                .get("response_status")
+                .map(|status| status.parse::<u16>().unwrap()),
+            response_headers: vec![],
+            response_body: attr.get("response_body").unwrap_or(&"").as_bytes().to_vec(),
+            price: attr.get("price").unwrap_or(&"0").parse::<u128>().unwrap(),
+            reserved: Charge {
+                debited: attr
+                    .get("reserved")
+                    .unwrap_or(&"0")
+                    .parse::<u128>()
+                    .unwrap(),
+                overage: 0,
+            },
+            created_at: parse_date(attr.get("created_at").unwrap_or(&"2001-01-01 00:00:00"))
+                .unwrap(),
+            completed_at: attr
+                .get("completed_at")
+                .map(|date| parse_date(date).unwrap()),
+        }
+    }
+
+    /// Rebuilds the response the job ended with, once it is done.
+    pub fn result(&self) -> Option<Upstream> {
+        Some(Upstream {
+            status: self.response_status?,
+            headers: self.response_headers.clone(),
+            body: self.response_body.clone(),
+            duration: Default::default(),
+        })
+    }
+}
+
+/// Returns true if a `Prefer` header value asks for an asynchronous answer.
+pub fn is_async(prefer: &str) -> bool {
+    prefer
+        .split([',', ';'])
+        .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+/// Checks a callback URL before a job is accepted with it. Results are
+/// signed with the subscriber's `webhook_secret`, so there has to be one.
+pub fn check_callback_url(url: &str, webhook_secret: &str) -> Result<(), String> {
+    alert::check_webhook_url(url).map_err(|problem| format!("{CALLBACK_URL} {problem}"))?;
+    match webhook_secret.is_empty() {
+        true => Err(format!(
+            "{CALLBACK_URL} needs a webhook secret, issued by PUT /portal/webhook"
+        )),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ask_for_async_answer_in_prefer_header() {
+        assert!(is_async("respond-async"));
+        assert!(is_async("return=minimal, Respond-Async; wait=10"));
+        assert!(!is_async("return=minimal"));
+        assert!(!is_async(""));
+    }
+
+    #[test]
+    fn accept_public_callback_urls_with_a_secret() {
+        assert!(check_callback_url("https://hooks.example.com/jobs", "secret").is_ok());
+        assert!(check_callback_url("ftp://hooks.example.com/jobs", "secret").is_err());
+        assert!(check_callback_url("https://hooks.example.com/a,b", "secret").is_err());
+        assert!(check_callback_url("http://169.254.169.254/latest", "secret").is_err());
+        assert!(check_callback_url("http://localhost:8000/jobs", "secret").is_err());
+        assert!(check_callback_url("https://hooks.example.com/jobs", "").is_err());
+    }
+}
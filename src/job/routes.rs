@@ -0,0 +1,108 @@
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+
+use crate::errors::{error, ApiError};
+use crate::guards::Authenticated;
+use crate::router::Upstream;
+
+use super::{job_list::FlatJobList, Job};
+
+pub type JobResult<T> = Result<T, Custom<Json<ApiError>>>;
+
+/// Finds job `id` if the authenticated consumer queued it.
+fn own_job(jobs: &FlatJobList, consumer_id: u128, id: u128) -> JobResult<Job> {
+    match jobs.get_by_id(id) {
+        Some(job) if job.consumer_id == consumer_id => Ok(job),
+        _ => Err(error(
+            Status::NotFound,
+            &format!("Job with id:{id} is not found"),
+        )),
+    }
+}
+
+#[get("/<id>")]
+fn status(key: Authenticated, jobs: &State<FlatJobList>, id: u128) -> JobResult<Json<Job>> {
+    own_job(jobs, key.0.id, id).map(Json)
+}
+
+/// Answers with the response the job ended with, as the service gave it.
+#[get("/<id>/result")]
+fn result(key: Authenticated, jobs: &State<FlatJobList>, id: u128) -> JobResult<Upstream> {
+    let job = own_job(jobs, key.0.id, id)?;
+    job.result().ok_or_else(|| {
+        error(
+            Status::Conflict,
+            &format!("Job with id:{id} is still {}", job.status.as_str()),
+        )
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![status, result]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::consumer::consumer_list::ConsumerList;
+    use crate::db::file_db::FlatTable;
+    use crate::job::{job_list::JobList, JobStatus};
+
+    fn table(contents: &str) -> Mutex<FlatTable<String, String>> {
+        Mutex::new(FlatTable::new_from_string(contents.to_string()))
+    }
+
+    #[test]
+    fn follow_own_job_until_result() {
+        let jobs = JobList::new(table(
+            "id, consumer, service, request, method, url, headers, body, callback_url, status, \
+             response_status, response_headers, response_body, price, reserved, reserved_overage, created_at, \
+             completed_at",
+        ));
+        let job = jobs.queue(Job::fake(&HashMap::new()));
+        let rocket = rocket::build()
+            .mount("/jobs", routes())
+            .manage(ConsumerList::new(table(
+                "id, subscriber, access_token\n1, 2, key-1\n2, 2, key-2",
+            )))
+            .manage(jobs);
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+        let get = |path: &str, key: &str| {
+            client
+                .get(path.to_string())
+                .header(Header::new("x-api-key", key.to_string()))
+                .dispatch()
+        };
+
+        assert_eq!(get("/jobs/1", "key-1").status(), Status::Ok);
+        assert_eq!(get("/jobs/1", "key-2").status(), Status::NotFound);
+        assert_eq!(get("/jobs/1/result", "key-1").status(), Status::Conflict);
+
+        let upstream = Upstream {
+            status: 201,
+            headers: vec![],
+            body: b"report".to_vec(),
+            duration: Duration::ZERO,
+        };
+        let now = chrono::Utc::now().naive_utc();
+        client.rocket().state::<FlatJobList>().unwrap().finish(
+            job.id,
+            JobStatus::Completed,
+            &upstream,
+            6,
+            now,
+        );
+        let result = get("/jobs/1/result", "key-1");
+        assert_eq!(result.status(), Status::Created);
+        assert_eq!(result.into_string().unwrap(), "report");
+    }
+}
@@ -0,0 +1,449 @@
+use std::sync::Arc;
+
+use chrono::{NaiveDateTime, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::tokio::time::{sleep, Duration};
+use rocket::{Orbit, Rocket};
+
+use crate::alert;
+use crate::biller::{self, Usage};
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::config::GatewayConfig;
+use crate::consumer::{consumer_list::FlatConsumerList, Consumer};
+use crate::db::Record;
+use crate::errors::ApiError;
+use crate::ledger::ledger_list::FlatLedgerList;
+use crate::logger;
+use crate::overage::overage_list::FlatOverageList;
+use crate::pricing::{self, pricing_list::FlatPricingRuleList, Metering};
+use crate::request::request_list::FlatRequestList;
+use crate::router::{self, Upstream};
+use crate::service::service_list::FlatServiceList;
+use crate::subscriber::subscriber_list::FlatSubscriptionList;
+use crate::transform::{self, transform_list::FlatTransformRuleList};
+
+use super::{job_list::FlatJobList, Job, JobStatus};
+
+/// The tables jobs are run and billed with.
+pub struct JobTables {
+    pub jobs: FlatJobList,
+    pub services: FlatServiceList,
+    pub consumers: FlatConsumerList,
+    pub subscriptions: FlatSubscriptionList,
+    pub pricing_rules: FlatPricingRuleList,
+    pub transform_rules: FlatTransformRuleList,
+    pub ledger: FlatLedgerList,
+    pub overages: FlatOverageList,
+    pub budget_usages: FlatBudgetUsageList,
+    pub requests: FlatRequestList,
+}
+
+impl JobTables {
+    /// Takes the lists managed by `rocket`, so that jobs and requests share
+    /// table locks, or `None` if one of them isn't managed.
+    pub fn managed(rocket: &Rocket<Orbit>) -> Option<JobTables> {
+        Some(JobTables {
+            jobs: rocket.state::<FlatJobList>()?.clone(),
+            services: rocket.state::<FlatServiceList>()?.clone(),
+            consumers: rocket.state::<FlatConsumerList>()?.clone(),
+            subscriptions: rocket.state::<FlatSubscriptionList>()?.clone(),
+            pricing_rules: rocket.state::<FlatPricingRuleList>()?.clone(),
+            transform_rules: rocket.state::<FlatTransformRuleList>()?.clone(),
+            ledger: rocket.state::<FlatLedgerList>()?.clone(),
+            overages: rocket.state::<FlatOverageList>()?.clone(),
+            budget_usages: rocket.state::<FlatBudgetUsageList>()?.clone(),
+            requests: rocket.state::<FlatRequestList>()?.clone(),
+        })
+    }
+}
+
+/// Builds the answer of a job the gateway ended itself.
+fn gateway_error(status: Status, message: String) -> Upstream {
+    let body =
+        rocket::serde::json::to_string(&ApiError { error: message }).expect("serialize error");
+    Upstream {
+        status: status.code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: body.into_bytes(),
+        duration: Default::default(),
+    }
+}
+
+/// Replaces the price reserved when `job` was queued with `price`, in the
+/// quota and the consumer's budget. The service already answered, so what the
+/// reservation doesn't cover is taken from what is left.
+fn settle(
+    tables: &JobTables,
+    consumer: &Consumer,
+    job: &Job,
+    reason: &str,
+    price: u128,
+    now: NaiveDateTime,
+) {
+    let subscription = tables
+        .subscriptions
+        .get_by_id(consumer.subscriber.subscription.id)
+        .unwrap_or_else(|| consumer.subscriber.subscription.clone());
+    let usage = Usage {
+        tokens: price,
+        reason,
+        request_id: Some(&job.request_id),
+    };
+    if let Err(e) = biller::settle(
+        &tables.subscriptions,
+        &tables.ledger,
+        &tables.overages,
+        subscription.id,
+        &job.reserved,
+        usage,
+        now,
+    ) {
+        logger::log(&format!("Job {} could not be settled: {e}", job.id));
+    }
+    let reserved = job.reserved.debited + job.reserved.overage;
+    let period = subscription.period_start();
+    biller::settle_budget(&tables.budget_usages, consumer.id, period, reserved, price);
+}
+
+/// Forwards `job` with `client` and stores the response it ends with,
+/// settling its reservation, then posts it to the callback URL with
+/// `webhooks`. Only a call the service answered below `500` is charged;
+/// anything else fails the job and gives the reservation back.
+pub async fn run(
+    tables: &JobTables,
+    client: &reqwest::Client,
+    webhooks: &reqwest::Client,
+    job: Job,
+) -> Option<Job> {
+    let service = tables.services.get_by_id(job.service_id);
+    let consumer = tables.consumers.get_by_id(job.consumer_id);
+    let (service, consumer) = match (service, consumer) {
+        (Some(service), Some(consumer)) => (service, consumer),
+        (_, consumer) => {
+            let gone = gateway_error(Status::NotFound, "The service or key is gone".to_string());
+            let now = Utc::now().naive_utc();
+            match consumer {
+                Some(consumer) => {
+                    let reason = format!("job {}", job.id);
+                    settle(tables, &consumer, &job, &reason, 0, now);
+                }
+                None => logger::log(&format!(
+                    "Reservation of job {} not returned: its key is gone",
+                    job.id
+                )),
+            }
+            return tables.jobs.finish(job.id, JobStatus::Failed, &gone, 0, now);
+        }
+    };
+
+    let forwarded = router::forward(
+        client,
+        &job.method,
+        &job.url,
+        &job.headers,
+        job.body.clone(),
+    )
+    .await;
+    let now = Utc::now().naive_utc();
+    let (status, upstream, price) = match forwarded {
+        Ok(mut upstream) => {
+            let metering = Metering {
+                method: job.method.clone(),
+                request_bytes: job.body.len() as u128,
+                response_bytes: upstream.body.len() as u128,
+                duration: upstream.duration,
+                headers: upstream
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.to_lowercase(), value.clone()))
+                    .collect(),
+            };
+            let transforms = tables.transform_rules.list_by_service(service.id);
+            transform::response_headers(&transforms, &mut upstream.headers);
+            let rules = tables.pricing_rules.list_by_service(service.id);
+            let price = pricing::settle(&service, &rules, &metering);
+            match upstream.is_failure() {
+                true => (JobStatus::Failed, upstream, 0),
+                false => (JobStatus::Completed, upstream, price),
+            }
+        }
+        Err(e) if e.is_timeout() => (
+            JobStatus::Failed,
+            gateway_error(
+                Status::GatewayTimeout,
+                format!("Service {} did not respond in time", service.slug),
+            ),
+            0,
+        ),
+        Err(_) => (
+            JobStatus::Failed,
+            gateway_error(
+                Status::BadGateway,
+                format!("Service {} is unreachable", service.slug),
+            ),
+            0,
+        ),
+    };
+
+    settle(tables, &consumer, &job, &service.slug, price, now);
+    tables.requests.create(Record::from([
+        ("id".to_string(), job.request_id.clone()),
+        ("product_slug".to_string(), service.product.slug.clone()),
+        ("service_slug".to_string(), service.slug.clone()),
+        ("service_version".to_string(), service.version.clone()),
+        ("url".to_string(), job.url.replace(',', "%2C")),
+        ("status".to_string(), upstream.status.to_string()),
+        ("price".to_string(), price.to_string()),
+        ("consumer".to_string(), consumer.id.to_string()),
+        ("service".to_string(), service.id.to_string()),
+    ]));
+    let done = tables.jobs.finish(job.id, status, &upstream, price, now)?;
+    if done.callback_url.is_some() && !notify(webhooks, &consumer, &done, now).await {
+        logger::log(&format!("Callback of job {} failed", done.id));
+    }
+    Some(done)
+}
+
+/// Posts the result of `job` to its callback URL, signed like alert webhooks,
+/// returning true if it was accepted. Without a secret to sign it with, as
+/// when the subscriber removed its webhook since, nothing is posted.
+pub async fn notify(
+    client: &reqwest::Client,
+    consumer: &Consumer,
+    job: &Job,
+    now: NaiveDateTime,
+) -> bool {
+    let secret = &consumer.subscriber.webhook_secret;
+    let (Some(url), Some(result), false) = (&job.callback_url, job.result(), secret.is_empty())
+    else {
+        return false;
+    };
+    let timestamp = now.and_utc().timestamp();
+    let signature = alert::sign(secret, timestamp, &result.body);
+    let mut request = client
+        .post(url)
+        .header("X-UWS-Event", job.status.event())
+        .header("X-UWS-Job", job.id.to_string())
+        .header("X-UWS-Status", result.status.to_string())
+        .header("X-UWS-Timestamp", timestamp.to_string())
+        .header("X-UWS-Signature", format!("sha256={signature}"));
+    for (name, value) in result
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+    {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request
+        .body(result.body)
+        .send()
+        .await
+        .map(|response| response.status().is_success())
+        .unwrap_or(false)
+}
+
+/// Starts the queued jobs every `interval` once the server is up, each in
+/// its own task, and forgets finished ones past `jobs.retention_hours`.
+pub struct JobRunner {
+    pub interval: Duration,
+}
+
+#[rocket::async_trait]
+impl Fairing for JobRunner {
+    fn info(&self) -> Info {
+        Info {
+            name: "Job runner",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let interval = self.interval;
+        let config = rocket
+            .state::<GatewayConfig>()
+            .map(|config| config.jobs.clone())
+            .unwrap_or_default();
+        let Some(tables) = JobTables::managed(rocket).map(Arc::new) else {
+            logger::log("Job runner not started: the tables it runs on are not managed");
+            return;
+        };
+        rocket::tokio::spawn(async move {
+            let client = router::client(config.timeout());
+            let webhooks = alert::webhook_client(config.timeout());
+            // jobs cut short by a restart are forwarded again
+            let requeued = tables.jobs.requeue_running();
+            if requeued > 0 {
+                logger::log(&format!("Requeued {requeued} interrupted jobs"));
+            }
+            loop {
+                sleep(interval).await;
+                for job in tables.jobs.start_queued() {
+                    let tables = tables.clone();
+                    let client = client.clone();
+                    let webhooks = webhooks.clone();
+                    rocket::tokio::spawn(async move {
+                        run(&tables, &client, &webhooks, job).await;
+                    });
+                }
+                let purged = tables
+                    .jobs
+                    .purge(Utc::now().naive_utc() - config.retention());
+                if purged > 0 {
+                    logger::log(&format!("Purged {purged} expired jobs"));
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::budget::budget_list::BudgetUsageList;
+    use crate::consumer::consumer_list::ConsumerList;
+    use crate::db::file_db::FlatTable;
+    use crate::job::job_list::JobList;
+    use crate::ledger::{self, ledger_list::LedgerList};
+    use crate::overage::overage_list::OverageList;
+    use crate::pricing::pricing_list::PricingRuleList;
+    use crate::request::request_list::RequestList;
+    use crate::router::stub;
+    use crate::service::service_list::ServiceList;
+    use crate::subscriber::subscriber_list::SubscriptionList;
+    use crate::transform::transform_list::TransformRuleList;
+
+    fn table(contents: &str) -> Mutex<FlatTable<String, String>> {
+        Mutex::new(FlatTable::new_from_string(contents.to_string()))
+    }
+
+    /// Tables with a job queued for `service_a` at `base_url`, on a
+    /// subscription with 10 tokens.
+    fn tables(base_url: &str) -> JobTables {
+        let tables = JobTables {
+            jobs: JobList::new(table(
+                "id, consumer, service, request, method, url, headers, body, callback_url, \
+                 status, response_status, response_headers, response_body, price, reserved, \
+                 reserved_overage, created_at,                  completed_at",
+            )),
+            services: ServiceList::new(table(&format!(
+                "id, name, slug, version, status, base_url, price, requests, product\n\
+                 1, Service A, service_a, v1.0.0, 1, {base_url}, 2, 10, 1"
+            ))),
+            consumers: ConsumerList::new(table("id, subscriber, access_token\n1, 2, key-1")),
+            subscriptions: SubscriptionList::new(table(
+                "id, name, status, price, quota, expiry_date, auto_renew, plan\n\
+                 2, Golden 50, 1, 50000, 10, 2030-10-01 00:00:00, false, 2",
+            )),
+            pricing_rules: PricingRuleList::new(table(
+                "id, service, kind, param, price\n1, 1, usage_header, X-Usage-Tokens, 1",
+            )),
+            transform_rules: TransformRuleList::new(table("id, service, kind, param, value")),
+            ledger: LedgerList::new(table(
+                "id, subscription, kind, amount, reason, request, created_at\n\
+                 1, 2, credit, 10, opening balance, , 2023-01-01 00:00:00",
+            )),
+            overages: OverageList::new(table(
+                "id, subscription, request, tokens, price, created_at",
+            )),
+            budget_usages: BudgetUsageList::new(table("id, consumer, period, tokens")),
+            requests: RequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )),
+        };
+        tables.jobs.queue(Job::fake(&HashMap::from([(
+            "url",
+            format!("{base_url}/reports").as_str(),
+        )])));
+        tables
+    }
+
+    #[rocket::async_test]
+    async fn charge_job_once_it_completes() {
+        let (base_url, _received) = stub::serve_once(
+            "HTTP/1.1 200 OK\r\nX-Usage-Tokens: 4\r\nContent-Length: 6\r\n\r\nreport",
+        );
+        let tables = tables(&base_url);
+        let job = tables.jobs.start_queued().remove(0);
+
+        let done = run(
+            &tables,
+            &reqwest::Client::new(),
+            &reqwest::Client::new(),
+            job,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(done.status, JobStatus::Completed);
+        assert_eq!(done.price, 6);
+        assert_eq!(done.result().unwrap().body, b"report");
+        assert_eq!(tables.subscriptions.get_by_id(2).unwrap().quota, 4);
+        assert_eq!(tables.requests.list()[0].price, 6);
+    }
+
+    #[rocket::async_test]
+    async fn settle_reservation_when_job_completes() {
+        let (base_url, _received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nX-Usage-Tokens: 1\r\nContent-Length: 2\r\n\r\nok");
+        let tables = tables(&base_url);
+        let subscription = tables.subscriptions.get_by_id(2).unwrap();
+        let mut job = tables.jobs.start_queued().remove(0);
+        let reservation = Usage {
+            tokens: 5,
+            reason: "service_a",
+            request_id: Some(&job.request_id),
+        };
+        let now = Utc::now().naive_utc();
+        job.reserved = biller::charge(
+            &tables.subscriptions,
+            &tables.ledger,
+            &tables.overages,
+            &subscription,
+            reservation,
+            now,
+        )
+        .unwrap();
+
+        let done = run(
+            &tables,
+            &reqwest::Client::new(),
+            &reqwest::Client::new(),
+            job,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(done.price, 3);
+        assert_eq!(tables.subscriptions.get_by_id(2).unwrap().quota, 7);
+        let entries = tables.ledger.list();
+        assert_eq!(entries.last().unwrap().amount, 2);
+        assert!(ledger::reconcile(&tables.subscriptions.list(), &entries).is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn fail_job_without_charging() {
+        let (base_url, _received) =
+            stub::serve_once("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        let tables = tables(&base_url);
+        let job = tables.jobs.start_queued().remove(0);
+
+        let done = run(
+            &tables,
+            &reqwest::Client::new(),
+            &reqwest::Client::new(),
+            job,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(done.status, JobStatus::Failed);
+        assert_eq!(done.response_status, Some(503));
+        assert_eq!(done.price, 0);
+        assert_eq!(tables.subscriptions.get_by_id(2).unwrap().quota, 10);
+        assert!(tables.ledger.list().len() == 1);
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+use crate::subscriber::{format_date, parse_date};
+
+use super::{Entry, EntryKind};
+
+/// The ledger is append-only: entries can be added and read but never changed.
+#[derive(Clone)]
+pub struct LedgerList<D> {
+    db: Arc<Mutex<D>>,
+    pub entries: Vec<Entry>,
+}
+
+pub type FlatLedgerList = LedgerList<FlatTable<String, String>>;
+
+impl FlatLedgerList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        LedgerList {
+            db: Arc::new(db),
+            entries: vec![],
+        }
+    }
+
+    pub fn get_by_id(&self, id: u128) -> Option<Entry> {
+        LedgerList::get_by_attr::<FlatTable<String, String>, Entry>(&self.db, "id", id.to_string())
+    }
+
+    pub fn list(&self) -> Vec<Entry> {
+        LedgerList::get_all::<FlatTable<String, String>, Entry>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Entry> {
+        LedgerList::search_records::<FlatTable<String, String>, Entry>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        LedgerList::count_records(&self.db, filter)
+    }
+
+    pub fn list_by_subscription(&self, subscription_id: u128) -> Vec<Entry> {
+        self.list()
+            .into_iter()
+            .filter(|entry| entry.subscription_id == subscription_id)
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn append(
+        &self,
+        subscription_id: u128,
+        kind: EntryKind,
+        amount: i128,
+        reason: &str,
+        request_id: Option<&str>,
+        operator: Option<&str>,
+        created_at: NaiveDateTime,
+    ) -> Entry {
+        LedgerList::insert_next::<FlatTable<String, String>, Entry>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("subscription".to_string(), subscription_id.to_string()),
+                ("kind".to_string(), kind.as_str().to_string()),
+                ("amount".to_string(), amount.to_string()),
+                // commas would split the flat table row
+                ("reason".to_string(), reason.replace(',', ";")),
+                (
+                    "request".to_string(),
+                    request_id.unwrap_or_default().to_string(),
+                ),
+                (
+                    "operator".to_string(),
+                    operator.unwrap_or_default().replace(',', ";"),
+                ),
+                ("created_at".to_string(), format_date(&created_at)),
+            ])
+        })
+    }
+}
+
+impl ModelAble<String, String> for FlatLedgerList {}
+
+impl From<Record<String, String>> for Entry {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("subscription"),
+            map.get("kind"),
+            map.get("amount"),
+            map.get("reason"),
+            map.get("request"),
+            map.get("created_at"),
+        ) {
+            (
+                Some(id),
+                Some(subscription_id),
+                Some(kind),
+                Some(amount),
+                Some(reason),
+                Some(request_id),
+                Some(created_at),
+            ) => Entry {
+                id: id.parse::<u128>().unwrap(),
+                subscription_id: subscription_id.parse::<u128>().unwrap(),
+                kind: EntryKind::parse(kind).expect("Invalid ledger entry kind"),
+                amount: amount.parse::<i128>().unwrap(),
+                reason: reason.clone(),
+                request_id: match request_id.is_empty() {
+                    true => None,
+                    false => Some(request_id.clone()),
+                },
+                // entries predating operators were all made by the gateway
+                operator: map
+                    .get("operator")
+                    .filter(|operator| !operator.is_empty())
+                    .cloned(),
+                created_at: parse_date(created_at).expect("Invalid ledger date"),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_entry() {
+        let table = "\
+        id, subscription, kind, amount, reason, request, created_at
+        1, 1, credit, 50, opening balance, , 2023-01-01 00:00:00
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let ledger = LedgerList::new(db);
+
+        let entry = ledger.append(
+            1,
+            EntryKind::Debit,
+            -2,
+            "service_a, v1.0.0",
+            Some("UUID-1"),
+            None,
+            parse_date("2023-01-02 00:00:00").unwrap(),
+        );
+
+        assert_eq!(entry.id, 2);
+        assert_eq!(entry.reason, "service_a; v1.0.0");
+        assert_eq!(ledger.get_by_id(1).unwrap().request_id, None);
+        assert_eq!(ledger.list_by_subscription(1).len(), 2)
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::subscriber::{parse_date, Subscription};
+
+pub mod ledger_list;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Debit,
+    Credit,
+    Refund,
+    Renewal,
+    Adjustment,
+}
+
+impl EntryKind {
+    pub fn parse(value: &str) -> Option<EntryKind> {
+        match value {
+            "debit" => Some(EntryKind::Debit),
+            "credit" => Some(EntryKind::Credit),
+            "refund" => Some(EntryKind::Refund),
+            "renewal" => Some(EntryKind::Renewal),
+            "adjustment" => Some(EntryKind::Adjustment),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Debit => "debit",
+            EntryKind::Credit => "credit",
+            EntryKind::Refund => "refund",
+            EntryKind::Renewal => "renewal",
+            EntryKind::Adjustment => "adjustment",
+        }
+    }
+}
+
+/// A single change to a subscription's quota. `amount` is negative for debits.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub id: u128,
+    pub subscription_id: u128,
+    pub kind: EntryKind,
+    pub amount: i128,
+    pub reason: String,
+    pub request_id: Option<String>,
+    /// Who made a manual change, `None` for the gateway's own entries.
+    pub operator: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Entry {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Entry {
+        Entry {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            subscription_id: attr
+                .get("subscription")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            kind: EntryKind::parse(attr.get("kind").unwrap_or(&"credit")).unwrap(),
+            amount: attr.get("amount").unwrap_or(&"10").parse::<i128>().unwrap(),
+            reason: attr.get("reason").unwrap_or(&"default_reason").to_string(),
+            request_id: attr.get("request").map(|id| id.to_string()),
+            operator: attr.get("operator").map(|operator| operator.to_string()),
+            created_at: parse_date(attr.get("created_at").unwrap_or(&"2001-01-01 00:00:00"))
+                .unwrap(),
+        }
+    }
+}
+
+/// Derives the quota of `subscription_id` from its ledger entries.
+pub fn balance(entries: &[Entry], subscription_id: u128) -> i128 {
+    entries
+        .iter()
+        .filter(|entry| entry.subscription_id == subscription_id)
+        .map(|entry| entry.amount)
+        .sum()
+}
+
+/// Returns the tokens still charged to `subscription_id` for `request_id`,
+/// net of what was given back, or `None` if the request was never charged
+/// to it.
+pub fn charged_for(entries: &[Entry], subscription_id: u128, request_id: &str) -> Option<i128> {
+    let entries = entries
+        .iter()
+        .filter(|entry| {
+            entry.subscription_id == subscription_id
+                && entry.request_id.as_deref() == Some(request_id)
+        })
+        .collect::<Vec<&Entry>>();
+    match entries.is_empty() {
+        true => None,
+        false => Some(-entries.iter().map(|entry| entry.amount).sum::<i128>()),
+    }
+}
+
+/// A subscription whose cached quota doesn't match its ledger.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub subscription_id: u128,
+    pub cached: u128,
+    pub derived: i128,
+}
+
+pub fn reconcile(subscriptions: &[Subscription], entries: &[Entry]) -> Vec<Mismatch> {
+    subscriptions
+        .iter()
+        .filter_map(|subscription| {
+            let derived = balance(entries, subscription.id);
+            match derived == subscription.quota as i128 {
+                true => None,
+                false => Some(Mismatch {
+                    subscription_id: subscription.id,
+                    cached: subscription.quota,
+                    derived,
+                }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::fake(&HashMap::from([("subscription", "1"), ("amount", "10")])),
+            Entry::fake(&HashMap::from([
+                ("subscription", "1"),
+                ("kind", "debit"),
+                ("amount", "-4"),
+            ])),
+            Entry::fake(&HashMap::from([("subscription", "2"), ("amount", "7")])),
+        ]
+    }
+
+    #[test]
+    fn derive_balance() {
+        assert_eq!(balance(&entries(), 1), 6);
+        assert_eq!(balance(&entries(), 3), 0);
+    }
+
+    #[test]
+    fn flag_mismatching_quota() {
+        let subscriptions = vec![
+            Subscription::fake(&HashMap::from([("id", "1"), ("quota", "6")])),
+            Subscription::fake(&HashMap::from([("id", "2"), ("quota", "9")])),
+        ];
+
+        assert_eq!(
+            reconcile(&subscriptions, &entries()),
+            vec![Mismatch {
+                subscription_id: 2,
+                cached: 9,
+                derived: 7
+            }]
+        );
+    }
+
+    #[test]
+    fn net_charge_of_request() {
+        let entries = vec![
+            Entry::fake(&HashMap::from([
+                ("kind", "debit"),
+                ("amount", "-5"),
+                ("request", "r-1"),
+            ])),
+            Entry::fake(&HashMap::from([
+                ("kind", "refund"),
+                ("amount", "2"),
+                ("request", "r-1"),
+            ])),
+        ];
+
+        assert_eq!(charged_for(&entries, 1, "r-1"), Some(3));
+        assert_eq!(charged_for(&entries, 2, "r-1"), None);
+        assert_eq!(charged_for(&entries, 1, "r-2"), None);
+    }
+}
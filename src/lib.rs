@@ -1,10 +1,40 @@
+#[macro_use]
+extern crate rocket;
+
+pub mod admin;
+pub mod alert;
+pub mod biller;
+pub mod budget;
+pub mod cache;
+pub mod cli;
+pub mod config;
 pub mod consumer;
 pub mod db;
+pub mod entitlement;
+pub mod errors;
 pub mod guards;
+pub mod idempotency;
+pub mod integrity;
+pub mod invoice;
+pub mod job;
+pub mod ledger;
+pub mod limiter;
+pub mod logger;
+pub mod migration;
+pub mod overage;
+pub mod plan;
+pub mod portal;
+pub mod pricing;
 pub mod product;
+pub mod renewal;
 pub mod request;
+pub mod router;
+pub mod sandbox;
+pub mod scheduler;
 pub mod service;
+pub mod snapshot;
 pub mod subscriber;
+pub mod transform;
 pub use crate::consumer::Consumer;
 
 #[cfg(test)]
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::NaiveDateTime;
+
+/// Counts each consumer's requests in one-minute windows.
+pub struct RateLimiter {
+    /// Requests allowed per window, `0` for no limit.
+    pub per_minute: u32,
+    windows: Mutex<HashMap<u128, (i64, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request of `consumer_id`, returning false if it is over the limit.
+    pub fn allow(&self, consumer_id: u128, now: NaiveDateTime) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        let window = now.and_utc().timestamp() / 60;
+        let mut windows = self.windows.lock().expect("lock rate limits");
+        let (started, count) = windows.entry(consumer_id).or_insert((window, 0));
+        if *started != window {
+            *started = window;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.per_minute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscriber::parse_date;
+
+    #[test]
+    fn limit_requests_per_minute() {
+        let limiter = RateLimiter::new(2);
+        let now = parse_date("2023-01-01 00:00:10").unwrap();
+
+        assert!(limiter.allow(1, now));
+        assert!(limiter.allow(1, now));
+        assert!(!limiter.allow(1, now));
+        assert!(limiter.allow(2, now));
+        assert!(limiter.allow(1, parse_date("2023-01-01 00:01:00").unwrap()));
+    }
+}
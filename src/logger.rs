@@ -0,0 +1,38 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::RwLock;
+
+use chrono::Utc;
+
+use crate::config::LogSink;
+
+/// Where `log` writes, standard output until the config says otherwise.
+static SINKS: RwLock<Vec<LogSink>> = RwLock::new(vec![]);
+
+pub fn set_sinks(sinks: Vec<LogSink>) {
+    *SINKS.write().expect("write log sinks") = sinks;
+}
+
+/// Writes a gateway message to every configured sink.
+pub fn log(message: &str) {
+    let sinks = SINKS.read().expect("read log sinks");
+    if sinks.is_empty() {
+        println!("{message}");
+    }
+    for sink in sinks.iter() {
+        match sink {
+            LogSink::Stdout => println!("{message}"),
+            LogSink::File { path } => {
+                let line = format!("{} {message}\n", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+                let written = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(line.as_bytes()));
+                if let Err(e) = written {
+                    eprintln!("Could not log to {}: {e}", path.display());
+                }
+            }
+        }
+    }
+}
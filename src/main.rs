@@ -1,16 +1,41 @@
 #[macro_use]
 extern crate rocket;
 
-use std::sync::Mutex;
-
+use uws_gateway::admin::{self, admin_list::AdminList};
+use uws_gateway::alert::alert_list::AlertList;
+use uws_gateway::biller::headers::QuotaHeaders;
+use uws_gateway::budget::budget_list::BudgetUsageList;
+use uws_gateway::cache::ResponseCache;
+use uws_gateway::config::GatewayConfig;
 use uws_gateway::consumer::consumer_list::ConsumerList;
+use uws_gateway::errors;
+use uws_gateway::idempotency::idempotency_list::IdempotencyList;
+use uws_gateway::integrity;
+use uws_gateway::job::{self, job_list::JobList, runner::JobRunner};
+use uws_gateway::ledger::ledger_list::LedgerList;
+use uws_gateway::limiter::RateLimiter;
+use uws_gateway::logger;
+use uws_gateway::migration::{self, FlatStore, MIGRATIONS};
+use uws_gateway::overage::overage_list::OverageList;
+use uws_gateway::plan::plan_list::PlanList;
+use uws_gateway::portal;
+use uws_gateway::pricing::pricing_list::PricingRuleList;
+use uws_gateway::product::product_list::ProductList;
+use uws_gateway::renewal::renewal_list::RenewalList;
+use uws_gateway::request::request_list::RequestList;
+use uws_gateway::router::{self, table::RoutingReload};
+use uws_gateway::sandbox::SandboxRequestList;
+use uws_gateway::scheduler::Scheduler;
+use uws_gateway::service::service_list::ServiceList;
+use uws_gateway::subscriber::subscriber_list::{SubscriberList, SubscriptionList};
+use uws_gateway::transform::transform_list::TransformRuleList;
 
-use uws_gateway::db::file_db::FlatTable;
-use uws_gateway::guards::{ApiKey, HostHeader};
+use uws_gateway::db::file_db::get_table_instance;
+use uws_gateway::guards::{Billable, HostHeader};
 
 #[get("/")]
-fn index(key: ApiKey, _host: HostHeader) -> String {
-    println!("New request from {:?}", key);
+fn index(billable: Billable, _host: HostHeader) -> String {
+    println!("New request from {:?}", billable.0.id);
     "Hello, world!".to_string()
 }
 
@@ -24,24 +49,105 @@ async fn delay(seconds: u64) -> String {
 
 #[launch]
 fn rocket() -> _ {
-    let db = Mutex::new(FlatTable::new("consumers".to_string()));
+    let config = GatewayConfig::load().unwrap_or_else(|e| panic!("Invalid gateway config: {e}"));
+    config.apply();
+    match migration::migrate(&mut FlatStore::open(), MIGRATIONS, false) {
+        Ok(applied) => {
+            for migration in applied {
+                logger::log(&format!(
+                    "Applied migration {}: {}",
+                    migration.version, migration.name
+                ));
+            }
+        }
+        Err(e) => panic!("Migrating the tables failed: {e}"),
+    }
+    let problems = integrity::check_storage();
+    if !problems.is_empty() {
+        let report = problems
+            .iter()
+            .map(|problem| format!("  {problem}"))
+            .collect::<Vec<String>>()
+            .join("\n");
+        panic!("Inconsistent tables, fix them before starting:\n{report}");
+    }
 
     println!("Running server..");
 
-    rocket::build()
+    rocket::custom(config.rocket_figment())
         .mount("/", routes![index, delay])
-        .manage(ConsumerList::new(db))
+        .mount("/admin", admin::routes::routes())
+        .mount("/portal", portal::routes::routes())
+        .mount("/jobs", job::routes::routes())
+        .mount("/", router::routes::routes())
+        .register("/", errors::catchers())
+        .manage(ConsumerList::new(get_table_instance("consumers")))
+        .manage(AdminList::new(get_table_instance("admins")))
+        .manage(SubscriberList::new(get_table_instance("subscribers")))
+        .manage(SubscriptionList::new(get_table_instance("subscriptions")))
+        .manage(PlanList::new(get_table_instance("plans")))
+        .manage(ProductList::new(get_table_instance("products")))
+        .manage(ServiceList::new(get_table_instance("services")))
+        .manage(RequestList::new(get_table_instance("requests")))
+        .manage(SandboxRequestList::new(get_table_instance(
+            "sandbox_requests",
+        )))
+        .manage(RenewalList::new(get_table_instance("renewals")))
+        .manage(LedgerList::new(get_table_instance("ledger")))
+        .manage(OverageList::new(get_table_instance("overages")))
+        .manage(BudgetUsageList::new(get_table_instance("budget_usage")))
+        .manage(IdempotencyList::new(get_table_instance("idempotency_keys")))
+        .manage(JobList::new(get_table_instance("jobs")))
+        .manage(PricingRuleList::new(get_table_instance("pricing_rules")))
+        .manage(TransformRuleList::new(get_table_instance(
+            "transform_rules",
+        )))
+        .manage(AlertList::new(get_table_instance("alerts")))
+        .manage(router::client(config.upstream.timeout()))
+        .manage(RateLimiter::new(config.limits.requests_per_minute))
+        .manage(ResponseCache::new(&config.cache))
+        .attach(QuotaHeaders)
+        .attach(RoutingReload {
+            interval: Duration::from_secs(1),
+        })
+        .attach(JobRunner {
+            interval: Duration::from_secs(1),
+        })
+        .attach(Scheduler {
+            interval: Duration::from_secs(60),
+            alerts: config.alerts.settings(),
+        })
+        .manage(config)
 }
 
 #[cfg(test)]
 mod test {
+    use std::fs;
+    use std::sync::Once;
+
     use super::rocket;
-    use rocket::http::{Header, Status};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::blocking::Client;
 
+    /// A gateway on a copy of the fixture tables, which have an admin,
+    /// portal keys and subscriptions that haven't expired.
+    fn client() -> Client {
+        static STORAGE: Once = Once::new();
+        STORAGE.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("uws-main-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            for entry in fs::read_dir("tests/fixtures/db").unwrap() {
+                let path = entry.unwrap().path();
+                fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
+            }
+            std::env::set_var("UWS_STORAGE__DIR", &dir);
+        });
+        Client::tracked(rocket()).expect("valid rocket instance")
+    }
+
     #[test]
     fn hello_world() {
-        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let client = client();
         let response = client
             .get(uri!(super::index))
             .header(Header {
@@ -53,9 +159,26 @@ mod test {
         assert_eq!(response.into_string().unwrap(), "Hello, world!");
     }
 
+    #[test]
+    fn quota_headers() {
+        let client = client();
+        let response = client
+            .get(uri!(super::index))
+            .header(Header::new("x-api-key", "user-1"))
+            .dispatch();
+        let headers = response.headers();
+        assert_eq!(headers.get_one("X-Quota-Limit"), Some("50"));
+        assert_eq!(headers.get_one("X-Quota-Remaining"), Some("10"));
+        assert_eq!(headers.get_one("X-Quota-Overage"), Some("0"));
+        assert_eq!(
+            headers.get_one("X-Quota-Warning"),
+            Some("quota almost exhausted")
+        );
+    }
+
     #[test]
     fn wrong_key_check() {
-        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let client = client();
         let response = client
             .get(uri!(super::index))
             .header(Header {
@@ -68,8 +191,203 @@ mod test {
 
     #[test]
     fn auth_check() {
-        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let client = client();
         let response = client.get(uri!(super::index)).dispatch();
         assert_eq!(response.status(), Status::Unauthorized);
     }
+
+    #[test]
+    fn portal_subscription() {
+        let client = client();
+        let response = client
+            .get("/portal/subscription")
+            .header(Header::new("x-subscriber-key", "sub-2"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("\"plan\":\"Golden 50\""));
+    }
+
+    #[test]
+    fn portal_usage_is_scoped_to_subscriber() {
+        let client = client();
+        let response = client
+            .get("/portal/usage")
+            .header(Header::new("x-subscriber-key", "sub-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_string().unwrap(),
+            "[{\"consumer\":1,\"requests\":1,\"tokens\":0}]"
+        );
+    }
+
+    #[test]
+    fn portal_cannot_touch_other_subscribers_consumers() {
+        let client = client();
+        let response = client
+            .delete("/portal/consumers/2/key")
+            .header(Header::new("x-subscriber-key", "sub-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn portal_rejects_invalid_webhook() {
+        let client = client();
+        let response = client
+            .put("/portal/webhook")
+            .header(Header::new("x-subscriber-key", "sub-1"))
+            .header(ContentType::JSON)
+            .body(r#"{"url": "ftp://example.com/hook"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn empty_api_key_is_rejected() {
+        let client = client();
+        let response = client
+            .get(uri!(super::index))
+            .header(Header::new("x-api-key", ""))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn admin_list_consumers() {
+        let client = client();
+        let response = client
+            .get("/admin/consumers?page=1&per_page=1")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("\"per_page\":1"));
+        assert!(body.contains("\"total\":2"));
+    }
+
+    #[test]
+    fn admin_list_routes() {
+        let client = client();
+        let response = client
+            .get("/admin/routes")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("\"slug\":\"service_b\""));
+        assert!(body.contains("\"slug\":\"product_b\""));
+    }
+
+    #[test]
+    fn admin_check_integrity() {
+        let client = client();
+        let response = client
+            .get("/admin/integrity")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), "[]");
+    }
+
+    #[test]
+    fn admin_filter_requests() {
+        let client = client();
+        let response = client
+            .get("/admin/requests?consumer=2&per_page=5")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("\"id\":\"UUID-2\""));
+        assert!(!body.contains("\"id\":\"UUID-1\""));
+        assert!(body.contains("\"total\":1"));
+    }
+
+    #[test]
+    fn admin_reconcile_ledger() {
+        let client = client();
+        let response = client
+            .get("/admin/ledger/reconcile")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), "[]");
+    }
+
+    #[test]
+    fn admin_invoices_as_csv() {
+        let client = client();
+        let response = client
+            .get("/admin/invoices?period=2022-10&subscriber=1&format=csv")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::CSV));
+        assert!(response.into_string().unwrap().contains(",plan_fee,"));
+    }
+
+    #[test]
+    fn admin_auth_check() {
+        let client = client();
+        let response = client
+            .get("/admin/consumers")
+            .header(Header::new("x-api-key", "user-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.into_string().unwrap(),
+            "{\"error\":\"Unauthorized\"}"
+        );
+    }
+
+    #[test]
+    fn admin_rejects_dangling_reference() {
+        let client = client();
+        let response = client
+            .post("/admin/consumers")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .header(ContentType::JSON)
+            .body(r#"{"access_token": "new-key", "subscriber": 999}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn admin_rejects_deleting_referenced_subscriber() {
+        let client = client();
+        let response = client
+            .delete("/admin/subscribers/1")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn admin_rejects_malformed_entitlements() {
+        let client = client();
+        let response = client
+            .put("/admin/plans/1")
+            .header(Header::new("x-admin-key", "admin-1"))
+            .header(ContentType::JSON)
+            .body(r#"{"entitlements": ["product_a//v1.0.0"]}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn portal_shows_consumer_budget() {
+        let client = client();
+        let response = client
+            .get("/portal/consumers/1/budget")
+            .header(Header::new("x-subscriber-key", "sub-1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("\"budget\":null"));
+        assert!(body.contains("\"used\":0"));
+    }
 }
@@ -0,0 +1,873 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+
+use chrono::Utc;
+
+use crate::db::file_db::{get_column_names, read_from_string, table_path, to_table_string};
+use crate::db::Record;
+use crate::subscriber::{format_date, SubscriptionStatus};
+
+/// The table recording the applied migrations.
+pub const VERSIONS_TABLE: &str = "schema_migrations";
+
+/// One change to the shape or content of a table.
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    /// Creates `table` with `columns` and no rows, unless it exists.
+    CreateTable {
+        table: &'static str,
+        columns: &'static [&'static str],
+    },
+    /// Adds `column`, backfilling rows without a value with `default`.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        default: &'static str,
+    },
+    RenameColumn {
+        table: &'static str,
+        from: &'static str,
+        to: &'static str,
+    },
+    /// Sets `column` of every row to what `value` computes from the row.
+    Transform {
+        table: &'static str,
+        column: &'static str,
+        value: fn(&Record<String, String>) -> String,
+    },
+    /// Inserts the row `row` builds from each row of `from` into `table`,
+    /// unless `table` already has rows.
+    Seed {
+        table: &'static str,
+        from: &'static str,
+        row: fn(&Record<String, String>) -> Record<String, String>,
+    },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::CreateTable { table, columns } => {
+                write!(f, "create {table} ({})", columns.join(", "))
+            }
+            Step::AddColumn {
+                table,
+                column,
+                default,
+            } => write!(f, "add `{column}` to {table}, defaulting to `{default}`"),
+            Step::RenameColumn { table, from, to } => {
+                write!(f, "rename `{from}` of {table} to `{to}`")
+            }
+            Step::Transform { table, column, .. } => write!(f, "rewrite `{column}` of {table}"),
+            Step::Seed { table, from, .. } => write!(f, "fill {table} from {from}"),
+        }
+    }
+}
+
+/// Steps applied together, bringing the storage to `version`.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub steps: &'static [Step],
+}
+
+/// The migrations of the gateway tables, in the order they are applied.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create plans admins and ledger",
+        steps: &[
+            Step::CreateTable {
+                table: "plans",
+                columns: &["id", "name", "price", "quota", "period"],
+            },
+            Step::Seed {
+                table: "plans",
+                from: "subscriptions",
+                row: plan_of_subscription,
+            },
+            Step::CreateTable {
+                table: "admins",
+                columns: &["id", "name", "access_token"],
+            },
+            Step::CreateTable {
+                table: "ledger",
+                columns: &[
+                    "id",
+                    "subscription",
+                    "kind",
+                    "amount",
+                    "reason",
+                    "request",
+                    "created_at",
+                ],
+            },
+            Step::Seed {
+                table: "ledger",
+                from: "subscriptions",
+                row: opening_balance,
+            },
+            Step::AddColumn {
+                table: "subscriptions",
+                column: "auto_renew",
+                default: "false",
+            },
+            Step::AddColumn {
+                table: "subscriptions",
+                column: "plan",
+                default: "",
+            },
+            Step::Transform {
+                table: "subscriptions",
+                column: "status",
+                value: served_status,
+            },
+            Step::Transform {
+                table: "subscriptions",
+                column: "plan",
+                value: own_plan,
+            },
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "add overage policies to plans",
+        steps: &[
+            Step::AddColumn {
+                table: "plans",
+                column: "overage_policy",
+                default: "hard",
+            },
+            Step::AddColumn {
+                table: "plans",
+                column: "overage_price",
+                default: "0",
+            },
+            Step::AddColumn {
+                table: "plans",
+                column: "overage_cap",
+                default: "0",
+            },
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "add portal keys and webhooks to subscribers",
+        steps: &[
+            Step::AddColumn {
+                table: "subscribers",
+                column: "access_token",
+                default: "",
+            },
+            Step::AddColumn {
+                table: "subscribers",
+                column: "webhook_url",
+                default: "",
+            },
+            Step::AddColumn {
+                table: "subscribers",
+                column: "webhook_secret",
+                default: "",
+            },
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "create renewal overage pricing rule and alert tables",
+        steps: &[
+            Step::CreateTable {
+                table: "renewals",
+                columns: &[
+                    "id",
+                    "subscription",
+                    "plan",
+                    "quota",
+                    "renewed_at",
+                    "expiry_date",
+                ],
+            },
+            Step::CreateTable {
+                table: "overages",
+                columns: &[
+                    "id",
+                    "subscription",
+                    "request",
+                    "tokens",
+                    "price",
+                    "created_at",
+                ],
+            },
+            Step::CreateTable {
+                table: "pricing_rules",
+                columns: &["id", "service", "kind", "param", "price"],
+            },
+            Step::CreateTable {
+                table: "alerts",
+                columns: &[
+                    "id",
+                    "subscriber",
+                    "subscription",
+                    "kind",
+                    "period",
+                    "status",
+                    "attempts",
+                    "next_attempt_at",
+                    "created_at",
+                ],
+            },
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "add plan entitlements and consumer key scopes",
+        steps: &[
+            Step::AddColumn {
+                table: "plans",
+                column: "entitlements",
+                default: "*",
+            },
+            Step::AddColumn {
+                table: "consumers",
+                column: "scopes",
+                default: "*",
+            },
+        ],
+    },
+    Migration {
+        version: 6,
+        name: "add consumer budgets",
+        steps: &[
+            Step::AddColumn {
+                table: "consumers",
+                column: "budget",
+                default: "",
+            },
+            Step::CreateTable {
+                table: "budget_usage",
+                columns: &["id", "consumer", "period", "tokens"],
+            },
+        ],
+    },
+    Migration {
+        version: 7,
+        name: "create idempotency key table",
+        steps: &[Step::CreateTable {
+            table: "idempotency_keys",
+            columns: &[
+                "id",
+                "consumer",
+                "key",
+                "fingerprint",
+                "status",
+                "headers",
+                "body",
+                "created_at",
+            ],
+        }],
+    },
+    Migration {
+        version: 8,
+        name: "add operators to ledger entries",
+        steps: &[Step::AddColumn {
+            table: "ledger",
+            column: "operator",
+            default: "",
+        }],
+    },
+    Migration {
+        version: 9,
+        name: "add sandbox keys and services",
+        steps: &[
+            Step::AddColumn {
+                table: "consumers",
+                column: "sandbox",
+                default: "false",
+            },
+            Step::AddColumn {
+                table: "services",
+                column: "sandbox_url",
+                default: "",
+            },
+            Step::CreateTable {
+                table: "sandbox_requests",
+                columns: &[
+                    "id",
+                    "product_slug",
+                    "service_slug",
+                    "service_version",
+                    "url",
+                    "status",
+                    "price",
+                    "consumer",
+                    "service",
+                ],
+            },
+        ],
+    },
+    Migration {
+        version: 10,
+        name: "create transform rule table",
+        steps: &[Step::CreateTable {
+            table: "transform_rules",
+            columns: &["id", "service", "kind", "param", "value"],
+        }],
+    },
+    Migration {
+        version: 11,
+        name: "add WebSocket billing to services",
+        steps: &[Step::AddColumn {
+            table: "services",
+            column: "websocket_billing",
+            default: "connection",
+        }],
+    },
+    Migration {
+        version: 12,
+        name: "add response caching to services",
+        steps: &[
+            Step::AddColumn {
+                table: "services",
+                column: "cache_ttl",
+                default: "",
+            },
+            Step::AddColumn {
+                table: "services",
+                column: "cache_headers",
+                default: "",
+            },
+        ],
+    },
+    Migration {
+        version: 13,
+        name: "create job table",
+        steps: &[Step::CreateTable {
+            table: "jobs",
+            columns: &[
+                "id",
+                "consumer",
+                "service",
+                "request",
+                "method",
+                "url",
+                "headers",
+                "body",
+                "callback_url",
+                "status",
+                "response_status",
+                "response_headers",
+                "response_body",
+                "price",
+                "reserved",
+                "reserved_overage",
+                "created_at",
+                "completed_at",
+            ],
+        }],
+    },
+];
+
+/// A monthly plan with the name, price and quota of a subscription made
+/// before plans existed, sharing its id.
+fn plan_of_subscription(subscription: &Record<String, String>) -> Record<String, String> {
+    let mut plan = Record::new();
+    for column in ["id", "name", "price", "quota"] {
+        let value = subscription.get(column).cloned().unwrap_or_default();
+        plan.insert(column.to_string(), value);
+    }
+    plan.insert("period".to_string(), "monthly".to_string());
+    plan
+}
+
+/// The credit that a subscription's quota so far opens its ledger with.
+fn opening_balance(subscription: &Record<String, String>) -> Record<String, String> {
+    let id = subscription.get("id").cloned().unwrap_or_default();
+    let quota = subscription.get("quota").cloned().unwrap_or_default();
+    Record::from([
+        ("id".to_string(), id.clone()),
+        ("subscription".to_string(), id),
+        ("kind".to_string(), "credit".to_string()),
+        ("amount".to_string(), quota),
+        ("reason".to_string(), "opening balance".to_string()),
+        ("request".to_string(), "".to_string()),
+        (
+            "created_at".to_string(),
+            format_date(&Utc::now().naive_utc()),
+        ),
+    ])
+}
+
+/// The status of a subscription once statuses are enforced. Before plans
+/// existed the status was stored but never read, so every subscription was
+/// served whatever it said, `2` included. Those subscriptions stay served as
+/// active ones, while subscriptions that have a plan keep their status.
+fn served_status(subscription: &Record<String, String>) -> String {
+    match subscription.get("plan").map(String::as_str) {
+        Some(plan) if !plan.is_empty() => subscription.get("status").cloned().unwrap_or_default(),
+        _ => (SubscriptionStatus::Active as u8).to_string(),
+    }
+}
+
+/// Links a subscription without a plan to the one seeded from it.
+fn own_plan(subscription: &Record<String, String>) -> String {
+    match subscription.get("plan").map(String::as_str) {
+        Some(plan) if !plan.is_empty() => plan.to_string(),
+        _ => subscription.get("id").cloned().unwrap_or_default(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MigrationError(pub String);
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub type MigrationResult<T> = Result<T, MigrationError>;
+
+/// Storage that migrations can be applied to. Applied steps only need to be
+/// durable once `commit` is called, so a dry run simply never commits.
+pub trait MigrationStore {
+    /// The version of the last applied migration, `0` if none was.
+    fn version(&mut self) -> MigrationResult<u32>;
+
+    fn apply(&mut self, step: &Step) -> MigrationResult<()>;
+
+    /// Records `migration` as applied.
+    fn set_version(&mut self, migration: &Migration) -> MigrationResult<()>;
+
+    fn commit(&mut self) -> MigrationResult<()>;
+}
+
+/// Applies the `migrations` newer than the store's version, all or none.
+/// With `dry_run`, they are checked against the store without being saved.
+pub fn migrate<'a, S: MigrationStore>(
+    store: &mut S,
+    migrations: &'a [Migration],
+    dry_run: bool,
+) -> MigrationResult<Vec<&'a Migration>> {
+    if migrations
+        .windows(2)
+        .any(|pair| pair[0].version >= pair[1].version)
+    {
+        return Err(MigrationError(
+            "Migrations should be ordered by increasing version".to_string(),
+        ));
+    }
+    if let Some(migration) = migrations
+        .iter()
+        .find(|migration| migration.name.contains(','))
+    {
+        return Err(MigrationError(format!(
+            "Migration {} should be named without commas",
+            migration.version
+        )));
+    }
+
+    let version = store.version()?;
+    let pending = migrations
+        .iter()
+        .filter(|migration| migration.version > version)
+        .collect::<Vec<&Migration>>();
+    for migration in &pending {
+        for step in migration.steps {
+            store.apply(step).map_err(|e| {
+                MigrationError(format!(
+                    "Migration {} ({}) failed to {step}: {e}",
+                    migration.version, migration.name
+                ))
+            })?;
+        }
+        store.set_version(migration)?;
+    }
+    if !dry_run && !pending.is_empty() {
+        store.commit()?;
+    }
+    Ok(pending)
+}
+
+/// A table's columns and rows as held during a migration.
+#[derive(Debug, Clone)]
+struct FlatContent {
+    columns: Vec<String>,
+    items: Vec<Record<String, String>>,
+}
+
+impl FlatContent {
+    fn parse(content: &str) -> MigrationResult<FlatContent> {
+        if content.trim().is_empty() {
+            return Err(MigrationError("the table has no header".to_string()));
+        }
+        Ok(FlatContent {
+            columns: get_column_names(content),
+            items: read_from_string(content),
+        })
+    }
+
+    fn has(&self, column: &str) -> bool {
+        self.columns.iter().any(|name| name == column)
+    }
+}
+
+/// Migrates the `<table>_table.txt` files, keeping the changed tables in
+/// memory until they are committed.
+#[derive(Debug, Default)]
+pub struct FlatStore {
+    tables: HashMap<String, Option<FlatContent>>,
+    changed: BTreeSet<String>,
+    /// Whether the tables come from and go to the storage directory.
+    files: bool,
+}
+
+impl FlatStore {
+    /// Works on the tables of the configured storage directory.
+    pub fn open() -> FlatStore {
+        FlatStore {
+            files: true,
+            ..FlatStore::default()
+        }
+    }
+
+    /// Works on the `contents` of tables by name, as with
+    /// `FlatTable::new_from_string`.
+    pub fn from_contents(contents: &[(&str, &str)]) -> MigrationResult<FlatStore> {
+        let mut store = FlatStore::default();
+        for (table, content) in contents {
+            store
+                .tables
+                .insert(table.to_string(), Some(FlatContent::parse(content)?));
+        }
+        Ok(store)
+    }
+
+    /// Returns a table as it would be written.
+    pub fn contents(&mut self, table: &str) -> MigrationResult<Option<String>> {
+        Ok(self
+            .load(table)?
+            .as_ref()
+            .map(|content| to_table_string(&content.columns, &content.items)))
+    }
+
+    fn load(&mut self, table: &str) -> MigrationResult<&mut Option<FlatContent>> {
+        if !self.tables.contains_key(table) {
+            let path = table_path(table);
+            let content = match self.files && path.exists() {
+                true => {
+                    let raw = fs::read_to_string(&path).map_err(|e| {
+                        MigrationError(format!("{} can't be read: {e}", path.display()))
+                    })?;
+                    Some(FlatContent::parse(&raw)?)
+                }
+                false => None,
+            };
+            self.tables.insert(table.to_string(), content);
+        }
+        Ok(self.tables.get_mut(table).expect("loaded table"))
+    }
+
+    fn existing(&mut self, table: &str) -> MigrationResult<&mut FlatContent> {
+        self.changed.insert(table.to_string());
+        self.load(table)?
+            .as_mut()
+            .ok_or_else(|| MigrationError(format!("{table} does not exist")))
+    }
+}
+
+impl MigrationStore for FlatStore {
+    fn version(&mut self) -> MigrationResult<u32> {
+        let content = match self.load(VERSIONS_TABLE)? {
+            Some(content) => content,
+            None => return Ok(0),
+        };
+        let mut version = 0;
+        for record in &content.items {
+            let applied = record
+                .get("version")
+                .and_then(|version| version.parse::<u32>().ok())
+                .ok_or_else(|| {
+                    MigrationError(format!("{VERSIONS_TABLE} has an invalid version"))
+                })?;
+            version = version.max(applied);
+        }
+        Ok(version)
+    }
+
+    fn apply(&mut self, step: &Step) -> MigrationResult<()> {
+        match *step {
+            Step::CreateTable { table, columns } => {
+                let content = self.load(table)?;
+                if content.is_none() {
+                    *content = Some(FlatContent {
+                        columns: columns.iter().map(|column| column.to_string()).collect(),
+                        items: vec![],
+                    });
+                    self.changed.insert(table.to_string());
+                }
+            }
+            Step::AddColumn {
+                table,
+                column,
+                default,
+            } => {
+                let content = self.existing(table)?;
+                if !content.has(column) {
+                    content.columns.push(column.to_string());
+                }
+                for record in content.items.iter_mut() {
+                    record
+                        .entry(column.to_string())
+                        .or_insert_with(|| default.to_string());
+                }
+            }
+            Step::RenameColumn { table, from, to } => {
+                let content = self.existing(table)?;
+                if content.has(to) {
+                    return Err(MigrationError(format!("`{to}` already exists")));
+                }
+                let index = content
+                    .columns
+                    .iter()
+                    .position(|name| name == from)
+                    .ok_or_else(|| MigrationError(format!("`{from}` does not exist")))?;
+                content.columns[index] = to.to_string();
+                for record in content.items.iter_mut() {
+                    if let Some(value) = record.remove(from) {
+                        record.insert(to.to_string(), value);
+                    }
+                }
+            }
+            Step::Transform {
+                table,
+                column,
+                value,
+            } => {
+                let content = self.existing(table)?;
+                if !content.has(column) {
+                    return Err(MigrationError(format!("`{column}` does not exist")));
+                }
+                for record in content.items.iter_mut() {
+                    let value = value(record);
+                    record.insert(column.to_string(), value);
+                }
+            }
+            Step::Seed { table, from, row } => {
+                let rows = self
+                    .load(from)?
+                    .as_ref()
+                    .ok_or_else(|| MigrationError(format!("{from} does not exist")))?
+                    .items
+                    .iter()
+                    .map(row)
+                    .collect::<Vec<Record<String, String>>>();
+                let content = self.existing(table)?;
+                if content.items.is_empty() {
+                    for mut record in rows {
+                        for column in &content.columns {
+                            record.entry(column.to_string()).or_default();
+                        }
+                        content.items.push(record);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_version(&mut self, migration: &Migration) -> MigrationResult<()> {
+        let content = self
+            .load(VERSIONS_TABLE)?
+            .get_or_insert_with(|| FlatContent {
+                columns: vec![
+                    "version".to_string(),
+                    "name".to_string(),
+                    "applied_at".to_string(),
+                ],
+                items: vec![],
+            });
+        content.items.push(Record::from([
+            ("version".to_string(), migration.version.to_string()),
+            ("name".to_string(), migration.name.to_string()),
+            (
+                "applied_at".to_string(),
+                format_date(&Utc::now().naive_utc()),
+            ),
+        ]));
+        self.changed.insert(VERSIONS_TABLE.to_string());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> MigrationResult<()> {
+        if !self.files {
+            return Ok(());
+        }
+        for table in &self.changed {
+            if let Some(Some(content)) = self.tables.get(table) {
+                let path = table_path(table);
+                fs::write(&path, to_table_string(&content.columns, &content.items)).map_err(
+                    |e| MigrationError(format!("{} can't be written: {e}", path.display())),
+                )?;
+            }
+        }
+        self.changed.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLANS: &str = "\
+    id, name, price, quota, period
+    1, Startup 500, 10000, 500, monthly
+    ";
+
+    fn double_quota(record: &Record<String, String>) -> String {
+        let quota = record["quota"].parse::<u128>().unwrap();
+        (quota * 2).to_string()
+    }
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "add overage policies",
+            steps: &[Step::AddColumn {
+                table: "plans",
+                column: "overage_policy",
+                default: "hard",
+            }],
+        },
+        Migration {
+            version: 2,
+            name: "rename quota",
+            steps: &[
+                Step::Transform {
+                    table: "plans",
+                    column: "quota",
+                    value: double_quota,
+                },
+                Step::RenameColumn {
+                    table: "plans",
+                    from: "quota",
+                    to: "tokens",
+                },
+            ],
+        },
+    ];
+
+    #[test]
+    fn apply_pending_migrations_in_order() {
+        let mut store = FlatStore::from_contents(&[("plans", PLANS)]).unwrap();
+
+        let applied = migrate(&mut store, MIGRATIONS, false).unwrap();
+
+        assert_eq!(applied.len(), 2);
+        assert_eq!(store.version(), Ok(2));
+        assert_eq!(
+            store.contents("plans").unwrap().unwrap(),
+            "id, name, price, tokens, period, overage_policy\n\
+             1, Startup 500, 10000, 1000, monthly, hard\n"
+        );
+        assert!(migrate(&mut store, MIGRATIONS, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn skip_applied_migrations() {
+        let versions = "version, name, applied_at\n1, add overage policies, 2024-01-01 00:00:00\n";
+        let mut store =
+            FlatStore::from_contents(&[("plans", PLANS), (VERSIONS_TABLE, versions)]).unwrap();
+
+        let applied = migrate(&mut store, MIGRATIONS, true).unwrap();
+
+        assert_eq!(applied[0].version, 2);
+        assert!(!store
+            .contents("plans")
+            .unwrap()
+            .unwrap()
+            .contains("overage"));
+    }
+
+    #[test]
+    fn report_failing_step() {
+        let mut store = FlatStore::from_contents(&[]).unwrap();
+
+        assert_eq!(
+            migrate(&mut store, MIGRATIONS, true)
+                .unwrap_err()
+                .to_string(),
+            "Migration 1 (add overage policies) failed to add `overage_policy` to plans, \
+             defaulting to `hard`: plans does not exist"
+        );
+    }
+
+    #[test]
+    fn upgrade_tables_from_before_plans() {
+        let subscriptions = "\
+        id, name, status, price, quota, expiry_date
+        1, Startup 500, 2, 10000, 50, 2022-10-01 00:00:00
+        ";
+        let mut store = FlatStore::from_contents(&[
+            ("subscriptions", subscriptions),
+            (
+                "subscribers",
+                "id, name, subscription\n1, Subscriber A, 1\n",
+            ),
+            ("consumers", "id, subscriber, access_token\n1, 1, A-1\n"),
+            ("services", "id, name, slug, base_url, price, product\n"),
+        ])
+        .unwrap();
+
+        migrate(&mut store, super::MIGRATIONS, true).unwrap();
+
+        let plans = store.contents("plans").unwrap().unwrap();
+        assert!(plans.contains("\n1, Startup 500, 10000, 50, monthly, hard, 0, 0, *\n"));
+        let ledger = store.contents("ledger").unwrap().unwrap();
+        assert!(ledger.contains("\n1, 1, credit, 50, opening balance, , "));
+        assert!(store
+            .contents("subscriptions")
+            .unwrap()
+            .unwrap()
+            .ends_with("\n1, Startup 500, 1, 10000, 50, 2022-10-01 00:00:00, false, 1\n"));
+        assert_eq!(
+            store.contents("admins").unwrap().unwrap(),
+            "id, name, access_token\n"
+        );
+    }
+
+    #[test]
+    fn keep_rows_of_existing_tables() {
+        let subscriptions = "\
+        id, name, status, price, quota, expiry_date, auto_renew, plan
+        1, Startup 500, 2, 10000, 50, 2030-10-01 00:00:00, true, 2
+        ";
+        let mut store = FlatStore::from_contents(&[
+            ("subscriptions", subscriptions),
+            (
+                "plans",
+                "id, name, price, quota, period\n2, Golden 50, 50000, 50, annual\n",
+            ),
+            (
+                "ledger",
+                "id, subscription, kind, amount, reason, request, created_at\n\
+                 7, 1, credit, 50, opening balance, , 2022-10-01 00:00:00\n",
+            ),
+        ])
+        .unwrap();
+
+        migrate(&mut store, &super::MIGRATIONS[..1], false).unwrap();
+
+        assert!(store
+            .contents("subscriptions")
+            .unwrap()
+            .unwrap()
+            .ends_with("\n1, Startup 500, 2, 10000, 50, 2030-10-01 00:00:00, true, 2\n"));
+        assert_eq!(store.contents("plans").unwrap().unwrap().lines().count(), 2);
+        assert_eq!(
+            store.contents("ledger").unwrap().unwrap().lines().count(),
+            2
+        );
+    }
+}
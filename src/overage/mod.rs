@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::subscriber::parse_date;
+
+pub mod overage_list;
+
+/// Tokens spent beyond the quota, billed separately at `price` per token.
+#[derive(Debug, Clone, Serialize)]
+pub struct Overage {
+    pub id: u128,
+    pub subscription_id: u128,
+    pub request_id: Option<String>,
+    pub tokens: u128,
+    pub price: u128,
+    pub created_at: NaiveDateTime,
+}
+
+impl Overage {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Overage {
+        Overage {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            subscription_id: attr
+                .get("subscription")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            request_id: attr.get("request").map(|id| id.to_string()),
+            tokens: attr.get("tokens").unwrap_or(&"1").parse::<u128>().unwrap(),
+            price: attr.get("price").unwrap_or(&"1").parse::<u128>().unwrap(),
+            created_at: parse_date(attr.get("created_at").unwrap_or(&"2001-01-01 00:00:00"))
+                .unwrap(),
+        }
+    }
+
+    pub fn cost(&self) -> u128 {
+        self.tokens * self.price
+    }
+}
+
+/// Sums the overage tokens of `subscription_id` recorded since `start`.
+pub fn used_since(overages: &[Overage], subscription_id: u128, start: NaiveDateTime) -> u128 {
+    overages
+        .iter()
+        .filter(|overage| overage.subscription_id == subscription_id && overage.created_at >= start)
+        .map(|overage| overage.tokens)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sum_overage_of_current_period() {
+        let overages = vec![
+            Overage::fake(&HashMap::from([
+                ("tokens", "3"),
+                ("created_at", "2023-01-20 00:00:00"),
+            ])),
+            Overage::fake(&HashMap::from([
+                ("tokens", "4"),
+                ("created_at", "2023-02-02 00:00:00"),
+            ])),
+            Overage::fake(&HashMap::from([
+                ("subscription", "2"),
+                ("tokens", "5"),
+                ("created_at", "2023-02-03 00:00:00"),
+            ])),
+        ];
+
+        assert_eq!(
+            used_since(&overages, 1, parse_date("2023-02-01 00:00:00").unwrap()),
+            4
+        );
+    }
+}
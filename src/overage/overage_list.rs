@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+use crate::subscriber::{format_date, parse_date};
+
+use super::Overage;
+
+#[derive(Clone)]
+pub struct OverageList<D> {
+    db: Arc<Mutex<D>>,
+    pub overages: Vec<Overage>,
+}
+
+pub type FlatOverageList = OverageList<FlatTable<String, String>>;
+
+impl FlatOverageList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        OverageList {
+            db: Arc::new(db),
+            overages: vec![],
+        }
+    }
+
+    pub fn list(&self) -> Vec<Overage> {
+        OverageList::get_all::<FlatTable<String, String>, Overage>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Overage> {
+        OverageList::search_records::<FlatTable<String, String>, Overage>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        OverageList::count_records(&self.db, filter)
+    }
+
+    pub fn create(
+        &self,
+        subscription_id: u128,
+        request_id: Option<&str>,
+        tokens: u128,
+        price: u128,
+        created_at: NaiveDateTime,
+    ) -> Overage {
+        OverageList::insert_next::<FlatTable<String, String>, Overage>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("subscription".to_string(), subscription_id.to_string()),
+                (
+                    "request".to_string(),
+                    request_id.unwrap_or_default().to_string(),
+                ),
+                ("tokens".to_string(), tokens.to_string()),
+                ("price".to_string(), price.to_string()),
+                ("created_at".to_string(), format_date(&created_at)),
+            ])
+        })
+    }
+
+    /// The overage recorded for `request_id`, if it went over the quota.
+    pub fn get_by_request(&self, request_id: &str) -> Option<Overage> {
+        OverageList::get_by_attr::<FlatTable<String, String>, Overage>(
+            &self.db,
+            "request",
+            request_id.to_string(),
+        )
+    }
+
+    /// Takes back up to `tokens` from the overage recorded for `request_id`,
+    /// returning how many were taken back.
+    pub fn release(&self, request_id: &str, tokens: u128) -> u128 {
+        let overage = match self.get_by_request(request_id) {
+            Some(overage) => overage,
+            None => return 0,
+        };
+        let released = tokens.min(overage.tokens);
+        OverageList::update_by_attr::<FlatTable<String, String>, Overage>(
+            &self.db,
+            "id",
+            overage.id.to_string(),
+            Record::from([(
+                "tokens".to_string(),
+                (overage.tokens - released).to_string(),
+            )]),
+        );
+        released
+    }
+}
+
+impl ModelAble<String, String> for FlatOverageList {}
+
+impl From<Record<String, String>> for Overage {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("subscription"),
+            map.get("request"),
+            map.get("tokens"),
+            map.get("price"),
+            map.get("created_at"),
+        ) {
+            (
+                Some(id),
+                Some(subscription_id),
+                Some(request_id),
+                Some(tokens),
+                Some(price),
+                Some(created_at),
+            ) => Overage {
+                id: id.parse::<u128>().unwrap(),
+                subscription_id: subscription_id.parse::<u128>().unwrap(),
+                request_id: match request_id.is_empty() {
+                    true => None,
+                    false => Some(request_id.clone()),
+                },
+                tokens: tokens.parse::<u128>().unwrap(),
+                price: price.parse::<u128>().unwrap(),
+                created_at: parse_date(created_at).expect("Invalid overage date"),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_overage() {
+        let table = "id, subscription, request, tokens, price, created_at".to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let overage_list = OverageList::new(db);
+
+        let overage = overage_list.create(
+            1,
+            Some("UUID-1"),
+            3,
+            30,
+            parse_date("2023-01-02 00:00:00").unwrap(),
+        );
+
+        assert_eq!(overage.id, 1);
+        assert_eq!(overage.cost(), 90);
+        assert_eq!(overage_list.list().len(), 1);
+        assert_eq!(overage_list.release("UUID-1", 5), 3);
+        assert_eq!(overage_list.list()[0].tokens, 0);
+    }
+}
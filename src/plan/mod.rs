@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use chrono::{Months, NaiveDateTime};
+use serde::Serialize;
+
+use crate::entitlement::Entitlements;
+
+pub mod plan_list;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BillingPeriod {
+    Monthly,
+    Annual,
+}
+
+impl BillingPeriod {
+    pub fn parse(value: &str) -> Option<BillingPeriod> {
+        match value {
+            "monthly" => Some(BillingPeriod::Monthly),
+            "annual" => Some(BillingPeriod::Annual),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BillingPeriod::Monthly => "monthly",
+            BillingPeriod::Annual => "annual",
+        }
+    }
+
+    /// Returns the start of the period ending at `end`.
+    pub fn previous(&self, end: NaiveDateTime) -> NaiveDateTime {
+        let months = match self {
+            BillingPeriod::Monthly => Months::new(1),
+            BillingPeriod::Annual => Months::new(12),
+        };
+        end.checked_sub_months(months)
+            .expect("period start is out of range")
+    }
+
+    /// Returns the end of the period starting at `start`.
+    pub fn next(&self, start: NaiveDateTime) -> NaiveDateTime {
+        let months = match self {
+            BillingPeriod::Monthly => Months::new(1),
+            BillingPeriod::Annual => Months::new(12),
+        };
+        start
+            .checked_add_months(months)
+            .expect("period end is out of range")
+    }
+}
+
+/// What happens once a subscription has spent its quota.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OveragePolicy {
+    /// Requests are refused.
+    Hard,
+    /// Requests go on and every extra token is billed at `overage_price`.
+    Soft,
+    /// Like `Soft`, up to `overage_cap` extra tokens per period.
+    Capped,
+}
+
+impl OveragePolicy {
+    pub fn parse(value: &str) -> Option<OveragePolicy> {
+        match value {
+            "hard" => Some(OveragePolicy::Hard),
+            "soft" => Some(OveragePolicy::Soft),
+            "capped" => Some(OveragePolicy::Capped),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OveragePolicy::Hard => "hard",
+            OveragePolicy::Soft => "soft",
+            OveragePolicy::Capped => "capped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub id: u128,
+    pub name: String,
+    pub price: u128,
+    pub quota: u128,
+    pub period: BillingPeriod,
+    pub overage_policy: OveragePolicy,
+    pub overage_price: u128,
+    pub overage_cap: u128,
+    /// The products, services and versions its subscribers may call.
+    pub entitlements: Entitlements,
+}
+
+impl Plan {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Plan {
+        Plan {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            name: attr.get("name").unwrap_or(&"default_plan").to_string(),
+            price: attr.get("price").unwrap_or(&"1").parse::<u128>().unwrap(),
+            quota: attr.get("quota").unwrap_or(&"10").parse::<u128>().unwrap(),
+            period: BillingPeriod::parse(attr.get("period").unwrap_or(&"monthly")).unwrap(),
+            overage_policy: OveragePolicy::parse(attr.get("overage_policy").unwrap_or(&"hard"))
+                .unwrap(),
+            overage_price: attr
+                .get("overage_price")
+                .unwrap_or(&"0")
+                .parse::<u128>()
+                .unwrap(),
+            overage_cap: attr
+                .get("overage_cap")
+                .unwrap_or(&"0")
+                .parse::<u128>()
+                .unwrap(),
+            entitlements: Entitlements::parse(attr.get("entitlements").unwrap_or(&"*")).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[test]
+    fn next_period_end() {
+        let start = NaiveDate::from_ymd_opt(2023, 1, 31)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+
+        assert_eq!(
+            BillingPeriod::Monthly.next(start).date(),
+            NaiveDate::from_ymd_opt(2023, 2, 28).unwrap()
+        );
+        assert_eq!(
+            BillingPeriod::Annual.next(start).date(),
+            NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::{Arc, Mutex};
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+use crate::entitlement::Entitlements;
+
+use super::{BillingPeriod, OveragePolicy, Plan};
+
+#[derive(Clone)]
+pub struct PlanList<D> {
+    db: Arc<Mutex<D>>,
+    pub plans: Vec<Plan>,
+}
+
+pub type FlatPlanList = PlanList<FlatTable<String, String>>;
+
+impl FlatPlanList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        PlanList {
+            db: Arc::new(db),
+            plans: vec![],
+        }
+    }
+
+    pub fn get_by_id(&self, id: u128) -> Option<Plan> {
+        PlanList::get_by_attr::<FlatTable<String, String>, Plan>(&self.db, "id", id.to_string())
+    }
+
+    pub fn list(&self) -> Vec<Plan> {
+        PlanList::get_all::<FlatTable<String, String>, Plan>(&self.db)
+    }
+
+    /// The table as it would be written to its file.
+    pub fn contents(&self) -> String {
+        self.db.lock().expect("lock db").contents()
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Plan> {
+        PlanList::search_records::<FlatTable<String, String>, Plan>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        PlanList::count_records(&self.db, filter)
+    }
+
+    pub fn create(&self, mut record: Record<String, String>) -> Plan {
+        PlanList::insert_next::<FlatTable<String, String>, Plan>(&self.db, |id| {
+            record.insert("id".to_string(), id.to_string());
+            record
+        })
+    }
+
+    pub fn update(&self, id: u128, changes: Record<String, String>) -> Option<Plan> {
+        PlanList::update_by_attr::<FlatTable<String, String>, Plan>(
+            &self.db,
+            "id",
+            id.to_string(),
+            changes,
+        )
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        PlanList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
+}
+
+impl ModelAble<String, String> for FlatPlanList {}
+
+impl From<Record<String, String>> for Plan {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("name"),
+            map.get("price"),
+            map.get("quota"),
+            map.get("period"),
+        ) {
+            (Some(id), Some(name), Some(price), Some(quota), Some(period)) => Plan {
+                id: id.parse::<u128>().unwrap(),
+                name: name.clone(),
+                price: price.parse::<u128>().unwrap(),
+                quota: quota.parse::<u128>().unwrap(),
+                period: BillingPeriod::parse(period).expect("Invalid billing period"),
+                // plans without overage columns keep the hard limit
+                overage_policy: map
+                    .get("overage_policy")
+                    .map(|policy| OveragePolicy::parse(policy).expect("Invalid overage policy"))
+                    .unwrap_or(OveragePolicy::Hard),
+                overage_price: map
+                    .get("overage_price")
+                    .map(|price| price.parse::<u128>().unwrap())
+                    .unwrap_or(0),
+                overage_cap: map
+                    .get("overage_cap")
+                    .map(|cap| cap.parse::<u128>().unwrap())
+                    .unwrap_or(0),
+                // plans predating entitlements include every product
+                entitlements: map
+                    .get("entitlements")
+                    .map(|grants| Entitlements::parse(grants).expect("Invalid entitlements"))
+                    .unwrap_or_else(Entitlements::all),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_plan_by_id() {
+        let table = "\
+        id, name, price, quota, period
+        1, Startup 500, 10000, 500, monthly
+        2, Golden 50, 50000, 50, annual
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let plan_list = PlanList::new(db);
+
+        let plan = plan_list.get_by_id(2).unwrap();
+
+        assert_eq!(plan.name, "Golden 50");
+        assert_eq!(plan.period, BillingPeriod::Annual);
+        assert_eq!(plan.overage_policy, OveragePolicy::Hard)
+    }
+
+    #[test]
+    fn get_plan_with_overage() {
+        let table = "\
+        id, name, price, quota, period, overage_policy, overage_price, overage_cap
+        1, Startup 500, 10000, 500, monthly, capped, 30, 100
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let plan_list = PlanList::new(db);
+
+        let plan = plan_list.get_by_id(1).unwrap();
+
+        assert_eq!(plan.overage_policy, OveragePolicy::Capped);
+        assert_eq!(plan.overage_price, 30);
+        assert_eq!(plan.overage_cap, 100)
+    }
+}
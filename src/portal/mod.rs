@@ -0,0 +1,175 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::{
+    consumer::Consumer,
+    plan::BillingPeriod,
+    request::Request,
+    subscriber::{Subscription, SubscriptionStatus},
+};
+
+pub mod routes;
+
+#[derive(Debug, Serialize)]
+pub struct QuotaStatus {
+    pub plan: String,
+    pub period: BillingPeriod,
+    pub status: SubscriptionStatus,
+    pub quota: u128,
+    pub expiry_date: NaiveDateTime,
+}
+
+impl From<&Subscription> for QuotaStatus {
+    fn from(subscription: &Subscription) -> Self {
+        QuotaStatus {
+            plan: subscription.name.clone(),
+            period: subscription.plan.period,
+            status: subscription.status,
+            quota: subscription.quota,
+            expiry_date: subscription.expiry_date,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ConsumerUsage {
+    pub consumer: u128,
+    pub requests: u128,
+    pub tokens: u128,
+}
+
+/// Sums the logged requests and tokens spent by each of `consumers`.
+pub fn aggregate_usage(consumers: &[Consumer], requests: &[Request]) -> Vec<ConsumerUsage> {
+    consumers
+        .iter()
+        .map(|consumer| {
+            let logged = requests
+                .iter()
+                .filter(|request| request.consumer.id == consumer.id);
+            ConsumerUsage {
+                consumer: consumer.id,
+                requests: logged.clone().count() as u128,
+                tokens: logged.map(|request| request.price).sum(),
+            }
+        })
+        .collect()
+}
+
+/// A call made with one of the subscriber's sandbox keys.
+#[derive(Debug, Serialize)]
+pub struct SandboxCall {
+    pub id: String,
+    pub consumer: u128,
+    pub product: String,
+    pub service: String,
+    pub version: String,
+    pub status: u32,
+}
+
+impl From<&Request> for SandboxCall {
+    fn from(request: &Request) -> Self {
+        SandboxCall {
+            id: request.id.clone(),
+            consumer: request.consumer.id,
+            product: request.product_slug.clone(),
+            service: request.service_slug.clone(),
+            version: request.service_version.clone(),
+            status: request.status,
+        }
+    }
+}
+
+/// A consumer's share of the subscription's quota in the current period.
+#[derive(Debug, Serialize)]
+pub struct ConsumerBudget {
+    pub consumer: u128,
+    /// `None` when the consumer may spend the whole quota.
+    pub budget: Option<u128>,
+    pub used: u128,
+    pub period_start: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConsumerKey {
+    pub consumer: u128,
+    pub access_token: String,
+    pub revoked: bool,
+    pub sandbox: bool,
+}
+
+impl ConsumerKey {
+    /// Shows the full key, only used right after it was issued.
+    pub fn revealed(consumer: &Consumer) -> Self {
+        ConsumerKey {
+            consumer: consumer.id,
+            access_token: consumer.access_token.clone(),
+            revoked: consumer.access_token.is_empty(),
+            sandbox: consumer.sandbox,
+        }
+    }
+
+    /// Hides all but the last 4 characters of the key.
+    pub fn masked(consumer: &Consumer) -> Self {
+        let visible = consumer
+            .access_token
+            .chars()
+            .skip(consumer.access_token.chars().count().saturating_sub(4))
+            .collect::<String>();
+        ConsumerKey {
+            consumer: consumer.id,
+            access_token: match visible.is_empty() {
+                true => String::new(),
+                false => format!("****{visible}"),
+            },
+            revoked: consumer.access_token.is_empty(),
+            sandbox: consumer.sandbox,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn aggregate_usage_per_consumer() {
+        let consumers = vec![
+            Consumer::fake(&HashMap::from([("id", "1")])),
+            Consumer::fake(&HashMap::from([("id", "2")])),
+        ];
+        let requests = vec![
+            Request::fake(&HashMap::from([("consumer", "1"), ("price", "2")])),
+            Request::fake(&HashMap::from([("consumer", "1"), ("price", "3")])),
+            Request::fake(&HashMap::from([("consumer", "3"), ("price", "7")])),
+        ];
+
+        let usage = aggregate_usage(&consumers, &requests);
+
+        assert_eq!(
+            usage,
+            vec![
+                ConsumerUsage {
+                    consumer: 1,
+                    requests: 2,
+                    tokens: 5
+                },
+                ConsumerUsage {
+                    consumer: 2,
+                    requests: 0,
+                    tokens: 0
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn mask_consumer_key() {
+        let consumer = Consumer::fake(&HashMap::from([("access_token", "uws-key-abcdef")]));
+        let revoked = Consumer::fake(&HashMap::from([("access_token", "")]));
+
+        assert_eq!(ConsumerKey::masked(&consumer).access_token, "****cdef");
+        assert!(ConsumerKey::masked(&revoked).revoked);
+    }
+}
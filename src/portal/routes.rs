@@ -0,0 +1,303 @@
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::alert::{self, alert_list::FlatAlertList, Alert};
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::consumer::{consumer_list::FlatConsumerList, Consumer};
+use crate::db::{Filter, Query, Record};
+use crate::entitlement::Entitlements;
+use crate::errors::{error, ApiError};
+use crate::guards::{generate_key, SubscriberKey};
+use crate::ledger::{ledger_list::FlatLedgerList, Entry};
+use crate::request::request_list::FlatRequestList;
+use crate::sandbox::SandboxRequestList;
+use crate::subscriber::subscriber_list::FlatSubscriberList;
+
+use super::{
+    aggregate_usage, ConsumerBudget, ConsumerKey, ConsumerUsage, QuotaStatus, SandboxCall,
+};
+
+pub type PortalResult<T> = Result<T, Custom<Json<ApiError>>>;
+
+fn own_consumers(consumers: &FlatConsumerList, subscriber_id: u128) -> Vec<Consumer> {
+    consumers.filter(&Query::default().filter(Filter::eq("subscriber", &subscriber_id.to_string())))
+}
+
+/// Finds consumer `id` if it belongs to the authenticated subscriber.
+fn own_consumer(
+    consumers: &FlatConsumerList,
+    subscriber_id: u128,
+    id: u128,
+) -> PortalResult<Consumer> {
+    match consumers.get_by_id(id) {
+        Some(consumer) if consumer.subscriber.id == subscriber_id => Ok(consumer),
+        _ => Err(error(
+            Status::NotFound,
+            &format!("Consumer with id:{id} is not found"),
+        )),
+    }
+}
+
+#[get("/subscription")]
+fn subscription(key: SubscriberKey) -> Json<QuotaStatus> {
+    Json(QuotaStatus::from(&key.0.subscription))
+}
+
+#[get("/usage")]
+fn usage(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    requests: &State<FlatRequestList>,
+) -> Json<Vec<ConsumerUsage>> {
+    let consumers = own_consumers(consumers, key.0.id);
+    Json(aggregate_usage(&consumers, &requests.list()))
+}
+
+#[get("/sandbox/requests")]
+fn sandbox_requests(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    requests: &State<SandboxRequestList>,
+) -> Json<Vec<SandboxCall>> {
+    let consumers = own_consumers(consumers, key.0.id)
+        .iter()
+        .map(|consumer| Filter::eq("consumer", &consumer.id.to_string()))
+        .collect::<Vec<Filter>>();
+    if consumers.is_empty() {
+        return Json(vec![]);
+    }
+    let query = Query::default().filter(Filter::Any(consumers));
+    Json(
+        requests
+            .filter(&query)
+            .iter()
+            .map(SandboxCall::from)
+            .collect(),
+    )
+}
+
+#[get("/ledger")]
+fn ledger(key: SubscriberKey, ledger: &State<FlatLedgerList>) -> Json<Vec<Entry>> {
+    Json(ledger.list_by_subscription(key.0.subscription.id))
+}
+
+#[get("/consumers")]
+fn list_consumers(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+) -> Json<Vec<ConsumerKey>> {
+    Json(
+        own_consumers(consumers, key.0.id)
+            .iter()
+            .map(ConsumerKey::masked)
+            .collect(),
+    )
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct NewConsumer {
+    /// Narrows what the plan includes for the new key.
+    pub scopes: Option<Vec<String>>,
+    /// Issues a sandbox key, served by mock upstreams and never charged.
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+#[post("/consumers", data = "<input>")]
+fn create_consumer(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    input: Option<Json<NewConsumer>>,
+) -> PortalResult<Custom<Json<ConsumerKey>>> {
+    let input = input.map(Json::into_inner).unwrap_or_default();
+    let scopes = match input.scopes {
+        Some(scopes) if scopes.is_empty() => {
+            return Err(error(
+                Status::UnprocessableEntity,
+                "`scopes` should list at least one grant",
+            ))
+        }
+        Some(scopes) => Entitlements::from_list(scopes.iter().map(String::as_str))
+            .map_err(|e| error(Status::UnprocessableEntity, &format!("`scopes`: {e}")))?,
+        None => Entitlements::all(),
+    };
+    let consumer = consumers.create(generate_key("key"), key.0.id, &scopes, None, input.sandbox);
+    Ok(Custom(
+        Status::Created,
+        Json(ConsumerKey::revealed(&consumer)),
+    ))
+}
+
+#[post("/consumers/<id>/key")]
+fn rotate_key(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    id: u128,
+) -> PortalResult<Json<ConsumerKey>> {
+    own_consumer(consumers, key.0.id, id)?;
+    let changes = Record::from([("access_token".to_string(), generate_key("key"))]);
+
+    consumers
+        .update(id, changes)
+        .map(|consumer| Json(ConsumerKey::revealed(&consumer)))
+        .ok_or_else(|| error(Status::NotFound, "Consumer is not found"))
+}
+
+#[delete("/consumers/<id>/key")]
+fn revoke_key(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    id: u128,
+) -> PortalResult<Status> {
+    own_consumer(consumers, key.0.id, id)?;
+    let changes = Record::from([("access_token".to_string(), String::new())]);
+
+    consumers
+        .update(id, changes)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| error(Status::NotFound, "Consumer is not found"))
+}
+
+fn consumer_budget(usages: &FlatBudgetUsageList, consumer: &Consumer) -> ConsumerBudget {
+    let period_start = consumer.subscriber.subscription.period_start();
+    ConsumerBudget {
+        consumer: consumer.id,
+        budget: consumer.budget,
+        used: usages.used(consumer.id, period_start),
+        period_start,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BudgetInput {
+    /// Tokens the consumer may spend per period, `null` to lift the budget.
+    pub budget: Option<u128>,
+}
+
+#[get("/consumers/<id>/budget")]
+fn get_budget(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    usages: &State<FlatBudgetUsageList>,
+    id: u128,
+) -> PortalResult<Json<ConsumerBudget>> {
+    let consumer = own_consumer(consumers, key.0.id, id)?;
+    Ok(Json(consumer_budget(usages, &consumer)))
+}
+
+#[put("/consumers/<id>/budget", data = "<input>")]
+fn set_budget(
+    key: SubscriberKey,
+    consumers: &State<FlatConsumerList>,
+    usages: &State<FlatBudgetUsageList>,
+    id: u128,
+    input: Json<BudgetInput>,
+) -> PortalResult<Json<ConsumerBudget>> {
+    own_consumer(consumers, key.0.id, id)?;
+    let budget = input.budget.map(|budget| budget.to_string());
+    let changes = Record::from([("budget".to_string(), budget.unwrap_or_default())]);
+
+    consumers
+        .update(id, changes)
+        .map(|consumer| Json(consumer_budget(usages, &consumer)))
+        .ok_or_else(|| error(Status::NotFound, "Consumer is not found"))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Verifies the `X-UWS-Signature` of the alerts posted to `url`. Like
+    /// keys, it is only shown when it is issued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookInput {
+    pub url: String,
+}
+
+#[get("/webhook")]
+fn get_webhook(key: SubscriberKey) -> PortalResult<Json<Webhook>> {
+    match key.0.webhook_url.is_empty() {
+        true => Err(error(Status::NotFound, "No webhook is set")),
+        false => Ok(Json(Webhook {
+            url: key.0.webhook_url,
+            secret: None,
+        })),
+    }
+}
+
+/// Sets the webhook alerts are posted to, issuing a new signing secret.
+#[put("/webhook", data = "<input>")]
+fn set_webhook(
+    key: SubscriberKey,
+    subscribers: &State<FlatSubscriberList>,
+    input: Json<WebhookInput>,
+) -> PortalResult<Json<Webhook>> {
+    let url = input.url.trim().to_string();
+    if let Err(problem) = alert::check_webhook_url(&url) {
+        return Err(error(
+            Status::UnprocessableEntity,
+            &format!("`url` {problem}"),
+        ));
+    }
+    let secret = generate_key("whsec");
+    let changes = Record::from([
+        ("webhook_url".to_string(), url.clone()),
+        ("webhook_secret".to_string(), secret.clone()),
+    ]);
+
+    subscribers
+        .update(key.0.id, changes)
+        .map(|_| {
+            Json(Webhook {
+                url,
+                secret: Some(secret),
+            })
+        })
+        .ok_or_else(|| error(Status::NotFound, "Subscriber is not found"))
+}
+
+#[delete("/webhook")]
+fn delete_webhook(
+    key: SubscriberKey,
+    subscribers: &State<FlatSubscriberList>,
+) -> PortalResult<Status> {
+    let changes = Record::from([
+        ("webhook_url".to_string(), String::new()),
+        ("webhook_secret".to_string(), String::new()),
+    ]);
+
+    subscribers
+        .update(key.0.id, changes)
+        .map(|_| Status::NoContent)
+        .ok_or_else(|| error(Status::NotFound, "Subscriber is not found"))
+}
+
+#[get("/alerts")]
+fn alerts(key: SubscriberKey, alerts: &State<FlatAlertList>) -> Json<Vec<Alert>> {
+    Json(alerts.list_by_subscriber(key.0.id))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        subscription,
+        usage,
+        sandbox_requests,
+        ledger,
+        list_consumers,
+        create_consumer,
+        rotate_key,
+        revoke_key,
+        get_budget,
+        set_budget,
+        get_webhook,
+        set_webhook,
+        delete_webhook,
+        alerts
+    ]
+}
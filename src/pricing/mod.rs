@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::service::Service;
+
+pub mod pricing_list;
+
+/// What a pricing rule charges for.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    /// `price` tokens when the request uses the HTTP method in `param`.
+    Method,
+    /// `price` tokens per started `param` bytes of request body.
+    RequestBytes,
+    /// `price` tokens per started `param` bytes of response body.
+    ResponseBytes,
+    /// `price` tokens per started `param` milliseconds spent upstream.
+    Duration,
+    /// `price` tokens per unit reported by the upstream in the `param` header.
+    UsageHeader,
+}
+
+impl RuleKind {
+    pub fn parse(value: &str) -> Option<RuleKind> {
+        match value {
+            "method" => Some(RuleKind::Method),
+            "request_bytes" => Some(RuleKind::RequestBytes),
+            "response_bytes" => Some(RuleKind::ResponseBytes),
+            "duration" => Some(RuleKind::Duration),
+            "usage_header" => Some(RuleKind::UsageHeader),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::Method => "method",
+            RuleKind::RequestBytes => "request_bytes",
+            RuleKind::ResponseBytes => "response_bytes",
+            RuleKind::Duration => "duration",
+            RuleKind::UsageHeader => "usage_header",
+        }
+    }
+
+    /// Returns true if the rule can be priced before the upstream responds.
+    pub fn is_known_upfront(&self) -> bool {
+        matches!(self, RuleKind::Method | RuleKind::RequestBytes)
+    }
+}
+
+/// A price added to a service's flat `price` depending on the work done.
+#[derive(Debug, Clone, Serialize)]
+pub struct PricingRule {
+    pub id: u128,
+    pub service_id: u128,
+    pub kind: RuleKind,
+    pub param: String,
+    pub price: u128,
+}
+
+/// What was measured for one proxied request.
+#[derive(Debug, Clone, Default)]
+pub struct Metering {
+    pub method: String,
+    pub request_bytes: u128,
+    pub response_bytes: u128,
+    pub duration: Duration,
+    /// Upstream response headers, with lowercase names.
+    pub headers: HashMap<String, String>,
+}
+
+impl PricingRule {
+    pub fn fake(attr: &HashMap<&str, &str>) -> PricingRule {
+        PricingRule {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            service_id: attr.get("service").unwrap_or(&"1").parse::<u128>().unwrap(),
+            kind: RuleKind::parse(attr.get("kind").unwrap_or(&"method")).unwrap(),
+            param: attr.get("param").unwrap_or(&"POST").to_string(),
+            price: attr.get("price").unwrap_or(&"1").parse::<u128>().unwrap(),
+        }
+    }
+
+    /// Returns the tokens this rule charges for `metering`.
+    pub fn price_of(&self, metering: &Metering) -> u128 {
+        let units = match self.kind {
+            RuleKind::Method => metering.method.eq_ignore_ascii_case(&self.param) as u128,
+            RuleKind::RequestBytes => per_unit(metering.request_bytes, &self.param),
+            RuleKind::ResponseBytes => per_unit(metering.response_bytes, &self.param),
+            RuleKind::Duration => per_unit(metering.duration.as_millis(), &self.param),
+            RuleKind::UsageHeader => metering
+                .headers
+                .get(&self.param.to_lowercase())
+                .and_then(|value| value.trim().parse::<u128>().ok())
+                .unwrap_or(0),
+        };
+        units * self.price
+    }
+}
+
+/// Counts the started units of `unit` size in `amount`.
+fn per_unit(amount: u128, unit: &str) -> u128 {
+    match unit.parse::<u128>() {
+        Ok(unit) if unit > 0 => amount.div_ceil(unit),
+        _ => 0,
+    }
+}
+
+/// Checks that `param` makes sense for a rule of `kind`.
+pub fn validate(kind: RuleKind, param: &str) -> Result<(), String> {
+    let is_unit = matches!(param.parse::<u128>(), Ok(unit) if unit > 0);
+    match kind {
+        RuleKind::Method | RuleKind::UsageHeader if param.trim().is_empty() => {
+            Err("`param` should name an HTTP method or header".to_string())
+        }
+        RuleKind::RequestBytes | RuleKind::ResponseBytes | RuleKind::Duration if !is_unit => {
+            Err("`param` should be a positive unit size".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Prices a request before forwarding it, from what is already known.
+pub fn estimate(service: &Service, rules: &[PricingRule], metering: &Metering) -> u128 {
+    service.price
+        + rules
+            .iter()
+            .filter(|rule| rule.kind.is_known_upfront())
+            .map(|rule| rule.price_of(metering))
+            .sum::<u128>()
+}
+
+/// Prices a request once the upstream has responded.
+pub fn settle(service: &Service, rules: &[PricingRule], metering: &Metering) -> u128 {
+    service.price
+        + rules
+            .iter()
+            .map(|rule| rule.price_of(metering))
+            .sum::<u128>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Vec<PricingRule> {
+        vec![
+            PricingRule::fake(&HashMap::from([("kind", "method"), ("param", "POST")])),
+            PricingRule::fake(&HashMap::from([
+                ("kind", "request_bytes"),
+                ("param", "1024"),
+                ("price", "2"),
+            ])),
+            PricingRule::fake(&HashMap::from([("kind", "duration"), ("param", "1000")])),
+            PricingRule::fake(&HashMap::from([
+                ("kind", "usage_header"),
+                ("param", "X-Usage-Tokens"),
+                ("price", "3"),
+            ])),
+        ]
+    }
+
+    #[test]
+    fn estimate_then_settle_price() {
+        let service = Service::fake(&HashMap::from([("price", "5")]));
+        let mut metering = Metering {
+            method: "post".to_string(),
+            request_bytes: 1025,
+            ..Metering::default()
+        };
+
+        assert_eq!(estimate(&service, &rules(), &metering), 5 + 1 + 4);
+
+        metering.duration = Duration::from_millis(1500);
+        metering
+            .headers
+            .insert("x-usage-tokens".to_string(), "7".to_string());
+
+        assert_eq!(settle(&service, &rules(), &metering), 5 + 1 + 4 + 2 + 21);
+    }
+
+    #[test]
+    fn validate_rule_param() {
+        assert!(validate(RuleKind::ResponseBytes, "0").is_err());
+        assert!(validate(RuleKind::UsageHeader, " ").is_err());
+        assert!(validate(RuleKind::Duration, "250").is_ok());
+    }
+}
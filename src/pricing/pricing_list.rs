@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+
+use super::{PricingRule, RuleKind};
+
+#[derive(Clone)]
+pub struct PricingRuleList<D> {
+    db: Arc<Mutex<D>>,
+    pub rules: Vec<PricingRule>,
+}
+
+pub type FlatPricingRuleList = PricingRuleList<FlatTable<String, String>>;
+
+impl FlatPricingRuleList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        PricingRuleList {
+            db: Arc::new(db),
+            rules: vec![],
+        }
+    }
+
+    pub fn get_by_id(&self, id: u128) -> Option<PricingRule> {
+        PricingRuleList::get_by_attr::<FlatTable<String, String>, PricingRule>(
+            &self.db,
+            "id",
+            id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<PricingRule> {
+        PricingRuleList::get_all::<FlatTable<String, String>, PricingRule>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<PricingRule> {
+        PricingRuleList::search_records::<FlatTable<String, String>, PricingRule>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        PricingRuleList::count_records(&self.db, filter)
+    }
+
+    pub fn list_by_service(&self, service_id: u128) -> Vec<PricingRule> {
+        self.list()
+            .into_iter()
+            .filter(|rule| rule.service_id == service_id)
+            .collect()
+    }
+
+    pub fn create(
+        &self,
+        service_id: u128,
+        kind: RuleKind,
+        param: String,
+        price: u128,
+    ) -> PricingRule {
+        PricingRuleList::insert_next::<FlatTable<String, String>, PricingRule>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("service".to_string(), service_id.to_string()),
+                ("kind".to_string(), kind.as_str().to_string()),
+                ("param".to_string(), param),
+                ("price".to_string(), price.to_string()),
+            ])
+        })
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        PricingRuleList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
+}
+
+impl ModelAble<String, String> for FlatPricingRuleList {}
+
+impl From<Record<String, String>> for PricingRule {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("service"),
+            map.get("kind"),
+            map.get("param"),
+            map.get("price"),
+        ) {
+            (Some(id), Some(service_id), Some(kind), Some(param), Some(price)) => PricingRule {
+                id: id.parse::<u128>().unwrap(),
+                service_id: service_id.parse::<u128>().unwrap(),
+                kind: RuleKind::parse(kind).expect("Invalid pricing rule kind"),
+                param: param.clone(),
+                price: price.parse::<u128>().unwrap(),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_rules_of_service() {
+        let table = "\
+        id, service, kind, param, price
+        1, 1, method, POST, 1
+        2, 2, usage_header, X-Usage-Tokens, 3
+        3, 1, response_bytes, 1024, 1
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let rule_list = PricingRuleList::new(db);
+
+        let rules = rule_list.list_by_service(1);
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].kind, RuleKind::ResponseBytes);
+    }
+}
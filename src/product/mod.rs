@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 
+use serde::Serialize;
+
 pub mod product_list;
+#[derive(Debug, Clone, Serialize)]
 pub struct Product {
     pub id: u128,
     pub slug: String,
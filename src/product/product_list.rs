@@ -1,20 +1,24 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
-use crate::db::{file_db::FlatTable, ModelAble};
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
 
 use super::Product;
 
+#[derive(Clone)]
 pub struct ProductList<D> {
-    db: Mutex<D>,
+    db: Arc<Mutex<D>>,
     pub products: Vec<Product>,
 }
 
-type FlatProductList = ProductList<FlatTable<String, String>>;
+pub type FlatProductList = ProductList<FlatTable<String, String>>;
 
 impl FlatProductList {
     pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
         ProductList {
-            db,
+            db: Arc::new(db),
             products: vec![],
         }
     }
@@ -34,27 +38,64 @@ impl FlatProductList {
             slug.to_string(),
         )
     }
+
+    pub fn list(&self) -> Vec<Product> {
+        ProductList::get_all::<FlatTable<String, String>, Product>(&self.db)
+    }
+
+    /// The table as it would be written to its file.
+    pub fn contents(&self) -> String {
+        self.db.lock().expect("lock db").contents()
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Product> {
+        ProductList::search_records::<FlatTable<String, String>, Product>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        ProductList::count_records(&self.db, filter)
+    }
+
+    /// Returns the raw rows, for checking them before they are converted.
+    pub fn records(&self) -> Vec<Record<String, String>> {
+        ProductList::get_all::<FlatTable<String, String>, Record<String, String>>(&self.db)
+    }
+
+    pub fn create(&self, slug: String, requests: u128) -> Product {
+        ProductList::insert_next::<FlatTable<String, String>, Product>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("slug".to_string(), slug),
+                ("requests".to_string(), requests.to_string()),
+            ])
+        })
+    }
+
+    pub fn update(&self, id: u128, changes: Record<String, String>) -> Option<Product> {
+        ProductList::update_by_attr::<FlatTable<String, String>, Product>(
+            &self.db,
+            "id",
+            id.to_string(),
+            changes,
+        )
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        ProductList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
 }
 
 impl ModelAble<String, String> for FlatProductList {}
 impl From<HashMap<String, String>> for Product {
     fn from(map: HashMap<String, String>) -> Self {
-        return match (
-            map.get("id"),
-            map.get("requests"),
-            map.get("slug"),
-        ) {
-            (
-                Some(id),
-                Some(requests),
-                Some(slug),
-            ) => Product {
+        match (map.get("id"), map.get("requests"), map.get("slug")) {
+            (Some(id), Some(requests), Some(slug)) => Product {
                 id: id.parse::<u128>().unwrap(),
                 requests: requests.parse::<u128>().unwrap(),
                 slug: slug.clone(),
             },
             _ => panic!("Can't convert!"),
-        };
+        }
     }
 }
 
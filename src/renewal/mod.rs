@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::subscriber::parse_date;
+
+pub mod renewal_list;
+
+/// A subscription period that was renewed and had its quota reset.
+#[derive(Debug, Clone, Serialize)]
+pub struct Renewal {
+    pub id: u128,
+    pub subscription_id: u128,
+    pub plan_id: u128,
+    pub quota: u128,
+    pub renewed_at: NaiveDateTime,
+    pub expiry_date: NaiveDateTime,
+}
+
+impl Renewal {
+    pub fn fake(attr: &HashMap<&str, &str>) -> Renewal {
+        Renewal {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            subscription_id: attr
+                .get("subscription")
+                .unwrap_or(&"1")
+                .parse::<u128>()
+                .unwrap(),
+            plan_id: attr.get("plan").unwrap_or(&"1").parse::<u128>().unwrap(),
+            quota: attr.get("quota").unwrap_or(&"10").parse::<u128>().unwrap(),
+            renewed_at: parse_date(attr.get("renewed_at").unwrap_or(&"2001-01-01 00:00:00"))
+                .unwrap(),
+            expiry_date: parse_date(attr.get("expiry_date").unwrap_or(&"2001-02-01 00:00:00"))
+                .unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {}
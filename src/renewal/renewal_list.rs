@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+use crate::subscriber::{format_date, parse_date};
+
+use super::Renewal;
+
+#[derive(Clone)]
+pub struct RenewalList<D> {
+    db: Arc<Mutex<D>>,
+    pub renewals: Vec<Renewal>,
+}
+
+pub type FlatRenewalList = RenewalList<FlatTable<String, String>>;
+
+impl FlatRenewalList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        RenewalList {
+            db: Arc::new(db),
+            renewals: vec![],
+        }
+    }
+
+    pub fn list(&self) -> Vec<Renewal> {
+        RenewalList::get_all::<FlatTable<String, String>, Renewal>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Renewal> {
+        RenewalList::search_records::<FlatTable<String, String>, Renewal>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        RenewalList::count_records(&self.db, filter)
+    }
+
+    pub fn create(
+        &self,
+        subscription_id: u128,
+        plan_id: u128,
+        quota: u128,
+        renewed_at: NaiveDateTime,
+        expiry_date: NaiveDateTime,
+    ) -> Renewal {
+        RenewalList::insert_next::<FlatTable<String, String>, Renewal>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("subscription".to_string(), subscription_id.to_string()),
+                ("plan".to_string(), plan_id.to_string()),
+                ("quota".to_string(), quota.to_string()),
+                ("renewed_at".to_string(), format_date(&renewed_at)),
+                ("expiry_date".to_string(), format_date(&expiry_date)),
+            ])
+        })
+    }
+}
+
+impl ModelAble<String, String> for FlatRenewalList {}
+
+impl From<Record<String, String>> for Renewal {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("subscription"),
+            map.get("plan"),
+            map.get("quota"),
+            map.get("renewed_at"),
+            map.get("expiry_date"),
+        ) {
+            (
+                Some(id),
+                Some(subscription_id),
+                Some(plan_id),
+                Some(quota),
+                Some(renewed_at),
+                Some(expiry_date),
+            ) => Renewal {
+                id: id.parse::<u128>().unwrap(),
+                subscription_id: subscription_id.parse::<u128>().unwrap(),
+                plan_id: plan_id.parse::<u128>().unwrap(),
+                quota: quota.parse::<u128>().unwrap(),
+                renewed_at: parse_date(renewed_at).expect("Invalid renewal date"),
+                expiry_date: parse_date(expiry_date).expect("Invalid expiry date"),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_renewal() {
+        let table = "\
+        id, subscription, plan, quota, renewed_at, expiry_date
+        1, 1, 1, 500, 2023-01-01 00:00:00, 2023-02-01 00:00:00
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let renewal_list = RenewalList::new(db);
+
+        let renewal = renewal_list.create(
+            2,
+            1,
+            500,
+            parse_date("2023-01-02 00:00:00").unwrap(),
+            parse_date("2023-02-02 00:00:00").unwrap(),
+        );
+
+        assert_eq!(renewal.id, 2);
+        assert_eq!(renewal_list.list().len(), 2)
+    }
+}
@@ -1,5 +1,7 @@
 use std::{collections::HashMap, sync::Mutex};
 
+use serde::Serialize;
+
 use crate::{
     consumer::consumer_list::ConsumerList,
     db::file_db::{get_table_instance, FlatTable},
@@ -9,6 +11,7 @@ use crate::{
 
 pub mod request_list;
 
+#[derive(Debug, Clone, Serialize)]
 pub struct Request {
     pub id: String,
     pub consumer: Consumer,
@@ -18,7 +21,7 @@ pub struct Request {
     pub service_version: String,
     pub url: String,
     pub status: u32,
-    pub price: u128
+    pub price: u128,
 }
 
 impl Request {
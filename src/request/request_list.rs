@@ -1,23 +1,27 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use crate::db::{
     file_db::{get_table_instance, FlatTable},
-    ModelAble,
+    Filter, ModelAble, Query, Record,
 };
 
 use super::Request;
 
+#[derive(Clone)]
 pub struct RequestList<D> {
-    db: Mutex<D>,
+    db: Arc<Mutex<D>>,
     pub requests: Vec<Request>,
 }
 
-type FlatRequestList = RequestList<FlatTable<String, String>>;
+pub type FlatRequestList = RequestList<FlatTable<String, String>>;
 
 impl FlatRequestList {
     pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
         RequestList {
-            db,
+            db: Arc::new(db),
             requests: vec![],
         }
     }
@@ -29,12 +33,46 @@ impl FlatRequestList {
             id.to_string(),
         )
     }
+
+    pub fn get_by_consumer(&self, consumer_id: u128) -> Option<Request> {
+        RequestList::get_by_attr::<FlatTable<String, String>, Request>(
+            &self.db,
+            "consumer",
+            consumer_id.to_string(),
+        )
+    }
+
+    pub fn get_by_service(&self, service_id: u128) -> Option<Request> {
+        RequestList::get_by_attr::<FlatTable<String, String>, Request>(
+            &self.db,
+            "service",
+            service_id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<Request> {
+        RequestList::get_all::<FlatTable<String, String>, Request>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Request> {
+        RequestList::search_records::<FlatTable<String, String>, Request>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        RequestList::count_records(&self.db, filter)
+    }
+
+    /// Logs a request. Its `id` is set by the caller so ledger entries can
+    /// reference it before the request is logged.
+    pub fn create(&self, record: Record<String, String>) -> Request {
+        RequestList::insert_record::<FlatTable<String, String>, Request>(&self.db, record)
+    }
 }
 
 impl ModelAble<String, String> for FlatRequestList {}
 impl From<HashMap<String, String>> for Request {
     fn from(map: HashMap<String, String>) -> Self {
-        return match (
+        match (
             map.get("id"),
             map.get("product_slug"),
             map.get("service_slug"),
@@ -73,7 +111,7 @@ impl From<HashMap<String, String>> for Request {
                 ),
             },
             _ => panic!("Can't convert!"),
-        };
+        }
     }
 }
 
@@ -101,5 +139,4 @@ mod tests {
 
         assert_eq!(request.id, id)
     }
-
 }
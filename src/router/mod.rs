@@ -0,0 +1,281 @@
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder, Response};
+
+use crate::service::Service;
+
+pub mod routes;
+pub mod stream;
+pub mod table;
+pub mod websocket;
+
+use stream::{Relay, Relayed};
+
+/// How long the gateway waits for an upstream service, or for the next part
+/// of a streamed response, unless configured.
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that only make sense for a single connection, so they are not
+/// relayed between the consumer and the upstream.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+    "trailer",
+    "content-length",
+];
+
+/// Returns the product slug of a host such as `product_a.uws.io`.
+pub fn product_slug(host: &str) -> &str {
+    host.split('.').next().unwrap_or_default()
+}
+
+/// Returns true if `name` can be relayed to the other side of the proxy.
+pub fn is_relayed(name: &str) -> bool {
+    !name.eq_ignore_ascii_case("host")
+        && !HOP_BY_HOP
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+}
+
+/// Builds the upstream URL of `path` on `service`.
+pub fn upstream_url(service: &Service, path: &str, query: Option<&str>) -> String {
+    let url = format!(
+        "{}/{}",
+        service.base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    match query {
+        Some(query) if !query.is_empty() => format!("{url}?{query}"),
+        _ => url,
+    }
+}
+
+/// Returns a client for calling upstream services, giving up when one stays
+/// silent for `timeout`, be it before answering or in the middle of a body.
+pub fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(timeout)
+        .read_timeout(timeout)
+        .build()
+        .expect("build upstream client")
+}
+
+/// A response received from an upstream service.
+#[derive(Debug)]
+pub struct Upstream {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub duration: Duration,
+}
+
+impl Upstream {
+    pub fn is_failure(&self) -> bool {
+        self.status >= 500
+    }
+}
+
+/// An upstream response whose body hasn't been read yet.
+pub struct UpstreamHead {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    started: Instant,
+    response: reqwest::Response,
+}
+
+impl UpstreamHead {
+    pub fn is_failure(&self) -> bool {
+        self.status >= 500
+    }
+
+    /// Reads the whole body.
+    pub async fn read(self) -> Result<Upstream, reqwest::Error> {
+        let body = self.response.bytes().await?.to_vec();
+        Ok(Upstream {
+            status: self.status,
+            headers: self.headers,
+            body,
+            duration: self.started.elapsed(),
+        })
+    }
+
+    /// Builds the consumer's response relaying the body as it arrives, e.g.
+    /// server-sent events, and calls `done` once the relay ends.
+    pub fn relay<'r>(self, done: impl FnOnce(Relayed) + Send + 'r) -> Response<'r> {
+        let mut response = Response::build();
+        response.status(Status::new(self.status));
+        for (name, value) in self.headers {
+            response.header_adjoin(Header::new(name, value));
+        }
+        response.streamed_body(Relay::new(self.response, self.started, done));
+        response.finalize()
+    }
+}
+
+/// Sends a request upstream, returning as soon as its response head arrives.
+pub async fn send(
+    client: &reqwest::Client,
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    body: impl Into<reqwest::Body>,
+) -> Result<UpstreamHead, reqwest::Error> {
+    let method = reqwest::Method::from_bytes(method.as_bytes()).unwrap_or(reqwest::Method::GET);
+    let mut request = client.request(method, url).body(body);
+    for (name, value) in headers.iter().filter(|(name, _)| is_relayed(name)) {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    let started = Instant::now();
+    let response = request.send().await?;
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| is_relayed(name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+
+    Ok(UpstreamHead {
+        status,
+        headers,
+        started,
+        response,
+    })
+}
+
+/// Sends a request upstream and reads its whole response.
+pub async fn forward(
+    client: &reqwest::Client,
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    body: impl Into<reqwest::Body>,
+) -> Result<Upstream, reqwest::Error> {
+    send(client, method, url, headers, body).await?.read().await
+}
+
+impl<'r> Responder<'r, 'static> for Upstream {
+    fn respond_to(self, _: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(Status::new(self.status));
+        for (name, value) in self.headers {
+            response.header_adjoin(Header::new(name, value));
+        }
+        response.sized_body(self.body.len(), Cursor::new(self.body));
+        response.ok()
+    }
+}
+
+/// A local HTTP server standing in for an upstream service in tests.
+#[cfg(test)]
+pub(crate) mod stub {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use tokio_tungstenite::tungstenite::accept_hdr;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    /// Serves one canned `response`, returning the server's base URL and a
+    /// receiver for the raw request it got.
+    pub fn serve_once(response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            // read the headers, then as much body as announced or chunked
+            loop {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if text
+                    .to_lowercase()
+                    .contains("transfer-encoding: chunked\r\n")
+                {
+                    if text.ends_with("\r\n0\r\n\r\n") || read == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(|value| value.trim().to_string())
+                        })
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            sender
+                .send(String::from_utf8_lossy(&request).to_string())
+                .unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        (base_url, receiver)
+    }
+
+    /// Accepts one WebSocket and reads from it until it closes, returning the
+    /// server's base URL and a receiver for the path it was opened at.
+    pub fn serve_websocket_once() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // the callback's error type is tungstenite's own
+            #[allow(clippy::result_large_err)]
+            let record_path = |request: &Request, response: Response| {
+                sender.send(request.uri().to_string()).unwrap();
+                Ok(response)
+            };
+            if let Ok(mut socket) = accept_hdr(stream, record_path) {
+                while socket.read().is_ok() {}
+            }
+        });
+        (base_url, receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn build_upstream_url_from_host_and_path() {
+        let service = Service::fake(&HashMap::from([("base_url", "http://127.0.0.1:8001/")]));
+
+        assert_eq!(product_slug("product_a.uws.io"), "product_a");
+        assert_eq!(
+            upstream_url(&service, "/users/1", Some("page=2")),
+            "http://127.0.0.1:8001/users/1?page=2"
+        );
+    }
+
+    #[test]
+    fn skip_hop_by_hop_headers() {
+        assert!(is_relayed("Content-Type"));
+        assert!(!is_relayed("Host"));
+        assert!(!is_relayed("Transfer-Encoding"));
+    }
+}
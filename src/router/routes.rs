@@ -0,0 +1,1413 @@
+use chrono::{NaiveDateTime, Utc};
+use rocket::data::{ByteUnit, Data};
+use rocket::http::{Method, Status};
+use rocket::outcome::Outcome as GuardOutcome;
+use rocket::response::Response;
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{Request, State};
+use uuid::Uuid;
+
+use crate::biller::headers::{set_quota_state, QuotaState};
+use crate::biller::{self, BillingError, Charge, Usage};
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::cache::{self, CachedResponse, ResponseCache, CACHE_HEADER};
+use crate::config::{
+    GatewayConfig, IdempotencyConfig, LimitsConfig, SandboxConfig, UpstreamConfig,
+};
+use crate::consumer::Consumer;
+use crate::db::Record;
+use crate::entitlement;
+use crate::errors::set_reason;
+use crate::guards::{check_billable, Authenticated};
+use crate::idempotency::{self, idempotency_list::FlatIdempotencyList, Claim, IDEMPOTENCY_KEY};
+use crate::job::{self, job_list::FlatJobList, Job, JobStatus};
+use crate::ledger::ledger_list::FlatLedgerList;
+use crate::logger;
+use crate::overage::overage_list::FlatOverageList;
+use crate::pricing::{self, pricing_list::FlatPricingRuleList, Metering, PricingRule};
+use crate::request::request_list::FlatRequestList;
+use crate::sandbox::{self, SandboxRequestList, SANDBOX_HEADER};
+use crate::service::{Service, WebSocketBilling};
+use crate::subscriber::{subscriber_list::FlatSubscriptionList, Subscription};
+use crate::transform::{self, transform_list::FlatTransformRuleList, Caller, TransformRule};
+
+use super::stream::{self, Ending, Relayed};
+use super::table::Routing;
+use super::websocket::{self, Meter, Tunnel};
+use super::{product_slug, send, upstream_url, Upstream, UpstreamHead};
+
+/// Proxied routes match anything, so they are tried after every other route.
+const PROXY_RANK: isize = 20;
+
+fn body_limit(req: &Request<'_>) -> ByteUnit {
+    match req.rocket().state::<GatewayConfig>() {
+        Some(config) => config.limits.body(),
+        None => LimitsConfig::default().body(),
+    }
+}
+
+/// The body size a request announces in its `Content-Length`, if any.
+fn announced_length(req: &Request<'_>) -> Option<u128> {
+    req.headers()
+        .get_one("Content-Length")
+        .and_then(|length| length.trim().parse::<u128>().ok())
+}
+
+/// The body of a proxied call. It is streamed upstream as the consumer sends
+/// it, unless it has to be read whole to be fingerprinted or kept.
+enum Payload<'r> {
+    Read(Vec<u8>),
+    Streamed(Box<Data<'r>>),
+}
+
+impl<'r> Payload<'r> {
+    async fn new(mut data: Data<'r>) -> Payload<'r> {
+        match data.peek(1).await.is_empty() && data.peek_complete() {
+            true => Payload::Read(vec![]),
+            false => Payload::Streamed(Box::new(data)),
+        }
+    }
+
+    /// Reads the whole body, up to the body limit.
+    async fn read(self, req: &'r Request<'_>) -> Result<Vec<u8>, Outcome<'r>> {
+        let data = match self {
+            Payload::Read(body) => return Ok(body),
+            Payload::Streamed(data) => *data,
+        };
+        match data.open(body_limit(req)).into_bytes().await {
+            Ok(body) if body.is_complete() => Ok(body.into_inner()),
+            Ok(_) => Err(fail(
+                req,
+                Status::PayloadTooLarge,
+                "Request body is too large".to_string(),
+            )),
+            Err(_) => Err(Outcome::Error(Status::BadRequest)),
+        }
+    }
+
+    /// The body size known before forwarding, for the estimated price.
+    fn len(&self, req: &Request<'_>) -> u128 {
+        match self {
+            Payload::Read(body) => body.len() as u128,
+            Payload::Streamed(_) => announced_length(req).unwrap_or_default(),
+        }
+    }
+}
+
+/// Why a call could not be sent upstream.
+enum Unsent {
+    /// The consumer's body grew too large or broke off.
+    Body(Status, String),
+    Upstream(reqwest::Error),
+}
+
+/// Sends a call upstream with its `payload` and returns the response head
+/// with the number of body bytes sent.
+async fn send_payload(
+    req: &Request<'_>,
+    client: &reqwest::Client,
+    url: &str,
+    headers: &[(String, String)],
+    payload: Payload<'_>,
+) -> Result<(UpstreamHead, u128), Unsent> {
+    let method = req.method().as_str();
+    let data = match payload {
+        Payload::Read(body) => {
+            let bytes = body.len() as u128;
+            return match send(client, method, url, headers, body).await {
+                Ok(head) => Ok((head, bytes)),
+                Err(e) => Err(Unsent::Upstream(e)),
+            };
+        }
+        Payload::Streamed(data) => *data,
+    };
+    let (body, pump) = stream::upload(data, body_limit(req));
+    let (head, pumped) = rocket::tokio::join!(send(client, method, url, headers, body), pump);
+    let bytes = pumped.map_err(|status| {
+        let reason = match status == Status::PayloadTooLarge {
+            true => "Request body is too large",
+            false => "Request body could not be read",
+        };
+        Unsent::Body(status, reason.to_string())
+    })?;
+    Ok((head.map_err(Unsent::Upstream)?, bytes))
+}
+
+/// Forwards `/<service>/<version>/<path..>` on a product host to the
+/// matching service the consumer is entitled to, charging its subscription
+/// for it.
+#[derive(Clone)]
+pub struct Proxy;
+
+fn fail<'r>(req: &'r Request<'_>, status: Status, reason: String) -> Outcome<'r> {
+    set_reason(req, reason);
+    Outcome::Error(status)
+}
+
+async fn state<'r, T: Send + Sync + 'static>(req: &'r Request<'_>) -> &'r T {
+    req.guard::<&State<T>>().await.unwrap().inner()
+}
+
+fn retention(req: &Request<'_>) -> chrono::Duration {
+    match req.rocket().state::<GatewayConfig>() {
+        Some(config) => config.idempotency.retention(),
+        None => IdempotencyConfig::default().retention(),
+    }
+}
+
+fn upstream_timeout(req: &Request<'_>) -> std::time::Duration {
+    match req.rocket().state::<GatewayConfig>() {
+        Some(config) => config.upstream.timeout(),
+        None => UpstreamConfig::default().timeout(),
+    }
+}
+
+fn fixtures_dir(req: &Request<'_>) -> std::path::PathBuf {
+    match req.rocket().state::<GatewayConfig>() {
+        Some(config) => config.sandbox.fixtures_dir.clone(),
+        None => SandboxConfig::default().fixtures_dir,
+    }
+}
+
+/// Returns the request's headers as the upstream should get them.
+fn upstream_headers(
+    req: &Request<'_>,
+    transforms: &[TransformRule],
+    caller: Caller,
+) -> Vec<(String, String)> {
+    let headers = req
+        .headers()
+        .iter()
+        .map(|header| (header.name().to_string(), header.value().to_string()))
+        .collect();
+    transform::request_headers(transforms, headers, caller)
+}
+
+/// Serves a sandbox key's call from the service's sandbox, or from its
+/// fixture if it has none, logging it apart and charging nothing.
+async fn serve_sandbox<'r>(
+    req: &'r Request<'_>,
+    consumer: &Consumer,
+    service: &Service,
+    transforms: &[TransformRule],
+    path: &str,
+    payload: Payload<'_>,
+) -> Outcome<'r> {
+    let request_id = Uuid::new_v4().to_string();
+    let query = req.uri().query().map(|query| query.as_str());
+    let (url, answer) = match sandbox::upstream(service) {
+        Some(sandboxed) => {
+            let url = upstream_url(&sandboxed, &transform::path(transforms, path), query);
+            let client = state::<reqwest::Client>(req).await;
+            let caller = Caller {
+                consumer_id: consumer.id,
+                subscriber_id: consumer.subscriber.id,
+                request_id: &request_id,
+            };
+            let headers = upstream_headers(req, transforms, caller);
+            let sent = match send_payload(req, client, &url, &headers, payload).await {
+                Ok((head, _)) => head.read().await.map_err(Unsent::Upstream),
+                Err(unsent) => Err(unsent),
+            };
+            let answer = match sent {
+                Ok(upstream) => Ok(upstream),
+                Err(Unsent::Body(status, reason)) => Err((status, reason)),
+                Err(Unsent::Upstream(e)) if e.is_timeout() => Err((
+                    Status::GatewayTimeout,
+                    format!(
+                        "Sandbox of service {} did not respond in time",
+                        service.slug
+                    ),
+                )),
+                Err(_) => Err((
+                    Status::BadGateway,
+                    format!("Sandbox of service {} is unreachable", service.slug),
+                )),
+            };
+            (url, answer)
+        }
+        None => {
+            let dir = fixtures_dir(req);
+            let answer = sandbox::fixture(&dir, service).ok_or_else(|| {
+                (
+                    Status::NotImplemented,
+                    format!("Service {} has no sandbox", service.slug),
+                )
+            });
+            (
+                sandbox::fixture_path(&dir, service).display().to_string(),
+                answer,
+            )
+        }
+    };
+
+    let status = match &answer {
+        Ok(upstream) => upstream.status,
+        Err((status, _)) => status.code,
+    };
+    state::<SandboxRequestList>(req).await.create(Record::from([
+        ("id".to_string(), request_id),
+        ("product_slug".to_string(), service.product.slug.clone()),
+        ("service_slug".to_string(), service.slug.clone()),
+        ("service_version".to_string(), service.version.clone()),
+        ("url".to_string(), url.replace(',', "%2C")),
+        ("status".to_string(), status.to_string()),
+        ("price".to_string(), "0".to_string()),
+        ("consumer".to_string(), consumer.id.to_string()),
+        ("service".to_string(), service.id.to_string()),
+    ]));
+
+    match answer {
+        Ok(mut upstream) => {
+            transform::response_headers(transforms, &mut upstream.headers);
+            upstream
+                .headers
+                .push((SANDBOX_HEADER.to_string(), "true".to_string()));
+            Outcome::from(req, upstream)
+        }
+        Err((status, reason)) => fail(req, status, reason),
+    }
+}
+
+/// Opens a WebSocket to the service and hands the consumer's upgraded
+/// connection over to relay it, charging as the service's
+/// `websocket_billing` says.
+async fn serve_websocket<'r>(
+    req: &'r Request<'_>,
+    consumer: Consumer,
+    service: Service,
+    transforms: &[TransformRule],
+    path: &str,
+) -> Outcome<'r> {
+    let accept = match websocket::accept_key(req) {
+        Some(accept) => accept,
+        None => {
+            return fail(
+                req,
+                Status::BadRequest,
+                "Invalid WebSocket handshake".to_string(),
+            )
+        }
+    };
+    if consumer.sandbox {
+        return fail(
+            req,
+            Status::NotImplemented,
+            "Sandbox keys can't open WebSockets".to_string(),
+        );
+    }
+    if check_billable(req, &consumer).await.is_err() {
+        return Outcome::Error(Status::PaymentRequired);
+    }
+
+    let request_id = Uuid::new_v4().to_string();
+    let caller = Caller {
+        consumer_id: consumer.id,
+        subscriber_id: consumer.subscriber.id,
+        request_id: &request_id,
+    };
+    let headers = upstream_headers(req, transforms, caller);
+    let url = websocket::socket_url(&upstream_url(
+        &service,
+        &transform::path(transforms, path),
+        req.uri().query().map(|query| query.as_str()),
+    ));
+    let subscriptions = state::<FlatSubscriptionList>(req).await;
+    let overages = state::<FlatOverageList>(req).await;
+    let subscription_id = consumer.subscriber.subscription.id;
+    let mut meter = Meter {
+        subscriptions,
+        ledger: state::<FlatLedgerList>(req).await,
+        overages,
+        budget_usages: state::<FlatBudgetUsageList>(req).await,
+        requests: state::<FlatRequestList>(req).await,
+        consumer,
+        service,
+        request_id,
+        url,
+        charged: 0,
+        status: None,
+    };
+
+    let (upstream, subprotocol) =
+        match websocket::connect(&meter.url, &headers, upstream_timeout(req)).await {
+            Ok(connected) => connected,
+            Err(status) => {
+                meter.status = Some(status.code);
+                let reason = match status == Status::GatewayTimeout {
+                    true => "did not respond in time",
+                    false => "is unreachable",
+                };
+                return fail(
+                    req,
+                    status,
+                    format!("Service {} {reason}", meter.service.slug),
+                );
+            }
+        };
+    // connections billed once or by the minute pay their first unit up front
+    if !meter.bills(WebSocketBilling::Message) {
+        if let Err(e) = meter.charge() {
+            return fail(req, Status::PaymentRequired, e);
+        }
+    }
+    meter.status = Some(Status::SwitchingProtocols.code);
+    report_quota(req, subscriptions, overages, subscription_id);
+
+    let mut response = Response::build();
+    response.raw_header("Sec-WebSocket-Accept", accept);
+    if let Some(subprotocol) = subprotocol {
+        response.raw_header("Sec-WebSocket-Protocol", subprotocol);
+    }
+    response.upgrade(websocket::PROTOCOL, Tunnel { upstream, meter });
+    Outcome::Success(response.finalize())
+}
+
+/// Everything needed to settle a charged call once its response was relayed.
+struct Settlement<'r> {
+    subscriptions: &'r FlatSubscriptionList,
+    ledger: &'r FlatLedgerList,
+    overages: &'r FlatOverageList,
+    budget_usages: &'r FlatBudgetUsageList,
+    requests: &'r FlatRequestList,
+    service: Service,
+    rules: Vec<PricingRule>,
+    metering: Metering,
+    subscription_id: u128,
+    consumer_id: u128,
+    period: NaiveDateTime,
+    reserved: Charge,
+    reserved_budget: u128,
+    request_id: String,
+    url: String,
+}
+
+impl Settlement<'_> {
+    /// Replaces the reserved price with the price of what was `relayed` and
+    /// logs the call, returning that price. Calls the upstream failed are not
+    /// charged, while a consumer that hangs up pays for what it got until then.
+    fn settle(mut self, status: u16, relayed: Option<Relayed>) -> u128 {
+        let (status, price) = match relayed {
+            Some(relayed) if relayed.ending == Ending::Failed => (Status::BadGateway.code, 0),
+            Some(relayed) if status < 500 => {
+                self.metering.response_bytes = relayed.bytes;
+                self.metering.duration = relayed.duration;
+                let price = pricing::settle(&self.service, &self.rules, &self.metering);
+                (status, price)
+            }
+            _ => (status, 0),
+        };
+        let usage = Usage {
+            tokens: price,
+            reason: &self.service.slug,
+            request_id: Some(&self.request_id),
+        };
+        let now = Utc::now().naive_utc();
+        if let Err(e) = biller::settle(
+            self.subscriptions,
+            self.ledger,
+            self.overages,
+            self.subscription_id,
+            &self.reserved,
+            usage,
+            now,
+        ) {
+            logger::log(&format!(
+                "Request {} could not be settled: {e}",
+                self.request_id
+            ));
+        }
+        biller::settle_budget(
+            self.budget_usages,
+            self.consumer_id,
+            self.period,
+            self.reserved_budget,
+            price,
+        );
+
+        self.requests.create(Record::from([
+            ("id".to_string(), self.request_id),
+            (
+                "product_slug".to_string(),
+                self.service.product.slug.clone(),
+            ),
+            ("service_slug".to_string(), self.service.slug.clone()),
+            ("service_version".to_string(), self.service.version.clone()),
+            ("url".to_string(), self.url.replace(',', "%2C")),
+            ("status".to_string(), status.to_string()),
+            ("price".to_string(), price.to_string()),
+            ("consumer".to_string(), self.consumer_id.to_string()),
+            ("service".to_string(), self.service.id.to_string()),
+        ]));
+        price
+    }
+}
+
+/// Reports the quota of the subscription as it stands on the response.
+fn report_quota(
+    req: &Request<'_>,
+    subscriptions: &FlatSubscriptionList,
+    overages: &FlatOverageList,
+    subscription_id: u128,
+) {
+    if let Some(updated) = subscriptions.get_by_id(subscription_id) {
+        let overage_used = biller::overage_used(&updated, &overages.list());
+        set_quota_state(req, QuotaState::new(&updated, overage_used));
+    }
+}
+
+/// Answers a call the upstream couldn't be reached for.
+fn upstream_error<'r>(req: &'r Request<'_>, service: &Service, e: &reqwest::Error) -> Outcome<'r> {
+    match e.is_timeout() {
+        true => fail(
+            req,
+            Status::GatewayTimeout,
+            format!("Service {} did not respond in time", service.slug),
+        ),
+        false => fail(
+            req,
+            Status::BadGateway,
+            format!("Service {} is unreachable", service.slug),
+        ),
+    }
+}
+
+/// Reserves the estimated price of a call from the consumer's budget and
+/// quota, so concurrent calls can't overspend. Returns the subscription it was
+/// reserved from, the start of its period and what was taken from the quota.
+async fn reserve(
+    req: &Request<'_>,
+    consumer: &Consumer,
+    reservation: Usage<'_>,
+) -> Result<(Subscription, NaiveDateTime, Charge), BillingError> {
+    let subscriptions = state::<FlatSubscriptionList>(req).await;
+    let subscription = subscriptions
+        .get_by_id(consumer.subscriber.subscription.id)
+        .unwrap_or_else(|| consumer.subscriber.subscription.clone());
+    let period = subscription.period_start();
+    let budget_usages = state::<FlatBudgetUsageList>(req).await;
+    let tokens = reservation.tokens;
+    biller::reserve_budget(budget_usages, consumer, period, tokens)?;
+    let reserved = biller::charge(
+        subscriptions,
+        state::<FlatLedgerList>(req).await,
+        state::<FlatOverageList>(req).await,
+        &subscription,
+        reservation,
+        Utc::now().naive_utc(),
+    )
+    .inspect_err(|_| biller::settle_budget(budget_usages, consumer.id, period, tokens, 0))?;
+    Ok((subscription, period, reserved))
+}
+
+/// Gives up the idempotency key claimed by a request that wasn't served.
+async fn release(req: &Request<'_>, claim: Option<u128>) {
+    if let Some(id) = claim {
+        state::<FlatIdempotencyList>(req).await.release(id);
+    }
+}
+
+/// Answers a call with the response cached for it, charging `price` rather
+/// than calling the service.
+async fn serve_cached<'r>(
+    req: &'r Request<'_>,
+    consumer: &Consumer,
+    service: &Service,
+    cached: CachedResponse,
+    price: u128,
+    claim: Option<u128>,
+    url: &str,
+) -> Outcome<'r> {
+    let subscriptions = state::<FlatSubscriptionList>(req).await;
+    let overages = state::<FlatOverageList>(req).await;
+    let budget_usages = state::<FlatBudgetUsageList>(req).await;
+    let subscription = subscriptions
+        .get_by_id(consumer.subscriber.subscription.id)
+        .unwrap_or_else(|| consumer.subscriber.subscription.clone());
+    let period = subscription.period_start();
+    let request_id = Uuid::new_v4().to_string();
+    if let Err(e) = biller::reserve_budget(budget_usages, consumer, period, price) {
+        release(req, claim).await;
+        return fail(req, Status::PaymentRequired, e.to_string());
+    }
+    let usage = Usage {
+        tokens: price,
+        reason: &service.slug,
+        request_id: Some(&request_id),
+    };
+    let now = Utc::now().naive_utc();
+    let ledger = state::<FlatLedgerList>(req).await;
+    if let Err(e) = biller::charge(subscriptions, ledger, overages, &subscription, usage, now) {
+        biller::settle_budget(budget_usages, consumer.id, period, price, 0);
+        release(req, claim).await;
+        return fail(req, Status::PaymentRequired, e.to_string());
+    }
+
+    state::<FlatRequestList>(req).await.create(Record::from([
+        ("id".to_string(), request_id),
+        ("product_slug".to_string(), service.product.slug.clone()),
+        ("service_slug".to_string(), service.slug.clone()),
+        ("service_version".to_string(), service.version.clone()),
+        ("url".to_string(), url.replace(',', "%2C")),
+        ("status".to_string(), cached.status.to_string()),
+        ("price".to_string(), price.to_string()),
+        ("consumer".to_string(), consumer.id.to_string()),
+        ("service".to_string(), service.id.to_string()),
+    ]));
+    report_quota(req, subscriptions, overages, subscription.id);
+    if let Some(id) = claim {
+        state::<FlatIdempotencyList>(req)
+            .await
+            .complete(id, &cached.hit());
+    }
+    Outcome::from(req, cached.hit())
+}
+
+/// Queues a call that prefers an asynchronous answer as a job, answering
+/// `202` with where to follow it. Nothing is charged until the job is done.
+async fn queue_job<'r>(
+    req: &'r Request<'_>,
+    consumer: &Consumer,
+    service: &Service,
+    transforms: &[TransformRule],
+    claim: Option<u128>,
+    url: String,
+    body: Vec<u8>,
+) -> Outcome<'r> {
+    let callback_url = req.headers().get_one(job::CALLBACK_URL).map(str::trim);
+    let secret = &consumer.subscriber.webhook_secret;
+    if let Some(Err(e)) = callback_url.map(|url| job::check_callback_url(url, secret)) {
+        release(req, claim).await;
+        return fail(req, Status::BadRequest, e);
+    }
+    let request_id = Uuid::new_v4().to_string();
+    let caller = Caller {
+        consumer_id: consumer.id,
+        subscriber_id: consumer.subscriber.id,
+        request_id: &request_id,
+    };
+    let mut headers = upstream_headers(req, transforms, caller);
+    headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case(job::PREFER) && !name.eq_ignore_ascii_case(job::CALLBACK_URL)
+    });
+    // the estimated price is reserved now and settled once the job is done
+    let metering = Metering {
+        method: req.method().as_str().to_string(),
+        request_bytes: body.len() as u128,
+        ..Metering::default()
+    };
+    let rules = state::<FlatPricingRuleList>(req)
+        .await
+        .list_by_service(service.id);
+    let reservation = Usage {
+        tokens: pricing::estimate(service, &rules, &metering),
+        reason: &service.slug,
+        request_id: Some(&request_id),
+    };
+    let reserved = match reserve(req, consumer, reservation).await {
+        Ok((_, _, reserved)) => reserved,
+        Err(e) => {
+            release(req, claim).await;
+            return fail(req, Status::PaymentRequired, e.to_string());
+        }
+    };
+    let queued = state::<FlatJobList>(req).await.queue(Job {
+        id: 0,
+        consumer_id: consumer.id,
+        service_id: service.id,
+        request_id,
+        method: req.method().as_str().to_string(),
+        url,
+        headers,
+        body,
+        callback_url: callback_url.map(str::to_string),
+        status: JobStatus::Queued,
+        response_status: None,
+        response_headers: vec![],
+        response_body: vec![],
+        price: 0,
+        reserved,
+        created_at: Utc::now().naive_utc(),
+        completed_at: None,
+    });
+
+    let accepted = Upstream {
+        status: Status::Accepted.code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Location".to_string(), format!("/jobs/{}", queued.id)),
+        ],
+        body: rocket::serde::json::to_string(&queued)
+            .expect("serialize job")
+            .into_bytes(),
+        duration: Default::default(),
+    };
+    if let Some(id) = claim {
+        state::<FlatIdempotencyList>(req)
+            .await
+            .complete(id, &accepted);
+    }
+    Outcome::from(req, accepted)
+}
+
+#[rocket::async_trait]
+impl Handler for Proxy {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let consumer = match req.guard::<Authenticated>().await {
+            GuardOutcome::Success(authenticated) => authenticated.0,
+            GuardOutcome::Error((status, _)) => return Outcome::Error(status),
+            GuardOutcome::Forward(status) => return Outcome::Forward((data, status)),
+        };
+
+        let segments = req.routed_segments(0..).collect::<Vec<&str>>();
+        let host = req.headers().get_one("Host").unwrap_or_default();
+        let routes = state::<Routing>(req).await.current();
+        let service = match routes.resolve(product_slug(host), segments[0], segments[1]) {
+            Some(service) => service.clone(),
+            None => {
+                return fail(
+                    req,
+                    Status::NotFound,
+                    format!("No service at /{}/{}", segments[0], segments[1]),
+                )
+            }
+        };
+        // a call the plan or key doesn't cover is refused before billing
+        if let Err(reason) = entitlement::authorize(&consumer, &service) {
+            return fail(req, Status::Forbidden, reason);
+        }
+
+        let transforms = state::<FlatTransformRuleList>(req)
+            .await
+            .list_by_service(service.id);
+        if websocket::is_upgrade(req) {
+            let path = segments[2..].join("/");
+            return serve_websocket(req, consumer, service, &transforms, &path).await;
+        }
+
+        if announced_length(req).is_some_and(|length| length > body_limit(req).as_u128()) {
+            return fail(
+                req,
+                Status::PayloadTooLarge,
+                "Request body is too large".to_string(),
+            );
+        }
+        let mut payload = Payload::new(data).await;
+        // sandbox keys go through the same checks but are never charged
+        if consumer.sandbox {
+            let path = segments[2..].join("/");
+            return serve_sandbox(req, &consumer, &service, &transforms, &path, payload).await;
+        }
+        // retries with the same key get the first response back, uncharged
+        let claim = match req.headers().get_one(IDEMPOTENCY_KEY) {
+            Some(key) if !idempotency::is_valid_key(key) => {
+                return fail(
+                    req,
+                    Status::BadRequest,
+                    format!(
+                        "{IDEMPOTENCY_KEY} should be 1 to 255 visible characters without commas"
+                    ),
+                )
+            }
+            Some(key) => {
+                let body = match payload.read(req).await {
+                    Ok(body) => body,
+                    Err(outcome) => return outcome,
+                };
+                let fingerprint = idempotency::fingerprint(
+                    req.method().as_str(),
+                    product_slug(host),
+                    &req.uri().to_string(),
+                    &body,
+                );
+                payload = Payload::Read(body);
+                let now = Utc::now().naive_utc();
+                let expired_before = now - retention(req);
+                match state::<FlatIdempotencyList>(req).await.claim(
+                    consumer.id,
+                    key,
+                    &fingerprint,
+                    now,
+                    expired_before,
+                ) {
+                    Claim::New(claimed) => Some(claimed.id),
+                    Claim::Replay(answered) => return Outcome::from(req, answered.replay()),
+                    Claim::InProgress => {
+                        return fail(
+                            req,
+                            Status::Conflict,
+                            format!("A request with this {IDEMPOTENCY_KEY} is still in progress"),
+                        )
+                    }
+                    Claim::Mismatch => {
+                        return fail(
+                            req,
+                            Status::UnprocessableEntity,
+                            format!("{IDEMPOTENCY_KEY} was already used for a different request"),
+                        )
+                    }
+                }
+            }
+            None => None,
+        };
+        if check_billable(req, &consumer).await.is_err() {
+            release(req, claim).await;
+            return Outcome::Error(Status::PaymentRequired);
+        }
+        let path = segments[2..].join("/");
+        let query = req.uri().query().map(|query| query.as_str());
+        let url = upstream_url(&service, &transform::path(&transforms, &path), query);
+        // identical GET calls of a subscriber to a caching service share one
+        // response
+        let caching = match req.rocket().state::<ResponseCache>() {
+            Some(cache) if service.cache_ttl.is_some() && req.method() == Method::Get => {
+                let headers = req
+                    .headers()
+                    .iter()
+                    .map(|header| (header.name().to_string(), header.value().to_string()))
+                    .collect::<Vec<_>>();
+                let key = cache::key(&service, consumer.subscriber.id, &path, query, &headers);
+                Some((cache, key))
+            }
+            _ => None,
+        };
+        if let Some((cache, key)) = &caching {
+            if let Some(cached) = cache.lookup(key, Utc::now().naive_utc()) {
+                let price = cache.hit_price(&cached);
+                return serve_cached(req, &consumer, &service, cached, price, claim, &url).await;
+            }
+        }
+        if req
+            .headers()
+            .get_one(job::PREFER)
+            .is_some_and(job::is_async)
+        {
+            let body = match payload.read(req).await {
+                Ok(body) => body,
+                Err(outcome) => {
+                    release(req, claim).await;
+                    return outcome;
+                }
+            };
+            return queue_job(req, &consumer, &service, &transforms, claim, url, body).await;
+        }
+        let metering = Metering {
+            method: req.method().as_str().to_string(),
+            request_bytes: payload.len(req),
+            ..Metering::default()
+        };
+        let rules = state::<FlatPricingRuleList>(req)
+            .await
+            .list_by_service(service.id);
+
+        let request_id = Uuid::new_v4().to_string();
+        let reservation = Usage {
+            tokens: pricing::estimate(&service, &rules, &metering),
+            reason: &service.slug,
+            request_id: Some(&request_id),
+        };
+        let reserved_budget = reservation.tokens;
+        let (subscription, period, reserved) = match reserve(req, &consumer, reservation).await {
+            Ok(reserved) => reserved,
+            Err(e) => {
+                release(req, claim).await;
+                return fail(req, Status::PaymentRequired, e.to_string());
+            }
+        };
+        let subscriptions = state::<FlatSubscriptionList>(req).await;
+        let ledger = state::<FlatLedgerList>(req).await;
+        let overages = state::<FlatOverageList>(req).await;
+        let budget_usages = state::<FlatBudgetUsageList>(req).await;
+
+        let caller = Caller {
+            consumer_id: consumer.id,
+            subscriber_id: consumer.subscriber.id,
+            request_id: &request_id,
+        };
+        let headers = upstream_headers(req, &transforms, caller);
+        let mut settlement = Settlement {
+            subscriptions,
+            ledger,
+            overages,
+            budget_usages,
+            requests: state::<FlatRequestList>(req).await,
+            service: service.clone(),
+            rules,
+            metering,
+            subscription_id: subscription.id,
+            consumer_id: consumer.id,
+            period,
+            reserved,
+            reserved_budget,
+            request_id: request_id.clone(),
+            url: url.clone(),
+        };
+        let client = state::<reqwest::Client>(req).await;
+        let mut head = match send_payload(req, client, &url, &headers, payload).await {
+            Ok((head, sent)) => {
+                settlement.metering.request_bytes = sent;
+                head
+            }
+            Err(unsent) => {
+                let status = match &unsent {
+                    Unsent::Body(status, _) => *status,
+                    Unsent::Upstream(_) => Status::BadGateway,
+                };
+                settlement.settle(status.code, None);
+                report_quota(req, subscriptions, overages, subscription.id);
+                release(req, claim).await;
+                return match unsent {
+                    Unsent::Body(status, reason) => fail(req, status, reason),
+                    Unsent::Upstream(e) => upstream_error(req, &service, &e),
+                };
+            }
+        };
+        settlement.metering.headers = head
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), value.clone()))
+            .collect();
+        // after metering, so usage headers can be hidden from the consumer
+        transform::response_headers(&transforms, &mut head.headers);
+
+        // a response that may be replayed or cached is read whole before it's
+        // answered
+        if claim.is_some() || caching.is_some() {
+            let mut upstream = head.read().await;
+            let price = match &upstream {
+                Ok(upstream) => {
+                    let relayed = Relayed {
+                        ending: Ending::Finished,
+                        bytes: upstream.body.len() as u128,
+                        duration: upstream.duration,
+                    };
+                    settlement.settle(upstream.status, Some(relayed))
+                }
+                Err(_) => settlement.settle(Status::BadGateway.code, None),
+            };
+            report_quota(req, subscriptions, overages, subscription.id);
+            if let (Ok(upstream), Some((cache, key))) = (&mut upstream, &caching) {
+                let ttl = cache::ttl(service.cache_ttl.unwrap_or_default(), &upstream.headers);
+                if let Some(ttl) = ttl.filter(|_| upstream.status == Status::Ok.code) {
+                    cache.keep(key, upstream, price, ttl, Utc::now().naive_utc());
+                }
+                upstream
+                    .headers
+                    .push((CACHE_HEADER.to_string(), "MISS".to_string()));
+            }
+            // failed calls can be retried with the same key
+            return match upstream {
+                Ok(upstream) if !upstream.is_failure() => {
+                    if let Some(id) = claim {
+                        state::<FlatIdempotencyList>(req)
+                            .await
+                            .complete(id, &upstream);
+                    }
+                    Outcome::from(req, upstream)
+                }
+                Ok(upstream) => {
+                    release(req, claim).await;
+                    Outcome::from(req, upstream)
+                }
+                Err(e) => {
+                    release(req, claim).await;
+                    upstream_error(req, &service, &e)
+                }
+            };
+        }
+
+        // other responses are relayed as they arrive and settled once they
+        // end, so the quota reported still holds the reservation
+        report_quota(req, subscriptions, overages, subscription.id);
+        let status = head.status;
+        Outcome::Success(head.relay(move |relayed| {
+            settlement.settle(status, Some(relayed));
+        }))
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    [
+        Method::Get,
+        Method::Post,
+        Method::Put,
+        Method::Patch,
+        Method::Delete,
+    ]
+    .into_iter()
+    .map(|method| Route::ranked(PROXY_RANK, method, "/<service>/<version>/<path..>", Proxy))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::budget::budget_list::BudgetUsageList;
+    use crate::config::CacheConfig;
+    use crate::consumer::consumer_list::ConsumerList;
+    use crate::db::file_db::{read_from_string, FlatTable};
+    use crate::idempotency::idempotency_list::IdempotencyList;
+    use crate::job::job_list::JobList;
+    use crate::ledger::ledger_list::LedgerList;
+    use crate::overage::overage_list::OverageList;
+    use crate::pricing::pricing_list::PricingRuleList;
+    use crate::request::request_list::RequestList;
+    use crate::router::table::RoutingTable;
+    use crate::router::{client, stub, UPSTREAM_TIMEOUT};
+    use crate::subscriber::subscriber_list::SubscriptionList;
+    use crate::transform::transform_list::TransformRuleList;
+
+    fn table(contents: &str) -> Mutex<FlatTable<String, String>> {
+        Mutex::new(FlatTable::new_from_string(contents.to_string()))
+    }
+
+    /// A gateway routing `service_a`, and `cached_a` whose responses are
+    /// cached, to `base_url`, changing calls to `service_a` by the
+    /// `transforms` rows.
+    fn gateway(base_url: &str, transforms: &str) -> rocket::Rocket<rocket::Build> {
+        let routing = RoutingTable::build(
+            &read_from_string("id, slug, requests\n1, product_a, 0"),
+            &read_from_string(&format!(
+                "id, name, slug, version, status, base_url, price, requests, product, cache_ttl\n\
+                 1, Service A, service_a, v1.0.0, 1, {base_url}, 2, 10, 1, \n\
+                 2, Cached A, cached_a, v1.0.0, 1, {base_url}, 2, 10, 1, 60"
+            )),
+        )
+        .unwrap();
+        rocket::build()
+            .mount("/", routes())
+            .manage(ConsumerList::new(table(
+                "id, subscriber, access_token\n1, 2, key-1",
+            )))
+            .manage(Routing::new(routing))
+            .manage(PricingRuleList::new(table(
+                "id, service, kind, param, price\n1, 1, usage_header, X-Usage-Tokens, 1",
+            )))
+            .manage(TransformRuleList::new(table(&format!(
+                "id, service, kind, param, value\n{transforms}"
+            ))))
+            .manage(SubscriptionList::new(table(
+                "id, name, status, price, quota, expiry_date, auto_renew, plan\n\
+                 2, Golden 50, 1, 50000, 10, 2030-10-01 00:00:00, false, 2",
+            )))
+            .manage(LedgerList::new(table(
+                "id, subscription, kind, amount, reason, request, created_at\n\
+                 1, 2, credit, 10, opening balance, , 2023-01-01 00:00:00",
+            )))
+            .manage(OverageList::new(table(
+                "id, subscription, request, tokens, price, created_at",
+            )))
+            .manage(RequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )))
+            .manage(BudgetUsageList::new(table("id, consumer, period, tokens")))
+            .manage(IdempotencyList::new(table(
+                "id, consumer, key, fingerprint, status, headers, body, created_at",
+            )))
+            .manage(JobList::new(table(
+                "id, consumer, service, request, method, url, headers, body, callback_url, \
+                 status, response_status, response_headers, response_body, price, reserved, \
+                 reserved_overage, created_at,                  completed_at",
+            )))
+            .manage(ResponseCache::new(&CacheConfig {
+                hit_price_percent: 50,
+                ..CacheConfig::default()
+            }))
+            .manage(client(UPSTREAM_TIMEOUT))
+    }
+
+    #[test]
+    fn proxy_and_settle_price() {
+        let (base_url, received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nX-Usage-Tokens: 4\r\nContent-Length: 2\r\n\r\nok");
+        let rocket = gateway(&base_url, "");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/users/1?page=2")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), "ok");
+        assert!(received
+            .recv()
+            .unwrap()
+            .starts_with("GET /users/1?page=2 HTTP/1.1\r\n"));
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 4);
+        let requests = rocket.state::<FlatRequestList>().unwrap().list();
+        assert_eq!(requests[0].price, 6);
+        assert_eq!(requests[0].status, 200);
+        let budget_usages = rocket.state::<FlatBudgetUsageList>().unwrap().list();
+        assert_eq!(budget_usages[0].tokens, 6);
+    }
+
+    #[test]
+    fn stream_request_body_to_service() {
+        let (base_url, received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nX-Usage-Tokens: 1\r\nContent-Length: 2\r\n\r\nok");
+        let client = Client::tracked(gateway(&base_url, "")).expect("valid rocket instance");
+        let body = "~".repeat(40_000);
+
+        let response = client
+            .post("/service_a/v1.0.0/uploads")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .body(&body)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().unwrap(), "ok");
+        let request = received.recv().unwrap();
+        assert!(request
+            .to_lowercase()
+            .contains("transfer-encoding: chunked\r\n"));
+        assert_eq!(request.matches('~').count(), 40_000);
+        let rocket = client.rocket();
+        assert_eq!(
+            rocket.state::<FlatRequestList>().unwrap().list()[0].price,
+            3
+        );
+    }
+
+    #[test]
+    fn refuse_request_body_over_the_limit() {
+        let (base_url, _received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let config = GatewayConfig {
+            limits: LimitsConfig {
+                body_mib: 1,
+                ..LimitsConfig::default()
+            },
+            ..GatewayConfig::default()
+        };
+        let client = Client::tracked(gateway(&base_url, "").manage(config)).expect("valid rocket");
+
+        let response = client
+            .post("/service_a/v1.0.0/uploads")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .body(vec![0; 2 * 1024 * 1024])
+            .dispatch();
+
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 10);
+    }
+
+    #[test]
+    fn serve_repeated_call_from_cache_at_hit_price() {
+        let (base_url, _received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let client = Client::tracked(gateway(&base_url, "")).expect("valid rocket instance");
+        let get = || {
+            client
+                .get("/cached_a/v1.0.0/users/1?page=2")
+                .header(Header::new("Host", "product_a.uws.io"))
+                .header(Header::new("x-api-key", "key-1"))
+                .dispatch()
+        };
+
+        let miss = get();
+        assert_eq!(miss.headers().get_one(CACHE_HEADER), Some("MISS"));
+        assert_eq!(miss.into_string().unwrap(), "ok");
+        let hit = get();
+        assert_eq!(hit.status(), Status::Ok);
+        assert_eq!(hit.headers().get_one(CACHE_HEADER), Some("HIT"));
+        assert_eq!(hit.into_string().unwrap(), "ok");
+
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 7);
+        let requests = rocket.state::<FlatRequestList>().unwrap().list();
+        let prices = requests
+            .iter()
+            .map(|request| request.price)
+            .collect::<Vec<_>>();
+        assert_eq!(prices, [2, 1]);
+    }
+
+    #[test]
+    fn queue_async_call_as_job_reserving_its_price() {
+        let client = Client::tracked(gateway("http://127.0.0.1:9", "")).expect("valid rocket");
+        let post = |callback_url: Option<&str>| {
+            let mut request = client
+                .post("/service_a/v1.0.0/reports")
+                .header(Header::new("Host", "product_a.uws.io"))
+                .header(Header::new("x-api-key", "key-1"))
+                .header(Header::new(job::PREFER, "respond-async"))
+                .body("{}");
+            if let Some(url) = callback_url {
+                request = request.header(Header::new(job::CALLBACK_URL, url.to_string()));
+            }
+            request.dispatch()
+        };
+
+        let accepted = post(None);
+        assert_eq!(accepted.status(), Status::Accepted);
+        assert_eq!(accepted.headers().get_one("Location"), Some("/jobs/1"));
+        assert_eq!(post(Some("hooks")).status(), Status::BadRequest);
+        // the subscriber has no webhook secret to sign results with
+        let unsigned = post(Some("https://hooks.example.com/jobs"));
+        assert_eq!(unsigned.status(), Status::BadRequest);
+
+        let rocket = client.rocket();
+        let jobs = rocket.state::<FlatJobList>().unwrap().list();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].status, JobStatus::Queued);
+        assert_eq!(jobs[0].url, "http://127.0.0.1:9/reports");
+        assert!(!jobs[0]
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(job::PREFER)));
+        assert_eq!(jobs[0].reserved.debited, 2);
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 8);
+        let budget_usages = rocket.state::<FlatBudgetUsageList>().unwrap().list();
+        assert_eq!(budget_usages[0].tokens, 2);
+        assert!(rocket.state::<FlatRequestList>().unwrap().list().is_empty());
+    }
+
+    #[test]
+    fn transform_call_between_consumer_and_upstream() {
+        let (base_url, received) = stub::serve_once(
+            "HTTP/1.1 200 OK\r\nX-Usage-Tokens: 4\r\nServer: stub\r\nContent-Length: 2\r\n\r\nok",
+        );
+        let transforms = "\
+            1, 1, set_request_header, Authorization, Bearer upstream\n\
+            2, 1, rewrite_path_prefix, /users, /v2/accounts\n\
+            3, 1, remove_response_header, X-Usage-Tokens,\n\
+            4, 1, set_response_header, Server, uws";
+        let client =
+            Client::tracked(gateway(&base_url, transforms)).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/users/1")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .header(Header::new("X-Consumer-Id", "9"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Usage-Tokens"), None);
+        assert_eq!(response.headers().get_one("Server"), Some("uws"));
+        assert_eq!(response.into_string().unwrap(), "ok");
+        let upstream = received.recv().unwrap().to_lowercase();
+        assert!(upstream.starts_with("get /v2/accounts/1 http/1.1\r\n"));
+        assert!(!upstream.contains("x-api-key"));
+        assert!(upstream.contains("authorization: bearer upstream\r\n"));
+        assert!(upstream.contains("x-consumer-id: 1\r\n"));
+        assert!(upstream.contains("x-subscriber-id: 2\r\n"));
+        let request_id = client.rocket().state::<FlatRequestList>().unwrap().list()[0]
+            .id
+            .clone();
+        assert!(upstream.contains(&format!("x-request-id: {request_id}\r\n")));
+        // the hidden usage header was still charged for
+        let subscriptions = client.rocket().state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 4);
+    }
+
+    #[test]
+    fn stream_events_and_settle_at_the_end() {
+        let (base_url, _received) = stub::serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n\
+             data: 1\n\ndata: 2\n\n",
+        );
+        let client = Client::tracked(gateway(&base_url, "")).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/events")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Content-Type"),
+            Some("text/event-stream")
+        );
+        assert_eq!(response.into_string().unwrap(), "data: 1\n\ndata: 2\n\n");
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 8);
+        let requests = rocket.state::<FlatRequestList>().unwrap().list();
+        assert_eq!(requests[0].price, 2);
+    }
+
+    #[test]
+    fn release_reservation_of_broken_stream() {
+        let (base_url, _received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\ndata");
+        let client = Client::tracked(gateway(&base_url, "")).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/events")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .dispatch();
+
+        // the head was already relayed when the body broke off
+        assert_eq!(response.status(), Status::Ok);
+        let _ = response.into_bytes();
+
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 10);
+        let requests = rocket.state::<FlatRequestList>().unwrap().list();
+        assert_eq!(requests[0].price, 0);
+        assert_eq!(requests[0].status, 502);
+        let budget_usages = rocket.state::<FlatBudgetUsageList>().unwrap().list();
+        assert!(budget_usages.iter().all(|usage| usage.tokens == 0));
+    }
+
+    #[test]
+    fn open_websocket_charged_per_connection() {
+        let (base_url, opened) = stub::serve_websocket_once();
+        let client = Client::tracked(gateway(&base_url, "")).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/chat")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .header(Header::new("Connection", "Upgrade"))
+            .header(Header::new("Upgrade", "websocket"))
+            .header(Header::new("Sec-WebSocket-Version", "13"))
+            .header(Header::new("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .dispatch();
+
+        assert_eq!(
+            response.headers().get_one("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(opened.recv().unwrap(), "/chat");
+        drop(response);
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 8);
+        let requests = rocket.state::<FlatRequestList>().unwrap().list();
+        assert_eq!(requests[0].status, 101);
+        assert_eq!(requests[0].price, 2);
+    }
+
+    #[test]
+    fn refuse_service_outside_key_scopes() {
+        let routing = RoutingTable::build(
+            &read_from_string("id, slug, requests\n1, product_a, 0"),
+            &read_from_string(
+                "id, name, slug, version, status, base_url, price, requests, product\n\
+                 1, Service A, service_a, v1.0.0, 1, http://127.0.0.1:9, 2, 10, 1",
+            ),
+        )
+        .unwrap();
+        let rocket = rocket::build()
+            .mount("/", routes())
+            .manage(ConsumerList::new(table(
+                "id, subscriber, access_token, scopes\n1, 2, key-1, product_b",
+            )))
+            .manage(Routing::new(routing))
+            .manage(RequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )));
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/users/1")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(client
+            .rocket()
+            .state::<FlatRequestList>()
+            .unwrap()
+            .list()
+            .is_empty());
+    }
+
+    #[test]
+    fn serve_sandbox_key_from_sandbox_url() {
+        let (sandbox_url, received) =
+            stub::serve_once("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nmock");
+        let routing = RoutingTable::build(
+            &read_from_string("id, slug, requests\n1, product_a, 0"),
+            &read_from_string(&format!(
+                "id, name, slug, version, status, base_url, price, requests, product, sandbox_url\n\
+                 1, Service A, service_a, v1.0.0, 1, http://127.0.0.1:9, 2, 10, 1, {sandbox_url}"
+            )),
+        )
+        .unwrap();
+        let rocket = rocket::build()
+            .mount("/", routes())
+            .manage(ConsumerList::new(table(
+                "id, subscriber, access_token, sandbox\n1, 2, key-1, true",
+            )))
+            .manage(Routing::new(routing))
+            .manage(RequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )))
+            .manage(SandboxRequestList::new(table(
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+            )))
+            .manage(TransformRuleList::new(table("id, service, kind, param, value")))
+            .manage(client(UPSTREAM_TIMEOUT));
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/service_a/v1.0.0/users/1")
+            .header(Header::new("Host", "product_a.uws.io"))
+            .header(Header::new("x-api-key", "key-1"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(SANDBOX_HEADER), Some("true"));
+        assert_eq!(response.into_string().unwrap(), "mock");
+        assert!(received
+            .recv()
+            .unwrap()
+            .starts_with("GET /users/1 HTTP/1.1\r\n"));
+        let rocket = client.rocket();
+        assert!(rocket.state::<FlatRequestList>().unwrap().list().is_empty());
+        let logged = rocket.state::<SandboxRequestList>().unwrap().list();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].price, 0);
+    }
+
+    #[test]
+    fn replay_retried_request_without_charging() {
+        let (base_url, _received) = stub::serve_once(
+            "HTTP/1.1 201 Created\r\nX-Usage-Tokens: 4\r\nContent-Length: 7\r\n\r\ncreated",
+        );
+        let client = Client::tracked(gateway(&base_url, "")).expect("valid rocket instance");
+        let post = |body: &'static str| {
+            client
+                .post("/service_a/v1.0.0/orders")
+                .header(Header::new("Host", "product_a.uws.io"))
+                .header(Header::new("x-api-key", "key-1"))
+                .header(Header::new(IDEMPOTENCY_KEY, "order-1"))
+                .body(body)
+                .dispatch()
+        };
+
+        assert_eq!(post("{}").status(), Status::Created);
+        let retry = post("{}");
+        assert_eq!(retry.status(), Status::Created);
+        assert_eq!(retry.headers().get_one(idempotency::REPLAYED), Some("true"));
+        assert_eq!(retry.into_string().unwrap(), "created");
+        assert_eq!(post("{\"id\":2}").status(), Status::UnprocessableEntity);
+
+        let rocket = client.rocket();
+        let subscriptions = rocket.state::<FlatSubscriptionList>().unwrap();
+        assert_eq!(subscriptions.get_by_id(2).unwrap().quota, 4);
+        assert_eq!(rocket.state::<FlatRequestList>().unwrap().list().len(), 1);
+    }
+}
@@ -0,0 +1,184 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use rocket::data::{ByteUnit, Data};
+use rocket::futures::future::BoxFuture;
+use rocket::futures::Stream;
+use rocket::http::Status;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use rocket::tokio::sync::mpsc;
+
+/// How much of a request body is read at a time when streaming it upstream.
+const UPLOAD_CHUNK: usize = 16 * 1024;
+
+/// How a relayed response ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ending {
+    /// The service sent its whole body.
+    Finished,
+    /// The consumer went away before the end.
+    Disconnected,
+    /// The service failed or went quiet for too long mid-stream.
+    Failed,
+}
+
+/// What was relayed of a streamed response by the time it ended.
+#[derive(Debug, Clone, Copy)]
+pub struct Relayed {
+    pub ending: Ending,
+    pub bytes: u128,
+    pub duration: Duration,
+}
+
+type Chunk = (reqwest::Response, reqwest::Result<Option<Vec<u8>>>);
+
+fn next_chunk(mut response: reqwest::Response) -> BoxFuture<'static, Chunk> {
+    Box::pin(async move {
+        let chunk = response
+            .chunk()
+            .await
+            .map(|chunk| chunk.map(|bytes| bytes.to_vec()));
+        (response, chunk)
+    })
+}
+
+/// Hands an upstream body to the consumer chunk by chunk as it arrives,
+/// telling `done` how it ended exactly once: at its end, on an upstream
+/// error, or when the consumer's side drops it.
+pub struct Relay<'r> {
+    next: Option<BoxFuture<'static, Chunk>>,
+    chunk: Vec<u8>,
+    offset: usize,
+    bytes: u128,
+    started: Instant,
+    done: Option<Box<dyn FnOnce(Relayed) + Send + 'r>>,
+}
+
+impl<'r> Relay<'r> {
+    pub fn new(
+        response: reqwest::Response,
+        started: Instant,
+        done: impl FnOnce(Relayed) + Send + 'r,
+    ) -> Relay<'r> {
+        Relay {
+            next: Some(next_chunk(response)),
+            chunk: vec![],
+            offset: 0,
+            bytes: 0,
+            started,
+            done: Some(Box::new(done)),
+        }
+    }
+
+    fn end(&mut self, ending: Ending) {
+        self.next = None;
+        if let Some(done) = self.done.take() {
+            done(Relayed {
+                ending,
+                bytes: self.bytes,
+                duration: self.started.elapsed(),
+            });
+        }
+    }
+}
+
+impl AsyncRead for Relay<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let relay = self.get_mut();
+        loop {
+            if relay.offset < relay.chunk.len() {
+                let rest = &relay.chunk[relay.offset..];
+                let len = rest.len().min(buf.remaining());
+                buf.put_slice(&rest[..len]);
+                relay.offset += len;
+                return Poll::Ready(Ok(()));
+            }
+            let next = match relay.next.as_mut() {
+                Some(next) => next,
+                None => return Poll::Ready(Ok(())),
+            };
+            match next.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready((response, Ok(Some(chunk)))) => {
+                    relay.bytes += chunk.len() as u128;
+                    relay.chunk = chunk;
+                    relay.offset = 0;
+                    relay.next = Some(next_chunk(response));
+                }
+                Poll::Ready((_, Ok(None))) => {
+                    relay.end(Ending::Finished);
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready((_, Err(e))) => {
+                    relay.end(Ending::Failed);
+                    return Poll::Ready(Err(io::Error::other(e)));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Relay<'_> {
+    fn drop(&mut self) {
+        self.end(Ending::Disconnected);
+    }
+}
+
+/// The part of a request body streamed upstream, as fed by `upload`.
+struct Uploaded(mpsc::Receiver<io::Result<Vec<u8>>>);
+
+impl Stream for Uploaded {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.poll_recv(cx)
+    }
+}
+
+/// Splits a consumer's request body into the body the upstream client sends
+/// and the pump feeding it chunk by chunk as it arrives. The pump has to run
+/// alongside the upstream call and returns how many bytes it fed. A body
+/// that breaks off or grows past `limit` fails the upstream call rather
+/// than reaching the service cut short.
+pub fn upload<'r>(
+    data: Data<'r>,
+    limit: ByteUnit,
+) -> (
+    reqwest::Body,
+    impl Future<Output = Result<u128, Status>> + Send + 'r,
+) {
+    let (sender, receiver) = mpsc::channel(1);
+    let pump = async move {
+        let mut stream = data.open(limit + 1);
+        let mut bytes = 0;
+        loop {
+            let mut chunk = vec![0; UPLOAD_CHUNK];
+            let failure = match stream.read(&mut chunk).await {
+                Ok(0) => return Ok(bytes),
+                Ok(read) if bytes + read as u128 > limit.as_u128() => Status::PayloadTooLarge,
+                Ok(read) => {
+                    bytes += read as u128;
+                    chunk.truncate(read);
+                    // the upstream stopped reading, e.g. it answered early
+                    if sender.send(Ok(chunk)).await.is_err() {
+                        return Ok(bytes);
+                    }
+                    continue;
+                }
+                Err(_) => Status::BadRequest,
+            };
+            let _ = sender
+                .send(Err(io::Error::other(failure.reason_lossy())))
+                .await;
+            return Err(failure);
+        }
+    };
+    (reqwest::Body::wrap_stream(Uploaded(receiver)), pump)
+}
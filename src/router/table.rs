@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::time::{sleep, Duration};
+use rocket::{Build, Orbit, Rocket};
+
+use crate::cache;
+use crate::db::file_db::{get_table_instance, table_path};
+use crate::db::Record;
+use crate::logger;
+use crate::product::{product_list::FlatProductList, Product};
+use crate::sandbox;
+use crate::service::{service_list::FlatServiceList, Service, WebSocketBilling};
+
+/// Where a service answers: its product slug, slug and version.
+type RouteKey = (String, String, String);
+
+/// The services the gateway routes to, checked and resolved ahead of requests.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: HashMap<RouteKey, Service>,
+}
+
+/// Every problem found in the services or products a table was built from.
+#[derive(Debug, PartialEq)]
+pub struct RoutingError(pub Vec<String>);
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("; "))
+    }
+}
+
+fn field<'a>(record: &'a Record<String, String>, name: &str) -> Result<&'a str, String> {
+    match record.get(name).map(|value| value.trim()) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(format!("`{name}` is missing")),
+    }
+}
+
+fn number<T: std::str::FromStr>(record: &Record<String, String>, name: &str) -> Result<T, String> {
+    field(record, name)?
+        .parse::<T>()
+        .map_err(|_| format!("`{name}` should be a number"))
+}
+
+fn parse_product(record: &Record<String, String>) -> Result<Product, String> {
+    Ok(Product {
+        id: number(record, "id")?,
+        slug: field(record, "slug")?.to_string(),
+        requests: number(record, "requests")?,
+    })
+}
+
+fn parse_service(
+    record: &Record<String, String>,
+    products: &HashMap<u128, Product>,
+) -> Result<Service, String> {
+    let product_id = number::<u128>(record, "product")?;
+    let base_url = field(record, "base_url")?;
+    if !(base_url.starts_with("http://") || base_url.starts_with("https://")) {
+        return Err("`base_url` should be an http(s) URL".to_string());
+    }
+    let sandbox_url = record
+        .get("sandbox_url")
+        .map(|url| url.trim())
+        .filter(|url| !url.is_empty());
+    sandbox::check_url(sandbox_url.unwrap_or_default())?;
+    let websocket_billing = match record.get("websocket_billing").map(|value| value.trim()) {
+        None | Some("") => WebSocketBilling::Connection,
+        Some(value) => WebSocketBilling::parse(value).ok_or_else(|| {
+            "`websocket_billing` should be `connection`, `message` or `minute`".to_string()
+        })?,
+    };
+
+    Ok(Service {
+        id: number(record, "id")?,
+        name: field(record, "name")?.to_string(),
+        requests: number(record, "requests")?,
+        slug: field(record, "slug")?.to_string(),
+        status: number(record, "status")?,
+        version: field(record, "version")?.to_string(),
+        base_url: base_url.to_string(),
+        price: number(record, "price")?,
+        product: products
+            .get(&product_id)
+            .cloned()
+            .ok_or_else(|| format!("product with id:{product_id} does not exist"))?,
+        sandbox_url: sandbox_url.map(str::to_string),
+        websocket_billing,
+        cache_ttl: match record.get("cache_ttl").map(|value| value.trim()) {
+            None | Some("") => None,
+            Some(_) => Some(number(record, "cache_ttl")?),
+        },
+        cache_headers: cache::parse_headers(
+            record
+                .get("cache_headers")
+                .map(String::as_str)
+                .unwrap_or_default(),
+        )?,
+    })
+}
+
+impl RoutingTable {
+    /// Builds a table from raw product and service rows, refusing it as a
+    /// whole if any row is invalid or two services answer at the same place.
+    pub fn build(
+        products: &[Record<String, String>],
+        services: &[Record<String, String>],
+    ) -> Result<RoutingTable, RoutingError> {
+        let mut errors = vec![];
+        let mut products_by_id = HashMap::new();
+        for (row, record) in products.iter().enumerate() {
+            match parse_product(record) {
+                Ok(product) => {
+                    products_by_id.insert(product.id, product);
+                }
+                Err(e) => errors.push(format!("product row {}: {e}", row + 1)),
+            }
+        }
+
+        let mut routes: HashMap<RouteKey, Service> = HashMap::new();
+        for (row, record) in services.iter().enumerate() {
+            let service = match parse_service(record, &products_by_id) {
+                Ok(service) => service,
+                Err(e) => {
+                    errors.push(format!("service row {}: {e}", row + 1));
+                    continue;
+                }
+            };
+            let key = (
+                service.product.slug.clone(),
+                service.slug.clone(),
+                service.version.clone(),
+            );
+            if let Some(existing) = routes.get(&key) {
+                errors.push(format!(
+                    "services with id:{} and id:{} both answer {}/{}/{}",
+                    existing.id, service.id, key.0, key.1, key.2
+                ));
+                continue;
+            }
+            routes.insert(key, service);
+        }
+
+        match errors.is_empty() {
+            true => Ok(RoutingTable { routes }),
+            false => Err(RoutingError(errors)),
+        }
+    }
+
+    /// Finds the service answering `/<service>/<version>` for `product`.
+    pub fn resolve(&self, product: &str, service: &str, version: &str) -> Option<&Service> {
+        self.routes.get(&(
+            product.to_string(),
+            service.to_string(),
+            version.to_string(),
+        ))
+    }
+
+    /// Returns the routed services, ordered by id.
+    pub fn services(&self) -> Vec<&Service> {
+        let mut services = self.routes.values().collect::<Vec<&Service>>();
+        services.sort_by_key(|service| service.id);
+        services
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+/// The routing table in use, shared by requests and swapped as a whole on
+/// reload so a request never sees half of a change.
+#[derive(Clone, Default)]
+pub struct Routing(Arc<RwLock<Arc<RoutingTable>>>);
+
+impl Routing {
+    pub fn new(table: RoutingTable) -> Self {
+        Routing(Arc::new(RwLock::new(Arc::new(table))))
+    }
+
+    pub fn current(&self) -> Arc<RoutingTable> {
+        self.0.read().expect("read routing table").clone()
+    }
+
+    /// Rebuilds the table from `products` and `services`. The current table is
+    /// kept if the new one is invalid.
+    pub fn reload(
+        &self,
+        products: &FlatProductList,
+        services: &FlatServiceList,
+    ) -> Result<usize, RoutingError> {
+        let table = RoutingTable::build(&products.records(), &services.records())?;
+        let routes = table.len();
+        *self.0.write().expect("write routing table") = Arc::new(table);
+        Ok(routes)
+    }
+
+    /// Reloads the table, logging the outcome of a reload caused by `trigger`.
+    pub fn reload_logged(
+        &self,
+        products: &FlatProductList,
+        services: &FlatServiceList,
+        trigger: &str,
+    ) {
+        match self.reload(products, services) {
+            Ok(routes) => logger::log(&format!(
+                "Routing table reloaded on {trigger}: {routes} routes"
+            )),
+            Err(e) => logger::log(&format!(
+                "Routing table kept, reload on {trigger} failed: {e}"
+            )),
+        }
+    }
+}
+
+fn modified(table: &str) -> Option<SystemTime> {
+    fs::metadata(table_path(table)).ok()?.modified().ok()
+}
+
+/// Loads the routing table at ignition and reloads it when the products or
+/// services tables change on disk, or when the gateway receives `SIGHUP`.
+pub struct RoutingReload {
+    /// How often the tables are checked for changes.
+    pub interval: Duration,
+}
+
+#[rocket::async_trait]
+impl Fairing for RoutingReload {
+    fn info(&self) -> Info {
+        Info {
+            name: "Routing table reload",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let routing = Routing::default();
+        routing.reload_logged(
+            &FlatProductList::new(get_table_instance("products")),
+            &FlatServiceList::new(get_table_instance("services")),
+            "start",
+        );
+        Ok(rocket.manage(routing))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let routing = match rocket.state::<Routing>() {
+            Some(routing) => routing.clone(),
+            None => return,
+        };
+        let interval = self.interval;
+
+        #[cfg(unix)]
+        {
+            use rocket::tokio::signal::unix::{signal, SignalKind};
+
+            let routing = routing.clone();
+            if let Ok(mut hangups) = signal(SignalKind::hangup()) {
+                rocket::tokio::spawn(async move {
+                    let products = FlatProductList::new(get_table_instance("products"));
+                    let services = FlatServiceList::new(get_table_instance("services"));
+                    while hangups.recv().await.is_some() {
+                        routing.reload_logged(&products, &services, "SIGHUP");
+                    }
+                });
+            }
+        }
+
+        rocket::tokio::spawn(async move {
+            let products = FlatProductList::new(get_table_instance("products"));
+            let services = FlatServiceList::new(get_table_instance("services"));
+            let mut seen = (modified("products"), modified("services"));
+            loop {
+                sleep(interval).await;
+                let current = (modified("products"), modified("services"));
+                if current != seen {
+                    seen = current;
+                    routing.reload_logged(&products, &services, "file change");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::db::file_db::{read_from_string, FlatTable};
+
+    const PRODUCTS: &str = "\
+    id, slug, requests
+    1, product_a, 0
+    2, product_b, 0
+    ";
+
+    const SERVICES: &str = "\
+    id, name, slug, version, status, base_url, price, requests, product
+    1, Service A, service_a, v1.0.0, 1, http://127.0.0.1:8001/, 2, 0, 1
+    2, Service A, service_a, v1.0.0, 1, http://127.0.0.1:8002/, 2, 0, 2
+    ";
+
+    #[test]
+    fn resolve_service_by_product_and_version() {
+        let table =
+            RoutingTable::build(&read_from_string(PRODUCTS), &read_from_string(SERVICES)).unwrap();
+
+        let service = table.resolve("product_b", "service_a", "v1.0.0").unwrap();
+
+        assert_eq!(service.id, 2);
+        assert_eq!(service.product.slug, "product_b");
+        assert!(table.resolve("product_a", "service_a", "v2.0.0").is_none());
+    }
+
+    #[test]
+    fn reject_invalid_services() {
+        let services = "\
+        id, name, slug, version, status, base_url, price, requests, product
+        1, Service A, service_a, v1.0.0, 1, http://127.0.0.1:8001/, 2, 0, 1
+        2, Service B, service_a, v1.0.0, 1, http://127.0.0.1:8002/, 2, 0, 1
+        3, Service C, service_c, v1.0.0, 1, 127.0.0.1, 2, 0, 1
+        4, Service D, service_d, v1.0.0, 1, http://127.0.0.1:8004/, 2, 0, 9
+        ";
+
+        let errors = RoutingTable::build(&read_from_string(PRODUCTS), &read_from_string(services))
+            .unwrap_err();
+
+        assert_eq!(
+            errors.0,
+            vec![
+                "services with id:1 and id:2 both answer product_a/service_a/v1.0.0",
+                "service row 3: `base_url` should be an http(s) URL",
+                "service row 4: product with id:9 does not exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_current_table_when_reload_fails() {
+        let list = |contents: &str| Mutex::new(FlatTable::new_from_string(contents.to_string()));
+        let products = FlatProductList::new(list(PRODUCTS));
+        let routing = Routing::default();
+        assert_eq!(
+            routing.reload(&products, &FlatServiceList::new(list(SERVICES))),
+            Ok(2)
+        );
+
+        let before = routing.current();
+        let broken = SERVICES.replace("http://127.0.0.1:8002/", "");
+
+        assert!(routing
+            .reload(&products, &FlatServiceList::new(list(&broken)))
+            .is_err());
+        assert!(Arc::ptr_eq(&before, &routing.current()));
+        assert!(routing
+            .current()
+            .resolve("product_b", "service_a", "v1.0.0")
+            .is_some());
+    }
+}
@@ -0,0 +1,372 @@
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+use chrono::Utc;
+use rocket::data::{IoHandler, IoStream};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::Status;
+use rocket::tokio::io::{AsyncRead, AsyncWrite};
+use rocket::tokio::net::TcpStream;
+use rocket::tokio::time::{interval_at, timeout, Instant};
+use rocket::Request;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::biller::{self, Usage};
+use crate::budget::budget_list::FlatBudgetUsageList;
+use crate::consumer::Consumer;
+use crate::db::Record;
+use crate::ledger::ledger_list::FlatLedgerList;
+use crate::overage::overage_list::FlatOverageList;
+use crate::request::request_list::FlatRequestList;
+use crate::service::{Service, WebSocketBilling};
+use crate::subscriber::subscriber_list::FlatSubscriptionList;
+
+use super::is_relayed;
+
+pub const PROTOCOL: &str = "websocket";
+
+/// The subprotocol header, the only handshake header relayed both ways.
+const SUBPROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Why a connection is closed when its subscription can't pay for more.
+const QUOTA_EXHAUSTED: &str = "Quota exhausted";
+
+pub type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Returns true if `req` asks to open a WebSocket.
+pub fn is_upgrade(req: &Request<'_>) -> bool {
+    req.headers()
+        .get("Upgrade")
+        .any(|value| value.eq_ignore_ascii_case(PROTOCOL))
+}
+
+/// Returns the `Sec-WebSocket-Accept` answering the handshake of `req`, if
+/// it is a valid one.
+pub fn accept_key(req: &Request<'_>) -> Option<String> {
+    let key = req.headers().get_one("Sec-WebSocket-Key")?;
+    match req.headers().get_one("Sec-WebSocket-Version") {
+        Some("13") => Some(derive_accept_key(key.trim().as_bytes())),
+        _ => None,
+    }
+}
+
+/// Returns the WebSocket URL of an upstream http(s) `url`.
+pub fn socket_url(url: &str) -> String {
+    match url.strip_prefix("http") {
+        Some(rest) => format!("ws{rest}"),
+        None => url.to_string(),
+    }
+}
+
+/// Opens a WebSocket to `url` with the consumer's relayed `headers`,
+/// returning it with the subprotocol the service picked, if any.
+pub async fn connect(
+    url: &str,
+    headers: &[(String, String)],
+    wait: Duration,
+) -> Result<(UpstreamSocket, Option<String>), Status> {
+    let mut request = url.into_client_request().map_err(|_| Status::BadGateway)?;
+    for (name, value) in headers.iter().filter(|(name, _)| {
+        is_relayed(name)
+            && (!name.to_lowercase().starts_with("sec-websocket-")
+                || name.eq_ignore_ascii_case(SUBPROTOCOL))
+    }) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            request.headers_mut().append(name, value);
+        }
+    }
+
+    match timeout(wait, tokio_tungstenite::connect_async(request)).await {
+        Ok(Ok((socket, response))) => {
+            let subprotocol = response
+                .headers()
+                .get(SUBPROTOCOL)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            Ok((socket, subprotocol))
+        }
+        Ok(Err(_)) => Err(Status::BadGateway),
+        Err(_) => Err(Status::GatewayTimeout),
+    }
+}
+
+/// Charges a connection to a service its `price` per unit of the service's
+/// `websocket_billing`, and logs it with what it cost once dropped.
+pub struct Meter<'r> {
+    pub subscriptions: &'r FlatSubscriptionList,
+    pub ledger: &'r FlatLedgerList,
+    pub overages: &'r FlatOverageList,
+    pub budget_usages: &'r FlatBudgetUsageList,
+    pub requests: &'r FlatRequestList,
+    pub consumer: Consumer,
+    pub service: Service,
+    pub request_id: String,
+    pub url: String,
+    pub charged: u128,
+    /// The status the connection is logged with, nothing if it is `None`.
+    pub status: Option<u16>,
+}
+
+impl Meter<'_> {
+    /// Charges one unit to the consumer's subscription and budget, refusing
+    /// it once either can't pay for it.
+    pub fn charge(&mut self) -> Result<(), String> {
+        let subscription = self
+            .subscriptions
+            .get_by_id(self.consumer.subscriber.subscription.id)
+            .unwrap_or_else(|| self.consumer.subscriber.subscription.clone());
+        let period = subscription.period_start();
+        let price = self.service.price;
+        biller::reserve_budget(self.budget_usages, &self.consumer, period, price)
+            .map_err(|e| e.to_string())?;
+        let usage = Usage {
+            tokens: price,
+            reason: &self.service.slug,
+            request_id: Some(&self.request_id),
+        };
+        let now = Utc::now().naive_utc();
+        if let Err(e) = biller::charge(
+            self.subscriptions,
+            self.ledger,
+            self.overages,
+            &subscription,
+            usage,
+            now,
+        ) {
+            biller::settle_budget(self.budget_usages, self.consumer.id, period, price, 0);
+            return Err(e.to_string());
+        }
+        self.charged += price;
+        Ok(())
+    }
+
+    pub fn bills(&self, billing: WebSocketBilling) -> bool {
+        self.service.websocket_billing == billing
+    }
+}
+
+impl Drop for Meter<'_> {
+    fn drop(&mut self) {
+        if let Some(status) = self.status {
+            self.requests.create(Record::from([
+                ("id".to_string(), self.request_id.clone()),
+                (
+                    "product_slug".to_string(),
+                    self.service.product.slug.clone(),
+                ),
+                ("service_slug".to_string(), self.service.slug.clone()),
+                ("service_version".to_string(), self.service.version.clone()),
+                ("url".to_string(), self.url.replace(',', "%2C")),
+                ("status".to_string(), status.to_string()),
+                ("price".to_string(), self.charged.to_string()),
+                ("consumer".to_string(), self.consumer.id.to_string()),
+                ("service".to_string(), self.service.id.to_string()),
+            ]));
+        }
+    }
+}
+
+/// Whether relaying goes on after a message.
+enum Flow {
+    Open,
+    Closed,
+    Exhausted,
+}
+
+async fn pass<S>(meter: &mut Meter<'_>, to: &mut WebSocketStream<S>, message: Message) -> Flow
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match message {
+        Message::Text(_) | Message::Binary(_) => {
+            if meter.bills(WebSocketBilling::Message) && meter.charge().is_err() {
+                return Flow::Exhausted;
+            }
+            match to.send(message).await {
+                Ok(()) => Flow::Open,
+                Err(_) => Flow::Closed,
+            }
+        }
+        Message::Close(frame) => {
+            let _ = to.send(Message::Close(frame)).await;
+            Flow::Closed
+        }
+        // each side answers pings on its own
+        _ => Flow::Open,
+    }
+}
+
+/// Relays messages both ways until either side closes, charging them as
+/// they pass, and closes the consumer's side once the quota runs out.
+pub async fn relay<C, U>(
+    mut consumer: WebSocketStream<C>,
+    mut upstream: WebSocketStream<U>,
+    meter: &mut Meter<'_>,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    // the first minute was paid when the connection opened
+    let mut minutes = interval_at(Instant::now() + MINUTE, MINUTE);
+    let per_minute = meter.bills(WebSocketBilling::Minute);
+    let flow = loop {
+        let flow = rocket::tokio::select! {
+            message = consumer.next() => match message {
+                Some(Ok(message)) => pass(meter, &mut upstream, message).await,
+                _ => Flow::Closed,
+            },
+            message = upstream.next() => match message {
+                Some(Ok(message)) => pass(meter, &mut consumer, message).await,
+                _ => Flow::Closed,
+            },
+            _ = minutes.tick(), if per_minute => match meter.charge() {
+                Ok(()) => Flow::Open,
+                Err(_) => Flow::Exhausted,
+            },
+        };
+        if !matches!(flow, Flow::Open) {
+            break flow;
+        }
+    };
+
+    let frame = match flow {
+        Flow::Exhausted => Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: QUOTA_EXHAUSTED.into(),
+        }),
+        _ => None,
+    };
+    let _ = consumer.close(frame).await;
+    let _ = upstream.close(None).await;
+}
+
+/// Takes over the consumer's upgraded connection to relay it to `upstream`.
+pub struct Tunnel<'r> {
+    pub upstream: UpstreamSocket,
+    pub meter: Meter<'r>,
+}
+
+#[rocket::async_trait]
+impl IoHandler for Tunnel<'_> {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let Tunnel {
+            upstream,
+            mut meter,
+        } = *Pin::into_inner(self);
+        let consumer = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        relay(consumer, upstream, &mut meter).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use rocket::tokio::io::duplex;
+
+    use super::*;
+    use crate::budget::budget_list::BudgetUsageList;
+    use crate::db::file_db::FlatTable;
+    use crate::ledger::ledger_list::LedgerList;
+    use crate::overage::overage_list::OverageList;
+    use crate::request::request_list::RequestList;
+    use crate::subscriber::subscriber_list::SubscriptionList;
+
+    fn table(contents: &str) -> Mutex<FlatTable<String, String>> {
+        Mutex::new(FlatTable::new_from_string(contents.to_string()))
+    }
+
+    #[test]
+    fn build_socket_url() {
+        assert_eq!(
+            socket_url("http://127.0.0.1:8001/chat"),
+            "ws://127.0.0.1:8001/chat"
+        );
+        assert_eq!(socket_url("https://ai.uws.io/chat"), "wss://ai.uws.io/chat");
+    }
+
+    #[rocket::async_test]
+    async fn charge_messages_until_quota_runs_out() {
+        let subscriptions = SubscriptionList::new(table(
+            "id, name, status, price, quota, expiry_date, auto_renew, plan\n\
+             1, Golden 50, 1, 50000, 2, 2030-10-01 00:00:00, false, 2",
+        ));
+        let ledger = LedgerList::new(table(
+            "id, subscription, kind, amount, reason, request, created_at\n\
+             1, 1, credit, 2, opening balance, , 2023-01-01 00:00:00",
+        ));
+        let overages = OverageList::new(table(
+            "id, subscription, request, tokens, price, created_at",
+        ));
+        let budget_usages = BudgetUsageList::new(table("id, consumer, period, tokens"));
+        let requests = RequestList::new(table(
+            "id, product_slug, service_slug, service_version, url, status, price, consumer, service",
+        ));
+        let mut meter = Meter {
+            subscriptions: &subscriptions,
+            ledger: &ledger,
+            overages: &overages,
+            budget_usages: &budget_usages,
+            requests: &requests,
+            consumer: Consumer::fake(&HashMap::new()),
+            service: Service::fake(&HashMap::from([("websocket_billing", "message")])),
+            request_id: "r-1".to_string(),
+            url: "ws://127.0.0.1:8001/chat".to_string(),
+            charged: 0,
+            status: Some(101),
+        };
+
+        let (gateway_side, consumer_side) = duplex(4096);
+        let (upstream_side, service_side) = duplex(4096);
+        let mut consumer =
+            WebSocketStream::from_raw_socket(consumer_side, Role::Client, None).await;
+        let mut service = WebSocketStream::from_raw_socket(service_side, Role::Server, None).await;
+        let tunnel = async {
+            relay(
+                WebSocketStream::from_raw_socket(gateway_side, Role::Server, None).await,
+                WebSocketStream::from_raw_socket(upstream_side, Role::Client, None).await,
+                &mut meter,
+            )
+            .await
+        };
+        let calls = async {
+            consumer.send(Message::text("hello")).await.unwrap();
+            let hello = service.next().await.unwrap().unwrap();
+            service.send(Message::text("hi")).await.unwrap();
+            let hi = consumer.next().await.unwrap().unwrap();
+            consumer.send(Message::text("again")).await.unwrap();
+            let closed = consumer.next().await.unwrap().unwrap();
+            (hello, hi, closed)
+        };
+        let (_, (hello, hi, closed)) = rocket::tokio::join!(tunnel, calls);
+
+        assert_eq!(hello, Message::text("hello"));
+        assert_eq!(hi, Message::text("hi"));
+        match closed {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Policy);
+                assert_eq!(frame.reason, QUOTA_EXHAUSTED);
+            }
+            message => panic!("expected a close frame, got {message:?}"),
+        }
+        assert_eq!(meter.charged, 2);
+        drop(meter);
+        assert_eq!(subscriptions.get_by_id(1).unwrap().quota, 0);
+        assert_eq!(requests.list()[0].price, 2);
+        assert_eq!(requests.list()[0].status, 101);
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::db::file_db::FlatTable;
+use crate::request::request_list::FlatRequestList;
+use crate::router::Upstream;
+use crate::service::Service;
+
+/// Response header telling a sandbox response apart from a real one.
+pub const SANDBOX_HEADER: &str = "X-Sandbox";
+
+/// Calls made with sandbox keys, logged in `sandbox_requests` so they never
+/// show up in usage, invoices or the ledger.
+pub struct SandboxRequestList(pub FlatRequestList);
+
+impl SandboxRequestList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        SandboxRequestList(FlatRequestList::new(db))
+    }
+}
+
+impl Deref for SandboxRequestList {
+    type Target = FlatRequestList;
+
+    fn deref(&self) -> &FlatRequestList {
+        &self.0
+    }
+}
+
+/// Checks a service's `sandbox_url`, which may be empty to use fixtures.
+pub fn check_url(url: &str) -> Result<(), String> {
+    let url = url.trim();
+    match url.is_empty() || url.starts_with("http://") || url.starts_with("https://") {
+        true => Ok(()),
+        false => Err("`sandbox_url` should be an http(s) URL".to_string()),
+    }
+}
+
+/// Returns where the fixture of `service` is read from:
+/// `<dir>/<service slug>/<version>.json`.
+pub fn fixture_path(dir: &Path, service: &Service) -> PathBuf {
+    dir.join(&service.slug)
+        .join(format!("{}.json", service.version))
+}
+
+/// Answers a sandbox call with the static fixture of `service`, if it has one.
+pub fn fixture(dir: &Path, service: &Service) -> Option<Upstream> {
+    let body = fs::read(fixture_path(dir, service)).ok()?;
+    Some(Upstream {
+        status: 200,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body,
+        duration: Duration::ZERO,
+    })
+}
+
+/// Returns the service to forward sandbox calls to, if `service` has a
+/// sandbox of its own.
+pub fn upstream(service: &Service) -> Option<Service> {
+    service.sandbox_url.as_ref().map(|sandbox_url| Service {
+        base_url: sandbox_url.clone(),
+        ..service.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn serve_fixture_of_service_version() {
+        let dir = std::env::temp_dir().join(format!("uws-fixtures-{}", std::process::id()));
+        let service = Service::fake(&HashMap::from([("slug", "users"), ("version", "v1")]));
+        fs::create_dir_all(dir.join("users")).unwrap();
+        fs::write(dir.join("users/v1.json"), r#"{"id": 1}"#).unwrap();
+
+        let answer = fixture(&dir, &service).unwrap();
+        let missing = fixture(&dir, &Service::fake(&HashMap::new()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(answer.status, 200);
+        assert_eq!(answer.body, br#"{"id": 1}"#);
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn forward_to_sandbox_url() {
+        let service = Service::fake(&HashMap::from([("sandbox_url", "http://127.0.0.1:9001")]));
+
+        assert_eq!(
+            upstream(&service).unwrap().base_url,
+            "http://127.0.0.1:9001"
+        );
+        assert!(upstream(&Service::fake(&HashMap::new())).is_none());
+    }
+}
@@ -0,0 +1,235 @@
+use chrono::{NaiveDateTime, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::time::{sleep, Duration};
+use rocket::{Orbit, Rocket};
+
+use crate::alert::{self, alert_list::FlatAlertList, AlertSettings, DeliveryStatus};
+use crate::biller;
+use crate::config::{GatewayConfig, IdempotencyConfig};
+use crate::db::Record;
+use crate::idempotency::idempotency_list::FlatIdempotencyList;
+use crate::ledger::{self, ledger_list::FlatLedgerList, EntryKind};
+use crate::logger;
+use crate::overage::overage_list::FlatOverageList;
+use crate::renewal::{renewal_list::FlatRenewalList, Renewal};
+use crate::router;
+use crate::subscriber::subscriber_list::{FlatSubscriberList, FlatSubscriptionList};
+use crate::subscriber::{format_date, Subscription, SubscriptionStatus};
+
+#[derive(Debug)]
+pub enum Transition {
+    Renewed(Renewal),
+    Suspended(u128),
+}
+
+/// Returns the first end of period after `now`, starting from the current expiry.
+fn next_expiry(subscription: &Subscription, now: NaiveDateTime) -> NaiveDateTime {
+    let mut expiry_date = subscription.expiry_date;
+    while expiry_date <= now {
+        expiry_date = subscription.plan.period.next(expiry_date);
+    }
+    expiry_date
+}
+
+/// Renews expired auto-renewing subscriptions and suspends the other expired ones.
+pub fn process_subscriptions(
+    subscriptions: &FlatSubscriptionList,
+    renewals: &FlatRenewalList,
+    ledger: &FlatLedgerList,
+    now: NaiveDateTime,
+) -> Vec<Transition> {
+    let mut transitions = vec![];
+    for subscription in subscriptions.list() {
+        if subscription.status != SubscriptionStatus::Active || !subscription.is_expired(now) {
+            continue;
+        }
+
+        if subscription.auto_renew {
+            let expiry_date = next_expiry(&subscription, now);
+            // fails only when the subscription was deleted in the meantime
+            if let Err(e) = biller::reset_quota(
+                subscriptions,
+                ledger,
+                &subscription,
+                subscription.plan.quota,
+                EntryKind::Renewal,
+                &format!("renewal of plan {}", subscription.plan.id),
+                None,
+                now,
+            ) {
+                logger::log(&format!(
+                    "Renewal of subscription {} skipped: {e}",
+                    subscription.id
+                ));
+                continue;
+            }
+            subscriptions.update(
+                subscription.id,
+                Record::from([("expiry_date".to_string(), format_date(&expiry_date))]),
+            );
+            let renewal = renewals.create(
+                subscription.id,
+                subscription.plan.id,
+                subscription.plan.quota,
+                now,
+                expiry_date,
+            );
+            transitions.push(Transition::Renewed(renewal));
+        } else {
+            subscriptions.update(
+                subscription.id,
+                Record::from([(
+                    "status".to_string(),
+                    (SubscriptionStatus::Suspended as u8).to_string(),
+                )]),
+            );
+            transitions.push(Transition::Suspended(subscription.id));
+        }
+    }
+    transitions
+}
+
+/// Runs the subscription lifecycle and alert jobs every `interval` once the
+/// server is up.
+pub struct Scheduler {
+    pub interval: Duration,
+    pub alerts: AlertSettings,
+}
+
+#[rocket::async_trait]
+impl Fairing for Scheduler {
+    fn info(&self) -> Info {
+        Info {
+            name: "Subscription scheduler",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let interval = self.interval;
+        let timeout = rocket
+            .state::<GatewayConfig>()
+            .map(|config| config.upstream.timeout())
+            .unwrap_or(router::UPSTREAM_TIMEOUT);
+        let retention = rocket
+            .state::<GatewayConfig>()
+            .map(|config| config.idempotency.retention())
+            .unwrap_or_else(|| IdempotencyConfig::default().retention());
+        let settings = self.alerts.clone();
+        // the managed lists, so that these jobs and requests share table locks
+        let (
+            Some(subscriptions),
+            Some(renewals),
+            Some(ledger),
+            Some(subscribers),
+            Some(overages),
+            Some(alerts),
+            Some(idempotency_keys),
+        ) = (
+            rocket.state::<FlatSubscriptionList>().cloned(),
+            rocket.state::<FlatRenewalList>().cloned(),
+            rocket.state::<FlatLedgerList>().cloned(),
+            rocket.state::<FlatSubscriberList>().cloned(),
+            rocket.state::<FlatOverageList>().cloned(),
+            rocket.state::<FlatAlertList>().cloned(),
+            rocket.state::<FlatIdempotencyList>().cloned(),
+        )
+        else {
+            logger::log("Scheduler not started: the tables it runs on are not managed");
+            return;
+        };
+        rocket::tokio::spawn(async move {
+            let client = alert::webhook_client(timeout);
+            loop {
+                sleep(interval).await;
+                let now = Utc::now().naive_utc();
+                for transition in process_subscriptions(&subscriptions, &renewals, &ledger, now) {
+                    logger::log(&format!("Subscription lifecycle: {:?}", transition));
+                }
+                for mismatch in ledger::reconcile(&subscriptions.list(), &ledger.list()) {
+                    logger::log(&format!("Ledger mismatch: {:?}", mismatch));
+                }
+                let purged = idempotency_keys.purge(now - retention);
+                if purged > 0 {
+                    logger::log(&format!("Purged {purged} expired idempotency keys"));
+                }
+                alert::queue_alerts(&subscribers, &alerts, &overages.list(), &settings, now);
+                for alert in
+                    alert::deliver_alerts(&client, &subscribers, &alerts, &settings, now).await
+                {
+                    if alert.status == DeliveryStatus::Failed {
+                        logger::log(&format!("Alert delivery failed: {:?}", alert));
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::db::file_db::FlatTable;
+    use crate::ledger::ledger_list::LedgerList;
+    use crate::renewal::renewal_list::RenewalList;
+    use crate::subscriber::parse_date;
+    use crate::subscriber::subscriber_list::SubscriptionList;
+
+    use super::*;
+
+    fn lists() -> (FlatSubscriptionList, FlatRenewalList, FlatLedgerList) {
+        let subscriptions = "\
+        id, name, status, price, quota, expiry_date, auto_renew, plan
+        1, Startup 500, 1, 10000, 3, 2023-01-01 00:00:00, true, 1
+        2, Golden 50, 1, 50000, 10, 2023-01-01 00:00:00, false, 2
+        3, Golden 50, 1, 50000, 10, 2024-01-01 00:00:00, false, 2
+        "
+        .to_string();
+        let renewals = "id, subscription, plan, quota, renewed_at, expiry_date".to_string();
+        let ledger = "id, subscription, kind, amount, reason, request, created_at".to_string();
+
+        (
+            SubscriptionList::new(Mutex::new(FlatTable::new_from_string(subscriptions))),
+            RenewalList::new(Mutex::new(FlatTable::new_from_string(renewals))),
+            LedgerList::new(Mutex::new(FlatTable::new_from_string(ledger))),
+        )
+    }
+
+    #[test]
+    fn renew_and_reset_quota() {
+        let (subscriptions, renewals, ledger) = lists();
+        let now = parse_date("2023-03-15 00:00:00").unwrap();
+
+        process_subscriptions(&subscriptions, &renewals, &ledger, now);
+
+        let renewed = subscriptions.get_by_id(1).unwrap();
+        assert_eq!(renewed.quota, 500);
+        assert_eq!(
+            renewed.expiry_date,
+            parse_date("2023-04-01 00:00:00").unwrap()
+        );
+        assert_eq!(renewals.list().len(), 1);
+        assert_eq!(renewals.list()[0].subscription_id, 1);
+        assert_eq!(ledger.list()[0].kind, EntryKind::Renewal);
+        assert_eq!(ledger.list()[0].amount, 497);
+    }
+
+    #[test]
+    fn suspend_expired_subscriptions() {
+        let (subscriptions, renewals, ledger) = lists();
+        let now = parse_date("2023-03-15 00:00:00").unwrap();
+
+        let transitions = process_subscriptions(&subscriptions, &renewals, &ledger, now);
+
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(
+            subscriptions.get_by_id(2).unwrap().status,
+            SubscriptionStatus::Suspended
+        );
+        assert_eq!(
+            subscriptions.get_by_id(3).unwrap().status,
+            SubscriptionStatus::Active
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use std::{collections::HashMap, sync::Mutex};
 
+use serde::Serialize;
+
 use crate::{
     db::file_db::{get_table_instance, FlatTable},
     product::{product_list::ProductList, Product},
@@ -7,6 +9,38 @@ use crate::{
 
 pub mod service_list;
 
+/// What a WebSocket connection to a service is charged its `price` for.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebSocketBilling {
+    /// Once, when the connection opens.
+    Connection,
+    /// Every text or binary message, whichever side sends it.
+    Message,
+    /// Every started minute the connection stays open.
+    Minute,
+}
+
+impl WebSocketBilling {
+    pub fn parse(value: &str) -> Option<WebSocketBilling> {
+        match value {
+            "connection" => Some(WebSocketBilling::Connection),
+            "message" => Some(WebSocketBilling::Message),
+            "minute" => Some(WebSocketBilling::Minute),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebSocketBilling::Connection => "connection",
+            WebSocketBilling::Message => "message",
+            WebSocketBilling::Minute => "minute",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Service {
     pub id: u128,
     pub name: String,
@@ -17,6 +51,15 @@ pub struct Service {
     pub base_url: String,
     pub price: u128,
     pub product: Product,
+    /// Where calls made with sandbox keys are forwarded instead of
+    /// `base_url`. Without it they are answered with a fixture.
+    pub sandbox_url: Option<String>,
+    pub websocket_billing: WebSocketBilling,
+    /// Seconds a `GET` response is cached for when the service doesn't say
+    /// otherwise in `Cache-Control`. `None` leaves its calls uncached.
+    pub cache_ttl: Option<u64>,
+    /// Request headers whose values tell cached responses apart.
+    pub cache_headers: Vec<String>,
 }
 
 impl Service {
@@ -42,6 +85,10 @@ impl Service {
             base_url,
             price,
             product: Service::fetch_product(get_table_instance("products"), product_id),
+            sandbox_url: None,
+            websocket_billing: WebSocketBilling::Connection,
+            cache_ttl: None,
+            cache_headers: vec![],
         }
     }
 
@@ -59,6 +106,16 @@ impl Service {
                 Some(product_id) => Product::fake(&HashMap::from([("id", *product_id)])),
                 None => Product::fake(&HashMap::new()),
             },
+            sandbox_url: attr.get("sandbox_url").map(|url| url.to_string()),
+            websocket_billing: WebSocketBilling::parse(
+                attr.get("websocket_billing").unwrap_or(&"connection"),
+            )
+            .unwrap(),
+            cache_ttl: attr.get("cache_ttl").map(|ttl| ttl.parse::<u64>().unwrap()),
+            cache_headers: attr
+                .get("cache_headers")
+                .map(|headers| headers.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
         }
     }
 
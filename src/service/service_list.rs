@@ -1,23 +1,27 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use crate::db::{
     file_db::{get_table_instance, FlatTable},
-    ModelAble,
+    Filter, ModelAble, Query, Record,
 };
 
-use super::Service;
+use super::{Service, WebSocketBilling};
 
+#[derive(Clone)]
 pub struct ServiceList<D> {
-    db: Mutex<D>,
+    db: Arc<Mutex<D>>,
     pub services: Vec<Service>,
 }
 
-type FlatServiceList = ServiceList<FlatTable<String, String>>;
+pub type FlatServiceList = ServiceList<FlatTable<String, String>>;
 
 impl FlatServiceList {
     pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
         ServiceList {
-            db,
+            db: Arc::new(db),
             services: vec![],
         }
     }
@@ -37,12 +41,62 @@ impl FlatServiceList {
             slug.to_string(),
         )
     }
+
+    pub fn get_by_product(&self, product_id: u128) -> Option<Service> {
+        ServiceList::get_by_attr::<FlatTable<String, String>, Service>(
+            &self.db,
+            "product",
+            product_id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<Service> {
+        ServiceList::get_all::<FlatTable<String, String>, Service>(&self.db)
+    }
+
+    /// The table as it would be written to its file.
+    pub fn contents(&self) -> String {
+        self.db.lock().expect("lock db").contents()
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Service> {
+        ServiceList::search_records::<FlatTable<String, String>, Service>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        ServiceList::count_records(&self.db, filter)
+    }
+
+    /// Returns the raw rows, for checking them before they are converted.
+    pub fn records(&self) -> Vec<Record<String, String>> {
+        ServiceList::get_all::<FlatTable<String, String>, Record<String, String>>(&self.db)
+    }
+
+    pub fn create(&self, mut record: Record<String, String>) -> Service {
+        ServiceList::insert_next::<FlatTable<String, String>, Service>(&self.db, |id| {
+            record.insert("id".to_string(), id.to_string());
+            record
+        })
+    }
+
+    pub fn update(&self, id: u128, changes: Record<String, String>) -> Option<Service> {
+        ServiceList::update_by_attr::<FlatTable<String, String>, Service>(
+            &self.db,
+            "id",
+            id.to_string(),
+            changes,
+        )
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        ServiceList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
 }
 
 impl ModelAble<String, String> for FlatServiceList {}
 impl From<HashMap<String, String>> for Service {
     fn from(map: HashMap<String, String>) -> Self {
-        return match (
+        match (
             map.get("id"),
             map.get("requests"),
             map.get("name"),
@@ -76,9 +130,28 @@ impl From<HashMap<String, String>> for Service {
                     get_table_instance("products"), // this is not testable
                     product_id.parse::<u128>().unwrap(),
                 ),
+                sandbox_url: map
+                    .get("sandbox_url")
+                    .filter(|url| !url.is_empty())
+                    .cloned(),
+                websocket_billing: map
+                    .get("websocket_billing")
+                    .filter(|billing| !billing.is_empty())
+                    .map(|billing| {
+                        WebSocketBilling::parse(billing).expect("Invalid WebSocket billing")
+                    })
+                    .unwrap_or(WebSocketBilling::Connection),
+                cache_ttl: map
+                    .get("cache_ttl")
+                    .filter(|ttl| !ttl.is_empty())
+                    .map(|ttl| ttl.parse::<u64>().expect("Invalid cache TTL")),
+                cache_headers: map
+                    .get("cache_headers")
+                    .map(|headers| headers.split_whitespace().map(str::to_string).collect())
+                    .unwrap_or_default(),
             },
             _ => panic!("Can't convert!"),
-        };
+        }
     }
 }
 
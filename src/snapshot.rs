@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::db::file_db::{get_column_names, read_from_string, table_path, to_table_string};
+use crate::integrity;
+use crate::migration::{FlatStore, MigrationStore, VERSIONS_TABLE};
+use crate::subscriber::format_date;
+
+/// The version of the snapshot document, bumped when its shape changes.
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+/// The tables every snapshot holds.
+pub const TABLES: &[&str] = &[
+    "plans",
+    "subscriptions",
+    "subscribers",
+    "consumers",
+    "products",
+    "services",
+    "pricing_rules",
+    "transform_rules",
+    "ledger",
+];
+
+/// The logs a snapshot holds on request. A restore empties those it lacks, as
+/// they would refer to records that are gone.
+pub const LOG_TABLES: &[&str] = &[
+    "requests",
+    "renewals",
+    "overages",
+    "alerts",
+    "budget_usage",
+    "idempotency_keys",
+    "sandbox_requests",
+    "jobs",
+];
+
+/// The columns holding keys, emptied in redacted snapshots. Transform rule
+/// values, and so the headers stored with jobs, may hold the credentials sent
+/// to services.
+const SECRETS: &[(&str, &str)] = &[
+    ("subscribers", "access_token"),
+    ("subscribers", "webhook_secret"),
+    ("consumers", "access_token"),
+    ("transform_rules", "value"),
+    ("jobs", "headers"),
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SnapshotTable {
+    pub columns: Vec<String>,
+    pub rows: Vec<BTreeMap<String, String>>,
+}
+
+/// The gateway data as one JSON document.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub format: u32,
+    /// The migration version of the tables it was taken from.
+    pub schema_version: u32,
+    pub created_at: String,
+    /// Whether the keys were left out.
+    pub redacted: bool,
+    pub tables: BTreeMap<String, SnapshotTable>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    /// Includes the request, renewal, overage and alert logs.
+    pub logs: bool,
+    /// Empties subscriber and consumer keys, webhook secrets and transform
+    /// rule values.
+    pub redact: bool,
+}
+
+/// Every problem preventing an export or a restore.
+#[derive(Debug, PartialEq)]
+pub struct SnapshotError(pub Vec<String>);
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+pub type SnapshotResult<T> = Result<T, SnapshotError>;
+
+fn error(message: String) -> SnapshotError {
+    SnapshotError(vec![message])
+}
+
+fn find<'a>(contents: &'a [(&str, String)], table: &str) -> Option<&'a str> {
+    contents
+        .iter()
+        .find(|(name, _)| *name == table)
+        .map(|(_, content)| content.as_str())
+}
+
+fn header(content: &str) -> Option<Vec<String>> {
+    match content.trim().is_empty() {
+        true => None,
+        false => Some(get_column_names(content)),
+    }
+}
+
+impl Snapshot {
+    /// Takes the `contents` of tables by name, at `schema_version`.
+    pub fn build(
+        contents: &[(&str, String)],
+        schema_version: u32,
+        options: ExportOptions,
+    ) -> SnapshotResult<Snapshot> {
+        let logs: &[&str] = match options.logs {
+            true => LOG_TABLES,
+            false => &[],
+        };
+        let mut tables = BTreeMap::new();
+        for table in TABLES.iter().chain(logs) {
+            let content = find(contents, table).unwrap_or_default();
+            let columns = header(content).ok_or_else(|| error(format!("{table} has no header")))?;
+            let mut rows = read_from_string(content)
+                .into_iter()
+                .map(|record| record.into_iter().collect::<BTreeMap<String, String>>())
+                .collect::<Vec<_>>();
+            if options.redact {
+                for (_, column) in SECRETS.iter().filter(|(name, _)| name == table) {
+                    for row in rows.iter_mut() {
+                        if let Some(value) = row.get_mut(*column) {
+                            value.clear();
+                        }
+                    }
+                }
+            }
+            tables.insert(table.to_string(), SnapshotTable { columns, rows });
+        }
+
+        Ok(Snapshot {
+            format: SNAPSHOT_FORMAT,
+            schema_version,
+            created_at: format_date(&Utc::now().naive_utc()),
+            redacted: options.redact,
+            tables,
+        })
+    }
+
+    /// Returns the table contents to write over the `current` ones, after
+    /// checking that the snapshot fits tables at `schema_version` and that
+    /// the result is consistent.
+    pub fn restore(
+        &self,
+        schema_version: u32,
+        current: &[(&str, String)],
+    ) -> SnapshotResult<Vec<(String, String)>> {
+        if self.format != SNAPSHOT_FORMAT {
+            return Err(error(format!(
+                "Snapshot format {} is not supported, expected {SNAPSHOT_FORMAT}",
+                self.format
+            )));
+        }
+        if self.schema_version != schema_version {
+            return Err(error(format!(
+                "Snapshot is at schema version {}, the tables at {schema_version}",
+                self.schema_version
+            )));
+        }
+
+        let mut errors = vec![];
+        for table in self.tables.keys() {
+            if !TABLES.contains(&table.as_str()) && !LOG_TABLES.contains(&table.as_str()) {
+                errors.push(format!("{table} can't be restored"));
+            }
+        }
+        let mut restored = vec![];
+        for table in TABLES.iter().chain(LOG_TABLES) {
+            let content = match self.tables.get(*table) {
+                Some(snapshot) => match to_content(table, snapshot) {
+                    Ok(content) => content,
+                    Err(e) => {
+                        errors.extend(e);
+                        continue;
+                    }
+                },
+                None if TABLES.contains(table) => {
+                    errors.push(format!("{table} is missing"));
+                    continue;
+                }
+                None => match find(current, table).and_then(header) {
+                    Some(columns) => to_table_string(&columns, &[]),
+                    None => {
+                        errors.push(format!("{table} has no header to keep"));
+                        continue;
+                    }
+                },
+            };
+            restored.push((table.to_string(), content));
+        }
+        if !errors.is_empty() {
+            return Err(SnapshotError(errors));
+        }
+
+        let mut checked = restored
+            .iter()
+            .map(|(table, content)| (table.as_str(), content.clone()))
+            .collect::<Vec<(&str, String)>>();
+        for table in ["admins", VERSIONS_TABLE] {
+            if let Some(content) = find(current, table) {
+                checked.push((table, content.to_string()));
+            }
+        }
+        let problems = integrity::check(&checked);
+        match problems.is_empty() {
+            true => Ok(restored),
+            false => Err(SnapshotError(
+                problems.iter().map(|problem| problem.to_string()).collect(),
+            )),
+        }
+    }
+}
+
+fn to_content(table: &str, snapshot: &SnapshotTable) -> Result<String, Vec<String>> {
+    let mut errors = vec![];
+    for (i, row) in snapshot.rows.iter().enumerate() {
+        for (column, value) in row {
+            if !snapshot.columns.contains(column) {
+                errors.push(format!("{table} row {}: `{column}` is not a column", i + 1));
+            }
+            if value.contains([',', '\n', '\r']) {
+                errors.push(format!(
+                    "{table} row {}: `{column}` can't hold commas or line breaks",
+                    i + 1
+                ));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let items = snapshot
+        .rows
+        .iter()
+        .map(|row| row.clone().into_iter().collect())
+        .collect::<Vec<_>>();
+    Ok(to_table_string(&snapshot.columns, &items))
+}
+
+fn read_storage(tables: &[&'static str]) -> SnapshotResult<Vec<(&'static str, String)>> {
+    tables
+        .iter()
+        .map(|table| {
+            let path = table_path(table);
+            fs::read_to_string(&path)
+                .map(|content| (*table, content))
+                .map_err(|e| error(format!("{} can't be read: {e}", path.display())))
+        })
+        .collect()
+}
+
+fn schema_version() -> SnapshotResult<u32> {
+    FlatStore::open()
+        .version()
+        .map_err(|e| error(e.to_string()))
+}
+
+/// Takes a snapshot of the configured storage.
+pub fn export_storage(options: ExportOptions) -> SnapshotResult<Snapshot> {
+    let mut tables = TABLES.to_vec();
+    if options.logs {
+        tables.extend(LOG_TABLES);
+    }
+    Snapshot::build(&read_storage(&tables)?, schema_version()?, options)
+}
+
+/// Replaces the configured storage's tables with `snapshot`, returning the
+/// restored tables. With `dry_run`, only checks that it could.
+pub fn restore_storage(snapshot: &Snapshot, dry_run: bool) -> SnapshotResult<Vec<String>> {
+    let mut tables = LOG_TABLES.to_vec();
+    tables.extend(["admins", VERSIONS_TABLE]);
+    let restored = snapshot.restore(schema_version()?, &read_storage(&tables)?)?;
+    if !dry_run {
+        for (table, content) in &restored {
+            let path = table_path(table);
+            fs::write(&path, content)
+                .map_err(|e| error(format!("{} can't be written: {e}", path.display())))?;
+        }
+    }
+    Ok(restored.into_iter().map(|(table, _)| table).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents() -> Vec<(&'static str, String)> {
+        let tables = [
+            ("plans", "id, name, price, quota, period\n1, Startup 500, 10000, 500, monthly\n"),
+            (
+                "subscriptions",
+                "id, name, status, price, quota, expiry_date, auto_renew, plan\n\
+                 1, Startup, 1, 10000, 50, 2030-01-01 00:00:00, false, 1\n",
+            ),
+            (
+                "subscribers",
+                "id, name, subscription, access_token\n1, Subscriber A, 1, sub-1\n",
+            ),
+            ("consumers", "id, subscriber, access_token\n1, 1, key-1\n"),
+            ("products", "id, slug, requests\n1, product_a, 0\n"),
+            (
+                "services",
+                "id, name, slug, version, status, base_url, price, requests, product\n\
+                 1, Service A, service_a, v1.0.0, 1, http://127.0.0.1:8001/, 2, 0, 1\n",
+            ),
+            ("pricing_rules", "id, service, kind, param, price\n"),
+            (
+                "transform_rules",
+                "id, service, kind, param, value\n\
+                 1, 1, set_request_header, Authorization, Bearer upstream-secret\n",
+            ),
+            (
+                "ledger",
+                "id, subscription, kind, amount, reason, request, created_at\n\
+                 1, 1, credit, 50, opening balance, , 2024-01-01 00:00:00\n",
+            ),
+            (
+                "requests",
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service\n\
+                 r-1, product_a, service_a, v1.0.0, /, 200, 2, 1, 1\n",
+            ),
+            (
+                "renewals",
+                "id, subscription, plan, quota, renewed_at, expiry_date\n",
+            ),
+            (
+                "overages",
+                "id, subscription, request, tokens, price, created_at\n",
+            ),
+            (
+                "alerts",
+                "id, subscriber, subscription, kind, period, status, attempts, next_attempt_at, created_at\n",
+            ),
+            ("budget_usage", "id, consumer, period, tokens\n"),
+            (
+                "idempotency_keys",
+                "id, consumer, key, fingerprint, status, headers, body, created_at\n",
+            ),
+            (
+                "sandbox_requests",
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service\n",
+            ),
+            (
+                "jobs",
+                "id, consumer, service, request, method, url, headers, body, callback_url, status, response_status, response_headers, response_body, price, reserved, reserved_overage, created_at, completed_at\n\
+                 1, 1, 1, r-2, POST, 687474703a2f2f3132372e302e302e313a383030312f7265706f727473, 5b5b22417574686f72697a6174696f6e222c2242656172657220757073747265616d2d736563726574225d5d, , , queued, , , , 0, 2, 0, 2024-01-01 00:00:00, \n",
+            ),
+        ];
+        tables
+            .iter()
+            .map(|(table, content)| (*table, content.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn restore_exported_tables() {
+        let snapshot = Snapshot::build(&contents(), 3, ExportOptions::default()).unwrap();
+        let json = rocket::serde::json::to_string(&snapshot).unwrap();
+        let snapshot = rocket::serde::json::from_str::<Snapshot>(&json).unwrap();
+
+        let restored = snapshot.restore(3, &contents()).unwrap();
+
+        assert_eq!(
+            restored[3],
+            ("consumers".to_string(), contents()[3].1.clone())
+        );
+        // logs left out of the snapshot are emptied
+        assert_eq!(
+            restored[9],
+            (
+                "requests".to_string(),
+                "id, product_slug, service_slug, service_version, url, status, price, consumer, service\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(restored.len(), 17);
+    }
+
+    #[test]
+    fn redact_keys() {
+        let options = ExportOptions {
+            logs: true,
+            redact: true,
+        };
+        let snapshot = Snapshot::build(&contents(), 3, options).unwrap();
+
+        assert!(snapshot.redacted);
+        assert_eq!(snapshot.tables["subscribers"].rows[0]["access_token"], "");
+        assert_eq!(snapshot.tables["consumers"].rows[0]["access_token"], "");
+        assert_eq!(snapshot.tables["transform_rules"].rows[0]["value"], "");
+        assert_eq!(
+            snapshot.tables["transform_rules"].rows[0]["param"],
+            "Authorization"
+        );
+        assert_eq!(
+            snapshot.tables["subscribers"].rows[0]["name"],
+            "Subscriber A"
+        );
+        assert_eq!(snapshot.tables["jobs"].rows[0]["headers"], "");
+        assert_eq!(snapshot.tables["requests"].rows.len(), 1);
+    }
+
+    #[test]
+    fn reject_inconsistent_snapshot() {
+        let mut snapshot = Snapshot::build(&contents(), 3, ExportOptions::default()).unwrap();
+        snapshot.tables.get_mut("consumers").unwrap().rows[0]
+            .insert("subscriber".to_string(), "9".to_string());
+
+        assert_eq!(
+            snapshot.restore(3, &contents()),
+            Err(SnapshotError(vec![
+                "consumers line 2: `subscriber`: subscribers with id:9 does not exist".to_string()
+            ]))
+        );
+        assert!(snapshot.restore(4, &contents()).is_err());
+    }
+}
@@ -1,19 +1,58 @@
 use std::{collections::HashMap, sync::Mutex};
 
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
 use crate::db::file_db::{get_table_instance, FlatTable};
+use crate::plan::{plan_list::PlanList, Plan};
 
 use self::subscriber_list::SubscriptionList;
 
 pub mod subscriber_list;
-#[derive(Debug, Clone)]
+
+/// Format of the dates stored in the tables.
+pub const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+pub fn parse_date(value: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(value.trim(), DATE_FORMAT).ok()
+}
+
+pub fn format_date(date: &NaiveDateTime) -> String {
+    date.format(DATE_FORMAT).to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionStatus {
+    Pending = 0,
+    Active = 1,
+    Suspended = 2,
+    Cancelled = 3,
+}
+
+impl SubscriptionStatus {
+    pub fn from_u8(value: u8) -> Option<SubscriptionStatus> {
+        match value {
+            0 => Some(SubscriptionStatus::Pending),
+            1 => Some(SubscriptionStatus::Active),
+            2 => Some(SubscriptionStatus::Suspended),
+            3 => Some(SubscriptionStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 
 pub struct Subscription {
     pub id: u128,
     pub name: String,
-    pub status: u8,
+    pub status: SubscriptionStatus,
     pub price: u128,
     pub quota: u128,
-    pub expiry_date: String,
+    pub expiry_date: NaiveDateTime,
+    pub auto_renew: bool,
+    pub plan: Plan,
 }
 
 impl Subscription {
@@ -21,16 +60,42 @@ impl Subscription {
         Subscription {
             id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
             name: attr.get("name").unwrap_or(&"default_service").to_string(),
-            status: attr.get("status").unwrap_or(&"0").parse::<u8>().unwrap(),
+            status: SubscriptionStatus::from_u8(
+                attr.get("status").unwrap_or(&"1").parse::<u8>().unwrap(),
+            )
+            .unwrap(),
             price: attr.get("price").unwrap_or(&"1").parse::<u128>().unwrap(),
             quota: attr.get("quota").unwrap_or(&"1").parse::<u128>().unwrap(),
-            expiry_date: attr
-                .get("expiry_date")
-                .unwrap_or(&"2001-01-01 00:00:00")
-                .to_string(),
+            expiry_date: parse_date(attr.get("expiry_date").unwrap_or(&"2001-01-01 00:00:00"))
+                .unwrap(),
+            auto_renew: attr
+                .get("auto_renew")
+                .unwrap_or(&"false")
+                .parse::<bool>()
+                .unwrap(),
+            plan: match attr.get("plan") {
+                Some(plan_id) => Plan::fake(&HashMap::from([("id", *plan_id)])),
+                None => Plan::fake(&HashMap::new()),
+            },
         }
     }
 
+    pub fn fetch_plan(db: Mutex<FlatTable<String, String>>, plan_id: u128) -> Plan {
+        let plan_list = PlanList::new(db);
+        plan_list
+            .get_by_id(plan_id)
+            .unwrap_or_else(|| panic!("Plan with id:{plan_id} is not found!"))
+    }
+
+    /// Returns when the current billing period started.
+    pub fn period_start(&self) -> NaiveDateTime {
+        self.plan.period.previous(self.expiry_date)
+    }
+
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        self.expiry_date <= now
+    }
+
     pub fn decrease_quota(&mut self, amount: u128) -> Option<()> {
         if amount > self.quota {
             return None;
@@ -47,19 +112,28 @@ impl Subscription {
         Some(())
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 
 pub struct Subscriber {
     pub id: u128,
     pub name: String,
+    pub access_token: String,
+    /// Where alerts are posted, empty when none is configured.
+    pub webhook_url: String,
+    /// Key signing the webhook payloads.
+    #[serde(skip)]
+    pub webhook_secret: String,
     pub subscription: Subscription,
 }
 
 impl Subscriber {
-    pub fn new(id: u128, name: String, subscription_id: u128) -> Subscriber {
+    pub fn new(id: u128, name: String, access_token: String, subscription_id: u128) -> Subscriber {
         Subscriber {
             id,
             name,
+            access_token,
+            webhook_url: String::new(),
+            webhook_secret: String::new(),
             subscription: Subscriber::fetch_subscription(
                 get_table_instance("subscriptions"),
                 subscription_id,
@@ -70,6 +144,9 @@ impl Subscriber {
         Subscriber {
             id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
             name: attr.get("name").unwrap_or(&"default_service").to_string(),
+            access_token: attr.get("access_token").unwrap_or(&"S-B-C").to_string(),
+            webhook_url: attr.get("webhook_url").unwrap_or(&"").to_string(),
+            webhook_secret: attr.get("webhook_secret").unwrap_or(&"").to_string(),
             subscription: match attr.get("subscription") {
                 Some(subscription_id) => {
                     Subscription::fake(&HashMap::from([("id", *subscription_id)]))
@@ -94,7 +171,7 @@ impl Subscriber {
 mod tests {
     use std::collections::HashMap;
 
-    use super::{Subscriber, Subscription};
+    use super::{parse_date, Subscriber, Subscription, SubscriptionStatus};
 
     #[test]
     fn test_fetching_subscription() {
@@ -128,6 +205,24 @@ mod tests {
         assert_eq!(subscription.quota, 1)
     }
 
+    #[test]
+    fn check_expiry() {
+        let subscription =
+            Subscription::fake(&HashMap::from([("expiry_date", "2023-05-01 12:00:00")]));
+
+        assert!(!subscription.is_expired(parse_date("2023-05-01 11:59:59").unwrap()));
+        assert!(subscription.is_expired(parse_date("2023-05-01 12:00:00").unwrap()));
+    }
+
+    #[test]
+    fn parse_status() {
+        assert_eq!(
+            SubscriptionStatus::from_u8(2),
+            Some(SubscriptionStatus::Suspended)
+        );
+        assert_eq!(SubscriptionStatus::from_u8(9), None);
+    }
+
     #[test]
     fn add_quota() {
         let mut subscription: Subscription = Subscription::fake(&HashMap::from([("quota", "1")]));
@@ -1,22 +1,23 @@
-use std::{sync::Mutex};
+use std::sync::{Arc, Mutex};
 
 use crate::db::{
     file_db::{get_table_instance, FlatTable},
-    ModelAble, Record,
+    Filter, ModelAble, Query, Record, Searchable, Writable,
 };
 
-use super::{Subscriber, Subscription};
+use super::{parse_date, Subscriber, Subscription, SubscriptionStatus};
 
 pub type FlatSubscriptionList = SubscriptionList<FlatTable<String, String>>;
+#[derive(Clone)]
 pub struct SubscriptionList<D> {
-    db: Mutex<D>,
+    db: Arc<Mutex<D>>,
     pub subscriptions: Vec<Subscription>,
 }
 
 impl FlatSubscriptionList {
     pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
         SubscriptionList {
-            db,
+            db: Arc::new(db),
             subscriptions: vec![],
         }
     }
@@ -28,46 +29,127 @@ impl FlatSubscriptionList {
             id.to_string(),
         )
     }
+
+    pub fn get_by_plan(&self, plan_id: u128) -> Option<Subscription> {
+        SubscriptionList::get_by_attr::<FlatTable<String, String>, Subscription>(
+            &self.db,
+            "plan",
+            plan_id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<Subscription> {
+        SubscriptionList::get_all::<FlatTable<String, String>, Subscription>(&self.db)
+    }
+
+    /// The table as it would be written to its file.
+    pub fn contents(&self) -> String {
+        self.db.lock().expect("lock db").contents()
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Subscription> {
+        SubscriptionList::search_records::<FlatTable<String, String>, Subscription>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        SubscriptionList::count_records(&self.db, filter)
+    }
+
+    pub fn create(&self, mut record: Record<String, String>) -> Subscription {
+        SubscriptionList::insert_next::<FlatTable<String, String>, Subscription>(&self.db, |id| {
+            record.insert("id".to_string(), id.to_string());
+            record
+        })
+    }
+
+    pub fn update(&self, id: u128, changes: Record<String, String>) -> Option<Subscription> {
+        SubscriptionList::update_by_attr::<FlatTable<String, String>, Subscription>(
+            &self.db,
+            "id",
+            id.to_string(),
+            changes,
+        )
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        SubscriptionList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
+
+    /// Runs `change` on the current quota of subscription `id` and stores what
+    /// it leaves. Reading, changing and writing happen under one lock, so
+    /// concurrent changes apply one after the other. Returns `None` when there
+    /// is no such subscription.
+    pub fn update_quota<T>(&self, id: u128, change: impl FnOnce(&mut u128) -> T) -> Option<T> {
+        let mut db = self.db.lock().expect("lock db");
+        let mut quota = db
+            .find_by("id", &id.to_string())?
+            .get("quota")?
+            .parse::<u128>()
+            .ok()?;
+        let before = quota;
+        let result = change(&mut quota);
+        if quota != before {
+            let changes = Record::from([("quota".to_string(), quota.to_string())]);
+            db.update_by("id", &id.to_string(), changes);
+        }
+        Some(result)
+    }
 }
 
 impl ModelAble<String, String> for FlatSubscriptionList {}
 
 impl From<Record<String, String>> for Subscription {
     fn from(map: Record<String, String>) -> Self {
-        return match (
+        match (
             map.get("id"),
             map.get("name"),
             map.get("status"),
             map.get("price"),
             map.get("quota"),
             map.get("expiry_date"),
+            map.get("auto_renew"),
+            map.get("plan"),
         ) {
-            (Some(id), Some(name), Some(status), Some(price), Some(quota), Some(expiry_date)) => {
-                Subscription {
-                    id: id.parse::<u128>().unwrap(),
-                    name: name.clone(),
-                    status: status.parse::<u8>().unwrap(),
-                    price: price.parse::<u128>().unwrap(),
-                    quota: quota.parse::<u128>().unwrap(),
-                    expiry_date: expiry_date.clone(),
-                }
-            }
+            (
+                Some(id),
+                Some(name),
+                Some(status),
+                Some(price),
+                Some(quota),
+                Some(expiry_date),
+                Some(auto_renew),
+                Some(plan_id),
+            ) => Subscription {
+                id: id.parse::<u128>().unwrap(),
+                name: name.clone(),
+                status: SubscriptionStatus::from_u8(status.parse::<u8>().unwrap())
+                    .expect("Invalid subscription status"),
+                price: price.parse::<u128>().unwrap(),
+                quota: quota.parse::<u128>().unwrap(),
+                expiry_date: parse_date(expiry_date).expect("Invalid expiry date"),
+                auto_renew: auto_renew.parse::<bool>().unwrap(),
+                plan: Subscription::fetch_plan(
+                    get_table_instance("plans"), // this is not testable
+                    plan_id.parse::<u128>().unwrap(),
+                ),
+            },
             _ => panic!("Can't convert! Invalid Structure. "),
-        };
+        }
     }
 }
 
 pub type FlatSubscriberList = SubscriberList<FlatTable<String, String>>;
 
+#[derive(Clone)]
 pub struct SubscriberList<D> {
-    db: Mutex<D>,
+    db: Arc<Mutex<D>>,
     pub subscribers: Vec<Subscriber>,
 }
 
 impl FlatSubscriberList {
     pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
         SubscriberList {
-            db,
+            db: Arc::new(db),
             subscribers: vec![],
         }
     }
@@ -79,23 +161,84 @@ impl FlatSubscriberList {
             id.to_string(),
         )
     }
+
+    pub fn get_by_access_token(&self, access_token: &str) -> Option<Subscriber> {
+        SubscriberList::get_by_attr::<FlatTable<String, String>, Subscriber>(
+            &self.db,
+            "access_token",
+            access_token.to_string(),
+        )
+    }
+
+    pub fn get_by_subscription(&self, subscription_id: u128) -> Option<Subscriber> {
+        SubscriberList::get_by_attr::<FlatTable<String, String>, Subscriber>(
+            &self.db,
+            "subscription",
+            subscription_id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<Subscriber> {
+        SubscriberList::get_all::<FlatTable<String, String>, Subscriber>(&self.db)
+    }
+
+    /// The table as it would be written to its file.
+    pub fn contents(&self) -> String {
+        self.db.lock().expect("lock db").contents()
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<Subscriber> {
+        SubscriberList::search_records::<FlatTable<String, String>, Subscriber>(&self.db, query)
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        SubscriberList::count_records(&self.db, filter)
+    }
+
+    pub fn create(&self, name: String, access_token: String, subscription_id: u128) -> Subscriber {
+        SubscriberList::insert_next::<FlatTable<String, String>, Subscriber>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("name".to_string(), name),
+                ("access_token".to_string(), access_token),
+                ("subscription".to_string(), subscription_id.to_string()),
+            ])
+        })
+    }
+
+    pub fn update(&self, id: u128, changes: Record<String, String>) -> Option<Subscriber> {
+        SubscriberList::update_by_attr::<FlatTable<String, String>, Subscriber>(
+            &self.db,
+            "id",
+            id.to_string(),
+            changes,
+        )
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        SubscriberList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
 }
 
 impl ModelAble<String, String> for FlatSubscriberList {}
 
 impl From<Record<String, String>> for Subscriber {
     fn from(map: Record<String, String>) -> Self {
-        return match (map.get("id"), map.get("name"), map.get("subscription")) {
+        match (map.get("id"), map.get("name"), map.get("subscription")) {
             (Some(id), Some(name), Some(subscription_id)) => Subscriber {
                 id: id.parse::<u128>().unwrap(),
                 name: name.clone(),
+                // older tables have no subscriber credentials
+                access_token: map.get("access_token").cloned().unwrap_or_default(),
+                webhook_url: map.get("webhook_url").cloned().unwrap_or_default(),
+                webhook_secret: map.get("webhook_secret").cloned().unwrap_or_default(),
                 subscription: Subscriber::fetch_subscription(
                     get_table_instance("subscriptions"), // this is not testable
                     subscription_id.parse::<u128>().unwrap(),
                 ),
             },
             _ => panic!("Can't convert!"),
-        };
+        }
     }
 }
 
@@ -123,14 +266,32 @@ mod tests {
         assert_eq!(subscriber.id, id)
     }
 
+    #[test]
+    fn get_subscriber_by_access_token() {
+        let table = "\
+        id, name, subscription, access_token
+        1, Subscriber A, 1, sub-1
+        2, Subscriber B, 2, sub-2
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let subscriber_list = SubscriberList::new(db);
+
+        let subscriber = subscriber_list.get_by_access_token("sub-2").unwrap();
+
+        assert_eq!(subscriber.id, 2);
+        assert_eq!(subscriber.access_token, "sub-2")
+    }
+
     #[test]
     fn get_subscription_by_id() {
         let id: u128 = 2;
 
         let table = "\
-        id, name, status, price, quota, expiry_date
-        1, Startup 500, 1, 10000, 50, 2022-10-01 00:00:00
-        2, Golden 50, 2, 50000, 10, 2022-10-01 00:00:00
+        id, name, status, price, quota, expiry_date, auto_renew, plan
+        1, Startup 500, 1, 10000, 50, 2022-10-01 00:00:00, true, 1
+        2, Golden 50, 2, 50000, 10, 2022-10-01 00:00:00, false, 2
         "
         .to_string();
 
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde::Serialize;
+
+pub mod transform_list;
+
+/// Request headers that authenticate with the gateway itself, so they are
+/// never relayed upstream.
+const CREDENTIALS: [&str; 3] = ["x-api-key", "x-subscriber-key", "x-admin-key"];
+
+pub const CONSUMER_ID: &str = "X-Consumer-Id";
+pub const SUBSCRIBER_ID: &str = "X-Subscriber-Id";
+pub const REQUEST_ID: &str = "X-Request-Id";
+
+/// What a transform rule changes between the consumer and the upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformKind {
+    /// Removes the `param` header from the request.
+    RemoveRequestHeader,
+    /// Sets the `param` request header to `value`, e.g. the upstream's own
+    /// credentials.
+    SetRequestHeader,
+    /// Replaces the `param` prefix of the upstream path with `value`.
+    RewritePathPrefix,
+    /// Replaces what the `param` pattern matches in the upstream path with
+    /// `value`, which can refer to groups as `$1`.
+    RewritePathRegex,
+    /// Removes the `param` header from the response.
+    RemoveResponseHeader,
+    /// Sets the `param` response header to `value`.
+    SetResponseHeader,
+}
+
+impl TransformKind {
+    pub fn parse(value: &str) -> Option<TransformKind> {
+        match value {
+            "remove_request_header" => Some(TransformKind::RemoveRequestHeader),
+            "set_request_header" => Some(TransformKind::SetRequestHeader),
+            "rewrite_path_prefix" => Some(TransformKind::RewritePathPrefix),
+            "rewrite_path_regex" => Some(TransformKind::RewritePathRegex),
+            "remove_response_header" => Some(TransformKind::RemoveResponseHeader),
+            "set_response_header" => Some(TransformKind::SetResponseHeader),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransformKind::RemoveRequestHeader => "remove_request_header",
+            TransformKind::SetRequestHeader => "set_request_header",
+            TransformKind::RewritePathPrefix => "rewrite_path_prefix",
+            TransformKind::RewritePathRegex => "rewrite_path_regex",
+            TransformKind::RemoveResponseHeader => "remove_response_header",
+            TransformKind::SetResponseHeader => "set_response_header",
+        }
+    }
+}
+
+/// A change a service's calls go through on their way to the upstream or
+/// back. Rules apply in the order they were added.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransformRule {
+    pub id: u128,
+    pub service_id: u128,
+    pub kind: TransformKind,
+    pub param: String,
+    pub value: String,
+}
+
+impl TransformRule {
+    pub fn fake(attr: &HashMap<&str, &str>) -> TransformRule {
+        TransformRule {
+            id: attr.get("id").unwrap_or(&"1").parse::<u128>().unwrap(),
+            service_id: attr.get("service").unwrap_or(&"1").parse::<u128>().unwrap(),
+            kind: TransformKind::parse(attr.get("kind").unwrap_or(&"set_request_header")).unwrap(),
+            param: attr.get("param").unwrap_or(&"X-Upstream-Key").to_string(),
+            value: attr.get("value").unwrap_or(&"secret").to_string(),
+        }
+    }
+}
+
+/// Who a proxied call is made for, told to the upstream in identity headers.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller<'a> {
+    pub consumer_id: u128,
+    pub subscriber_id: u128,
+    pub request_id: &'a str,
+}
+
+pub(crate) fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Checks that `param` and `value` make sense for a rule of `kind`.
+pub fn validate(kind: TransformKind, param: &str, value: &str) -> Result<(), String> {
+    match kind {
+        TransformKind::RemoveRequestHeader
+        | TransformKind::SetRequestHeader
+        | TransformKind::RemoveResponseHeader
+        | TransformKind::SetResponseHeader
+            if !is_header_name(param) =>
+        {
+            Err("`param` should be a header name".to_string())
+        }
+        TransformKind::SetRequestHeader | TransformKind::SetResponseHeader if value.is_empty() => {
+            Err("`value` can't be empty".to_string())
+        }
+        TransformKind::RewritePathPrefix if !param.starts_with('/') => {
+            Err("`param` should be a path starting with `/`".to_string())
+        }
+        TransformKind::RewritePathRegex => Regex::new(param)
+            .map(|_| ())
+            .map_err(|_| "`param` should be a valid regular expression".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn remove(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+}
+
+fn set(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    remove(headers, name);
+    headers.push((name.to_string(), value.to_string()));
+}
+
+/// Returns the headers sent upstream: without the gateway's credentials,
+/// changed by `rules` and telling who the caller is. Identity headers sent by
+/// the consumer are replaced, so they can be trusted.
+pub fn request_headers(
+    rules: &[TransformRule],
+    mut headers: Vec<(String, String)>,
+    caller: Caller,
+) -> Vec<(String, String)> {
+    for name in CREDENTIALS {
+        remove(&mut headers, name);
+    }
+    for rule in rules {
+        match rule.kind {
+            TransformKind::RemoveRequestHeader => remove(&mut headers, &rule.param),
+            TransformKind::SetRequestHeader => set(&mut headers, &rule.param, &rule.value),
+            _ => {}
+        }
+    }
+    set(&mut headers, CONSUMER_ID, &caller.consumer_id.to_string());
+    set(
+        &mut headers,
+        SUBSCRIBER_ID,
+        &caller.subscriber_id.to_string(),
+    );
+    set(&mut headers, REQUEST_ID, caller.request_id);
+    headers
+}
+
+/// Returns the upstream path, starting with `/`, rewritten by `rules`.
+pub fn path(rules: &[TransformRule], path: &str) -> String {
+    let mut path = format!("/{}", path.trim_start_matches('/'));
+    for rule in rules {
+        match rule.kind {
+            TransformKind::RewritePathPrefix => {
+                if let Some(rest) = path.strip_prefix(&rule.param) {
+                    path = format!("{}{rest}", rule.value);
+                }
+            }
+            TransformKind::RewritePathRegex => {
+                if let Ok(pattern) = Regex::new(&rule.param) {
+                    path = pattern.replace_all(&path, rule.value.as_str()).to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+    path
+}
+
+/// Changes the headers of an upstream response by `rules`.
+pub fn response_headers(rules: &[TransformRule], headers: &mut Vec<(String, String)>) {
+    for rule in rules {
+        match rule.kind {
+            TransformKind::RemoveResponseHeader => remove(headers, &rule.param),
+            TransformKind::SetResponseHeader => set(headers, &rule.param, &rule.value),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: &str, param: &str, value: &str) -> TransformRule {
+        TransformRule::fake(&HashMap::from([
+            ("kind", kind),
+            ("param", param),
+            ("value", value),
+        ]))
+    }
+
+    fn header(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn strip_credentials_and_tell_caller() {
+        let rules = vec![
+            rule("remove_request_header", "Cookie", ""),
+            rule("set_request_header", "Authorization", "Bearer upstream"),
+        ];
+        let caller = Caller {
+            consumer_id: 3,
+            subscriber_id: 2,
+            request_id: "r-1",
+        };
+
+        let headers = request_headers(
+            &rules,
+            vec![
+                header("x-api-key", "key-1"),
+                header("Cookie", "session=1"),
+                header("Authorization", "Bearer consumer"),
+                header("X-Consumer-Id", "1"),
+                header("Accept", "application/json"),
+            ],
+            caller,
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                header("Accept", "application/json"),
+                header("Authorization", "Bearer upstream"),
+                header(CONSUMER_ID, "3"),
+                header(SUBSCRIBER_ID, "2"),
+                header(REQUEST_ID, "r-1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrite_path_in_order() {
+        let rules = vec![
+            rule("rewrite_path_prefix", "/users", "/v2/accounts"),
+            rule(
+                "rewrite_path_regex",
+                "^/v2/accounts/([0-9]+)$",
+                "/v2/accounts/$1/profile",
+            ),
+        ];
+
+        assert_eq!(path(&rules, "users/7"), "/v2/accounts/7/profile");
+        assert_eq!(path(&rules, "orders/7"), "/orders/7");
+    }
+
+    #[test]
+    fn change_response_headers() {
+        let rules = vec![
+            rule("remove_response_header", "Server", ""),
+            rule("set_response_header", "Cache-Control", "no-store"),
+        ];
+        let mut headers = vec![
+            header("server", "nginx"),
+            header("Content-Type", "text/plain"),
+        ];
+
+        response_headers(&rules, &mut headers);
+
+        assert_eq!(
+            headers,
+            vec![
+                header("Content-Type", "text/plain"),
+                header("Cache-Control", "no-store"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_rules() {
+        assert!(validate(TransformKind::SetRequestHeader, "X Key", "1").is_err());
+        assert!(validate(TransformKind::SetResponseHeader, "X-Key", "").is_err());
+        assert!(validate(TransformKind::RewritePathPrefix, "users", "/v2").is_err());
+        assert!(validate(TransformKind::RewritePathRegex, "([0-9]", "").is_err());
+        assert!(validate(TransformKind::RemoveRequestHeader, "Cookie", "").is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::{Arc, Mutex};
+
+use crate::db::{file_db::FlatTable, Filter, ModelAble, Query, Record};
+
+use super::{TransformKind, TransformRule};
+
+#[derive(Clone)]
+pub struct TransformRuleList<D> {
+    db: Arc<Mutex<D>>,
+    pub rules: Vec<TransformRule>,
+}
+
+pub type FlatTransformRuleList = TransformRuleList<FlatTable<String, String>>;
+
+impl FlatTransformRuleList {
+    pub fn new(db: Mutex<FlatTable<String, String>>) -> Self {
+        TransformRuleList {
+            db: Arc::new(db),
+            rules: vec![],
+        }
+    }
+
+    pub fn get_by_id(&self, id: u128) -> Option<TransformRule> {
+        TransformRuleList::get_by_attr::<FlatTable<String, String>, TransformRule>(
+            &self.db,
+            "id",
+            id.to_string(),
+        )
+    }
+
+    pub fn list(&self) -> Vec<TransformRule> {
+        TransformRuleList::get_all::<FlatTable<String, String>, TransformRule>(&self.db)
+    }
+
+    pub fn filter(&self, query: &Query) -> Vec<TransformRule> {
+        TransformRuleList::search_records::<FlatTable<String, String>, TransformRule>(
+            &self.db, query,
+        )
+    }
+
+    pub fn count(&self, filter: Option<&Filter>) -> usize {
+        TransformRuleList::count_records(&self.db, filter)
+    }
+
+    pub fn list_by_service(&self, service_id: u128) -> Vec<TransformRule> {
+        self.list()
+            .into_iter()
+            .filter(|rule| rule.service_id == service_id)
+            .collect()
+    }
+
+    pub fn create(
+        &self,
+        service_id: u128,
+        kind: TransformKind,
+        param: String,
+        value: String,
+    ) -> TransformRule {
+        TransformRuleList::insert_next::<FlatTable<String, String>, TransformRule>(&self.db, |id| {
+            Record::from([
+                ("id".to_string(), id.to_string()),
+                ("service".to_string(), service_id.to_string()),
+                ("kind".to_string(), kind.as_str().to_string()),
+                ("param".to_string(), param),
+                ("value".to_string(), value),
+            ])
+        })
+    }
+
+    pub fn delete(&self, id: u128) -> Option<()> {
+        TransformRuleList::delete_by_attr(&self.db, "id", id.to_string()).map(|_| ())
+    }
+}
+
+impl ModelAble<String, String> for FlatTransformRuleList {}
+
+impl From<Record<String, String>> for TransformRule {
+    fn from(map: Record<String, String>) -> Self {
+        match (
+            map.get("id"),
+            map.get("service"),
+            map.get("kind"),
+            map.get("param"),
+            map.get("value"),
+        ) {
+            (Some(id), Some(service_id), Some(kind), Some(param), Some(value)) => TransformRule {
+                id: id.parse::<u128>().unwrap(),
+                service_id: service_id.parse::<u128>().unwrap(),
+                kind: TransformKind::parse(kind).expect("Invalid transform rule kind"),
+                param: param.clone(),
+                value: value.clone(),
+            },
+            _ => panic!("Can't convert!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_rules_of_service() {
+        let table = "\
+        id, service, kind, param, value
+        1, 1, remove_request_header, Cookie,
+        2, 2, set_request_header, Authorization, Bearer upstream
+        3, 1, rewrite_path_prefix, /users, /v2/users
+        "
+        .to_string();
+
+        let db = Mutex::new(FlatTable::new_from_string(table));
+        let rule_list = TransformRuleList::new(db);
+
+        let rules = rule_list.list_by_service(1);
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].kind, TransformKind::RewritePathPrefix);
+        assert_eq!(rules[1].value, "/v2/users");
+    }
+}